@@ -1,7 +1,14 @@
+use std::env;
+use std::path::PathBuf;
+
 fn main() {
     tonic_build::configure()
         .protoc_arg("--experimental_allow_proto3_optional")
-        .compile_protos(&["protos/hold.proto"], &["protos"])
+        .file_descriptor_set_path(
+            PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR not set"))
+                .join("hold_descriptor.bin"),
+        )
+        .compile_protos(&["protos/hold.proto", "protos/health.proto"], &["protos"])
         .unwrap_or_else(|e| panic!("Could not build protos: {}", e));
 
     built::write_built_file()
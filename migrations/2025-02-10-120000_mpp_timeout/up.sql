@@ -0,0 +1,2 @@
+ALTER TABLE invoices
+    ADD COLUMN mpp_timeout INTEGER;
@@ -0,0 +1,4 @@
+ALTER TABLE invoices
+    DROP COLUMN accepted_at;
+ALTER TABLE invoices
+    DROP COLUMN settlement_deadline;
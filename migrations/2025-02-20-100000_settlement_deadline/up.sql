@@ -0,0 +1,4 @@
+ALTER TABLE invoices
+    ADD COLUMN settlement_deadline INTEGER;
+ALTER TABLE invoices
+    ADD COLUMN accepted_at TIMESTAMP;
@@ -0,0 +1,23 @@
+CREATE TABLE htlcs_old (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    invoice_id INTEGER REFERENCES invoices (id),
+    state TEXT NOT NULL,
+    scid TEXT NOT NULL,
+    channel_id INTEGER NOT NULL,
+    msat INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT INTO htlcs_old SELECT id, invoice_id, state, scid, channel_id, msat, created_at FROM htlcs;
+DROP TABLE htlcs;
+ALTER TABLE htlcs_old RENAME TO htlcs;
+
+CREATE TABLE invoice_state_log_old (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    invoice_id INTEGER NOT NULL REFERENCES invoices (id),
+    old_state TEXT NOT NULL,
+    new_state TEXT NOT NULL,
+    changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT INTO invoice_state_log_old SELECT id, invoice_id, old_state, new_state, changed_at FROM invoice_state_log;
+DROP TABLE invoice_state_log;
+ALTER TABLE invoice_state_log_old RENAME TO invoice_state_log;
@@ -0,0 +1,2 @@
+ALTER TABLE invoices
+    ADD COLUMN cancel_reason TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE invoices
+    ADD COLUMN hash_algorithm TEXT NOT NULL DEFAULT 'sha256';
@@ -0,0 +1,7 @@
+CREATE TABLE invoice_state_log (
+    id BIGSERIAL PRIMARY KEY,
+    invoice_id BIGINT NOT NULL REFERENCES invoices (id),
+    old_state TEXT NOT NULL,
+    new_state TEXT NOT NULL,
+    changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,6 @@
+ALTER TABLE htlcs
+    DROP CONSTRAINT htlcs_invoice_id_fkey,
+    ADD CONSTRAINT htlcs_invoice_id_fkey FOREIGN KEY (invoice_id) REFERENCES invoices (id) ON DELETE CASCADE;
+ALTER TABLE invoice_state_log
+    DROP CONSTRAINT invoice_state_log_invoice_id_fkey,
+    ADD CONSTRAINT invoice_state_log_invoice_id_fkey FOREIGN KEY (invoice_id) REFERENCES invoices (id) ON DELETE CASCADE;
@@ -0,0 +1,3 @@
+DROP INDEX invoices_label_idx;
+ALTER TABLE invoices
+    DROP COLUMN label;
@@ -0,0 +1,3 @@
+ALTER TABLE invoices
+    ADD COLUMN label TEXT;
+CREATE UNIQUE INDEX invoices_label_idx ON invoices (label);
@@ -0,0 +1,2 @@
+ALTER TABLE htlcs
+    DROP COLUMN accepted_at;
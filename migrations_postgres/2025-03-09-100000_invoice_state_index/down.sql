@@ -0,0 +1 @@
+DROP INDEX invoices_state_id_idx;
@@ -0,0 +1 @@
+CREATE INDEX invoices_state_id_idx ON invoices (state, id);
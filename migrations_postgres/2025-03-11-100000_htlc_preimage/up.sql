@@ -0,0 +1,2 @@
+ALTER TABLE htlcs
+    ADD COLUMN preimage BYTEA;
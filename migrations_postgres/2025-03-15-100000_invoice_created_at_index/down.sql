@@ -0,0 +1 @@
+DROP INDEX invoices_created_at_id_idx;
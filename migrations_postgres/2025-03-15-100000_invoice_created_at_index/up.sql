@@ -0,0 +1 @@
+CREATE INDEX invoices_created_at_id_idx ON invoices (created_at, id);
@@ -0,0 +1,133 @@
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::database::model::InvoiceState;
+use std::time::Duration;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone)]
+pub struct Cleaner<T> {
+    invoice_helper: T,
+    interval: Duration,
+    age: u64,
+    // Paid invoices are only cleaned when set
+    paid_age: Option<u64>,
+}
+
+impl<T> Cleaner<T>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+{
+    pub fn new(invoice_helper: T, interval: Duration, age: u64, paid_age: Option<u64>) -> Self {
+        Cleaner {
+            invoice_helper,
+            interval,
+            age,
+            paid_age,
+        }
+    }
+
+    pub async fn start(&self, cancellation_token: CancellationToken) {
+        info!(
+            "Cleaning cancelled invoices older than {} seconds every {} seconds",
+            self.age,
+            self.interval.as_secs()
+        );
+        if let Some(paid_age) = self.paid_age {
+            info!("Cleaning paid invoices older than {} seconds", paid_age);
+        }
+        let mut interval = time::interval(self.interval);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = cancellation_token.cancelled() => {
+                    debug!("Stopping invoice cleaner");
+                    break;
+                }
+            };
+
+            match self
+                .invoice_helper
+                .clean(Some(InvoiceState::Cancelled), Some(self.age), false)
+            {
+                Ok(0) => {}
+                Ok(cleaned) => info!(cleaned, "Cleaned cancelled invoices"),
+                Err(err) => warn!(error = %err, "Could not clean cancelled invoices"),
+            };
+
+            if let Some(paid_age) = self.paid_age {
+                match self.invoice_helper.clean_paid(Some(paid_age)) {
+                    Ok(0) => {}
+                    Ok(cleaned) => info!(cleaned, "Cleaned paid invoices"),
+                    Err(err) => warn!(error = %err, "Could not clean paid invoices"),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cleaner::Cleaner;
+    use crate::database::helpers::invoice_helper::InvoiceHelper;
+    use crate::database::model::{InvoiceInsertable, InvoiceState};
+    use crate::database::test_utils::{invoice_insertable, test_database};
+    use chrono::{TimeDelta, Utc};
+    use diesel::sql_types::{Binary, Timestamp};
+    use diesel::{sql_query, RunQueryDsl};
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn clean_old_cancelled() {
+        let database = test_database("cleaner");
+        let helper = database.helper();
+
+        for (payment_hash, state, age) in [
+            (1, InvoiceState::Cancelled, 7_200),
+            (2, InvoiceState::Cancelled, 7_200),
+            (3, InvoiceState::Cancelled, 60),
+            (4, InvoiceState::Unpaid, 7_200),
+            (5, InvoiceState::Paid, 7_200),
+            (6, InvoiceState::Paid, 60),
+        ] {
+            helper
+                .insert(&InvoiceInsertable {
+                    state: state.into(),
+                    ..invoice_insertable(&[payment_hash])
+                })
+                .unwrap();
+
+            sql_query("UPDATE invoices SET created_at = ? WHERE payment_hash = ?")
+                .bind::<Timestamp, _>(Utc::now().naive_utc() - TimeDelta::seconds(age))
+                .bind::<Binary, _>(vec![payment_hash])
+                .execute(&mut database.pool.get().unwrap())
+                .unwrap();
+        }
+
+        let token = CancellationToken::new();
+        let cleaner = Cleaner::new(helper.clone(), Duration::from_secs(60), 3_600, Some(3_600));
+
+        let cleaner_token = token.clone();
+        let cleaner_thread = tokio::spawn(async move {
+            cleaner.start(cleaner_token).await;
+        });
+
+        // The first tick of the interval is immediate
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        token.cancel();
+        tokio::time::timeout(Duration::from_secs(1), cleaner_thread)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let remaining = helper
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|invoice| invoice.invoice.payment_hash)
+            .collect::<Vec<_>>();
+        assert_eq!(remaining, vec![vec![3], vec![4], vec![6]]);
+    }
+}
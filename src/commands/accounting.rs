@@ -0,0 +1,94 @@
+use crate::commands::structs::{parse_args, FromArr, ParamsError};
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::encoder::InvoiceEncoder;
+use crate::State;
+use chrono::{NaiveDate, NaiveTime, Utc};
+use cln_plugin::Plugin;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Days of the daily breakdown when no start date is set
+const DEFAULT_DAYS: u32 = 30;
+
+#[derive(Debug, Deserialize)]
+struct AccountingRequest {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl FromArr for AccountingRequest {
+    fn from_arr(arr: Vec<Value>) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        // Empty strings are allowed as placeholders for skipped positional arguments
+        let get_str = |index: usize| {
+            arr.get(index)
+                .and_then(|res| res.as_str())
+                .filter(|res| !res.is_empty())
+                .map(|res| res.to_string())
+        };
+
+        Ok(AccountingRequest {
+            from: get_str(0),
+            to: get_str(1),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DailyVolume {
+    date: NaiveDate,
+    volume_msat: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct AccountingResponse {
+    total_volume_msat: u64,
+    volume_by_day: Vec<DailyVolume>,
+}
+
+fn parse_date(date: &str) -> anyhow::Result<NaiveDate> {
+    match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        Ok(res) => Ok(res),
+        Err(err) => Err(ParamsError::InvalidParam(format!(
+            "invalid ISO 8601 date {}: {}",
+            date, err
+        ))
+        .into()),
+    }
+}
+
+pub async fn accounting<T, E>(plugin: Plugin<State<T, E>>, args: Value) -> anyhow::Result<Value>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+    E: InvoiceEncoder + Sync + Send + Clone,
+{
+    let params = parse_args::<AccountingRequest>(args)?;
+    let from = params.from.as_deref().map(parse_date).transpose()?;
+    let to = params.to.as_deref().map(parse_date).transpose()?;
+
+    let helper = &plugin.state().invoice_helper;
+
+    // Both dates are inclusive
+    let total_volume_msat = helper.total_volume_msat(
+        from.map(|from| from.and_time(NaiveTime::MIN)),
+        to.and_then(|to| to.and_hms_micro_opt(23, 59, 59, 999_999)),
+    )?;
+
+    let days = match from {
+        Some(from) => (Utc::now().date_naive() - from).num_days().max(0) as u32 + 1,
+        None => DEFAULT_DAYS,
+    };
+    let volume_by_day = helper
+        .volume_by_day(days)?
+        .into_iter()
+        .filter(|(date, _)| to.is_none_or(|to| *date <= to))
+        .map(|(date, volume_msat)| DailyVolume { date, volume_msat })
+        .collect();
+
+    Ok(serde_json::to_value(&AccountingResponse {
+        total_volume_msat,
+        volume_by_day,
+    })?)
+}
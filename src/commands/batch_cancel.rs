@@ -0,0 +1,77 @@
+use crate::commands::structs::{parse_args, BatchFailure, FromArr, ParamsError};
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::encoder::InvoiceEncoder;
+use crate::State;
+use cln_plugin::Plugin;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Deserialize)]
+struct BatchCancelRequest {
+    payment_hashes: Vec<String>,
+}
+
+impl FromArr for BatchCancelRequest {
+    fn from_arr(arr: Vec<Value>) -> anyhow::Result<BatchCancelRequest> {
+        if arr.is_empty() {
+            return Err(ParamsError::TooFewParams.into());
+        }
+
+        Ok(BatchCancelRequest {
+            payment_hashes: arr[0]
+                .as_array()
+                .ok_or(ParamsError::ParseError)?
+                .iter()
+                .map(|hash| {
+                    hash.as_str()
+                        .map(|hash| hash.to_string())
+                        .ok_or(ParamsError::ParseError)
+                })
+                .collect::<Result<Vec<String>, ParamsError>>()?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BatchCancelResponse {
+    cancelled: Vec<String>,
+    failed: Vec<BatchFailure>,
+}
+
+pub async fn batch_cancel<T, E>(plugin: Plugin<State<T, E>>, args: Value) -> anyhow::Result<Value>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+    E: InvoiceEncoder + Sync + Send + Clone,
+{
+    let params = parse_args::<BatchCancelRequest>(args)?;
+    let payment_hashes = params
+        .payment_hashes
+        .into_iter()
+        .map(hex::decode)
+        .collect::<Result<Vec<Vec<u8>>, hex::FromHexError>>()?;
+
+    let mut response = BatchCancelResponse {
+        cancelled: Vec::new(),
+        failed: Vec::new(),
+    };
+
+    for (payment_hash, res) in plugin
+        .state()
+        .settler
+        .clone()
+        .cancel_batch(&payment_hashes)
+        .await
+    {
+        let payment_hash = hex::encode(payment_hash);
+
+        match res {
+            Ok(_) => response.cancelled.push(payment_hash),
+            Err(err) => response.failed.push(BatchFailure {
+                payment_hash,
+                error: err.to_string(),
+            }),
+        }
+    }
+
+    Ok(serde_json::to_value(&response)?)
+}
@@ -0,0 +1,81 @@
+use crate::commands::structs::{parse_args, BatchFailure, FromArr, ParamsError};
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::encoder::InvoiceEncoder;
+use crate::State;
+use bitcoin::hashes::{sha256, Hash};
+use cln_plugin::Plugin;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Deserialize)]
+struct BatchSettleRequest {
+    preimages: Vec<String>,
+}
+
+impl FromArr for BatchSettleRequest {
+    fn from_arr(arr: Vec<Value>) -> anyhow::Result<BatchSettleRequest> {
+        if arr.is_empty() {
+            return Err(ParamsError::TooFewParams.into());
+        }
+
+        Ok(BatchSettleRequest {
+            preimages: arr[0]
+                .as_array()
+                .ok_or(ParamsError::ParseError)?
+                .iter()
+                .map(|preimage| {
+                    preimage
+                        .as_str()
+                        .map(|preimage| preimage.to_string())
+                        .ok_or(ParamsError::ParseError)
+                })
+                .collect::<Result<Vec<String>, ParamsError>>()?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BatchSettleResponse {
+    settled: Vec<String>,
+    failed: Vec<BatchFailure>,
+}
+
+pub async fn batch_settle<T, E>(plugin: Plugin<State<T, E>>, args: Value) -> anyhow::Result<Value>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+    E: InvoiceEncoder + Sync + Send + Clone,
+{
+    let params = parse_args::<BatchSettleRequest>(args)?;
+    let preimages = params
+        .preimages
+        .into_iter()
+        .map(hex::decode)
+        .collect::<Result<Vec<Vec<u8>>, hex::FromHexError>>()?;
+
+    let results = plugin
+        .state()
+        .settler
+        .clone()
+        .settle_batch(&preimages)
+        .await;
+
+    let mut response = BatchSettleResponse {
+        settled: Vec::new(),
+        failed: Vec::new(),
+    };
+
+    for (preimage, res) in preimages.iter().zip(results) {
+        let payment_hash: sha256::Hash = Hash::hash(preimage);
+        let payment_hash = hex::encode(payment_hash);
+
+        match res {
+            Ok(_) => response.settled.push(payment_hash),
+            Err(err) => response.failed.push(BatchFailure {
+                payment_hash,
+                error: err.to_string(),
+            }),
+        }
+    }
+
+    Ok(serde_json::to_value(&response)?)
+}
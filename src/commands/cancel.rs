@@ -1,14 +1,21 @@
 use crate::commands::structs::{parse_args, FromArr, ParamsError};
 use crate::database::helpers::invoice_helper::InvoiceHelper;
 use crate::encoder::InvoiceEncoder;
+use crate::hooks::FailureMessage;
+use crate::settler::SettleError;
 use crate::State;
 use cln_plugin::Plugin;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Debug, Deserialize)]
+// 32 bytes encoded as hex
+const PAYMENT_HASH_HEX_LENGTH: usize = 64;
+
+#[derive(Debug, Deserialize, PartialEq)]
 struct CancelRequest {
-    payment_hash: String,
+    payment_hash: Option<String>,
+    invoice: Option<String>,
+    reason: Option<String>,
 }
 
 impl FromArr for CancelRequest {
@@ -17,12 +24,60 @@ impl FromArr for CancelRequest {
             return Err(ParamsError::TooFewParams.into());
         }
 
+        // Empty strings are allowed as placeholders for skipped positional arguments
+        let get_str = |index: usize| -> anyhow::Result<Option<String>> {
+            match arr.get(index) {
+                Some(Value::Null) | None => Ok(None),
+                Some(value) => Ok(Some(value.as_str().ok_or(ParamsError::ParseError)?)
+                    .filter(|res| !res.is_empty())
+                    .map(|res| res.to_string())),
+            }
+        };
+
+        let first = get_str(0)?;
+        let invoice = get_str(1)?;
+        let reason = get_str(2)?;
+
+        // A single parameter that is not hex is an invoice
+        if invoice.is_none() {
+            if let Some(first) = first {
+                return Ok(if first.chars().all(|c| c.is_ascii_hexdigit()) {
+                    parse_payment_hash(&first)?;
+                    CancelRequest {
+                        payment_hash: Some(first),
+                        invoice: None,
+                        reason,
+                    }
+                } else {
+                    CancelRequest {
+                        payment_hash: None,
+                        invoice: Some(first),
+                        reason,
+                    }
+                });
+            }
+        }
+
         Ok(CancelRequest {
-            payment_hash: arr[0].as_str().ok_or(ParamsError::ParseError)?.to_string(),
+            payment_hash: first,
+            invoice,
+            reason,
         })
     }
 }
 
+fn parse_payment_hash(payment_hash: &str) -> Result<Vec<u8>, ParamsError> {
+    if payment_hash.len() != PAYMENT_HASH_HEX_LENGTH {
+        return Err(ParamsError::InvalidParam(format!(
+            "payment_hash has to be {} hex characters",
+            PAYMENT_HASH_HEX_LENGTH
+        )));
+    }
+
+    hex::decode(payment_hash)
+        .map_err(|_| ParamsError::InvalidParam("payment_hash is not valid hex".to_string()))
+}
+
 #[derive(Debug, Serialize)]
 struct CancelResponse {}
 
@@ -32,9 +87,115 @@ where
     E: InvoiceEncoder + Sync + Send + Clone,
 {
     let params = parse_args::<CancelRequest>(args)?;
-    let payment_hash = hex::decode(params.payment_hash)?;
 
-    plugin.state().settler.clone().cancel(&payment_hash).await?;
+    let payment_hash = match (params.payment_hash, params.invoice) {
+        (Some(_), Some(_)) => {
+            return Err(ParamsError::InvalidParam(
+                "only one of payment_hash and invoice can be set".to_string(),
+            )
+            .into())
+        }
+        (None, None) => return Err(ParamsError::TooFewParams.into()),
+        (Some(payment_hash), None) => parse_payment_hash(&payment_hash)?,
+        (None, Some(invoice)) => match plugin.state().invoice_helper.get_by_bolt11(&invoice)? {
+            Some(invoice) => invoice.invoice.payment_hash,
+            None => return Err(SettleError::InvoiceNotFound.into()),
+        },
+    };
+
+    plugin
+        .state()
+        .settler
+        .clone()
+        .cancel(
+            &payment_hash,
+            FailureMessage::IncorrectPaymentDetails,
+            params.reason.as_deref(),
+        )
+        .await?;
 
     Ok(serde_json::to_value(&CancelResponse {})?)
 }
+
+#[cfg(test)]
+mod test {
+    use crate::commands::cancel::{parse_payment_hash, CancelRequest};
+    use crate::commands::structs::parse_args;
+    use serde_json::json;
+
+    const PAYMENT_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000011";
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(
+            parse_args::<CancelRequest>(json!([PAYMENT_HASH])).unwrap(),
+            CancelRequest {
+                payment_hash: Some(PAYMENT_HASH.to_string()),
+                invoice: None,
+                reason: None,
+            }
+        );
+        assert_eq!(
+            parse_args::<CancelRequest>(json!(["lnbcrt1"])).unwrap(),
+            CancelRequest {
+                payment_hash: None,
+                invoice: Some("lnbcrt1".to_string()),
+                reason: None,
+            }
+        );
+        assert_eq!(
+            parse_args::<CancelRequest>(json!(["", "lnbcrt1"])).unwrap(),
+            CancelRequest {
+                payment_hash: None,
+                invoice: Some("lnbcrt1".to_string()),
+                reason: None,
+            }
+        );
+        assert_eq!(
+            parse_args::<CancelRequest>(json!({"invoice": "lnbcrt1"})).unwrap(),
+            CancelRequest {
+                payment_hash: None,
+                invoice: Some("lnbcrt1".to_string()),
+                reason: None,
+            }
+        );
+        assert_eq!(
+            parse_args::<CancelRequest>(json!([PAYMENT_HASH, "", "swap expired"])).unwrap(),
+            CancelRequest {
+                payment_hash: Some(PAYMENT_HASH.to_string()),
+                invoice: None,
+                reason: Some("swap expired".to_string()),
+            }
+        );
+        assert_eq!(
+            parse_args::<CancelRequest>(json!([]))
+                .err()
+                .unwrap()
+                .to_string(),
+            "too few parameters"
+        );
+        assert_eq!(
+            parse_args::<CancelRequest>(json!(["0011"]))
+                .err()
+                .unwrap()
+                .to_string(),
+            "invalid parameter: payment_hash has to be 64 hex characters"
+        );
+    }
+
+    #[test]
+    fn parse_payment_hash_malformed() {
+        assert_eq!(parse_payment_hash(PAYMENT_HASH).unwrap().len(), 32);
+        assert_eq!(
+            parse_payment_hash("0011").err().unwrap().to_string(),
+            "invalid parameter: payment_hash has to be 64 hex characters"
+        );
+        assert_eq!(
+            parse_payment_hash(&"zz".repeat(32))
+                .err()
+                .unwrap()
+                .to_string(),
+            "invalid parameter: payment_hash is not valid hex"
+        );
+    }
+}
@@ -1,14 +1,20 @@
 use crate::commands::structs::{parse_args, FromArr, ParamsError};
 use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::database::model::InvoiceState;
 use crate::encoder::InvoiceEncoder;
 use crate::State;
 use cln_plugin::Plugin;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Debug, Deserialize)]
+// Cleans cancelled and paid invoices
+const STATE_ALL: &str = "all";
+
+#[derive(Debug, Deserialize, PartialEq)]
 struct CleanRequest {
     age: Option<u64>,
+    state: Option<String>,
+    dry_run: Option<bool>,
 }
 
 impl FromArr for CleanRequest {
@@ -16,19 +22,29 @@ impl FromArr for CleanRequest {
     where
         Self: Sized,
     {
-        if arr.is_empty() {
-            return Ok(Self { age: None });
-        }
-
         Ok(Self {
-            age: Some(arr[0].as_u64().ok_or(ParamsError::ParseError)?),
+            age: match arr.first() {
+                Some(Value::Null) | None => None,
+                Some(age) => Some(age.as_u64().ok_or(ParamsError::ParseError)?),
+            },
+            state: match arr.get(1) {
+                Some(Value::Null) | None => None,
+                Some(state) => Some(state.as_str().ok_or(ParamsError::ParseError)?.to_string())
+                    .filter(|state| !state.is_empty()),
+            },
+            dry_run: match arr.get(2) {
+                Some(Value::Null) | None => None,
+                Some(dry_run) => Some(dry_run.as_bool().ok_or(ParamsError::ParseError)?),
+            },
         })
     }
 }
 
 #[derive(Debug, Serialize)]
-struct CleanResponse {
-    pub cleaned: usize,
+#[serde(untagged)]
+enum CleanResponse {
+    Cleaned { cleaned: usize },
+    DryRun { would_clean: usize },
 }
 
 pub async fn clean<T, E>(plugin: Plugin<State<T, E>>, args: Value) -> anyhow::Result<Value>
@@ -37,8 +53,67 @@ where
     E: InvoiceEncoder + Sync + Send + Clone,
 {
     let params = parse_args::<CleanRequest>(args)?;
+    let states = match params.state.as_deref() {
+        Some(STATE_ALL) => vec![Some(InvoiceState::Cancelled), Some(InvoiceState::Paid)],
+        Some(state) => vec![Some(InvoiceState::try_from(state)?)],
+        None => vec![None],
+    };
+    let dry_run = params.dry_run.unwrap_or(false);
 
-    let cleaned = plugin.state().invoice_helper.clean_cancelled(params.age)?;
+    let mut count = 0;
+    for state in states {
+        count += plugin
+            .state()
+            .invoice_helper
+            .clean(state, params.age, dry_run)?;
+    }
 
-    Ok(serde_json::to_value(&CleanResponse { cleaned })?)
+    Ok(serde_json::to_value(&if dry_run {
+        CleanResponse::DryRun { would_clean: count }
+    } else {
+        CleanResponse::Cleaned { cleaned: count }
+    })?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::commands::clean::CleanRequest;
+    use crate::commands::structs::parse_args;
+    use serde_json::json;
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(
+            parse_args::<CleanRequest>(json!([])).unwrap(),
+            CleanRequest {
+                age: None,
+                state: None,
+                dry_run: None,
+            }
+        );
+        assert_eq!(
+            parse_args::<CleanRequest>(json!([60, "paid", true])).unwrap(),
+            CleanRequest {
+                age: Some(60),
+                state: Some("paid".to_string()),
+                dry_run: Some(true),
+            }
+        );
+        assert_eq!(
+            parse_args::<CleanRequest>(json!({"state": "all"})).unwrap(),
+            CleanRequest {
+                age: None,
+                state: Some("all".to_string()),
+                dry_run: None,
+            }
+        );
+        assert_eq!(
+            parse_args::<CleanRequest>(json!({"dry_run": true})).unwrap(),
+            CleanRequest {
+                age: None,
+                state: None,
+                dry_run: Some(true),
+            }
+        );
+    }
 }
@@ -0,0 +1,45 @@
+use crate::commands::structs::{parse_args, FromArr, ParamsError};
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::encoder::InvoiceEncoder;
+use crate::State;
+use cln_plugin::Plugin;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Deserialize)]
+struct DeleteRequest {
+    payment_hash: String,
+}
+
+impl FromArr for DeleteRequest {
+    fn from_arr(arr: Vec<Value>) -> anyhow::Result<DeleteRequest> {
+        if arr.is_empty() {
+            return Err(ParamsError::TooFewParams.into());
+        }
+
+        Ok(DeleteRequest {
+            payment_hash: arr[0].as_str().ok_or(ParamsError::ParseError)?.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DeleteResponse {
+    deleted: bool,
+}
+
+pub async fn delete<T, E>(plugin: Plugin<State<T, E>>, args: Value) -> anyhow::Result<Value>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+    E: InvoiceEncoder + Sync + Send + Clone,
+{
+    let params = parse_args::<DeleteRequest>(args)?;
+    let payment_hash = hex::decode(params.payment_hash)?;
+
+    Ok(serde_json::to_value(&DeleteResponse {
+        deleted: plugin
+            .state()
+            .invoice_helper
+            .delete_by_payment_hash(&payment_hash)?,
+    })?)
+}
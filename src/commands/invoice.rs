@@ -1,18 +1,110 @@
 use crate::commands::structs::{parse_args, FromArr, ParamsError};
 use crate::database::helpers::invoice_helper::InvoiceHelper;
-use crate::database::model::{InvoiceInsertable, InvoiceState};
-use crate::encoder::{InvoiceBuilder, InvoiceEncoder};
+use crate::database::model::{HashAlgorithm, InvoiceInsertable, InvoiceState, MAX_LABEL_LENGTH};
+use crate::encoder::{InvoiceBuilder, InvoiceDescription, InvoiceDetails, InvoiceEncoder};
+use crate::grpc::service::hold;
+use crate::grpc::transformers::transform_route_hints;
 use crate::State;
 use anyhow::Result;
 use cln_plugin::Plugin;
+use lightning_invoice::RouteHint;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, PartialEq)]
 struct InvoiceRequest {
     payment_hash: String,
     amount: u64,
+    description: Option<String>,
+    expiry: Option<u64>,
+    min_cltv: Option<u64>,
+    mpp_timeout: Option<u64>,
+    label: Option<String>,
+    // JSON encoded array of routing hints
+    routing_hints: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoutingHintParam {
+    hops: Vec<HopParam>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HopParam {
+    public_key: String,
+    short_channel_id: u64,
+    base_fee: u64,
+    ppm_fee: u64,
+    cltv_expiry_delta: u64,
+}
+
+impl InvoiceRequest {
+    fn validate(&self) -> Result<()> {
+        if self.expiry == Some(0) {
+            return Err(ParamsError::InvalidParam("expiry has to be positive".to_string()).into());
+        }
+
+        if let Some(label) = &self.label {
+            if label.len() > MAX_LABEL_LENGTH {
+                return Err(ParamsError::InvalidParam(format!(
+                    "label has to be at most {} bytes",
+                    MAX_LABEL_LENGTH
+                ))
+                .into());
+            }
+        }
+
+        if let Some(min_cltv) = self.min_cltv {
+            if i32::try_from(min_cltv).is_err() {
+                return Err(ParamsError::InvalidParam(format!(
+                    "min_cltv has to be at most {}",
+                    i32::MAX
+                ))
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn route_hints(&self) -> Result<Option<Vec<RouteHint>>> {
+        let hints = match &self.routing_hints {
+            Some(hints) => hints,
+            None => return Ok(None),
+        };
+
+        let invalid = |err: &dyn Display| {
+            ParamsError::InvalidParam(format!("could not parse routing_hints: {}", err))
+        };
+
+        let hints = serde_json::from_str::<Vec<RoutingHintParam>>(hints)
+            .map_err(|err| invalid(&err))?
+            .into_iter()
+            .map(|hint| {
+                Ok(hold::RoutingHint {
+                    hops: hint
+                        .hops
+                        .into_iter()
+                        .map(|hop| {
+                            Ok(hold::Hop {
+                                public_key: hex::decode(hop.public_key)
+                                    .map_err(|err| invalid(&err))?,
+                                short_channel_id: hop.short_channel_id,
+                                base_fee: hop.base_fee,
+                                ppm_fee: hop.ppm_fee,
+                                cltv_expiry_delta: hop.cltv_expiry_delta,
+                            })
+                        })
+                        .collect::<Result<Vec<_>, ParamsError>>()?,
+                })
+            })
+            .collect::<Result<Vec<_>, ParamsError>>()?;
+
+        Ok(Some(
+            transform_route_hints(hints).map_err(|err| invalid(&err))?,
+        ))
+    }
 }
 
 impl FromArr for InvoiceRequest {
@@ -21,9 +113,31 @@ impl FromArr for InvoiceRequest {
             return Err(ParamsError::TooFewParams.into());
         }
 
+        let get_u64 = |index: usize| -> Result<Option<u64>, ParamsError> {
+            match arr.get(index) {
+                Some(Value::Null) | None => Ok(None),
+                Some(value) => Ok(Some(value.as_u64().ok_or(ParamsError::ParseError)?)),
+            }
+        };
+
         Ok(InvoiceRequest {
             payment_hash: arr[0].as_str().ok_or(ParamsError::ParseError)?.to_string(),
             amount: arr[1].as_u64().ok_or(ParamsError::ParseError)?,
+            description: match arr.get(2) {
+                Some(Value::Null) | None => None,
+                Some(value) => Some(value.as_str().ok_or(ParamsError::ParseError)?.to_string()),
+            },
+            expiry: get_u64(3)?,
+            min_cltv: get_u64(4)?,
+            mpp_timeout: get_u64(5)?,
+            label: match arr.get(6) {
+                Some(Value::Null) | None => None,
+                Some(value) => Some(value.as_str().ok_or(ParamsError::ParseError)?.to_string()),
+            },
+            routing_hints: match arr.get(7) {
+                Some(Value::Null) | None => None,
+                Some(value) => Some(value.as_str().ok_or(ParamsError::ParseError)?.to_string()),
+            },
         })
     }
 }
@@ -31,6 +145,8 @@ impl FromArr for InvoiceRequest {
 #[derive(Debug, Serialize)]
 struct InvoiceResponse {
     bolt11: String,
+    #[serde(flatten)]
+    details: InvoiceDetails,
 }
 
 pub async fn invoice<T, E>(plugin: Plugin<State<T, E>>, args: Value) -> Result<Value>
@@ -39,22 +155,261 @@ where
     E: InvoiceEncoder + Sync + Send + Clone,
 {
     let params = parse_args::<InvoiceRequest>(args)?;
+    params.validate()?;
+    let route_hints = params.route_hints()?;
+
     let payment_hash = hex::decode(params.payment_hash)?;
+    let mpp_timeout = match params.mpp_timeout {
+        Some(timeout) => Some(i64::try_from(timeout)?),
+        None => None,
+    };
 
-    let invoice = plugin
-        .state()
-        .encoder
-        .encode(InvoiceBuilder::new(&payment_hash).amount_msat(params.amount))
-        .await?;
+    let mut builder = InvoiceBuilder::new(&payment_hash).amount_msat(params.amount);
+
+    if let Some(description) = params.description {
+        builder = builder.description(InvoiceDescription::Description(description));
+    }
+
+    if let Some(expiry) = params.expiry {
+        builder = builder.expiry(expiry);
+    }
+
+    if let Some(min_cltv) = params.min_cltv {
+        builder = builder.min_final_cltv_expiry_delta(min_cltv);
+    }
+
+    if let Some(route_hints) = route_hints {
+        builder = builder.route_hints(route_hints);
+    }
+
+    let invoice = plugin.state().encoder.encode(builder).await?;
     plugin.state().invoice_helper.insert(&InvoiceInsertable {
         bolt11: invoice.clone(),
         payment_hash: payment_hash.clone(),
+        hash_algorithm: HashAlgorithm::default().into(),
         state: InvoiceState::Unpaid.into(),
+        mpp_timeout,
+        settlement_deadline: None,
+        label: params.label.clone(),
     })?;
     plugin
         .state()
         .settler
-        .new_invoice(invoice.clone(), payment_hash, params.amount);
+        .new_invoice(invoice.clone(), payment_hash, params.amount, params.label);
+
+    Ok(serde_json::to_value(&InvoiceResponse {
+        details: InvoiceDetails::from_bolt11(&invoice)?,
+        bolt11: invoice,
+    })?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::commands::invoice::InvoiceRequest;
+    use crate::commands::structs::parse_args;
+    use bitcoin::hashes::{sha256, Hash};
+    use lightning_invoice::{Bolt11Invoice, Currency, PaymentSecret};
+    use secp256k1::{Secp256k1, SecretKey};
+    use serde_json::json;
+    use std::str::FromStr;
+
+    const ROUTING_HINTS: &str = r#"[{"hops": [{"public_key": "02a1633cafcc01ebfb6d78e39f687a1f0995c62fc95f51ead10a02ee0be551b5dc", "short_channel_id": 123, "base_fee": 1000, "ppm_fee": 100, "cltv_expiry_delta": 80}]}]"#;
+
+    #[test]
+    fn parse_required_only() {
+        let req = parse_args::<InvoiceRequest>(json!(["00", 1_000])).unwrap();
+        assert_eq!(
+            req,
+            InvoiceRequest {
+                payment_hash: "00".to_string(),
+                amount: 1_000,
+                description: None,
+                expiry: None,
+                min_cltv: None,
+                mpp_timeout: None,
+                label: None,
+                routing_hints: None,
+            }
+        );
+        assert!(req.validate().is_ok());
+    }
+
+    #[test]
+    fn parse_too_few() {
+        assert_eq!(
+            parse_args::<InvoiceRequest>(json!(["00"]))
+                .err()
+                .unwrap()
+                .to_string(),
+            "too few parameters"
+        );
+    }
+
+    #[test]
+    fn parse_description() {
+        let req = parse_args::<InvoiceRequest>(json!(["00", 1_000, "memo"])).unwrap();
+        assert_eq!(req.description, Some("memo".to_string()));
+        assert_eq!(req.expiry, None);
+        assert_eq!(req.min_cltv, None);
+        assert_eq!(req.mpp_timeout, None);
+    }
+
+    #[test]
+    fn parse_expiry() {
+        let req = parse_args::<InvoiceRequest>(json!(["00", 1_000, "memo", 3_600])).unwrap();
+        assert_eq!(req.description, Some("memo".to_string()));
+        assert_eq!(req.expiry, Some(3_600));
+        assert_eq!(req.min_cltv, None);
+        assert_eq!(req.mpp_timeout, None);
+    }
+
+    #[test]
+    fn parse_all() {
+        let req =
+            parse_args::<InvoiceRequest>(json!(["00", 1_000, "memo", 3_600, 144, 30, "swap-1"]))
+                .unwrap();
+        assert_eq!(
+            req,
+            InvoiceRequest {
+                payment_hash: "00".to_string(),
+                amount: 1_000,
+                description: Some("memo".to_string()),
+                expiry: Some(3_600),
+                min_cltv: Some(144),
+                mpp_timeout: Some(30),
+                label: Some("swap-1".to_string()),
+                routing_hints: None,
+            }
+        );
+        assert!(req.validate().is_ok());
+    }
+
+    #[test]
+    fn parse_skipped_with_null() {
+        let req = parse_args::<InvoiceRequest>(json!(["00", 1_000, null, null, 144])).unwrap();
+        assert_eq!(req.description, None);
+        assert_eq!(req.expiry, None);
+        assert_eq!(req.min_cltv, Some(144));
+        assert_eq!(req.mpp_timeout, None);
+    }
+
+    #[test]
+    fn parse_object() {
+        let req = parse_args::<InvoiceRequest>(json!({
+            "payment_hash": "00",
+            "amount": 1_000,
+            "min_cltv": 80,
+        }))
+        .unwrap();
+        assert_eq!(req.description, None);
+        assert_eq!(req.expiry, None);
+        assert_eq!(req.min_cltv, Some(80));
+        assert_eq!(req.mpp_timeout, None);
+    }
 
-    Ok(serde_json::to_value(&InvoiceResponse { bolt11: invoice })?)
+    #[test]
+    fn parse_invalid_type() {
+        assert!(parse_args::<InvoiceRequest>(json!(["00", 1_000, "memo", "3600"])).is_err());
+    }
+
+    #[test]
+    fn validate_expiry_zero() {
+        let req = parse_args::<InvoiceRequest>(json!(["00", 1_000, "memo", 0])).unwrap();
+        assert_eq!(
+            req.validate().err().unwrap().to_string(),
+            "invalid parameter: expiry has to be positive"
+        );
+    }
+
+    #[test]
+    fn validate_min_cltv_too_big() {
+        let req =
+            parse_args::<InvoiceRequest>(json!(["00", 1_000, "memo", 3_600, i32::MAX as u64 + 1]))
+                .unwrap();
+        assert_eq!(
+            req.validate().err().unwrap().to_string(),
+            format!("invalid parameter: min_cltv has to be at most {}", i32::MAX)
+        );
+    }
+
+    #[test]
+    fn validate_label_too_long() {
+        let req = parse_args::<InvoiceRequest>(json!({
+            "payment_hash": "00",
+            "amount": 1_000,
+            "label": "a".repeat(256),
+        }))
+        .unwrap();
+        assert_eq!(
+            req.validate().err().unwrap().to_string(),
+            "invalid parameter: label has to be at most 255 bytes"
+        );
+    }
+
+    #[test]
+    fn parse_routing_hints() {
+        let req = parse_args::<InvoiceRequest>(json!({
+            "payment_hash": "00",
+            "amount": 1_000,
+            "routing_hints": ROUTING_HINTS,
+        }))
+        .unwrap();
+
+        let route_hints = req.route_hints().unwrap().unwrap();
+        assert_eq!(route_hints.len(), 1);
+
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let mut builder = lightning_invoice::InvoiceBuilder::new(Currency::Regtest)
+            .description("".to_string())
+            .current_timestamp()
+            .payment_hash(sha256::Hash::hash(&[0]))
+            .payment_secret(PaymentSecret([0; 32]))
+            .min_final_cltv_expiry_delta(80);
+        for hint in route_hints.clone() {
+            builder = builder.private_route(hint);
+        }
+        let invoice = builder
+            .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &secret_key))
+            .unwrap();
+
+        let decoded = Bolt11Invoice::from_str(&invoice.to_string()).unwrap();
+        assert_eq!(decoded.route_hints(), route_hints);
+
+        let hop = &decoded.route_hints()[0].0[0];
+        assert_eq!(
+            hop.src_node_id.to_string(),
+            "02a1633cafcc01ebfb6d78e39f687a1f0995c62fc95f51ead10a02ee0be551b5dc"
+        );
+        assert_eq!(hop.short_channel_id, 123);
+        assert_eq!(hop.fees.base_msat, 1_000);
+        assert_eq!(hop.fees.proportional_millionths, 100);
+        assert_eq!(hop.cltv_expiry_delta, 80);
+    }
+
+    #[test]
+    fn parse_routing_hints_invalid() {
+        let req = parse_args::<InvoiceRequest>(json!({
+            "payment_hash": "00",
+            "amount": 1_000,
+            "routing_hints": "[{\"hops\": ",
+        }))
+        .unwrap();
+        assert!(req
+            .route_hints()
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("invalid parameter: could not parse routing_hints"));
+
+        let req = parse_args::<InvoiceRequest>(json!({
+            "payment_hash": "00",
+            "amount": 1_000,
+            "routing_hints": ROUTING_HINTS.replace("02a1", "00a1"),
+        }))
+        .unwrap();
+        assert_eq!(
+            req.route_hints().err().unwrap().to_string(),
+            "invalid parameter: could not parse routing_hints: malformed public key"
+        );
+    }
 }
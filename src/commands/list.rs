@@ -1,18 +1,28 @@
 use crate::commands::structs::{parse_args, FromArr, ParamsError};
 use crate::database::helpers::invoice_helper::InvoiceHelper;
-use crate::database::model::{HoldInvoice, Htlc};
+use crate::database::model::{HoldInvoice, Htlc, InvoiceState, InvoiceStateLog, SortField};
 use crate::encoder::InvoiceEncoder;
 use crate::State;
+use chrono::{DateTime, NaiveDateTime};
 use cln_plugin::Plugin;
 use lightning_invoice::Bolt11Invoice;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
 use std::str::FromStr;
 
 #[derive(Debug, Deserialize)]
 struct ListInvoicesRequest {
     payment_hash: Option<String>,
     bolt11: Option<String>,
+    state: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    label: Option<String>,
+    sort_by: Option<String>,
+    sort_desc: Option<bool>,
+    scid: Option<String>,
+    preimage: Option<String>,
 }
 
 impl FromArr for ListInvoicesRequest {
@@ -20,21 +30,43 @@ impl FromArr for ListInvoicesRequest {
     where
         Self: Sized,
     {
+        // Empty strings are allowed as placeholders for skipped positional arguments
+        let get_str = |index: usize| {
+            arr.get(index)
+                .and_then(|res| res.as_str())
+                .filter(|res| !res.is_empty())
+                .map(|res| res.to_string())
+        };
+
         Ok(ListInvoicesRequest {
-            payment_hash: if !arr.is_empty() {
-                arr[0].as_str().map(|res| res.to_string())
-            } else {
-                None
-            },
-            bolt11: if arr.len() > 1 {
-                arr[1].as_str().map(|res| res.to_string())
-            } else {
-                None
+            payment_hash: get_str(0),
+            bolt11: get_str(1),
+            state: get_str(2),
+            from: get_str(3),
+            to: get_str(4),
+            label: get_str(5),
+            sort_by: get_str(6),
+            sort_desc: match arr.get(7) {
+                Some(Value::Null) | None => None,
+                Some(sort_desc) => Some(sort_desc.as_bool().ok_or(ParamsError::ParseError)?),
             },
+            scid: get_str(8),
+            preimage: get_str(9),
         })
     }
 }
 
+fn parse_timestamp(timestamp: &str) -> anyhow::Result<NaiveDateTime> {
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(res) => Ok(res.naive_utc()),
+        Err(err) => Err(ParamsError::InvalidParam(format!(
+            "invalid RFC 3339 timestamp {}: {}",
+            timestamp, err
+        ))
+        .into()),
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct PrettyHoldInvoice {
     pub id: i64,
@@ -45,7 +77,32 @@ struct PrettyHoldInvoice {
     pub created_at: chrono::NaiveDateTime,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub settled_at: Option<chrono::NaiveDateTime>,
-    pub htlcs: Vec<Htlc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mpp_timeout: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settlement_deadline: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accepted_at: Option<chrono::NaiveDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancel_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub hash_algorithm: String,
+    pub htlcs: Vec<PrettyHtlc>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub state_log: Vec<InvoiceStateLog>,
+}
+
+#[derive(Debug, Serialize)]
+struct PrettyHtlc {
+    #[serde(flatten)]
+    pub htlc: Htlc,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preimage: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hold_duration_seconds: Option<u64>,
 }
 
 impl From<HoldInvoice> for PrettyHoldInvoice {
@@ -58,7 +115,23 @@ impl From<HoldInvoice> for PrettyHoldInvoice {
             state: value.invoice.state.clone(),
             created_at: value.invoice.created_at,
             settled_at: value.invoice.settled_at,
-            htlcs: value.htlcs.clone(),
+            mpp_timeout: value.invoice.mpp_timeout,
+            settlement_deadline: value.invoice.settlement_deadline,
+            accepted_at: value.invoice.accepted_at,
+            label: value.invoice.label.clone(),
+            cancel_reason: value.invoice.cancel_reason.clone(),
+            expires_at: value.invoice.expires_at,
+            hash_algorithm: value.invoice.hash_algorithm.clone(),
+            htlcs: value
+                .htlcs
+                .into_iter()
+                .map(|htlc| PrettyHtlc {
+                    preimage: htlc.preimage.as_ref().map(hex::encode),
+                    hold_duration_seconds: htlc.hold_duration_seconds(value.invoice.settled_at),
+                    htlc,
+                })
+                .collect(),
+            state_log: Vec::new(),
         }
     }
 }
@@ -74,7 +147,18 @@ where
     E: InvoiceEncoder + Sync + Send + Clone,
 {
     let params = parse_args::<ListInvoicesRequest>(args)?;
-    if params.bolt11.is_some() && params.payment_hash.is_some() {
+    if [
+        params.payment_hash.is_some(),
+        params.bolt11.is_some(),
+        params.label.is_some(),
+        params.scid.is_some(),
+        params.preimage.is_some(),
+    ]
+    .iter()
+    .filter(|set| **set)
+    .count()
+        > 1
+    {
         return Err(ParamsError::TooManyParams.into());
     }
 
@@ -86,18 +170,96 @@ where
         None
     };
 
-    let invoices = match payment_hash {
-        Some(hash) => match plugin.state().invoice_helper.get_by_payment_hash(&hash)? {
-            Some(invoice) => vec![invoice],
-            None => Vec::new(),
-        },
-        None => plugin.state().invoice_helper.get_all()?,
+    let state = match params.state {
+        Some(state) => Some(InvoiceState::try_from(&state)?),
+        None => None,
     };
 
+    let sort_by = params
+        .sort_by
+        .as_deref()
+        .map(SortField::try_from)
+        .transpose()?;
+
+    let from = params.from.as_deref().map(parse_timestamp).transpose()?;
+    let to = params.to.as_deref().map(parse_timestamp).transpose()?;
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err(ParamsError::InvalidParam("from has to be before to".to_string()).into());
+        }
+    }
+
+    let preimage = params.preimage.map(hex::decode).transpose()?;
+
+    let invoices = match (payment_hash, params.label, params.scid, preimage) {
+        (Some(hash), _, _, _) => plugin
+            .state()
+            .invoice_helper
+            .get_by_payment_hash(&hash)?
+            .into_iter()
+            .collect(),
+        (None, Some(label), _, _) => plugin
+            .state()
+            .invoice_helper
+            .get_by_label(&label)?
+            .into_iter()
+            .collect(),
+        // Only the HTLCs via the channel are included
+        (None, None, Some(scid), _) => {
+            HoldInvoice::from_htlc_pairs(plugin.state().invoice_helper.get_htlcs_by_scid(&scid)?)
+        }
+        (None, None, None, Some(preimage)) => plugin
+            .state()
+            .invoice_helper
+            .get_by_preimage(&preimage)?
+            .into_iter()
+            .collect(),
+        (None, None, None, None) => {
+            // State and time range are filtered below
+            if let Some(sort_by) = sort_by {
+                plugin
+                    .state()
+                    .invoice_helper
+                    .get_all_sorted(sort_by, params.sort_desc.unwrap_or(false))?
+            } else if from.is_some() || to.is_some() {
+                plugin.state().invoice_helper.get_in_date_range(from, to)?
+            } else {
+                match state {
+                    Some(state) => plugin.state().invoice_helper.get_by_state(state)?,
+                    None => plugin.state().invoice_helper.get_all()?,
+                }
+            }
+        }
+    }
+    .into_iter()
+    .filter(|invoice| match state {
+        Some(state) => invoice.invoice.state == state.to_string(),
+        None => true,
+    })
+    .filter(|invoice| {
+        from.is_none_or(|from| invoice.invoice.created_at >= from)
+            && to.is_none_or(|to| invoice.invoice.created_at <= to)
+    })
+    .collect::<Vec<HoldInvoice>>();
+
+    let mut state_log: HashMap<i64, Vec<InvoiceStateLog>> = HashMap::new();
+    for entry in plugin.state().invoice_helper.get_state_log(
+        &invoices
+            .iter()
+            .map(|invoice| invoice.invoice.id)
+            .collect::<Vec<i64>>(),
+    )? {
+        state_log.entry(entry.invoice_id).or_default().push(entry);
+    }
+
     Ok(serde_json::to_value(&ListInvoicesResponse {
         holdinvoices: invoices
             .into_iter()
-            .map(|e| e.into())
+            .map(|e| {
+                let mut invoice: PrettyHoldInvoice = e.into();
+                invoice.state_log = state_log.remove(&invoice.id).unwrap_or_default();
+                invoice
+            })
             .collect::<Vec<PrettyHoldInvoice>>(),
     })?)
 }
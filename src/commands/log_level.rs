@@ -0,0 +1,72 @@
+use crate::commands::structs::{parse_args, FromArr, ParamsError};
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::encoder::InvoiceEncoder;
+use crate::logging::{self, LogLevel};
+use crate::State;
+use cln_plugin::Plugin;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct LogLevelRequest {
+    level: String,
+}
+
+impl FromArr for LogLevelRequest {
+    fn from_arr(arr: Vec<Value>) -> anyhow::Result<LogLevelRequest> {
+        if arr.is_empty() {
+            return Err(ParamsError::TooFewParams.into());
+        }
+
+        Ok(LogLevelRequest {
+            level: arr[0].as_str().ok_or(ParamsError::ParseError)?.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct LogLevelResponse {
+    level: String,
+}
+
+pub async fn log_level<T, E>(_plugin: Plugin<State<T, E>>, args: Value) -> anyhow::Result<Value>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+    E: InvoiceEncoder + Sync + Send + Clone,
+{
+    let params = parse_args::<LogLevelRequest>(args)?;
+    let level = match LogLevel::try_from(params.level.as_str()) {
+        Ok(level) => level,
+        Err(err) => return Err(ParamsError::InvalidParam(err.to_string()).into()),
+    };
+
+    logging::set_level(level);
+
+    Ok(serde_json::to_value(&LogLevelResponse {
+        level: level.to_string(),
+    })?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::commands::log_level::LogLevelRequest;
+    use crate::commands::structs::parse_args;
+    use serde_json::json;
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(
+            parse_args::<LogLevelRequest>(json!(["debug"])).unwrap(),
+            LogLevelRequest {
+                level: "debug".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_args::<LogLevelRequest>(json!({"level": "warn"})).unwrap(),
+            LogLevelRequest {
+                level: "warn".to_string(),
+            }
+        );
+        assert!(parse_args::<LogLevelRequest>(json!([])).is_err());
+    }
+}
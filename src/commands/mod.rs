@@ -1,12 +1,32 @@
+mod accounting;
+mod batch_cancel;
+mod batch_settle;
 mod cancel;
 mod clean;
+mod delete;
 mod invoice;
 mod list;
+mod log_level;
+mod pause;
+mod set_option;
 mod settle;
+mod stats;
+mod status;
 mod structs;
+mod wait;
 
+pub use accounting::accounting;
+pub use batch_cancel::batch_cancel;
+pub use batch_settle::batch_settle;
 pub use cancel::cancel;
 pub use clean::clean;
+pub use delete::delete;
 pub use invoice::invoice;
 pub use list::list_invoices;
+pub use log_level::log_level;
+pub use pause::{pause, resume};
+pub use set_option::set_option;
 pub use settle::settle;
+pub use stats::stats;
+pub use status::status;
+pub use wait::wait;
@@ -0,0 +1,55 @@
+use crate::commands::structs::{parse_args, FromArr, ParamsError};
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::encoder::InvoiceEncoder;
+use crate::State;
+use cln_plugin::Plugin;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Deserialize)]
+struct PauseRequest {
+    payment_hash: String,
+}
+
+impl FromArr for PauseRequest {
+    fn from_arr(arr: Vec<Value>) -> anyhow::Result<PauseRequest> {
+        if arr.is_empty() {
+            return Err(ParamsError::TooFewParams.into());
+        }
+
+        Ok(PauseRequest {
+            payment_hash: arr[0].as_str().ok_or(ParamsError::ParseError)?.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PauseResponse {}
+
+pub async fn pause<T, E>(plugin: Plugin<State<T, E>>, args: Value) -> anyhow::Result<Value>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+    E: InvoiceEncoder + Sync + Send + Clone,
+{
+    let params = parse_args::<PauseRequest>(args)?;
+    plugin
+        .state()
+        .settler
+        .pause(&hex::decode(params.payment_hash)?)?;
+
+    Ok(serde_json::to_value(&PauseResponse {})?)
+}
+
+pub async fn resume<T, E>(plugin: Plugin<State<T, E>>, args: Value) -> anyhow::Result<Value>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+    E: InvoiceEncoder + Sync + Send + Clone,
+{
+    let params = parse_args::<PauseRequest>(args)?;
+    plugin
+        .state()
+        .settler
+        .resume(&hex::decode(params.payment_hash)?)?;
+
+    Ok(serde_json::to_value(&PauseResponse {})?)
+}
@@ -0,0 +1,126 @@
+use crate::commands::structs::{parse_args, FromArr, ParamsError};
+use crate::config::OPTION_MPP_TIMEOUT;
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::encoder::InvoiceEncoder;
+use crate::State;
+use cln_plugin::Plugin;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct SetOptionRequest {
+    option_name: String,
+    value: Value,
+}
+
+impl FromArr for SetOptionRequest {
+    fn from_arr(arr: Vec<Value>) -> anyhow::Result<SetOptionRequest> {
+        if arr.len() < 2 {
+            return Err(ParamsError::TooFewParams.into());
+        }
+
+        Ok(SetOptionRequest {
+            option_name: arr[0].as_str().ok_or(ParamsError::ParseError)?.to_string(),
+            value: arr[1].clone(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SetOptionResponse {
+    option_name: String,
+    value: Value,
+}
+
+// The CLI sends numbers as strings when they are quoted
+fn parse_seconds(option_name: &str, value: &Value) -> anyhow::Result<u64> {
+    let seconds = match value {
+        Value::Number(number) => number.as_u64(),
+        Value::String(string) => string.parse::<u64>().ok(),
+        _ => None,
+    };
+
+    seconds.ok_or_else(|| {
+        ParamsError::InvalidParam(format!(
+            "{} has to be a positive number of seconds",
+            option_name
+        ))
+        .into()
+    })
+}
+
+pub async fn set_option<T, E>(plugin: Plugin<State<T, E>>, args: Value) -> anyhow::Result<Value>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+    E: InvoiceEncoder + Sync + Send + Clone,
+{
+    let params = parse_args::<SetOptionRequest>(args)?;
+
+    let value = match params.option_name.as_str() {
+        name if name == OPTION_MPP_TIMEOUT.name => {
+            let seconds = parse_seconds(name, &params.value)?;
+            plugin.state().settler.set_mpp_timeout(seconds);
+            Value::from(seconds)
+        }
+        name => {
+            return Err(ParamsError::InvalidParam(format!(
+                "option {} cannot be changed at runtime",
+                name
+            ))
+            .into())
+        }
+    };
+
+    Ok(serde_json::to_value(&SetOptionResponse {
+        option_name: params.option_name,
+        value,
+    })?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::commands::set_option::{parse_seconds, SetOptionRequest};
+    use crate::commands::structs::parse_args;
+    use serde_json::json;
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(
+            parse_args::<SetOptionRequest>(json!(["hold-mpp-timeout", 30])).unwrap(),
+            SetOptionRequest {
+                option_name: "hold-mpp-timeout".to_string(),
+                value: json!(30),
+            }
+        );
+        assert_eq!(
+            parse_args::<SetOptionRequest>(
+                json!({"option_name": "hold-mpp-timeout", "value": "30"})
+            )
+            .unwrap(),
+            SetOptionRequest {
+                option_name: "hold-mpp-timeout".to_string(),
+                value: json!("30"),
+            }
+        );
+        assert_eq!(
+            parse_args::<SetOptionRequest>(json!(["hold-mpp-timeout"]))
+                .err()
+                .unwrap()
+                .to_string(),
+            "too few parameters"
+        );
+    }
+
+    #[test]
+    fn parse_seconds_value() {
+        assert_eq!(parse_seconds("timeout", &json!(30)).unwrap(), 30);
+        assert_eq!(parse_seconds("timeout", &json!("30")).unwrap(), 30);
+
+        for value in [json!(-1), json!("a"), json!(1.5), json!(null)] {
+            assert_eq!(
+                parse_seconds("timeout", &value).err().unwrap().to_string(),
+                "invalid parameter: timeout has to be a positive number of seconds"
+            );
+        }
+    }
+}
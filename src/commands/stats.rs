@@ -0,0 +1,90 @@
+use crate::commands::structs::{parse_args, FromArr, ParamsError};
+use crate::database::helpers::invoice_helper::{InvoiceHelper, InvoiceHelperError};
+use crate::database::model::{InvoiceState, InvoiceStatistics};
+use crate::encoder::InvoiceEncoder;
+use crate::State;
+use cln_plugin::Plugin;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+const UNPAID_AGE_WARNING_SECONDS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct StatsRequest {
+    payment_hash: Option<String>,
+}
+
+impl FromArr for StatsRequest {
+    fn from_arr(arr: Vec<Value>) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            payment_hash: match arr.first() {
+                Some(Value::Null) | None => None,
+                Some(payment_hash) => Some(
+                    payment_hash
+                        .as_str()
+                        .ok_or(ParamsError::ParseError)?
+                        .to_string(),
+                ),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StatsResponse {
+    #[serde(flatten)]
+    statistics: InvoiceStatistics,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    htlc_count_by_state: Option<HashMap<String, u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    amount_paid_msat: Option<u64>,
+}
+
+pub async fn stats<T, E>(plugin: Plugin<State<T, E>>, args: Value) -> anyhow::Result<Value>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+    E: InvoiceEncoder + Sync + Send + Clone,
+{
+    let params = parse_args::<StatsRequest>(args)?;
+    let helper = &plugin.state().invoice_helper;
+
+    let statistics = helper.get_statistics()?;
+
+    if let Some(age) = statistics.oldest_unpaid_age_seconds {
+        if age > UNPAID_AGE_WARNING_SECONDS {
+            warn!("Oldest unpaid invoice was created {} seconds ago", age);
+        }
+    }
+
+    let mut response = StatsResponse {
+        statistics,
+        htlc_count_by_state: None,
+        amount_paid_msat: None,
+    };
+
+    if let Some(payment_hash) = params.payment_hash {
+        let invoice = match helper.get_by_payment_hash(&hex::decode(&payment_hash)?)? {
+            Some(invoice) => invoice.invoice,
+            None => return Err(InvoiceHelperError::InvoiceNotFound(payment_hash).into()),
+        };
+
+        response.htlc_count_by_state = Some(
+            helper
+                .count_htlcs(invoice.id)?
+                .into_iter()
+                .map(|(state, count)| (state.to_string(), count))
+                .collect(),
+        );
+        response.amount_paid_msat = Some(
+            helper.sum_htlc_msat(invoice.id, InvoiceState::Accepted)?
+                + helper.sum_htlc_msat(invoice.id, InvoiceState::Paid)?,
+        );
+    }
+
+    Ok(serde_json::to_value(&response)?)
+}
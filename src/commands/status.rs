@@ -0,0 +1,37 @@
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::encoder::InvoiceEncoder;
+use crate::settler::HoldSummary;
+use crate::State;
+use cln_plugin::Plugin;
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Debug, Serialize)]
+struct StatusResponse {
+    #[serde(flatten)]
+    summary: HoldSummary,
+    active_streams: u64,
+    database: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub async fn status<T, E>(plugin: Plugin<State<T, E>>, _args: Value) -> anyhow::Result<Value>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+    E: InvoiceEncoder + Sync + Send + Clone,
+{
+    let error = plugin
+        .state()
+        .invoice_helper
+        .ping()
+        .err()
+        .map(|err| err.to_string());
+
+    Ok(serde_json::to_value(&StatusResponse {
+        summary: plugin.state().settler.get_hold_summary().await,
+        active_streams: plugin.state().stream_limiter.active(),
+        database: if error.is_none() { "ok" } else { "error" },
+        error,
+    })?)
+}
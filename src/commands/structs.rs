@@ -1,4 +1,5 @@
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::Value;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
@@ -8,20 +9,28 @@ pub enum ParamsError {
     ParseError,
     TooFewParams,
     TooManyParams,
+    InvalidParam(String),
 }
 
 impl Display for ParamsError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match *self {
+        match self {
             ParamsError::ParseError => write!(f, "could not parse request"),
             ParamsError::TooFewParams => write!(f, "too few parameters"),
             ParamsError::TooManyParams => write!(f, "too many parameters"),
+            ParamsError::InvalidParam(msg) => write!(f, "invalid parameter: {}", msg),
         }
     }
 }
 
 impl Error for ParamsError {}
 
+#[derive(Debug, Serialize)]
+pub struct BatchFailure {
+    pub payment_hash: String,
+    pub error: String,
+}
+
 pub trait FromArr {
     fn from_arr(arr: Vec<Value>) -> anyhow::Result<Self>
     where
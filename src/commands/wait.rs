@@ -0,0 +1,203 @@
+use crate::commands::structs::{parse_args, FromArr, ParamsError};
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::database::model::{HoldInvoice, InvoiceState};
+use crate::encoder::InvoiceEncoder;
+use crate::State;
+use anyhow::{anyhow, Result};
+use cln_plugin::Plugin;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+const DEFAULT_TIMEOUT_SECONDS: u64 = 300;
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct WaitRequest {
+    payment_hash: String,
+    timeout: Option<u64>,
+}
+
+impl FromArr for WaitRequest {
+    fn from_arr(arr: Vec<Value>) -> Result<WaitRequest> {
+        if arr.is_empty() {
+            return Err(ParamsError::TooFewParams.into());
+        }
+
+        Ok(WaitRequest {
+            payment_hash: arr[0].as_str().ok_or(ParamsError::ParseError)?.to_string(),
+            timeout: match arr.get(1) {
+                Some(Value::Null) | None => None,
+                Some(value) => Some(value.as_u64().ok_or(ParamsError::ParseError)?),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct WaitResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preimage: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl WaitResponse {
+    fn from_invoice(invoice: HoldInvoice) -> Option<Self> {
+        match InvoiceState::try_from(&invoice.invoice.state).ok()? {
+            InvoiceState::Paid => Some(WaitResponse {
+                state: Some(InvoiceState::Paid.to_string()),
+                preimage: invoice.invoice.preimage.map(hex::encode),
+                error: None,
+            }),
+            InvoiceState::Cancelled => Some(WaitResponse {
+                state: Some(InvoiceState::Cancelled.to_string()),
+                preimage: None,
+                error: None,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub async fn wait<T, E>(plugin: Plugin<State<T, E>>, args: Value) -> Result<Value>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+    E: InvoiceEncoder + Sync + Send + Clone,
+{
+    let params = parse_args::<WaitRequest>(args)?;
+    let payment_hash = hex::decode(params.payment_hash)?;
+
+    // Subscribe before reading the current state, so that no update in between is missed
+    let mut state_rx = plugin.state().settler.state_rx();
+
+    let get_final_state = || -> Result<Option<WaitResponse>> {
+        match plugin
+            .state()
+            .invoice_helper
+            .get_by_payment_hash(&payment_hash)?
+        {
+            Some(invoice) => Ok(WaitResponse::from_invoice(invoice)),
+            None => Err(anyhow!("invoice not found")),
+        }
+    };
+
+    if let Some(res) = get_final_state()? {
+        return Ok(serde_json::to_value(&res)?);
+    }
+
+    let res = tokio::time::timeout(
+        Duration::from_secs(params.timeout.unwrap_or(DEFAULT_TIMEOUT_SECONDS)),
+        async {
+            loop {
+                match state_rx.recv().await {
+                    Ok(update) => {
+                        if update.payment_hash != payment_hash || !update.state.is_final() {
+                            continue;
+                        }
+                    }
+                    // Updates for the invoice could have been skipped
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => {
+                        return Err(anyhow!("invoice state update channel closed"));
+                    }
+                };
+
+                if let Some(res) = get_final_state()? {
+                    return Ok(res);
+                }
+            }
+        },
+    )
+    .await;
+
+    Ok(serde_json::to_value(&match res {
+        Ok(res) => res?,
+        Err(_) => WaitResponse {
+            state: None,
+            preimage: None,
+            error: Some("timeout".to_string()),
+        },
+    })?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::commands::structs::parse_args;
+    use crate::commands::wait::{WaitRequest, WaitResponse};
+    use crate::database::model::{HoldInvoice, Invoice, InvoiceState};
+    use serde_json::json;
+
+    fn hold_invoice(state: InvoiceState, preimage: Option<Vec<u8>>) -> HoldInvoice {
+        HoldInvoice {
+            invoice: Invoice {
+                cancel_reason: None,
+                expires_at: None,
+                label: None,
+                preimage,
+                id: 0,
+                payment_hash: vec![],
+                hash_algorithm: "sha256".to_string(),
+                bolt11: "".to_string(),
+                state: state.to_string(),
+                created_at: Default::default(),
+                settled_at: None,
+                mpp_timeout: None,
+                settlement_deadline: None,
+                accepted_at: None,
+            },
+            htlcs: vec![],
+        }
+    }
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(
+            parse_args::<WaitRequest>(json!(["00"])).unwrap(),
+            WaitRequest {
+                payment_hash: "00".to_string(),
+                timeout: None,
+            }
+        );
+        assert_eq!(
+            parse_args::<WaitRequest>(json!(["00", 10])).unwrap(),
+            WaitRequest {
+                payment_hash: "00".to_string(),
+                timeout: Some(10),
+            }
+        );
+        assert_eq!(
+            parse_args::<WaitRequest>(json!([]))
+                .err()
+                .unwrap()
+                .to_string(),
+            "too few parameters"
+        );
+    }
+
+    #[test]
+    fn response_from_invoice() {
+        assert_eq!(
+            WaitResponse::from_invoice(hold_invoice(InvoiceState::Paid, Some(vec![1, 2]))),
+            Some(WaitResponse {
+                state: Some("paid".to_string()),
+                preimage: Some("0102".to_string()),
+                error: None,
+            })
+        );
+        assert_eq!(
+            WaitResponse::from_invoice(hold_invoice(InvoiceState::Cancelled, None)),
+            Some(WaitResponse {
+                state: Some("cancelled".to_string()),
+                preimage: None,
+                error: None,
+            })
+        );
+        assert_eq!(
+            WaitResponse::from_invoice(hold_invoice(InvoiceState::Accepted, None)),
+            None
+        );
+    }
+}
@@ -7,6 +7,41 @@ pub const OPTION_DATABASE: options::DefaultStringConfigOption =
         "hold database",
     );
 
+pub const OPTION_DATABASE_BUSY_TIMEOUT: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-database-busy-timeout",
+        5_000,
+        "hold SQLite busy timeout in milliseconds",
+    );
+
+pub const OPTION_DATABASE_POOL_SIZE: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-database-pool-size",
+        10,
+        "hold maximal number of database connections",
+    );
+
+pub const OPTION_DATABASE_MIN_IDLE: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-database-min-idle",
+        1,
+        "hold minimal number of idle database connections",
+    );
+
+pub const OPTION_DATABASE_CONNECT_TIMEOUT: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-database-connect-timeout",
+        30,
+        "hold timeout in seconds for getting a database connection",
+    );
+
+pub const OPTION_DATABASE_CACHE_TTL: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-database-cache-ttl",
+        30,
+        "hold seconds for which invoices looked up by payment hash are cached; 0 to disable",
+    );
+
 pub const OPTION_MPP_TIMEOUT: options::DefaultIntegerConfigOption =
     options::ConfigOption::new_i64_with_default(
         "hold-mpp-timeout",
@@ -14,12 +49,186 @@ pub const OPTION_MPP_TIMEOUT: options::DefaultIntegerConfigOption =
         "hold MPP timeout in seconds",
     );
 
+pub const OPTION_MPP_CHECK_INTERVAL: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-mpp-check-interval",
+        15,
+        "hold interval in seconds in which MPP timeouts are checked",
+    );
+
+pub const OPTION_UNDERPAYMENT_TOLERANCE: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-underpayment-tolerance",
+        0,
+        "hold underpayment tolerance in millisatoshis",
+    );
+
+// String, because there is no float config option
+pub const OPTION_OVERPAYMENT_FACTOR: options::DefaultStringConfigOption =
+    options::ConfigOption::new_str_with_default(
+        "hold-overpayment-factor",
+        "2.0",
+        "hold factor of the invoice amount up to which HTLCs are accepted",
+    );
+
+pub const OPTION_MAX_PENDING_HTLCS: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-max-pending-htlcs",
+        1_000,
+        "hold maximal number of HTLCs that are held at the same time",
+    );
+
+pub const OPTION_AUTO_CLEAN_INTERVAL: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-auto-clean-interval",
+        0,
+        "hold interval in seconds in which cancelled invoices are cleaned; 0 to disable",
+    );
+
+pub const OPTION_AUTO_CLEAN_AGE: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-auto-clean-age",
+        86_400,
+        "hold minimal age in seconds of cancelled invoices that are cleaned automatically",
+    );
+
+pub const OPTION_AUTO_CLEAN_PAID: options::DefaultBooleanConfigOption =
+    options::ConfigOption::new_bool_with_default(
+        "hold-auto-clean-paid",
+        false,
+        "hold also cleans paid invoices automatically",
+    );
+
+pub const OPTION_AUTO_CLEAN_PAID_AGE: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-auto-clean-paid-age",
+        604_800,
+        "hold minimal age in seconds of paid invoices that are cleaned automatically",
+    );
+
+pub const OPTION_INVOICE_DESCRIPTION_DEFAULT: options::DefaultStringConfigOption =
+    options::ConfigOption::new_str_with_default(
+        "hold-invoice-description-default",
+        "",
+        "hold description of invoices that are created without one",
+    );
+
+pub const OPTION_CLN_RPC_POOL_SIZE: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-cln-rpc-pool-size",
+        4,
+        "hold number of connections to the CLN RPC used to sign invoices",
+    );
+
 pub const OPTION_GRPC_HOST: options::DefaultStringConfigOption =
     options::ConfigOption::new_str_with_default("hold-grpc-host", "127.0.0.1", "hold gRPC host");
 
+pub const OPTION_GRPC_BIND_BOTH: options::DefaultBooleanConfigOption =
+    options::ConfigOption::new_bool_with_default(
+        "hold-grpc-bind-both",
+        false,
+        "hold gRPC listens on IPv4 and IPv6",
+    );
+
 pub const OPTION_GRPC_PORT: options::DefaultIntegerConfigOption =
     options::ConfigOption::new_i64_with_default(
         "hold-grpc-port",
         9292,
         "hold gRPC post; set to -1 to disable",
     );
+
+pub const OPTION_GRPC_MAX_SEND_MESSAGE_SIZE: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-grpc-max-send-message-size",
+        0,
+        "hold maximal size in bytes of gRPC messages the server sends; 0 for no limit",
+    );
+
+pub const OPTION_GRPC_MAX_RECV_MESSAGE_SIZE: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-grpc-max-recv-message-size",
+        0,
+        "hold maximal size in bytes of gRPC messages the server receives; 0 for 4 MB",
+    );
+
+pub const OPTION_GRPC_KEEPALIVE_TIME: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-grpc-keepalive-time",
+        30,
+        "hold interval in seconds of HTTP/2 keepalive pings; keeps streams of mobile clients alive behind NATs; 0 disables them",
+    );
+
+pub const OPTION_GRPC_KEEPALIVE_TIMEOUT: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-grpc-keepalive-timeout",
+        10,
+        "hold seconds after which a connection whose keepalive ping was not answered is closed",
+    );
+
+pub const OPTION_GRPC_TCP_KEEPALIVE: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-grpc-tcp-keepalive",
+        30,
+        "hold interval in seconds of TCP keepalive probes; important for stable mobile clients; 0 disables them",
+    );
+
+pub const OPTION_GRPC_RATE_LIMIT_INVOICES: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-grpc-rate-limit-invoices",
+        100,
+        "hold maximal number of invoices created via gRPC per minute; 0 for no limit",
+    );
+
+pub const OPTION_GRPC_CONCURRENT_STREAMS: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-grpc-concurrent-streams",
+        100,
+        "hold maximal number of concurrent gRPC requests per connection; 0 for no limit",
+    );
+
+pub const OPTION_GRPC_GLOBAL_MAX_STREAMS: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-grpc-global-max-streams",
+        10_000,
+        "hold maximal number of open Track and TrackAll streams across all connections; 0 for no limit",
+    );
+
+pub const OPTION_METRICS_PORT: options::DefaultIntegerConfigOption =
+    options::ConfigOption::new_i64_with_default(
+        "hold-metrics-port",
+        -1,
+        "hold Prometheus metrics port on the gRPC host; set to -1 to disable",
+    );
+
+pub const OPTION_LOG_LEVEL: options::DefaultStringConfigOption =
+    options::ConfigOption::new_str_with_default(
+        "hold-log-level",
+        "info",
+        "hold most verbose level of its log messages; trace, debug, info, warn or error",
+    );
+
+pub const OPTION_LOG_FORMAT: options::DefaultStringConfigOption =
+    options::ConfigOption::new_str_with_default(
+        "hold-log-format",
+        "text",
+        "hold log format; text or json",
+    );
+
+pub const OPTION_WEBHOOK_URL: options::StringConfigOption =
+    options::ConfigOption::new_str_no_default(
+        "hold-webhook-url",
+        "hold URL to which invoice state updates are POSTed",
+    );
+
+pub const OPTION_WEBHOOK_SECRET: options::StringConfigOption =
+    options::ConfigOption::new_str_no_default(
+        "hold-webhook-secret",
+        "hold secret with which webhook payloads are signed",
+    );
+
+pub const OPTION_TIME_EXPIRY_CANCEL: options::DefaultBooleanConfigOption =
+    options::ConfigOption::new_bool_with_default(
+        "hold-time-expiry-cancel",
+        true,
+        "hold cancels unpaid invoices once their expiry was reached",
+    );
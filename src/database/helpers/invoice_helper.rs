@@ -1,29 +1,107 @@
 use crate::database::model::{
-    HoldInvoice, Htlc, HtlcInsertable, Invoice, InvoiceInsertable, InvoiceState,
+    HashAlgorithm, HoldInvoice, Htlc, HtlcInsertable, Invoice, InvoiceInsertable, InvoiceState,
+    InvoiceStateLog, InvoiceStateLogInsertable, InvoiceStatistics, SortField,
+    MAX_CANCEL_REASON_LENGTH, MAX_LABEL_LENGTH,
 };
-use crate::database::schema::{htlcs, invoices};
-use crate::database::Pool;
+use crate::database::schema::{htlcs, invoice_state_log, invoices};
+use crate::database::{AnyConnection, Pool};
 use anyhow::{anyhow, Result};
-use chrono::{TimeDelta, Utc};
-use diesel::dsl::delete;
+use bitcoin::hashes::{sha256, Hash};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+use diesel::connection::SimpleConnection;
+use diesel::dsl::{count_star, delete};
+use diesel::result::DatabaseErrorKind;
+use diesel::sql_types::{BigInt, Double, Nullable, Text, Timestamp};
 use diesel::{
-    insert_into, update, BelongingToDsl, BoolExpressionMethods, Connection, ExpressionMethods,
-    GroupedBy,
+    insert_into, sql_query, update, BelongingToDsl, BoolExpressionMethods, ExpressionMethods,
+    GroupedBy, QueryableByName,
 };
-use diesel::{QueryDsl, RunQueryDsl, SelectableHelper};
+use diesel::{Connection, OptionalExtension, QueryDsl, RunQueryDsl, SelectableHelper};
+use lightning_invoice::Bolt11Invoice;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::ops::Sub;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq)]
+pub enum InvoiceHelperError {
+    DuplicatePaymentHash(String),
+    DuplicateLabel(String),
+    LabelTooLong(usize),
+    CancelReasonTooLong(usize),
+    InvalidCleanState(InvoiceState),
+    InvalidDeleteState(InvoiceState),
+    InvalidSettleState(InvoiceState),
+    InvoiceNotFound(String),
+    HtlcNotFound(i64),
+}
+
+impl Display for InvoiceHelperError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvoiceHelperError::DuplicatePaymentHash(payment_hash) => {
+                write!(
+                    f,
+                    "invoice with payment hash {} exists already",
+                    payment_hash
+                )
+            }
+            InvoiceHelperError::DuplicateLabel(label) => {
+                write!(f, "invoice with label {} exists already", label)
+            }
+            InvoiceHelperError::LabelTooLong(length) => {
+                write!(
+                    f,
+                    "label has to be at most {} bytes long, but is {}",
+                    MAX_LABEL_LENGTH, length
+                )
+            }
+            InvoiceHelperError::CancelReasonTooLong(length) => {
+                write!(
+                    f,
+                    "cancel reason has to be at most {} characters long, but is {}",
+                    MAX_CANCEL_REASON_LENGTH, length
+                )
+            }
+            InvoiceHelperError::InvalidCleanState(state) => {
+                write!(f, "invoices in state {} cannot be cleaned", state)
+            }
+            InvoiceHelperError::InvalidDeleteState(state) => {
+                write!(f, "invoices in state {} cannot be deleted", state)
+            }
+            InvoiceHelperError::InvalidSettleState(state) => {
+                write!(f, "invoices in state {} cannot be settled", state)
+            }
+            InvoiceHelperError::InvoiceNotFound(payment_hash) => {
+                write!(f, "no invoice with payment hash {} found", payment_hash)
+            }
+            InvoiceHelperError::HtlcNotFound(id) => {
+                write!(f, "no HTLC with ID {} found", id)
+            }
+        }
+    }
+}
+
+impl Error for InvoiceHelperError {}
 
 pub trait InvoiceHelper {
     fn insert(&self, invoice: &InvoiceInsertable) -> Result<usize>;
     fn insert_htlc(&self, htlc: &HtlcInsertable) -> Result<usize>;
 
+    /// Only updates the invoice when it is still in `state`; returns 0 when a concurrent
+    /// update changed the state already
     fn set_invoice_state(
         &self,
         id: i64,
         state: InvoiceState,
         new_state: InvoiceState,
     ) -> Result<usize>;
-    fn set_invoice_preimage(&self, id: i64, preimage: &[u8]) -> Result<usize>;
+    /// Only records the preimage; the states of the invoice and its HTLCs are not changed
+    fn set_invoice_preimage(&self, payment_hash: &[u8], preimage: &[u8]) -> Result<()>;
+    fn set_invoice_cancel_reason(&self, payment_hash: &[u8], reason: &str) -> Result<()>;
     fn set_htlc_state_by_id(
         &self,
         htlc_id: i64,
@@ -36,36 +114,281 @@ pub trait InvoiceHelper {
         state: InvoiceState,
         new_state: InvoiceState,
     ) -> Result<usize>;
+    fn set_htlc_preimage(&self, htlc_id: i64, preimage: &[u8]) -> Result<()>;
+    /// Accepts the unpaid invoice in a single statement, but only when its accepted HTLCs
+    /// sum up to at least `amount_threshold`. Returns whether the invoice was accepted by this call
+    fn conditional_accept(&self, invoice_id: i64, amount_threshold: u64) -> Result<bool>;
 
-    fn clean_cancelled(&self, age: Option<u64>) -> Result<usize>;
+    fn clean(&self, state: Option<InvoiceState>, age: Option<u64>, dry_run: bool) -> Result<usize>;
+    /// Deletes paid invoices older than `age` seconds with their HTLCs, so that their
+    /// preimages do not stay around; accepted invoices are never touched
+    fn clean_paid(&self, age: Option<u64>) -> Result<usize>;
+    /// Deletes an unpaid or cancelled invoice and its HTLCs; returns false when there is none
+    fn delete_by_payment_hash(&self, payment_hash: &[u8]) -> Result<bool>;
+    /// Settles the accepted invoice with the payment hash and inserts a new invoice in one
+    /// transaction; neither is done when one of them fails. Returns the settled invoice
+    fn settle_and_insert(
+        &self,
+        payment_hash: &[u8],
+        preimage: &[u8],
+        invoice: &InvoiceInsertable,
+    ) -> Result<Invoice>;
 
     fn get_all(&self) -> Result<Vec<HoldInvoice>>;
-    fn get_paginated(&self, index_start: i64, limit: u64) -> Result<Vec<HoldInvoice>>;
+    /// Invoices that were not settled are last when sorting by `settled_at`
+    fn get_all_sorted(&self, field: SortField, desc: bool) -> Result<Vec<HoldInvoice>>;
+    /// Gets up to `limit` invoices with an ID greater than `after_id`, ordered by ID
+    fn get_paginated(
+        &self,
+        after_id: i64,
+        limit: u64,
+        state: Option<InvoiceState>,
+    ) -> Result<Vec<HoldInvoice>>;
+    /// Pages through the invoices ordered by creation time; `after` is the creation time and ID
+    /// of the last invoice of the previous page, because the creation time alone is not unique
+    fn get_paginated_by_time(
+        &self,
+        after: Option<(NaiveDateTime, i64)>,
+        limit: u64,
+    ) -> Result<Vec<HoldInvoice>>;
     fn get_by_payment_hash(&self, payment_hash: &[u8]) -> Result<Option<HoldInvoice>>;
+    /// Only finds invoices whose preimage was recorded when they were settled
+    fn get_by_preimage(&self, preimage: &[u8]) -> Result<Option<HoldInvoice>>;
+    fn get_by_bolt11(&self, bolt11: &str) -> Result<Option<HoldInvoice>>;
+    fn get_by_label(&self, label: &str) -> Result<Option<HoldInvoice>>;
+    fn batch_get_by_payment_hashes(&self, payment_hashes: &[Vec<u8>]) -> Result<Vec<HoldInvoice>>;
+    fn get_by_state(&self, state: InvoiceState) -> Result<Vec<HoldInvoice>>;
+    /// Unpaid invoices whose BOLT11 expiry is less than `within_seconds` away or passed already
+    fn get_expiring_soon(&self, within_seconds: u64) -> Result<Vec<HoldInvoice>>;
+    fn get_oldest_unpaid(&self) -> Result<Option<HoldInvoice>>;
+    fn count_by_state(&self) -> Result<HashMap<InvoiceState, u64>>;
+    fn get_in_date_range(
+        &self,
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>,
+    ) -> Result<Vec<HoldInvoice>>;
+    fn get_created_in_range(
+        &self,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Result<Vec<HoldInvoice>>;
+    fn get_settled_in_range(
+        &self,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Result<Vec<HoldInvoice>>;
+    fn get_state_log(&self, invoice_ids: &[i64]) -> Result<Vec<InvoiceStateLog>>;
+    fn get_statistics(&self) -> Result<InvoiceStatistics>;
+    fn avg_hold_duration_seconds(&self) -> Result<Option<f64>>;
+    fn get_pending_htlc_count(&self) -> Result<u64>;
+    fn get_accepted_invoice_count(&self) -> Result<u64>;
+    /// Counts the HTLCs of an invoice by state without loading them
+    fn count_htlcs(&self, invoice_id: i64) -> Result<HashMap<InvoiceState, u64>>;
+    fn sum_htlc_msat(&self, invoice_id: i64, state: InvoiceState) -> Result<u64>;
+    /// Accepted HTLCs that came in via the channel and the invoices they belong to
+    fn get_htlcs_by_scid(&self, scid: &str) -> Result<Vec<(Invoice, Htlc)>>;
+    /// Sums the paid HTLCs created within the inclusive bounds; without bounds all are summed
+    fn total_volume_msat(
+        &self,
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>,
+    ) -> Result<u64>;
+    /// Sums the paid HTLCs by the day they were created on, over the last `days` days
+    fn volume_by_day(&self, days: u32) -> Result<Vec<(NaiveDate, u64)>>;
+
+    fn ping(&self) -> Result<()>;
+}
+
+#[derive(QueryableByName, Debug)]
+struct StatisticsRow {
+    #[diesel(sql_type = BigInt)]
+    unpaid: i64,
+    #[diesel(sql_type = BigInt)]
+    accepted: i64,
+    #[diesel(sql_type = BigInt)]
+    paid: i64,
+    #[diesel(sql_type = BigInt)]
+    cancelled: i64,
+    #[diesel(sql_type = BigInt)]
+    paused: i64,
+    #[diesel(sql_type = BigInt)]
+    total_settled_msat: i64,
+    #[diesel(sql_type = Nullable<Double>)]
+    avg_settlement_seconds: Option<f64>,
+    #[diesel(sql_type = BigInt)]
+    pending_htlc_count: i64,
+}
+
+#[derive(QueryableByName, Debug)]
+struct AvgRow {
+    #[diesel(sql_type = Nullable<Double>)]
+    avg: Option<f64>,
+}
+
+#[derive(QueryableByName, Debug)]
+struct SumRow {
+    #[diesel(sql_type = BigInt)]
+    sum: i64,
+}
+
+#[derive(QueryableByName, Debug)]
+struct DailySumRow {
+    #[diesel(sql_type = Text)]
+    day: String,
+    #[diesel(sql_type = BigInt)]
+    sum: i64,
+}
+
+#[derive(Debug, Default)]
+struct InvoiceCache {
+    entries: HashMap<Vec<u8>, (HoldInvoice, Instant)>,
+    // Bumped on every invalidation, so that reads that raced with a write are not cached
+    generation: u64,
 }
 
 #[derive(Clone, Debug)]
 pub struct InvoiceHelperDatabase {
     pool: Pool,
+    cache_ttl: Duration,
+    cache: Arc<Mutex<InvoiceCache>>,
 }
 
 impl InvoiceHelperDatabase {
     pub fn new(pool: Pool) -> Self {
-        InvoiceHelperDatabase { pool }
+        InvoiceHelperDatabase {
+            pool,
+            cache_ttl: Duration::ZERO,
+            cache: Arc::new(Mutex::new(InvoiceCache::default())),
+        }
+    }
+
+    /// Caches lookups by payment hash for `ttl`; a zero TTL disables the cache
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    fn lock_cache(&self) -> MutexGuard<'_, InvoiceCache> {
+        match self.cache.lock() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn invalidate_cache<F>(&self, is_affected: F)
+    where
+        F: Fn(&HoldInvoice) -> bool,
+    {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+
+        let mut cache = self.lock_cache();
+        cache.generation += 1;
+        cache
+            .entries
+            .retain(|_, (invoice, _)| !is_affected(invoice));
+    }
+
+    fn load_by_payment_hash(&self, payment_hash: &[u8]) -> Result<Option<HoldInvoice>> {
+        let mut con = self.pool.get()?;
+
+        let invoices = invoices::dsl::invoices
+            .select(Invoice::as_select())
+            .filter(invoices::dsl::payment_hash.eq(payment_hash))
+            .limit(1)
+            .load(&mut con)?;
+
+        if invoices.is_empty() {
+            return Ok(None);
+        }
+
+        let invoice = invoices[0].clone();
+        let htlcs = Htlc::belonging_to(&vec![invoice.clone()])
+            .select(Htlc::as_select())
+            .load(&mut con)?;
+
+        Ok(Some(HoldInvoice::new(invoice, htlcs)))
+    }
+
+    fn insert_state_log(
+        con: &mut AnyConnection,
+        invoice_id: i64,
+        old: InvoiceState,
+        new: InvoiceState,
+    ) -> Result<usize> {
+        Ok(insert_into(invoice_state_log::dsl::invoice_state_log)
+            .values(&InvoiceStateLogInsertable {
+                invoice_id,
+                old_state: old.to_string(),
+                new_state: new.to_string(),
+            })
+            .execute(con)?)
+    }
+
+    fn insert_invoice(con: &mut AnyConnection, invoice: &InvoiceInsertable) -> Result<usize> {
+        if let Some(label) = &invoice.label {
+            if label.len() > MAX_LABEL_LENGTH {
+                return Err(InvoiceHelperError::LabelTooLong(label.len()).into());
+            }
+        }
+        HashAlgorithm::try_from(&invoice.hash_algorithm)?;
+
+        // Invoices that cannot be decoded are stored without expiry
+        let expires_at = Bolt11Invoice::from_str(&invoice.bolt11)
+            .ok()
+            .and_then(|decoded| decoded.expires_at())
+            .and_then(|expires_at| DateTime::from_timestamp(expires_at.as_secs() as i64, 0))
+            .map(|expires_at| expires_at.naive_utc());
+
+        match insert_into(invoices::dsl::invoices)
+            .values((invoice, invoices::dsl::expires_at.eq(expires_at)))
+            .execute(con)
+        {
+            Ok(res) => Ok(res),
+            Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info)) => {
+                // SQLite only reports the violated column in the message
+                let is_label = info.constraint_name() == Some("invoices_label_idx")
+                    || info.message().contains("invoices.label");
+
+                Err(match (is_label, &invoice.label) {
+                    (true, Some(label)) => InvoiceHelperError::DuplicateLabel(label.clone()),
+                    _ => {
+                        InvoiceHelperError::DuplicatePaymentHash(hex::encode(&invoice.payment_hash))
+                    }
+                }
+                .into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn load_htlcs(con: &mut AnyConnection, invoices: Vec<Invoice>) -> Result<Vec<HoldInvoice>> {
+        let htlcs = Htlc::belonging_to(&invoices)
+            .select(Htlc::as_select())
+            .load(con)?;
+
+        Ok(htlcs
+            .grouped_by(&invoices)
+            .into_iter()
+            .zip(invoices)
+            .map(|(htlcs, invoice)| HoldInvoice::new(invoice, htlcs))
+            .collect())
     }
 }
 
 impl InvoiceHelper for InvoiceHelperDatabase {
     fn insert(&self, invoice: &InvoiceInsertable) -> Result<usize> {
-        Ok(insert_into(invoices::dsl::invoices)
-            .values(invoice)
-            .execute(&mut self.pool.get()?)?)
+        Self::insert_invoice(&mut *self.pool.get()?, invoice)
     }
 
     fn insert_htlc(&self, htlc: &HtlcInsertable) -> Result<usize> {
-        Ok(insert_into(htlcs::dsl::htlcs)
+        let inserted = insert_into(htlcs::dsl::htlcs)
             .values(htlc)
-            .execute(&mut self.pool.get()?)?)
+            .execute(&mut self.pool.get()?)?;
+
+        self.invalidate_cache(|invoice| invoice.invoice.id == htlc.invoice_id);
+        Ok(inserted)
     }
 
     fn set_invoice_state(
@@ -76,27 +399,76 @@ impl InvoiceHelper for InvoiceHelperDatabase {
     ) -> Result<usize> {
         state.validate_transition(new_state)?;
 
-        if new_state != InvoiceState::Paid {
-            Ok(update(invoices::dsl::invoices)
-                .filter(invoices::dsl::id.eq(id))
-                .set(invoices::dsl::state.eq(new_state.to_string()))
-                .execute(&mut self.pool.get()?)?)
-        } else {
-            Ok(update(invoices::dsl::invoices)
-                .filter(invoices::dsl::id.eq(id))
-                .set((
-                    invoices::dsl::state.eq(new_state.to_string()),
-                    invoices::dsl::settled_at.eq(Some(Utc::now().naive_utc())),
-                ))
-                .execute(&mut self.pool.get()?)?)
-        }
+        let now = Some(Utc::now().naive_utc());
+        // The transition is logged in the same transaction, so that the state and its
+        // history cannot get out of sync
+        let updated = self.pool.get()?.transaction(|con| -> Result<usize> {
+            let query = update(invoices::dsl::invoices).filter(
+                invoices::dsl::id
+                    .eq(id)
+                    .and(invoices::dsl::state.eq(state.to_string())),
+            );
+
+            let updated = match new_state {
+                InvoiceState::Paid => query
+                    .set((
+                        invoices::dsl::state.eq(new_state.to_string()),
+                        invoices::dsl::settled_at.eq(now),
+                    ))
+                    .execute(con)?,
+                InvoiceState::Accepted if state != new_state => query
+                    .set((
+                        invoices::dsl::state.eq(new_state.to_string()),
+                        invoices::dsl::accepted_at.eq(now),
+                    ))
+                    .execute(con)?,
+                _ => query
+                    .set(invoices::dsl::state.eq(new_state.to_string()))
+                    .execute(con)?,
+            };
+
+            if updated > 0 && state != new_state {
+                Self::insert_state_log(con, id, state, new_state)?;
+            }
+
+            Ok(updated)
+        })?;
+        self.invalidate_cache(|invoice| invoice.invoice.id == id);
+
+        Ok(updated)
     }
 
-    fn set_invoice_preimage(&self, id: i64, preimage: &[u8]) -> Result<usize> {
-        Ok(update(invoices::dsl::invoices)
-            .filter(invoices::dsl::id.eq(id))
+    fn set_invoice_preimage(&self, payment_hash: &[u8], preimage: &[u8]) -> Result<()> {
+        let updated = update(invoices::dsl::invoices)
+            .filter(invoices::dsl::payment_hash.eq(payment_hash))
             .set(invoices::dsl::preimage.eq(preimage))
-            .execute(&mut self.pool.get()?)?)
+            .execute(&mut self.pool.get()?)?;
+        self.invalidate_cache(|invoice| invoice.invoice.payment_hash == payment_hash);
+
+        if updated == 0 {
+            return Err(InvoiceHelperError::InvoiceNotFound(hex::encode(payment_hash)).into());
+        }
+
+        Ok(())
+    }
+
+    fn set_invoice_cancel_reason(&self, payment_hash: &[u8], reason: &str) -> Result<()> {
+        let length = reason.chars().count();
+        if length > MAX_CANCEL_REASON_LENGTH {
+            return Err(InvoiceHelperError::CancelReasonTooLong(length).into());
+        }
+
+        let updated = update(invoices::dsl::invoices)
+            .filter(invoices::dsl::payment_hash.eq(payment_hash))
+            .set(invoices::dsl::cancel_reason.eq(reason))
+            .execute(&mut self.pool.get()?)?;
+        self.invalidate_cache(|invoice| invoice.invoice.payment_hash == payment_hash);
+
+        if updated == 0 {
+            return Err(InvoiceHelperError::InvoiceNotFound(hex::encode(payment_hash)).into());
+        }
+
+        Ok(())
     }
 
     fn set_htlc_state_by_id(
@@ -107,10 +479,13 @@ impl InvoiceHelper for InvoiceHelperDatabase {
     ) -> Result<usize> {
         state.validate_transition(new_state)?;
 
-        Ok(update(htlcs::dsl::htlcs)
+        let updated = update(htlcs::dsl::htlcs)
             .filter(htlcs::dsl::id.eq(htlc_id))
             .set(htlcs::dsl::state.eq(new_state.to_string()))
-            .execute(&mut self.pool.get()?)?)
+            .execute(&mut self.pool.get()?)?;
+
+        self.invalidate_cache(|invoice| invoice.htlcs.iter().any(|htlc| htlc.id == htlc_id));
+        Ok(updated)
     }
 
     fn set_htlc_states_by_invoice(
@@ -121,39 +496,190 @@ impl InvoiceHelper for InvoiceHelperDatabase {
     ) -> Result<usize> {
         state.validate_transition(new_state)?;
 
-        Ok(update(htlcs::dsl::htlcs)
+        let updated = update(htlcs::dsl::htlcs)
             .filter(htlcs::dsl::invoice_id.eq(invoice_id))
             .set(htlcs::dsl::state.eq(new_state.to_string()))
-            .execute(&mut self.pool.get()?)?)
+            .execute(&mut self.pool.get()?)?;
+
+        self.invalidate_cache(|invoice| invoice.invoice.id == invoice_id);
+        Ok(updated)
+    }
+
+    fn set_htlc_preimage(&self, htlc_id: i64, preimage: &[u8]) -> Result<()> {
+        let updated = update(htlcs::dsl::htlcs)
+            .filter(htlcs::dsl::id.eq(htlc_id))
+            .set(htlcs::dsl::preimage.eq(preimage))
+            .execute(&mut self.pool.get()?)?;
+        self.invalidate_cache(|invoice| invoice.htlcs.iter().any(|htlc| htlc.id == htlc_id));
+
+        if updated == 0 {
+            return Err(InvoiceHelperError::HtlcNotFound(htlc_id).into());
+        }
+
+        Ok(())
+    }
+
+    fn conditional_accept(&self, invoice_id: i64, amount_threshold: u64) -> Result<bool> {
+        let accepted = self.pool.get()?.transaction(|con| -> Result<bool> {
+            // The HTLCs are summed by the same statement that updates the invoice,
+            // so that concurrently inserted HTLCs cannot be missed
+            let updated = sql_query(
+                "UPDATE invoices SET state = $1, accepted_at = $2 WHERE id = $3 AND state = $4 \
+                AND (SELECT COALESCE(SUM(msat), 0) FROM htlcs \
+                    WHERE invoice_id = $5 AND state = $6) >= $7",
+            )
+            .bind::<Text, _>(InvoiceState::Accepted.to_string())
+            .bind::<Timestamp, _>(Utc::now().naive_utc())
+            .bind::<BigInt, _>(invoice_id)
+            .bind::<Text, _>(InvoiceState::Unpaid.to_string())
+            .bind::<BigInt, _>(invoice_id)
+            .bind::<Text, _>(InvoiceState::Accepted.to_string())
+            .bind::<BigInt, _>(amount_threshold as i64)
+            .execute(con)?;
+            if updated == 0 {
+                return Ok(false);
+            }
+
+            Self::insert_state_log(
+                con,
+                invoice_id,
+                InvoiceState::Unpaid,
+                InvoiceState::Accepted,
+            )?;
+            Ok(true)
+        })?;
+        self.invalidate_cache(|invoice| invoice.invoice.id == invoice_id);
+
+        Ok(accepted)
     }
 
-    fn clean_cancelled(&self, age: Option<u64>) -> Result<usize> {
+    fn clean(&self, state: Option<InvoiceState>, age: Option<u64>, dry_run: bool) -> Result<usize> {
+        // Only invoices in a final state can be cleaned
+        let state = state.unwrap_or(InvoiceState::Cancelled);
+        if !state.is_final() {
+            return Err(InvoiceHelperError::InvalidCleanState(state).into());
+        }
+
         let age = match TimeDelta::new(age.unwrap_or(0) as i64, 0) {
             Some(age) => age,
             None => return Err(anyhow!("invalid age")),
         };
 
         let now = Utc::now().naive_utc().sub(age);
+        let invoice_clause = invoices::dsl::state
+            .eq(state.to_string())
+            .and(invoices::dsl::created_at.le(now));
 
         let mut con = self.pool.get()?;
-        con.transaction(|tx| {
-            let invoice_clause = invoices::dsl::state
-                .eq(InvoiceState::Cancelled.to_string())
-                .and(invoices::dsl::created_at.le(now));
+        if dry_run {
+            let count: i64 = invoices::dsl::invoices
+                .filter(invoice_clause)
+                .count()
+                .get_result(&mut con)?;
+            return Ok(count as usize);
+        }
+
+        // HTLCs and state log entries are removed by the cascading foreign keys
+        let deleted = delete(invoices::dsl::invoices.filter(invoice_clause)).execute(&mut con)?;
+
+        self.invalidate_cache(|invoice| invoice.invoice.state == state.to_string());
+        Ok(deleted)
+    }
+
+    fn clean_paid(&self, age: Option<u64>) -> Result<usize> {
+        self.clean(Some(InvoiceState::Paid), age, false)
+    }
 
-            let invoices = invoices::dsl::invoices
+    fn delete_by_payment_hash(&self, payment_hash: &[u8]) -> Result<bool> {
+        let deleted = self.pool.get()?.transaction(|con| -> Result<bool> {
+            let invoice = match invoices::dsl::invoices
                 .select(Invoice::as_select())
-                .filter(invoice_clause.clone())
-                .load(tx)?;
+                .filter(invoices::dsl::payment_hash.eq(payment_hash))
+                .first(con)
+                .optional()?
+            {
+                Some(invoice) => invoice,
+                None => return Ok(false),
+            };
 
-            delete(
-                htlcs::dsl::htlcs
-                    .filter(htlcs::dsl::invoice_id.eq_any(invoices.iter().map(|i| i.id))),
-            )
-            .execute(tx)?;
+            // Invoices with HTLCs that are or were held must not disappear
+            let state = InvoiceState::try_from(&invoice.state)?;
+            if state != InvoiceState::Unpaid && state != InvoiceState::Cancelled {
+                return Err(InvoiceHelperError::InvalidDeleteState(state).into());
+            }
 
-            Ok(delete(invoices::dsl::invoices.filter(invoice_clause)).execute(tx)?)
-        })
+            delete(htlcs::dsl::htlcs.filter(htlcs::dsl::invoice_id.eq(invoice.id))).execute(con)?;
+            delete(invoices::dsl::invoices.filter(invoices::dsl::id.eq(invoice.id)))
+                .execute(con)?;
+
+            Ok(true)
+        })?;
+
+        if deleted {
+            self.invalidate_cache(|invoice| invoice.invoice.payment_hash == payment_hash);
+        }
+        Ok(deleted)
+    }
+
+    fn settle_and_insert(
+        &self,
+        payment_hash: &[u8],
+        preimage: &[u8],
+        invoice: &InvoiceInsertable,
+    ) -> Result<Invoice> {
+        let settled = self.pool.get()?.transaction(|con| -> Result<Invoice> {
+            let mut settled = match invoices::dsl::invoices
+                .select(Invoice::as_select())
+                .filter(invoices::dsl::payment_hash.eq(payment_hash))
+                .first(con)
+                .optional()?
+            {
+                Some(invoice) => invoice,
+                None => {
+                    return Err(
+                        InvoiceHelperError::InvoiceNotFound(hex::encode(payment_hash)).into(),
+                    )
+                }
+            };
+
+            let state = InvoiceState::try_from(&settled.state)?;
+            if state != InvoiceState::Accepted {
+                return Err(InvoiceHelperError::InvalidSettleState(state).into());
+            }
+
+            settled.state = InvoiceState::Paid.to_string();
+            settled.settled_at = Some(Utc::now().naive_utc());
+            settled.preimage = Some(preimage.to_vec());
+            update(invoices::dsl::invoices)
+                .filter(invoices::dsl::id.eq(settled.id))
+                .set((
+                    invoices::dsl::state.eq(&settled.state),
+                    invoices::dsl::settled_at.eq(settled.settled_at),
+                    invoices::dsl::preimage.eq(preimage),
+                ))
+                .execute(con)?;
+            update(htlcs::dsl::htlcs)
+                .filter(
+                    htlcs::dsl::invoice_id
+                        .eq(settled.id)
+                        .and(htlcs::dsl::state.eq(InvoiceState::Accepted.to_string())),
+                )
+                .set((
+                    htlcs::dsl::state.eq(InvoiceState::Paid.to_string()),
+                    htlcs::dsl::preimage.eq(preimage),
+                ))
+                .execute(con)?;
+            Self::insert_state_log(con, settled.id, state, InvoiceState::Paid)?;
+
+            Self::insert_invoice(con, invoice)?;
+
+            Ok(settled)
+        })?;
+
+        self.invalidate_cache(|cached| {
+            cached.invoice.id == settled.id || cached.invoice.payment_hash == invoice.payment_hash
+        });
+        Ok(settled)
     }
 
     fn get_all(&self) -> Result<Vec<HoldInvoice>> {
@@ -163,57 +689,1686 @@ impl InvoiceHelper for InvoiceHelperDatabase {
             .select(Invoice::as_select())
             .order_by(invoices::dsl::id)
             .load(&mut con)?;
-        let htlcs = Htlc::belonging_to(&invoices)
-            .select(Htlc::as_select())
+
+        Self::load_htlcs(&mut con, invoices)
+    }
+
+    fn get_all_sorted(&self, field: SortField, desc: bool) -> Result<Vec<HoldInvoice>> {
+        let mut con = self.pool.get()?;
+
+        let query = invoices::dsl::invoices
+            .select(Invoice::as_select())
+            .into_boxed();
+
+        let query = match (field, desc) {
+            (SortField::Id, false) => query.order_by(invoices::dsl::id.asc()),
+            (SortField::Id, true) => query.order_by(invoices::dsl::id.desc()),
+            (SortField::CreatedAt, false) => query.order_by(invoices::dsl::created_at.asc()),
+            (SortField::CreatedAt, true) => query.order_by(invoices::dsl::created_at.desc()),
+            // Databases disagree on where NULLs are sorted, so it is made explicit
+            (SortField::SettledAt, false) => query.order_by((
+                invoices::dsl::settled_at.is_null(),
+                invoices::dsl::settled_at.asc(),
+            )),
+            (SortField::SettledAt, true) => query.order_by((
+                invoices::dsl::settled_at.is_null(),
+                invoices::dsl::settled_at.desc(),
+            )),
+            (SortField::State, false) => query.order_by(invoices::dsl::state.asc()),
+            (SortField::State, true) => query.order_by(invoices::dsl::state.desc()),
+        };
+
+        let invoices = query.then_order_by(invoices::dsl::id).load(&mut con)?;
+
+        Self::load_htlcs(&mut con, invoices)
+    }
+
+    fn get_paginated(
+        &self,
+        after_id: i64,
+        limit: u64,
+        state: Option<InvoiceState>,
+    ) -> Result<Vec<HoldInvoice>> {
+        let mut con = self.pool.get()?;
+
+        let mut query = invoices::dsl::invoices
+            .select(Invoice::as_select())
+            .filter(invoices::dsl::id.gt(after_id))
+            .into_boxed();
+
+        if let Some(state) = state {
+            query = query.filter(invoices::dsl::state.eq(state.to_string()));
+        }
+
+        let invoices = query
+            .order_by(invoices::dsl::id)
+            .limit(i64::try_from(limit)?)
             .load(&mut con)?;
 
-        Ok(htlcs
-            .grouped_by(&invoices)
-            .into_iter()
-            .zip(invoices)
-            .map(|(htlcs, invoice)| HoldInvoice::new(invoice, htlcs))
-            .collect())
+        Self::load_htlcs(&mut con, invoices)
+    }
+
+    fn get_paginated_by_time(
+        &self,
+        after: Option<(NaiveDateTime, i64)>,
+        limit: u64,
+    ) -> Result<Vec<HoldInvoice>> {
+        let mut con = self.pool.get()?;
+
+        let mut query = invoices::dsl::invoices
+            .select(Invoice::as_select())
+            .into_boxed();
+
+        if let Some((created_at, id)) = after {
+            query = query.filter(
+                invoices::dsl::created_at
+                    .gt(created_at)
+                    .or(invoices::dsl::created_at
+                        .eq(created_at)
+                        .and(invoices::dsl::id.gt(id))),
+            );
+        }
+
+        let invoices = query
+            .order_by((invoices::dsl::created_at, invoices::dsl::id))
+            .limit(i64::try_from(limit)?)
+            .load(&mut con)?;
+
+        Self::load_htlcs(&mut con, invoices)
+    }
+
+    fn get_by_payment_hash(&self, payment_hash: &[u8]) -> Result<Option<HoldInvoice>> {
+        let generation = if !self.cache_ttl.is_zero() {
+            let mut cache = self.lock_cache();
+            let cached = match cache.entries.get(payment_hash) {
+                Some((invoice, cached_at)) if cached_at.elapsed() < self.cache_ttl => {
+                    Some(invoice.clone())
+                }
+                Some(_) => {
+                    cache.entries.remove(payment_hash);
+                    None
+                }
+                None => None,
+            };
+
+            if cached.is_some() {
+                return Ok(cached);
+            }
+
+            Some(cache.generation)
+        } else {
+            None
+        };
+
+        let invoice = self.load_by_payment_hash(payment_hash)?;
+
+        if let (Some(generation), Some(invoice)) = (generation, &invoice) {
+            let mut cache = self.lock_cache();
+            if cache.generation == generation {
+                cache
+                    .entries
+                    .insert(payment_hash.to_vec(), (invoice.clone(), Instant::now()));
+            }
+        }
+
+        Ok(invoice)
+    }
+
+    fn get_by_preimage(&self, preimage: &[u8]) -> Result<Option<HoldInvoice>> {
+        let payment_hash: sha256::Hash = Hash::hash(preimage);
+        Ok(self
+            .get_by_payment_hash(&payment_hash[..])?
+            .filter(|invoice| invoice.invoice.preimage.as_deref() == Some(preimage)))
+    }
+
+    fn get_by_bolt11(&self, bolt11: &str) -> Result<Option<HoldInvoice>> {
+        let invoice = Bolt11Invoice::from_str(bolt11)?;
+        self.get_by_payment_hash(&invoice.payment_hash()[..])
+    }
+
+    fn get_by_label(&self, label: &str) -> Result<Option<HoldInvoice>> {
+        let mut con = self.pool.get()?;
+
+        let invoices = invoices::dsl::invoices
+            .select(Invoice::as_select())
+            .filter(invoices::dsl::label.eq(label))
+            .limit(1)
+            .load(&mut con)?;
+
+        Ok(Self::load_htlcs(&mut con, invoices)?.into_iter().next())
     }
 
-    fn get_paginated(&self, index_start: i64, limit: u64) -> Result<Vec<HoldInvoice>> {
+    fn batch_get_by_payment_hashes(&self, payment_hashes: &[Vec<u8>]) -> Result<Vec<HoldInvoice>> {
         let mut con = self.pool.get()?;
 
         let invoices = invoices::dsl::invoices
             .select(Invoice::as_select())
-            .filter(invoices::dsl::id.ge(index_start))
+            .filter(invoices::dsl::payment_hash.eq_any(payment_hashes))
             .order_by(invoices::dsl::id)
-            .limit(limit as i64)
             .load(&mut con)?;
-        let htlcs = Htlc::belonging_to(&invoices)
-            .select(Htlc::as_select())
+
+        Self::load_htlcs(&mut con, invoices)
+    }
+
+    fn get_by_state(&self, state: InvoiceState) -> Result<Vec<HoldInvoice>> {
+        let mut con = self.pool.get()?;
+
+        let invoices = invoices::dsl::invoices
+            .select(Invoice::as_select())
+            .filter(invoices::dsl::state.eq(state.to_string()))
+            .order_by(invoices::dsl::id)
             .load(&mut con)?;
 
-        Ok(htlcs
-            .grouped_by(&invoices)
-            .into_iter()
-            .zip(invoices)
-            .map(|(htlcs, invoice)| HoldInvoice::new(invoice, htlcs))
-            .collect())
+        Self::load_htlcs(&mut con, invoices)
     }
 
-    fn get_by_payment_hash(&self, payment_hash: &[u8]) -> Result<Option<HoldInvoice>> {
+    fn get_expiring_soon(&self, within_seconds: u64) -> Result<Vec<HoldInvoice>> {
         let mut con = self.pool.get()?;
 
         let invoices = invoices::dsl::invoices
             .select(Invoice::as_select())
-            .filter(invoices::dsl::payment_hash.eq(payment_hash))
+            .filter(invoices::dsl::state.eq(InvoiceState::Unpaid.to_string()))
+            .filter(invoices::dsl::expires_at.is_not_null())
+            .filter(
+                invoices::dsl::expires_at
+                    .lt(Utc::now().naive_utc() + TimeDelta::seconds(within_seconds as i64)),
+            )
+            .order_by(invoices::dsl::id)
+            .load(&mut con)?;
+
+        Self::load_htlcs(&mut con, invoices)
+    }
+
+    fn get_oldest_unpaid(&self) -> Result<Option<HoldInvoice>> {
+        let mut con = self.pool.get()?;
+
+        let invoices = invoices::dsl::invoices
+            .select(Invoice::as_select())
+            .filter(invoices::dsl::state.eq(InvoiceState::Unpaid.to_string()))
+            .order_by((invoices::dsl::created_at.asc(), invoices::dsl::id.asc()))
             .limit(1)
             .load(&mut con)?;
 
-        if invoices.is_empty() {
-            return Ok(None);
+        Ok(Self::load_htlcs(&mut con, invoices)?.into_iter().next())
+    }
+
+    fn count_by_state(&self) -> Result<HashMap<InvoiceState, u64>> {
+        let counts = invoices::dsl::invoices
+            .group_by(invoices::dsl::state)
+            .select((invoices::dsl::state, count_star()))
+            .load::<(String, i64)>(&mut self.pool.get()?)?;
+
+        let mut res = HashMap::from([
+            (InvoiceState::Paid, 0),
+            (InvoiceState::Unpaid, 0),
+            (InvoiceState::Accepted, 0),
+            (InvoiceState::Cancelled, 0),
+            (InvoiceState::Paused, 0),
+        ]);
+        for (state, count) in counts {
+            res.insert(InvoiceState::try_from(&state)?, count as u64);
         }
 
-        let invoice = invoices[0].clone();
-        let htlcs = Htlc::belonging_to(&vec![invoice.clone()])
-            .select(Htlc::as_select())
-            .load(&mut con)?;
+        Ok(res)
+    }
 
-        Ok(Some(HoldInvoice::new(invoice, htlcs)))
+    fn get_in_date_range(
+        &self,
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>,
+    ) -> Result<Vec<HoldInvoice>> {
+        let mut con = self.pool.get()?;
+
+        let mut query = invoices::dsl::invoices
+            .select(Invoice::as_select())
+            .into_boxed();
+
+        if let Some(from) = from {
+            query = query.filter(invoices::dsl::created_at.ge(from));
+        }
+
+        if let Some(to) = to {
+            query = query.filter(invoices::dsl::created_at.le(to));
+        }
+
+        let invoices = query.order_by(invoices::dsl::id).load(&mut con)?;
+
+        Self::load_htlcs(&mut con, invoices)
+    }
+
+    fn get_created_in_range(
+        &self,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Result<Vec<HoldInvoice>> {
+        self.get_in_date_range(Some(from), Some(to))
+    }
+
+    fn get_settled_in_range(
+        &self,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Result<Vec<HoldInvoice>> {
+        let mut con = self.pool.get()?;
+
+        let invoices = invoices::dsl::invoices
+            .select(Invoice::as_select())
+            .filter(invoices::dsl::settled_at.between(from, to))
+            .order_by(invoices::dsl::id)
+            .load(&mut con)?;
+
+        Self::load_htlcs(&mut con, invoices)
+    }
+
+    fn get_state_log(&self, invoice_ids: &[i64]) -> Result<Vec<InvoiceStateLog>> {
+        Ok(invoice_state_log::dsl::invoice_state_log
+            .select(InvoiceStateLog::as_select())
+            .filter(invoice_state_log::dsl::invoice_id.eq_any(invoice_ids))
+            .order_by(invoice_state_log::dsl::id)
+            .load(&mut self.pool.get()?)?)
+    }
+
+    fn get_statistics(&self) -> Result<InvoiceStatistics> {
+        let mut con = self.pool.get()?;
+
+        let settlement_seconds = match *con {
+            AnyConnection::Postgresql(_) => "EXTRACT(EPOCH FROM (settled_at - created_at))",
+            AnyConnection::Sqlite(_) => "(julianday(settled_at) - julianday(created_at)) * 86400",
+        };
+
+        let count_state = |state: InvoiceState| {
+            format!(
+                "CAST(COALESCE(SUM(CASE WHEN state = '{}' THEN 1 ELSE 0 END), 0) AS BIGINT) AS {}",
+                state, state
+            )
+        };
+
+        let row = sql_query(format!(
+            "SELECT {}, {}, {}, {}, {}, \
+            (SELECT CAST(COALESCE(SUM(msat), 0) AS BIGINT) FROM htlcs WHERE state = '{}') \
+                AS total_settled_msat, \
+            CAST(AVG(CASE WHEN state = '{}' AND settled_at IS NOT NULL THEN {} END) \
+                AS DOUBLE PRECISION) AS avg_settlement_seconds, \
+            (SELECT COUNT(*) FROM htlcs WHERE state = '{}') AS pending_htlc_count \
+            FROM invoices",
+            count_state(InvoiceState::Unpaid),
+            count_state(InvoiceState::Accepted),
+            count_state(InvoiceState::Paid),
+            count_state(InvoiceState::Cancelled),
+            count_state(InvoiceState::Paused),
+            InvoiceState::Paid,
+            InvoiceState::Paid,
+            settlement_seconds,
+            InvoiceState::Accepted,
+        ))
+        .get_result::<StatisticsRow>(&mut con)?;
+
+        Ok(InvoiceStatistics {
+            count_by_state: HashMap::from([
+                (InvoiceState::Unpaid.to_string(), row.unpaid as u64),
+                (InvoiceState::Accepted.to_string(), row.accepted as u64),
+                (InvoiceState::Paid.to_string(), row.paid as u64),
+                (InvoiceState::Cancelled.to_string(), row.cancelled as u64),
+                (InvoiceState::Paused.to_string(), row.paused as u64),
+            ]),
+            total_settled_msat: row.total_settled_msat as u64,
+            avg_settlement_seconds: row.avg_settlement_seconds,
+            pending_htlc_count: row.pending_htlc_count as u64,
+            avg_hold_duration_seconds: self.avg_hold_duration_seconds()?,
+            oldest_unpaid_age_seconds: self.get_oldest_unpaid()?.map(|invoice| {
+                (Utc::now().naive_utc() - invoice.invoice.created_at)
+                    .num_seconds()
+                    .max(0) as u64
+            }),
+        })
+    }
+
+    fn avg_hold_duration_seconds(&self) -> Result<Option<f64>> {
+        let mut con = self.pool.get()?;
+
+        let hold_seconds = match *con {
+            AnyConnection::Postgresql(_) => {
+                "EXTRACT(EPOCH FROM (invoices.settled_at - htlcs.accepted_at))"
+            }
+            AnyConnection::Sqlite(_) => {
+                "(julianday(invoices.settled_at) - julianday(htlcs.accepted_at)) * 86400"
+            }
+        };
+
+        let row = sql_query(format!(
+            "SELECT CAST(AVG({}) AS DOUBLE PRECISION) AS avg FROM htlcs \
+            INNER JOIN invoices ON invoices.id = htlcs.invoice_id \
+            WHERE htlcs.state = '{}' AND htlcs.accepted_at IS NOT NULL \
+                AND invoices.settled_at IS NOT NULL",
+            hold_seconds,
+            InvoiceState::Paid,
+        ))
+        .get_result::<AvgRow>(&mut con)?;
+
+        Ok(row.avg)
+    }
+
+    fn get_pending_htlc_count(&self) -> Result<u64> {
+        let count = htlcs::dsl::htlcs
+            .filter(htlcs::dsl::state.eq(InvoiceState::Accepted.to_string()))
+            .count()
+            .get_result::<i64>(&mut self.pool.get()?)?;
+
+        Ok(count as u64)
+    }
+
+    fn get_accepted_invoice_count(&self) -> Result<u64> {
+        let count = invoices::dsl::invoices
+            .filter(invoices::dsl::state.eq(InvoiceState::Accepted.to_string()))
+            .count()
+            .get_result::<i64>(&mut self.pool.get()?)?;
+
+        Ok(count as u64)
+    }
+
+    fn count_htlcs(&self, invoice_id: i64) -> Result<HashMap<InvoiceState, u64>> {
+        let counts = htlcs::dsl::htlcs
+            .filter(htlcs::dsl::invoice_id.eq(invoice_id))
+            .group_by(htlcs::dsl::state)
+            .select((htlcs::dsl::state, count_star()))
+            .load::<(String, i64)>(&mut self.pool.get()?)?;
+
+        let mut res = HashMap::from([
+            (InvoiceState::Paid, 0),
+            (InvoiceState::Unpaid, 0),
+            (InvoiceState::Accepted, 0),
+            (InvoiceState::Cancelled, 0),
+        ]);
+        for (state, count) in counts {
+            res.insert(InvoiceState::try_from(&state)?, count as u64);
+        }
+
+        Ok(res)
+    }
+
+    fn sum_htlc_msat(&self, invoice_id: i64, state: InvoiceState) -> Result<u64> {
+        let row = sql_query(
+            "SELECT CAST(COALESCE(SUM(msat), 0) AS BIGINT) AS sum FROM htlcs \
+            WHERE invoice_id = $1 AND state = $2",
+        )
+        .bind::<BigInt, _>(invoice_id)
+        .bind::<Text, _>(state.to_string())
+        .get_result::<SumRow>(&mut self.pool.get()?)?;
+
+        Ok(row.sum as u64)
+    }
+
+    fn get_htlcs_by_scid(&self, scid: &str) -> Result<Vec<(Invoice, Htlc)>> {
+        Ok(htlcs::dsl::htlcs
+            .inner_join(invoices::dsl::invoices)
+            .filter(
+                htlcs::dsl::scid
+                    .eq(scid)
+                    .and(htlcs::dsl::state.eq(InvoiceState::Accepted.to_string())),
+            )
+            .select((Invoice::as_select(), Htlc::as_select()))
+            .order_by(htlcs::dsl::id)
+            .load(&mut self.pool.get()?)?)
+    }
+
+    fn total_volume_msat(
+        &self,
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>,
+    ) -> Result<u64> {
+        let row = sql_query(
+            "SELECT CAST(COALESCE(SUM(msat), 0) AS BIGINT) AS sum FROM htlcs \
+            WHERE state = $1 AND ($2 IS NULL OR created_at >= $2) \
+            AND ($3 IS NULL OR created_at <= $3)",
+        )
+        .bind::<Text, _>(InvoiceState::Paid.to_string())
+        .bind::<Nullable<Timestamp>, _>(from)
+        .bind::<Nullable<Timestamp>, _>(to)
+        .get_result::<SumRow>(&mut self.pool.get()?)?;
+
+        Ok(row.sum as u64)
+    }
+
+    fn volume_by_day(&self, days: u32) -> Result<Vec<(NaiveDate, u64)>> {
+        if days == 0 {
+            return Ok(Vec::new());
+        }
+
+        let since =
+            (Utc::now().date_naive() - TimeDelta::days(days as i64 - 1)).and_time(NaiveTime::MIN);
+
+        let rows = sql_query(
+            "SELECT CAST(DATE(created_at) AS TEXT) AS day, \
+            CAST(COALESCE(SUM(msat), 0) AS BIGINT) AS sum FROM htlcs \
+            WHERE state = $1 AND created_at >= $2 \
+            GROUP BY DATE(created_at) ORDER BY day",
+        )
+        .bind::<Text, _>(InvoiceState::Paid.to_string())
+        .bind::<Timestamp, _>(since)
+        .load::<DailySumRow>(&mut self.pool.get()?)?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok((
+                    NaiveDate::parse_from_str(&row.day, "%Y-%m-%d")?,
+                    row.sum as u64,
+                ))
+            })
+            .collect()
+    }
+
+    fn ping(&self) -> Result<()> {
+        Ok(self.pool.get()?.batch_execute("SELECT 1")?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::helpers::invoice_helper::{InvoiceHelper, InvoiceHelperError};
+    use crate::database::model::{
+        HashAlgorithm, HoldInvoice, HtlcInsertable, InvoiceInsertable, InvoiceState, SortField,
+    };
+    use crate::database::schema::{htlcs, invoices};
+    use crate::database::test_utils::{insert_invoice, invoice_insertable, test_database};
+    use bitcoin::hashes::{sha256, Hash};
+    use chrono::{TimeDelta, Utc};
+    use diesel::sql_types::Text;
+    use diesel::{sql_query, update, ExpressionMethods, QueryDsl, QueryableByName, RunQueryDsl};
+    use lightning_invoice::Bolt11Invoice;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    #[test]
+    fn insert_duplicate_payment_hash() {
+        let database = test_database("insert-duplicate");
+        let helper = database.helper();
+
+        let invoice = invoice_insertable(&[1, 2, 3]);
+        assert_eq!(helper.insert(&invoice).unwrap(), 1);
+
+        let err = helper.insert(&invoice).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<InvoiceHelperError>().unwrap(),
+            &InvoiceHelperError::DuplicatePaymentHash("010203".to_string())
+        );
+        assert_eq!(
+            err.to_string(),
+            "invoice with payment hash 010203 exists already"
+        );
+    }
+
+    #[test]
+    fn insert_hash_algorithm() {
+        let database = test_database("insert-hash-algorithm");
+        let helper = database.helper();
+
+        let mut invoice = invoice_insertable(&[1, 2, 3]);
+        assert_eq!(helper.insert(&invoice).unwrap(), 1);
+
+        let fetched = helper.get_by_payment_hash(&[1, 2, 3]).unwrap().unwrap();
+        assert_eq!(fetched.invoice.hash_algorithm, "sha256");
+        assert_eq!(
+            HashAlgorithm::try_from(&fetched.invoice.hash_algorithm).unwrap(),
+            HashAlgorithm::Sha256
+        );
+
+        invoice.payment_hash = vec![4, 5, 6];
+        invoice.hash_algorithm = "sha512".to_string();
+        let err = helper.insert(&invoice).err().unwrap();
+        assert_eq!(err.to_string(), "unknown hash algorithm: sha512");
+        assert!(helper.get_by_payment_hash(&[4, 5, 6]).unwrap().is_none());
+    }
+
+    #[test]
+    fn get_statistics() {
+        let database = test_database("get-statistics");
+        let helper = database.helper();
+
+        let statistics = helper.get_statistics().unwrap();
+        assert_eq!(statistics.count_by_state.get("unpaid"), Some(&0));
+        assert_eq!(statistics.total_settled_msat, 0);
+        assert_eq!(statistics.avg_settlement_seconds, None);
+        assert_eq!(statistics.pending_htlc_count, 0);
+        assert_eq!(statistics.avg_hold_duration_seconds, None);
+        assert_eq!(statistics.oldest_unpaid_age_seconds, None);
+
+        for payment_hash in [vec![1], vec![2], vec![3]] {
+            insert_invoice(&helper, &payment_hash);
+        }
+
+        let accepted_ago =
+            |seconds: i64| Some(Utc::now().naive_utc() - TimeDelta::seconds(seconds));
+        for (invoice_id, state, msat, accepted_at) in [
+            (1, InvoiceState::Paid, 1_000, accepted_ago(60)),
+            (1, InvoiceState::Paid, 2_000, None),
+            (2, InvoiceState::Accepted, 3_000, accepted_ago(600)),
+        ] {
+            helper
+                .insert_htlc(&HtlcInsertable {
+                    accepted_at,
+                    invoice_id,
+                    msat,
+                    state: state.into(),
+                    scid: "".to_string(),
+                    channel_id: 0,
+                })
+                .unwrap();
+        }
+
+        helper
+            .set_invoice_state(1, InvoiceState::Unpaid, InvoiceState::Accepted)
+            .unwrap();
+        helper
+            .set_invoice_state(1, InvoiceState::Accepted, InvoiceState::Paid)
+            .unwrap();
+        helper
+            .set_invoice_state(2, InvoiceState::Unpaid, InvoiceState::Accepted)
+            .unwrap();
+
+        let statistics = helper.get_statistics().unwrap();
+        assert_eq!(statistics.count_by_state.get("unpaid"), Some(&1));
+        assert_eq!(statistics.count_by_state.get("accepted"), Some(&1));
+        assert_eq!(statistics.count_by_state.get("paid"), Some(&1));
+        assert_eq!(statistics.count_by_state.get("cancelled"), Some(&0));
+        assert_eq!(statistics.count_by_state.get("paused"), Some(&0));
+        assert_eq!(statistics.total_settled_msat, 3_000);
+        assert!(statistics.avg_settlement_seconds.unwrap() < 5.0);
+        assert_eq!(statistics.pending_htlc_count, 1);
+
+        // Only the paid HTLC with an acceptance time counts
+        let avg_hold_duration = statistics.avg_hold_duration_seconds.unwrap();
+        assert!((59.0..65.0).contains(&avg_hold_duration));
+        assert_eq!(
+            helper.avg_hold_duration_seconds().unwrap(),
+            statistics.avg_hold_duration_seconds
+        );
+        assert!(statistics.oldest_unpaid_age_seconds.unwrap() < 5);
+    }
+
+    #[test]
+    fn get_oldest_unpaid() {
+        let database = test_database("get-oldest-unpaid");
+        let helper = database.helper();
+        assert!(helper.get_oldest_unpaid().unwrap().is_none());
+
+        for (payment_hash, state, hours_ago) in [
+            (1, InvoiceState::Unpaid, 1),
+            (2, InvoiceState::Unpaid, 30),
+            (3, InvoiceState::Cancelled, 48),
+            (4, InvoiceState::Unpaid, 5),
+        ] {
+            helper
+                .insert(&InvoiceInsertable {
+                    state: state.into(),
+                    ..invoice_insertable(&[payment_hash])
+                })
+                .unwrap();
+
+            update(invoices::dsl::invoices)
+                .filter(invoices::dsl::payment_hash.eq(vec![payment_hash]))
+                .set(
+                    invoices::dsl::created_at
+                        .eq(Utc::now().naive_utc() - TimeDelta::hours(hours_ago)),
+                )
+                .execute(&mut helper.pool.get().unwrap())
+                .unwrap();
+        }
+
+        let oldest = helper.get_oldest_unpaid().unwrap().unwrap();
+        assert_eq!(oldest.invoice.payment_hash, vec![2]);
+
+        let age = helper
+            .get_statistics()
+            .unwrap()
+            .oldest_unpaid_age_seconds
+            .unwrap();
+        assert!((30 * 3_600..30 * 3_600 + 5).contains(&age));
+    }
+
+    #[test]
+    fn conditional_accept() {
+        let database = test_database("conditional-accept");
+        let helper = database.helper();
+
+        insert_invoice(&helper, &[1]);
+        for (msat, state) in [
+            (600, InvoiceState::Accepted),
+            (1_000, InvoiceState::Cancelled),
+        ] {
+            helper
+                .insert_htlc(&HtlcInsertable {
+                    accepted_at: None,
+                    invoice_id: 1,
+                    msat,
+                    state: state.into(),
+                    scid: "".to_string(),
+                    channel_id: 0,
+                })
+                .unwrap();
+        }
+
+        // Cancelled HTLCs do not count
+        assert!(!helper.conditional_accept(1, 1_000).unwrap());
+
+        helper
+            .insert_htlc(&HtlcInsertable {
+                accepted_at: None,
+                invoice_id: 1,
+                msat: 400,
+                state: InvoiceState::Accepted.into(),
+                scid: "".to_string(),
+                channel_id: 0,
+            })
+            .unwrap();
+        assert!(!helper.conditional_accept(1, 1_001).unwrap());
+
+        assert!(helper.conditional_accept(1, 1_000).unwrap());
+        let invoice = helper.get_by_payment_hash(&[1]).unwrap().unwrap().invoice;
+        assert_eq!(invoice.state, InvoiceState::Accepted.to_string());
+        assert!(invoice.accepted_at.is_some());
+
+        // Accepting again is a no-op
+        assert!(!helper.conditional_accept(1, 1_000).unwrap());
+        assert_eq!(helper.get_state_log(&[1]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn set_htlc_preimage() {
+        let database = test_database("set-htlc-preimage");
+        let helper = database.helper();
+
+        insert_invoice(&helper, &[1]);
+        for _ in 0..2 {
+            helper
+                .insert_htlc(&HtlcInsertable {
+                    accepted_at: None,
+                    invoice_id: 1,
+                    msat: 1_000,
+                    state: InvoiceState::Accepted.into(),
+                    scid: "".to_string(),
+                    channel_id: 0,
+                })
+                .unwrap();
+        }
+
+        helper.set_htlc_preimage(2, &[2]).unwrap();
+
+        let htlcs = helper.get_by_payment_hash(&[1]).unwrap().unwrap().htlcs;
+        assert_eq!(htlcs[0].preimage, None);
+        assert_eq!(htlcs[1].preimage, Some(vec![2]));
+
+        assert_eq!(
+            helper
+                .set_htlc_preimage(3, &[2])
+                .err()
+                .unwrap()
+                .downcast_ref::<InvoiceHelperError>()
+                .unwrap(),
+            &InvoiceHelperError::HtlcNotFound(3)
+        );
+    }
+
+    #[test]
+    fn set_invoice_preimage() {
+        let database = test_database("set-invoice-preimage");
+        let helper = database.helper();
+
+        insert_invoice(&helper, &[1]);
+
+        helper.set_invoice_preimage(&[1], &[2]).unwrap();
+
+        let invoice = helper.get_by_payment_hash(&[1]).unwrap().unwrap().invoice;
+        assert_eq!(invoice.preimage, Some(vec![2]));
+        assert_eq!(invoice.state, InvoiceState::Unpaid.to_string());
+        assert_eq!(invoice.settled_at, None);
+
+        assert_eq!(
+            helper
+                .set_invoice_preimage(&[3], &[2])
+                .err()
+                .unwrap()
+                .downcast_ref::<InvoiceHelperError>()
+                .unwrap(),
+            &InvoiceHelperError::InvoiceNotFound("03".to_string())
+        );
+    }
+
+    #[test]
+    fn get_by_payment_hash_cached() {
+        let database = test_database("get-by-payment-hash-cached");
+        let helper = database.helper().cache_ttl(Duration::from_secs(60));
+
+        insert_invoice(&helper, &[1]);
+
+        let get_label = || {
+            helper
+                .get_by_payment_hash(&[1])
+                .unwrap()
+                .unwrap()
+                .invoice
+                .label
+        };
+        let set_label_directly = |label: &str| {
+            update(invoices::dsl::invoices)
+                .filter(invoices::dsl::payment_hash.eq(vec![1]))
+                .set(invoices::dsl::label.eq(label))
+                .execute(&mut helper.pool.get().unwrap())
+                .unwrap();
+        };
+
+        assert_eq!(get_label(), None);
+
+        // Changes that bypass the helper are not seen while the entry is cached
+        set_label_directly("bypassed");
+        assert_eq!(get_label(), None);
+
+        // Writes through the helper invalidate the entry
+        helper
+            .set_invoice_state(1, InvoiceState::Unpaid, InvoiceState::Accepted)
+            .unwrap();
+        let invoice = helper.get_by_payment_hash(&[1]).unwrap().unwrap();
+        assert_eq!(invoice.invoice.state, InvoiceState::Accepted.to_string());
+        assert_eq!(invoice.invoice.label, Some("bypassed".to_string()));
+
+        helper
+            .insert_htlc(&HtlcInsertable {
+                accepted_at: None,
+                invoice_id: 1,
+                state: InvoiceState::Accepted.to_string(),
+                scid: "scid".to_string(),
+                channel_id: 0,
+                msat: 1_000,
+            })
+            .unwrap();
+        assert_eq!(
+            helper
+                .get_by_payment_hash(&[1])
+                .unwrap()
+                .unwrap()
+                .htlcs
+                .len(),
+            1
+        );
+
+        helper
+            .set_htlc_state_by_id(1, InvoiceState::Accepted, InvoiceState::Cancelled)
+            .unwrap();
+        assert_eq!(
+            helper.get_by_payment_hash(&[1]).unwrap().unwrap().htlcs[0].state,
+            InvoiceState::Cancelled.to_string()
+        );
+
+        // Entries expire after the TTL
+        let expiring = helper.clone().cache_ttl(Duration::from_millis(50));
+        set_label_directly("expired");
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            expiring
+                .get_by_payment_hash(&[1])
+                .unwrap()
+                .unwrap()
+                .invoice
+                .label,
+            Some("expired".to_string())
+        );
+    }
+
+    #[test]
+    fn get_paginated() {
+        let database = test_database("get-paginated");
+        let helper = database.helper();
+
+        for payment_hash in 1..=5 {
+            insert_invoice(&helper, &[payment_hash]);
+        }
+
+        // Deleting rows between pages must not skip or repeat any
+        let mut ids = Vec::new();
+        let mut after_id = 0;
+        loop {
+            let page = helper.get_paginated(after_id, 2, None).unwrap();
+            if page.is_empty() {
+                break;
+            }
+            assert!(page.len() <= 2);
+
+            if after_id == 0 {
+                helper
+                    .set_invoice_state(1, InvoiceState::Unpaid, InvoiceState::Cancelled)
+                    .unwrap();
+                helper
+                    .clean(Some(InvoiceState::Cancelled), None, false)
+                    .unwrap();
+            }
+
+            after_id = page.last().unwrap().invoice.id;
+            ids.extend(page.into_iter().map(|invoice| invoice.invoice.id));
+        }
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+
+        let page = helper
+            .get_paginated(0, 10, Some(InvoiceState::Unpaid))
+            .unwrap();
+        assert_eq!(
+            page.iter()
+                .map(|invoice| invoice.invoice.id)
+                .collect::<Vec<_>>(),
+            vec![2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn get_paginated_by_time() {
+        let database = test_database("get-paginated-by-time");
+        let helper = database.helper();
+
+        let now = Utc::now().naive_utc();
+        // IDs 2 and 4 share their creation time
+        for (payment_hash, created_ago) in [(1, 10), (2, 30), (3, 20), (4, 30), (5, 0)] {
+            insert_invoice(&helper, &[payment_hash]);
+
+            update(invoices::dsl::invoices)
+                .filter(invoices::dsl::payment_hash.eq(vec![payment_hash]))
+                .set(invoices::dsl::created_at.eq(now - TimeDelta::seconds(created_ago)))
+                .execute(&mut helper.pool.get().unwrap())
+                .unwrap();
+        }
+
+        let mut ids = Vec::new();
+        let mut after = None;
+        loop {
+            let page = helper.get_paginated_by_time(after, 2).unwrap();
+            if page.is_empty() {
+                break;
+            }
+            assert!(page.len() <= 2);
+
+            let last = &page.last().unwrap().invoice;
+            after = Some((last.created_at, last.id));
+            ids.extend(page.into_iter().map(|invoice| invoice.invoice.id));
+        }
+        assert_eq!(ids, vec![2, 4, 3, 1, 5]);
+    }
+
+    #[test]
+    fn get_by_bolt11() {
+        let database = test_database("get-by-bolt11");
+        let helper = database.helper();
+
+        let bolt11 = "lnbc10n1pnvfs4vsp57npt9tx2glnkx29ng98cmc0lt0as8se4x4776rtwqp3gr3hj807qpp5ysnte2hh3nv4z0jd4pfe5wla956zxxg3rmxs5ux4v0xfwplvlm8sdqdw3jhxar5v4ehgxqyjw5qcqpjrzjq2rnwvp7zt9cgeparuqcrqft2kd9dm6a0z6vg0gucrqurutaezrjyrze2uqq2wcqqyqqqqdyqqqqqpqqvs9qxpqysgqjkdwjjuzfy5ek4k9xgsv0ysrc3lg349caqqh3yearxmv4zgyqhqyuntk4gyjpvpezcc66v5lyzxm240wdfgp6cqkwt7fv2nngjjnlrspmaakpk";
+        assert!(helper.get_by_bolt11(bolt11).unwrap().is_none());
+
+        let payment_hash = Bolt11Invoice::from_str(bolt11).unwrap().payment_hash()[..].to_vec();
+        helper
+            .insert(&InvoiceInsertable {
+                bolt11: bolt11.to_string(),
+                ..invoice_insertable(&payment_hash)
+            })
+            .unwrap();
+
+        let invoice = helper.get_by_bolt11(bolt11).unwrap().unwrap();
+        assert_eq!(invoice.invoice.payment_hash, payment_hash);
+        assert_eq!(
+            invoice.invoice.expires_at.unwrap().and_utc().timestamp() as u64,
+            Bolt11Invoice::from_str(bolt11)
+                .unwrap()
+                .expires_at()
+                .unwrap()
+                .as_secs()
+        );
+
+        assert!(helper.get_by_bolt11("invalid").is_err());
+    }
+
+    #[test]
+    fn get_by_preimage() {
+        let database = test_database("get-by-preimage");
+        let helper = database.helper();
+
+        let preimage = vec![1; 32];
+        let payment_hash: sha256::Hash = Hash::hash(&preimage);
+        insert_invoice(&helper, &payment_hash[..]);
+
+        // Not settled yet
+        assert!(helper.get_by_preimage(&preimage).unwrap().is_none());
+
+        helper
+            .set_invoice_state(1, InvoiceState::Unpaid, InvoiceState::Accepted)
+            .unwrap();
+        helper
+            .set_invoice_state(1, InvoiceState::Accepted, InvoiceState::Paid)
+            .unwrap();
+        helper
+            .set_invoice_preimage(&payment_hash[..], &preimage)
+            .unwrap();
+
+        let invoice = helper.get_by_preimage(&preimage).unwrap().unwrap();
+        assert_eq!(invoice.invoice.payment_hash, payment_hash[..].to_vec());
+        assert_eq!(invoice.invoice.state, InvoiceState::Paid.to_string());
+        assert!(helper.get_by_preimage(&[2; 32]).unwrap().is_none());
+    }
+
+    #[test]
+    fn count_by_state() {
+        let database = test_database("count-by-state");
+        let helper = database.helper();
+
+        for payment_hash in [vec![1], vec![2], vec![3]] {
+            insert_invoice(&helper, &payment_hash);
+        }
+        helper
+            .set_invoice_state(1, InvoiceState::Unpaid, InvoiceState::Cancelled)
+            .unwrap();
+
+        let counts = helper.count_by_state().unwrap();
+        assert_eq!(counts.len(), 5);
+        assert_eq!(counts[&InvoiceState::Unpaid], 2);
+        assert_eq!(counts[&InvoiceState::Cancelled], 1);
+        assert_eq!(counts[&InvoiceState::Accepted], 0);
+        assert_eq!(counts[&InvoiceState::Paid], 0);
+        assert_eq!(counts[&InvoiceState::Paused], 0);
+    }
+
+    #[test]
+    fn pending_counts() {
+        let database = test_database("pending-counts");
+        let helper = database.helper();
+
+        assert_eq!(helper.get_pending_htlc_count().unwrap(), 0);
+        assert_eq!(helper.get_accepted_invoice_count().unwrap(), 0);
+
+        for payment_hash in [vec![1], vec![2]] {
+            insert_invoice(&helper, &payment_hash);
+        }
+
+        for (channel_id, state) in [
+            (0, InvoiceState::Accepted),
+            (1, InvoiceState::Accepted),
+            (2, InvoiceState::Cancelled),
+        ] {
+            helper
+                .insert_htlc(&HtlcInsertable {
+                    accepted_at: None,
+                    invoice_id: 1,
+                    state: state.to_string(),
+                    scid: "scid".to_string(),
+                    channel_id,
+                    msat: 1_000,
+                })
+                .unwrap();
+        }
+        helper
+            .set_invoice_state(1, InvoiceState::Unpaid, InvoiceState::Accepted)
+            .unwrap();
+
+        assert_eq!(helper.get_pending_htlc_count().unwrap(), 2);
+        assert_eq!(helper.get_accepted_invoice_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn total_volume_msat() {
+        let database = test_database("total-volume");
+        let helper = database.helper();
+        assert_eq!(helper.total_volume_msat(None, None).unwrap(), 0);
+        assert!(helper.volume_by_day(7).unwrap().is_empty());
+
+        insert_invoice(&helper, &[1]);
+
+        let now = Utc::now().naive_utc();
+        for (channel_id, state, msat, age_days) in [
+            (0, InvoiceState::Paid, 1_000, 0),
+            (1, InvoiceState::Paid, 2_000, 0),
+            (2, InvoiceState::Paid, 4_000, 2),
+            (3, InvoiceState::Cancelled, 8_000, 0),
+            (4, InvoiceState::Paid, 16_000, 10),
+        ] {
+            helper
+                .insert_htlc(&HtlcInsertable {
+                    accepted_at: None,
+                    invoice_id: 1,
+                    state: state.to_string(),
+                    scid: "scid".to_string(),
+                    channel_id,
+                    msat,
+                })
+                .unwrap();
+            update(htlcs::dsl::htlcs)
+                .filter(htlcs::dsl::channel_id.eq(channel_id))
+                .set(htlcs::dsl::created_at.eq(now - TimeDelta::days(age_days)))
+                .execute(&mut helper.pool.get().unwrap())
+                .unwrap();
+        }
+
+        assert_eq!(helper.total_volume_msat(None, None).unwrap(), 23_000);
+        assert_eq!(
+            helper
+                .total_volume_msat(Some(now - TimeDelta::days(3)), None)
+                .unwrap(),
+            7_000
+        );
+        assert_eq!(
+            helper
+                .total_volume_msat(
+                    Some(now - TimeDelta::days(3)),
+                    Some(now - TimeDelta::days(1))
+                )
+                .unwrap(),
+            4_000
+        );
+
+        assert_eq!(
+            helper.volume_by_day(7).unwrap(),
+            vec![
+                ((now - TimeDelta::days(2)).date(), 4_000),
+                (now.date(), 3_000),
+            ]
+        );
+        assert!(helper.volume_by_day(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn count_htlcs() {
+        let database = test_database("count-htlcs");
+        let helper = database.helper();
+
+        for payment_hash in [vec![1], vec![2]] {
+            insert_invoice(&helper, &payment_hash);
+        }
+
+        for (invoice_id, channel_id, state, msat) in [
+            (1, 0, InvoiceState::Accepted, 1_000),
+            (1, 1, InvoiceState::Accepted, 2_000),
+            (1, 2, InvoiceState::Cancelled, 4_000),
+            (2, 3, InvoiceState::Accepted, 8_000),
+        ] {
+            helper
+                .insert_htlc(&HtlcInsertable {
+                    accepted_at: None,
+                    invoice_id,
+                    state: state.to_string(),
+                    scid: "scid".to_string(),
+                    channel_id,
+                    msat,
+                })
+                .unwrap();
+        }
+
+        let counts = helper.count_htlcs(1).unwrap();
+        assert_eq!(counts.len(), 4);
+        assert_eq!(counts[&InvoiceState::Accepted], 2);
+        assert_eq!(counts[&InvoiceState::Cancelled], 1);
+        assert_eq!(counts[&InvoiceState::Paid], 0);
+        assert_eq!(counts[&InvoiceState::Unpaid], 0);
+
+        assert_eq!(
+            helper.sum_htlc_msat(1, InvoiceState::Accepted).unwrap(),
+            3_000
+        );
+        assert_eq!(
+            helper.sum_htlc_msat(1, InvoiceState::Cancelled).unwrap(),
+            4_000
+        );
+        assert_eq!(helper.sum_htlc_msat(1, InvoiceState::Paid).unwrap(), 0);
+        assert_eq!(helper.sum_htlc_msat(3, InvoiceState::Accepted).unwrap(), 0);
+    }
+
+    #[test]
+    fn batch_get_by_payment_hashes() {
+        let database = test_database("batch-get-by-payment-hashes");
+        let helper = database.helper();
+
+        let payment_hashes = (0..50u8).map(|i| vec![i]).collect::<Vec<Vec<u8>>>();
+        for (i, payment_hash) in payment_hashes.iter().enumerate() {
+            insert_invoice(&helper, payment_hash);
+
+            for _ in 0..(i % 3) {
+                helper
+                    .insert_htlc(&HtlcInsertable {
+                        accepted_at: None,
+                        invoice_id: i as i64 + 1,
+                        msat: 1_000,
+                        state: InvoiceState::Accepted.into(),
+                        scid: "".to_string(),
+                        channel_id: 0,
+                    })
+                    .unwrap();
+            }
+        }
+
+        let invoices = helper.batch_get_by_payment_hashes(&payment_hashes).unwrap();
+        assert_eq!(invoices.len(), payment_hashes.len());
+        for (i, invoice) in invoices.iter().enumerate() {
+            assert_eq!(invoice.invoice.payment_hash, payment_hashes[i]);
+            assert_eq!(invoice.htlcs.len(), i % 3);
+            assert!(invoice
+                .htlcs
+                .iter()
+                .all(|htlc| htlc.invoice_id == invoice.invoice.id));
+        }
+
+        assert_eq!(
+            helper
+                .batch_get_by_payment_hashes(&[vec![1], vec![255]])
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn clean() {
+        let database = test_database("clean");
+        let helper = database.helper();
+
+        for payment_hash in [vec![1], vec![2], vec![3]] {
+            insert_invoice(&helper, &payment_hash);
+        }
+        for invoice_id in [1, 2] {
+            helper
+                .insert_htlc(&HtlcInsertable {
+                    accepted_at: None,
+                    invoice_id,
+                    msat: 1_000,
+                    state: InvoiceState::Cancelled.into(),
+                    scid: "".to_string(),
+                    channel_id: 0,
+                })
+                .unwrap();
+        }
+        helper
+            .set_invoice_state(1, InvoiceState::Unpaid, InvoiceState::Cancelled)
+            .unwrap();
+        helper
+            .set_invoice_state(2, InvoiceState::Unpaid, InvoiceState::Accepted)
+            .unwrap();
+        helper
+            .set_invoice_state(2, InvoiceState::Accepted, InvoiceState::Paid)
+            .unwrap();
+
+        assert_eq!(
+            helper
+                .clean(Some(InvoiceState::Unpaid), None, true)
+                .err()
+                .unwrap()
+                .downcast_ref::<InvoiceHelperError>()
+                .unwrap(),
+            &InvoiceHelperError::InvalidCleanState(InvoiceState::Unpaid)
+        );
+
+        assert_eq!(helper.clean(None, Some(3_600), false).unwrap(), 0);
+        assert_eq!(helper.clean(None, None, true).unwrap(), 1);
+        assert_eq!(helper.get_all().unwrap().len(), 3);
+
+        assert_eq!(helper.clean(None, None, false).unwrap(), 1);
+        assert_eq!(
+            helper.clean(Some(InvoiceState::Paid), None, false).unwrap(),
+            1
+        );
+
+        let remaining = helper.get_all().unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].invoice.id, 3);
+        assert!(helper.get_state_log(&[1, 2]).unwrap().is_empty());
+
+        let mut con = helper.pool.get().unwrap();
+        let htlc_count: i64 = htlcs::dsl::htlcs.count().get_result(&mut con).unwrap();
+        assert_eq!(htlc_count, 0);
+    }
+
+    #[test]
+    fn clean_paid() {
+        let database = test_database("clean-paid");
+        let helper = database.helper();
+
+        for payment_hash in [vec![1], vec![2], vec![3]] {
+            insert_invoice(&helper, &payment_hash);
+        }
+        for invoice_id in [1, 2, 3] {
+            helper
+                .insert_htlc(&HtlcInsertable {
+                    accepted_at: None,
+                    invoice_id,
+                    msat: 1_000,
+                    state: InvoiceState::Accepted.into(),
+                    scid: "".to_string(),
+                    channel_id: 0,
+                })
+                .unwrap();
+            helper
+                .set_invoice_state(invoice_id, InvoiceState::Unpaid, InvoiceState::Accepted)
+                .unwrap();
+        }
+        helper
+            .settle_and_insert(&[1], &[0], &invoice_insertable(&[4]))
+            .unwrap();
+        helper
+            .set_invoice_state(2, InvoiceState::Accepted, InvoiceState::Cancelled)
+            .unwrap();
+
+        assert_eq!(helper.clean_paid(Some(3_600)).unwrap(), 0);
+        assert_eq!(helper.clean_paid(None).unwrap(), 1);
+        assert!(helper.get_by_payment_hash(&[1]).unwrap().is_none());
+
+        // Accepted invoices are in-flight and the cancelled one is not paid
+        let remaining = helper
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|invoice| invoice.invoice.payment_hash)
+            .collect::<Vec<_>>();
+        assert_eq!(remaining, vec![vec![2], vec![3], vec![4]]);
+
+        let mut con = helper.pool.get().unwrap();
+        let htlc_count: i64 = htlcs::dsl::htlcs.count().get_result(&mut con).unwrap();
+        assert_eq!(htlc_count, 2);
+    }
+
+    #[test]
+    fn delete_by_payment_hash() {
+        let database = test_database("delete-by-payment-hash");
+        let helper = database.helper();
+
+        for payment_hash in [vec![1], vec![2], vec![3], vec![4]] {
+            insert_invoice(&helper, &payment_hash);
+        }
+        for invoice_id in [1, 2, 3, 4] {
+            helper
+                .insert_htlc(&HtlcInsertable {
+                    accepted_at: None,
+                    invoice_id,
+                    msat: 1_000,
+                    state: InvoiceState::Accepted.into(),
+                    scid: "".to_string(),
+                    channel_id: 0,
+                })
+                .unwrap();
+        }
+        helper
+            .set_invoice_state(2, InvoiceState::Unpaid, InvoiceState::Cancelled)
+            .unwrap();
+        helper
+            .set_invoice_state(3, InvoiceState::Unpaid, InvoiceState::Accepted)
+            .unwrap();
+        helper
+            .set_invoice_state(4, InvoiceState::Unpaid, InvoiceState::Accepted)
+            .unwrap();
+        helper
+            .set_invoice_state(4, InvoiceState::Accepted, InvoiceState::Paid)
+            .unwrap();
+
+        for (payment_hash, state) in [
+            (vec![3], InvoiceState::Accepted),
+            (vec![4], InvoiceState::Paid),
+        ] {
+            assert_eq!(
+                helper
+                    .delete_by_payment_hash(&payment_hash)
+                    .err()
+                    .unwrap()
+                    .downcast_ref::<InvoiceHelperError>()
+                    .unwrap(),
+                &InvoiceHelperError::InvalidDeleteState(state)
+            );
+        }
+
+        assert!(helper.delete_by_payment_hash(&[1]).unwrap());
+        assert!(helper.delete_by_payment_hash(&[2]).unwrap());
+        assert!(!helper.delete_by_payment_hash(&[1]).unwrap());
+        assert!(!helper.delete_by_payment_hash(&[5]).unwrap());
+
+        assert!(helper.get_by_payment_hash(&[1]).unwrap().is_none());
+        assert!(helper.get_by_payment_hash(&[2]).unwrap().is_none());
+
+        let remaining = helper.get_all().unwrap();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining.iter().all(|invoice| invoice.htlcs.len() == 1));
+
+        let mut con = helper.pool.get().unwrap();
+        let htlc_count: i64 = htlcs::dsl::htlcs.count().get_result(&mut con).unwrap();
+        assert_eq!(htlc_count, 2);
+    }
+
+    #[test]
+    fn settle_and_insert() {
+        let database = test_database("settle-and-insert");
+        let helper = database.helper();
+
+        let new_invoice = |payment_hash: Vec<u8>| invoice_insertable(&payment_hash);
+
+        for payment_hash in [vec![1], vec![2]] {
+            helper.insert(&new_invoice(payment_hash)).unwrap();
+        }
+        helper
+            .insert_htlc(&HtlcInsertable {
+                accepted_at: None,
+                invoice_id: 1,
+                msat: 1_000,
+                state: InvoiceState::Accepted.into(),
+                scid: "".to_string(),
+                channel_id: 0,
+            })
+            .unwrap();
+        helper
+            .set_invoice_state(1, InvoiceState::Unpaid, InvoiceState::Accepted)
+            .unwrap();
+
+        assert_eq!(
+            helper
+                .settle_and_insert(&[2], &[0], &new_invoice(vec![3]))
+                .err()
+                .unwrap()
+                .downcast_ref::<InvoiceHelperError>()
+                .unwrap(),
+            &InvoiceHelperError::InvalidSettleState(InvoiceState::Unpaid)
+        );
+
+        // A failed insert rolls back the settlement
+        assert_eq!(
+            helper
+                .settle_and_insert(&[1], &[0], &new_invoice(vec![2]))
+                .err()
+                .unwrap()
+                .downcast_ref::<InvoiceHelperError>()
+                .unwrap(),
+            &InvoiceHelperError::DuplicatePaymentHash("02".to_string())
+        );
+        let invoice = helper.get_by_payment_hash(&[1]).unwrap().unwrap();
+        assert_eq!(invoice.invoice.state, InvoiceState::Accepted.to_string());
+        assert_eq!(invoice.invoice.preimage, None);
+        assert_eq!(invoice.htlcs[0].state, InvoiceState::Accepted.to_string());
+        assert_eq!(invoice.htlcs[0].preimage, None);
+        assert_eq!(helper.get_state_log(&[1]).unwrap().len(), 1);
+        assert!(helper.get_by_payment_hash(&[3]).unwrap().is_none());
+
+        let settled = helper
+            .settle_and_insert(&[1], &[0], &new_invoice(vec![3]))
+            .unwrap();
+        assert_eq!(settled.state, InvoiceState::Paid.to_string());
+
+        let invoice = helper.get_by_payment_hash(&[1]).unwrap().unwrap();
+        assert_eq!(invoice.invoice.state, InvoiceState::Paid.to_string());
+        assert_eq!(invoice.invoice.preimage, Some(vec![0]));
+        assert!(invoice.invoice.settled_at.is_some());
+        assert_eq!(invoice.htlcs[0].state, InvoiceState::Paid.to_string());
+        assert_eq!(invoice.htlcs[0].preimage, Some(vec![0]));
+        assert_eq!(helper.get_state_log(&[1]).unwrap().len(), 2);
+
+        let created = helper.get_by_payment_hash(&[3]).unwrap().unwrap();
+        assert_eq!(created.invoice.state, InvoiceState::Unpaid.to_string());
+    }
+
+    #[test]
+    fn set_invoice_state_log() {
+        let database = test_database("set-invoice-state-log");
+        let helper = database.helper();
+
+        insert_invoice(&helper, &[1]);
+
+        assert_eq!(
+            helper
+                .set_invoice_state(1, InvoiceState::Unpaid, InvoiceState::Accepted)
+                .unwrap(),
+            1
+        );
+        // Updates that do not change the state are not logged
+        assert_eq!(
+            helper
+                .set_invoice_state(1, InvoiceState::Accepted, InvoiceState::Accepted)
+                .unwrap(),
+            1
+        );
+        // Neither are updates of a state that changed already
+        assert_eq!(
+            helper
+                .set_invoice_state(1, InvoiceState::Unpaid, InvoiceState::Cancelled)
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            helper
+                .set_invoice_state(1, InvoiceState::Accepted, InvoiceState::Paid)
+                .unwrap(),
+            1
+        );
+
+        let log = helper.get_state_log(&[1]).unwrap();
+        assert_eq!(
+            log.iter()
+                .map(|entry| (
+                    entry.invoice_id,
+                    entry.old_state.as_str(),
+                    entry.new_state.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![(1, "unpaid", "accepted"), (1, "accepted", "paid")]
+        );
+        assert!(log[0].changed_at <= log[1].changed_at);
+    }
+
+    #[test]
+    fn get_in_time_range() {
+        let database = test_database("get-in-time-range");
+        let helper = database.helper();
+
+        let now = Utc::now().naive_utc();
+        let mut con = helper.pool.get().unwrap();
+
+        for (id, hours_ago, settled) in [(1, 3, true), (2, 2, false), (3, 1, true)] {
+            insert_invoice(&helper, &[id as u8]);
+
+            let created_at = now - TimeDelta::hours(hours_ago);
+            update(invoices::dsl::invoices)
+                .filter(invoices::dsl::id.eq(id))
+                .set((
+                    invoices::dsl::created_at.eq(created_at),
+                    invoices::dsl::settled_at
+                        .eq(Some(created_at + TimeDelta::minutes(30)).filter(|_| settled)),
+                ))
+                .execute(&mut con)
+                .unwrap();
+        }
+
+        let ids = |invoices: Vec<HoldInvoice>| {
+            invoices
+                .into_iter()
+                .map(|invoice| invoice.invoice.id)
+                .collect::<Vec<i64>>()
+        };
+
+        assert_eq!(
+            ids(helper
+                .get_created_in_range(now - TimeDelta::hours(4), now)
+                .unwrap()),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            ids(helper
+                .get_created_in_range(now - TimeDelta::minutes(150), now - TimeDelta::minutes(90))
+                .unwrap()),
+            vec![2]
+        );
+
+        assert_eq!(
+            ids(helper
+                .get_settled_in_range(now - TimeDelta::hours(4), now)
+                .unwrap()),
+            vec![1, 3]
+        );
+        assert_eq!(
+            ids(helper
+                .get_settled_in_range(now - TimeDelta::hours(1), now)
+                .unwrap()),
+            vec![3]
+        );
+        assert!(helper
+            .get_settled_in_range(now - TimeDelta::minutes(140), now - TimeDelta::minutes(90))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn get_all_sorted() {
+        let database = test_database("get-all-sorted");
+        let helper = database.helper();
+
+        let now = Utc::now().naive_utc();
+        let mut con = helper.pool.get().unwrap();
+
+        for (id, created_minutes_ago, settled_minutes_ago, state) in [
+            (1, 240, Some(60), InvoiceState::Paid),
+            (2, 180, None, InvoiceState::Unpaid),
+            (3, 60, Some(30), InvoiceState::Paid),
+            (4, 120, Some(90), InvoiceState::Paid),
+            (5, 30, None, InvoiceState::Accepted),
+        ] {
+            insert_invoice(&helper, &[id as u8]);
+
+            update(invoices::dsl::invoices)
+                .filter(invoices::dsl::id.eq(id))
+                .set((
+                    invoices::dsl::state.eq(state.to_string()),
+                    invoices::dsl::created_at.eq(now - TimeDelta::minutes(created_minutes_ago)),
+                    invoices::dsl::settled_at.eq(settled_minutes_ago
+                        .map(|minutes_ago| now - TimeDelta::minutes(minutes_ago))),
+                ))
+                .execute(&mut con)
+                .unwrap();
+        }
+
+        let ids = |field: SortField, desc: bool| {
+            helper
+                .get_all_sorted(field, desc)
+                .unwrap()
+                .into_iter()
+                .map(|invoice| invoice.invoice.id)
+                .collect::<Vec<i64>>()
+        };
+
+        assert_eq!(ids(SortField::Id, false), vec![1, 2, 3, 4, 5]);
+        assert_eq!(ids(SortField::Id, true), vec![5, 4, 3, 2, 1]);
+        assert_eq!(ids(SortField::CreatedAt, false), vec![1, 2, 4, 3, 5]);
+        assert_eq!(ids(SortField::CreatedAt, true), vec![5, 3, 4, 2, 1]);
+        assert_eq!(ids(SortField::SettledAt, false), vec![4, 1, 3, 2, 5]);
+        assert_eq!(ids(SortField::SettledAt, true), vec![3, 1, 4, 2, 5]);
+        assert_eq!(ids(SortField::State, false), vec![5, 1, 3, 4, 2]);
+        assert_eq!(ids(SortField::State, true), vec![2, 1, 3, 4, 5]);
+    }
+
+    #[test]
+    fn get_htlcs_by_scid() {
+        let database = test_database("get-htlcs-by-scid");
+        let helper = database.helper();
+
+        for payment_hash in [vec![1], vec![2], vec![3]] {
+            insert_invoice(&helper, &payment_hash);
+        }
+
+        for (invoice_id, scid, channel_id, state) in [
+            (1, "811x1x0", 0, InvoiceState::Accepted),
+            (2, "811x1x0", 1, InvoiceState::Accepted),
+            (2, "811x1x0", 2, InvoiceState::Accepted),
+            (2, "812x1x0", 0, InvoiceState::Accepted),
+            (3, "811x1x0", 3, InvoiceState::Cancelled),
+        ] {
+            helper
+                .insert_htlc(&HtlcInsertable {
+                    accepted_at: None,
+                    invoice_id,
+                    msat: 1_000,
+                    state: state.into(),
+                    scid: scid.to_string(),
+                    channel_id,
+                })
+                .unwrap();
+        }
+
+        let pairs = helper.get_htlcs_by_scid("811x1x0").unwrap();
+        assert_eq!(
+            pairs
+                .iter()
+                .map(|(invoice, htlc)| (invoice.payment_hash.clone(), htlc.channel_id))
+                .collect::<Vec<_>>(),
+            vec![(vec![1], 0), (vec![2], 1), (vec![2], 2)]
+        );
+
+        let invoices = HoldInvoice::from_htlc_pairs(pairs);
+        assert_eq!(invoices.len(), 2);
+        assert_eq!(invoices[0].invoice.id, 1);
+        assert_eq!(invoices[0].htlcs.len(), 1);
+        assert_eq!(invoices[1].invoice.id, 2);
+        assert_eq!(invoices[1].htlcs.len(), 2);
+
+        assert_eq!(helper.get_htlcs_by_scid("812x1x0").unwrap().len(), 1);
+        assert!(helper.get_htlcs_by_scid("813x1x0").unwrap().is_empty());
+    }
+
+    #[test]
+    fn label() {
+        let database = test_database("label");
+        let helper = database.helper();
+
+        let invoice = |payment_hash: u8, label: Option<String>| InvoiceInsertable {
+            label,
+            ..invoice_insertable(&[payment_hash])
+        };
+
+        helper
+            .insert(&invoice(1, Some("swap".to_string())))
+            .unwrap();
+        helper.insert(&invoice(2, None)).unwrap();
+        helper.insert(&invoice(3, None)).unwrap();
+
+        assert_eq!(
+            helper
+                .insert(&invoice(4, Some("swap".to_string())))
+                .err()
+                .unwrap()
+                .downcast_ref::<InvoiceHelperError>()
+                .unwrap(),
+            &InvoiceHelperError::DuplicateLabel("swap".to_string())
+        );
+        assert_eq!(
+            helper
+                .insert(&invoice(1, Some("other".to_string())))
+                .err()
+                .unwrap()
+                .downcast_ref::<InvoiceHelperError>()
+                .unwrap(),
+            &InvoiceHelperError::DuplicatePaymentHash("01".to_string())
+        );
+        assert_eq!(
+            helper
+                .insert(&invoice(5, Some("a".repeat(256))))
+                .err()
+                .unwrap()
+                .downcast_ref::<InvoiceHelperError>()
+                .unwrap(),
+            &InvoiceHelperError::LabelTooLong(256)
+        );
+
+        let found = helper.get_by_label("swap").unwrap().unwrap();
+        assert_eq!(found.invoice.payment_hash, vec![1]);
+        assert_eq!(found.invoice.label, Some("swap".to_string()));
+        assert!(helper.get_by_label("other").unwrap().is_none());
+    }
+
+    #[test]
+    fn label_lookup_uses_index() {
+        #[derive(QueryableByName)]
+        struct QueryPlanRow {
+            #[diesel(sql_type = Text)]
+            detail: String,
+        }
+
+        let database = test_database("label-index");
+        let pool = database.pool.clone();
+
+        let plan = sql_query("EXPLAIN QUERY PLAN SELECT * FROM invoices WHERE label = ? LIMIT 1")
+            .bind::<Text, _>("swap")
+            .load::<QueryPlanRow>(&mut pool.get().unwrap())
+            .unwrap();
+        assert!(plan
+            .iter()
+            .any(|row| row.detail.contains("USING INDEX invoices_label_idx")));
     }
 }
@@ -9,6 +9,9 @@ use std::time::Duration;
 
 pub mod helpers;
 pub mod model;
+pub mod monitor;
+#[cfg(test)]
+pub mod test_utils;
 
 mod schema;
 
@@ -34,6 +37,9 @@ impl diesel::r2d2::CustomizeConnection<AnyConnection, diesel::r2d2::Error> for C
                     if let Some(d) = self.busy_timeout {
                         conn.batch_execute(&format!("PRAGMA busy_timeout = {};", d.as_millis()))?;
                     }
+                    conn.batch_execute("PRAGMA journal_mode = WAL;")?;
+                    conn.batch_execute("PRAGMA synchronous = NORMAL;")?;
+                    conn.batch_execute("PRAGMA foreign_keys = ON;")?;
                 }
                 _ => {}
             }
@@ -45,7 +51,26 @@ impl diesel::r2d2::CustomizeConnection<AnyConnection, diesel::r2d2::Error> for C
 
 pub type Pool = r2d2::Pool<ConnectionManager<AnyConnection>>;
 
-pub fn connect(url: &str) -> Result<Pool, Box<dyn Error + Send + Sync>> {
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    pub max_size: u32,
+    pub min_idle: u32,
+    pub connection_timeout: Duration,
+    pub busy_timeout: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            max_size: 10,
+            min_idle: 1,
+            connection_timeout: Duration::from_secs(30),
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+pub fn connect(url: &str, options: &PoolOptions) -> Result<Pool, Box<dyn Error + Send + Sync>> {
     let db_name = if is_postgres_connection_url(url) {
         "PostgreSQL"
     } else {
@@ -55,8 +80,11 @@ pub fn connect(url: &str) -> Result<Pool, Box<dyn Error + Send + Sync>> {
     debug!("Connecting to {} database", db_name);
     let manager: ConnectionManager<AnyConnection> = ConnectionManager::new(url);
     let pool = Pool::builder()
+        .max_size(options.max_size)
+        .min_idle(Some(options.min_idle))
+        .connection_timeout(options.connection_timeout)
         .connection_customizer(Box::new(ConnectionOptions {
-            busy_timeout: Some(Duration::from_secs(5)),
+            busy_timeout: Some(options.busy_timeout),
         }))
         .build(manager)?;
 
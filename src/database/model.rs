@@ -1,6 +1,8 @@
+use bitcoin::hashes::{sha256, Hash};
 use diesel::internal::derives::multiconnection::chrono;
 use diesel::{AsChangeset, Associations, Identifiable, Insertable, Queryable, Selectable};
 use serde::Serialize;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 
@@ -14,16 +16,30 @@ pub struct Invoice {
     pub state: String,
     pub created_at: chrono::NaiveDateTime,
     pub settled_at: Option<chrono::NaiveDateTime>,
+    pub mpp_timeout: Option<i64>,
+    pub settlement_deadline: Option<i64>,
+    pub accepted_at: Option<chrono::NaiveDateTime>,
+    pub label: Option<String>,
+    pub cancel_reason: Option<String>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub hash_algorithm: String,
 }
 
 #[derive(Insertable, Debug, PartialEq, Clone)]
 #[diesel(table_name = crate::database::schema::invoices)]
 pub struct InvoiceInsertable {
     pub payment_hash: Vec<u8>,
+    pub hash_algorithm: String,
     pub bolt11: String,
     pub state: String,
+    pub mpp_timeout: Option<i64>,
+    pub settlement_deadline: Option<i64>,
+    pub label: Option<String>,
 }
 
+pub const MAX_LABEL_LENGTH: usize = 255;
+pub const MAX_CANCEL_REASON_LENGTH: usize = 255;
+
 #[derive(
     Queryable,
     Identifiable,
@@ -46,6 +62,19 @@ pub struct Htlc {
     pub channel_id: i64,
     pub msat: i64,
     pub created_at: chrono::NaiveDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accepted_at: Option<chrono::NaiveDateTime>,
+    // Serialized hex encoded by the commands that show HTLCs
+    #[serde(skip_serializing)]
+    pub preimage: Option<Vec<u8>>,
+}
+
+impl Htlc {
+    /// Seconds the HTLC was held between being accepted and the invoice being settled
+    pub fn hold_duration_seconds(&self, settled_at: Option<chrono::NaiveDateTime>) -> Option<u64> {
+        let held = settled_at? - self.accepted_at?;
+        u64::try_from(held.num_seconds()).ok()
+    }
 }
 
 #[derive(Insertable, Debug, PartialEq, Clone)]
@@ -56,6 +85,67 @@ pub struct HtlcInsertable {
     pub scid: String,
     pub channel_id: i64,
     pub msat: i64,
+    pub accepted_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Queryable, Identifiable, Selectable, Associations, Serialize, Debug, PartialEq, Clone)]
+#[diesel(belongs_to(Invoice))]
+#[diesel(table_name = crate::database::schema::invoice_state_log)]
+pub struct InvoiceStateLog {
+    pub id: i64,
+    pub invoice_id: i64,
+    pub old_state: String,
+    pub new_state: String,
+    pub changed_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug, PartialEq, Clone)]
+#[diesel(table_name = crate::database::schema::invoice_state_log)]
+pub struct InvoiceStateLogInsertable {
+    pub invoice_id: i64,
+    pub old_state: String,
+    pub new_state: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SortFieldParsingError {
+    Unknown(String),
+}
+
+impl Display for SortFieldParsingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SortFieldParsingError::Unknown(field) => write!(
+                f,
+                "unknown sort field {}; has to be id, created_at, settled_at or state",
+                field
+            ),
+        }
+    }
+}
+
+impl Error for SortFieldParsingError {}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SortField {
+    Id,
+    CreatedAt,
+    SettledAt,
+    State,
+}
+
+impl TryFrom<&str> for SortField {
+    type Error = SortFieldParsingError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "id" => Ok(SortField::Id),
+            "created_at" => Ok(SortField::CreatedAt),
+            "settled_at" => Ok(SortField::SettledAt),
+            "state" => Ok(SortField::State),
+            &_ => Err(SortFieldParsingError::Unknown(value.to_string())),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -94,12 +184,14 @@ impl Display for InvoiceStateParsingError {
 
 impl Error for InvoiceStateParsingError {}
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum InvoiceState {
     Paid = 0,
     Unpaid = 1,
     Accepted = 2,
     Cancelled = 3,
+    /// Does not accept new HTLCs until it is resumed
+    Paused = 4,
 }
 
 impl Display for InvoiceState {
@@ -115,6 +207,7 @@ impl From<InvoiceState> for String {
             InvoiceState::Unpaid => "unpaid",
             InvoiceState::Accepted => "accepted",
             InvoiceState::Cancelled => "cancelled",
+            InvoiceState::Paused => "paused",
         }
         .to_string()
     }
@@ -129,6 +222,7 @@ impl TryFrom<&str> for InvoiceState {
             "unpaid" => Ok(InvoiceState::Unpaid),
             "accepted" => Ok(InvoiceState::Accepted),
             "cancelled" => Ok(InvoiceState::Cancelled),
+            "paused" => Ok(InvoiceState::Paused),
             &_ => Err(InvoiceStateParsingError::InvalidInvariant(
                 value.to_string(),
             )),
@@ -144,6 +238,75 @@ impl TryFrom<&String> for InvoiceState {
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum HashAlgorithmParsingError {
+    Unknown(String),
+}
+
+impl Display for HashAlgorithmParsingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashAlgorithmParsingError::Unknown(algorithm) => {
+                write!(f, "unknown hash algorithm: {}", algorithm)
+            }
+        }
+    }
+}
+
+impl Error for HashAlgorithmParsingError {}
+
+/// Algorithm with which the payment hash of an invoice is derived from its preimage
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Into::<String>::into(*self).as_str())
+    }
+}
+
+impl From<HashAlgorithm> for String {
+    fn from(value: HashAlgorithm) -> Self {
+        match value {
+            HashAlgorithm::Sha256 => "sha256",
+        }
+        .to_string()
+    }
+}
+
+impl TryFrom<&str> for HashAlgorithm {
+    type Error = HashAlgorithmParsingError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            &_ => Err(HashAlgorithmParsingError::Unknown(value.to_string())),
+        }
+    }
+}
+
+impl TryFrom<&String> for HashAlgorithm {
+    type Error = HashAlgorithmParsingError;
+
+    fn try_from(value: &String) -> Result<Self, Self::Error> {
+        HashAlgorithm::try_from(value.as_str())
+    }
+}
+
+impl HashAlgorithm {
+    pub fn hash(&self, preimage: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha256 => {
+                let hash: sha256::Hash = Hash::hash(preimage);
+                hash[..].to_vec()
+            }
+        }
+    }
+}
+
 impl InvoiceState {
     pub fn is_final(&self) -> bool {
         *self == InvoiceState::Paid || *self == InvoiceState::Cancelled
@@ -156,15 +319,24 @@ impl InvoiceState {
 
         match *self {
             InvoiceState::Unpaid => {
-                if new_state != InvoiceState::Accepted && new_state != InvoiceState::Cancelled {
+                if new_state != InvoiceState::Accepted
+                    && new_state != InvoiceState::Cancelled
+                    && new_state != InvoiceState::Paused
+                {
                     return Err(StateTransitionError::InvalidTransition(*self, new_state));
                 }
             }
             InvoiceState::Accepted => {
-                if new_state == InvoiceState::Unpaid {
+                if new_state == InvoiceState::Unpaid || new_state == InvoiceState::Paused {
                     return Err(StateTransitionError::InvalidTransition(*self, new_state));
                 }
             }
+            // Paused invoices have to be resumed before they can be paid
+            InvoiceState::Paused
+                if new_state == InvoiceState::Accepted || new_state == InvoiceState::Paid =>
+            {
+                return Err(StateTransitionError::InvalidTransition(*self, new_state));
+            }
             _ => {}
         };
 
@@ -172,6 +344,16 @@ impl InvoiceState {
     }
 }
 
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct InvoiceStatistics {
+    pub count_by_state: HashMap<String, u64>,
+    pub total_settled_msat: u64,
+    pub avg_settlement_seconds: Option<f64>,
+    pub pending_htlc_count: u64,
+    pub avg_hold_duration_seconds: Option<f64>,
+    pub oldest_unpaid_age_seconds: Option<u64>,
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct HoldInvoice {
     pub invoice: Invoice,
@@ -200,13 +382,31 @@ impl HoldInvoice {
             .iter()
             .any(|htlc| htlc.scid == scid && htlc.channel_id == id as i64)
     }
+
+    /// Groups the HTLCs by their invoice in the order the invoices first appear
+    pub fn from_htlc_pairs(pairs: Vec<(Invoice, Htlc)>) -> Vec<HoldInvoice> {
+        let mut invoices: Vec<HoldInvoice> = Vec::new();
+        for (invoice, htlc) in pairs {
+            match invoices
+                .iter_mut()
+                .find(|existing| existing.invoice.id == invoice.id)
+            {
+                Some(existing) => existing.htlcs.push(htlc),
+                None => invoices.push(HoldInvoice::new(invoice, vec![htlc])),
+            }
+        }
+
+        invoices
+    }
 }
 
 #[cfg(test)]
 mod test {
     use crate::database::model::{
-        HoldInvoice, Htlc, Invoice, InvoiceState, InvoiceStateParsingError, StateTransitionError,
+        HoldInvoice, Htlc, Invoice, InvoiceState, InvoiceStateParsingError, SortField,
+        SortFieldParsingError, StateTransitionError,
     };
+    use chrono::{TimeDelta, Utc};
 
     #[test]
     fn invoice_state_to_string() {
@@ -231,6 +431,10 @@ mod test {
             InvoiceState::try_from("cancelled").unwrap(),
             InvoiceState::Cancelled
         );
+        assert_eq!(
+            InvoiceState::try_from("paused").unwrap(),
+            InvoiceState::Paused
+        );
 
         assert_eq!(
             InvoiceState::try_from("invalid").err().unwrap(),
@@ -238,6 +442,27 @@ mod test {
         );
     }
 
+    #[test]
+    fn sort_field_from_str() {
+        assert_eq!(SortField::try_from("id").unwrap(), SortField::Id);
+        assert_eq!(
+            SortField::try_from("created_at").unwrap(),
+            SortField::CreatedAt
+        );
+        assert_eq!(
+            SortField::try_from("settled_at").unwrap(),
+            SortField::SettledAt
+        );
+        assert_eq!(SortField::try_from("state").unwrap(), SortField::State);
+
+        let err = SortField::try_from("amount").err().unwrap();
+        assert_eq!(err, SortFieldParsingError::Unknown("amount".to_string()));
+        assert_eq!(
+            err.to_string(),
+            "unknown sort field amount; has to be id, created_at, settled_at or state"
+        );
+    }
+
     #[test]
     fn invoice_state_from_string() {
         assert_eq!(
@@ -349,23 +574,61 @@ mod test {
         );
     }
 
+    #[test]
+    fn invoice_state_validate_transition_paused() {
+        for (state, new_state) in [
+            (InvoiceState::Unpaid, InvoiceState::Paused),
+            (InvoiceState::Paused, InvoiceState::Unpaid),
+            (InvoiceState::Paused, InvoiceState::Cancelled),
+        ] {
+            assert!(state.validate_transition(new_state).is_ok());
+        }
+
+        for (state, new_state) in [
+            (InvoiceState::Paused, InvoiceState::Accepted),
+            (InvoiceState::Paused, InvoiceState::Paid),
+            (InvoiceState::Accepted, InvoiceState::Paused),
+        ] {
+            assert_eq!(
+                state.validate_transition(new_state).err().unwrap(),
+                StateTransitionError::InvalidTransition(state, new_state)
+            );
+        }
+        assert_eq!(
+            InvoiceState::Paid
+                .validate_transition(InvoiceState::Paused)
+                .err()
+                .unwrap(),
+            StateTransitionError::IsFinal(InvoiceState::Paid)
+        );
+    }
+
     #[test]
     fn hold_invoice_amount_paid_msat() {
         let mut invoice = HoldInvoice::new(
             Invoice {
+                cancel_reason: None,
+                expires_at: None,
+                label: None,
                 id: 0,
                 payment_hash: vec![],
+                hash_algorithm: "sha256".to_string(),
                 preimage: None,
                 bolt11: "".to_string(),
                 state: "".to_string(),
                 created_at: Default::default(),
                 settled_at: None,
+                mpp_timeout: None,
+                settlement_deadline: None,
+                accepted_at: None,
             },
             vec![],
         );
         assert_eq!(invoice.amount_paid_msat(), 0);
 
         invoice.htlcs.push(Htlc {
+            accepted_at: None,
+            preimage: None,
             id: 0,
             invoice_id: 0,
             state: InvoiceState::Cancelled.to_string(),
@@ -377,6 +640,8 @@ mod test {
         assert_eq!(invoice.amount_paid_msat(), 0);
 
         invoice.htlcs.push(Htlc {
+            accepted_at: None,
+            preimage: None,
             id: 0,
             invoice_id: 0,
             state: InvoiceState::Accepted.to_string(),
@@ -388,6 +653,8 @@ mod test {
         assert_eq!(invoice.amount_paid_msat(), 10_000);
 
         invoice.htlcs.push(Htlc {
+            accepted_at: None,
+            preimage: None,
             id: 0,
             invoice_id: 0,
             state: InvoiceState::Paid.to_string(),
@@ -403,16 +670,25 @@ mod test {
     fn hold_invoice_htlc_is_known() {
         let invoice = HoldInvoice::new(
             Invoice {
+                cancel_reason: None,
+                expires_at: None,
+                label: None,
                 id: 0,
                 payment_hash: vec![],
+                hash_algorithm: "sha256".to_string(),
                 preimage: None,
                 bolt11: "".to_string(),
                 state: "".to_string(),
                 created_at: Default::default(),
                 settled_at: None,
+                mpp_timeout: None,
+                settlement_deadline: None,
+                accepted_at: None,
             },
             vec![
                 Htlc {
+                    accepted_at: None,
+                    preimage: None,
                     id: 0,
                     invoice_id: 0,
                     state: InvoiceState::Accepted.to_string(),
@@ -422,6 +698,8 @@ mod test {
                     created_at: Default::default(),
                 },
                 Htlc {
+                    accepted_at: None,
+                    preimage: None,
                     id: 0,
                     invoice_id: 0,
                     state: InvoiceState::Accepted.to_string(),
@@ -437,4 +715,38 @@ mod test {
         assert!(invoice.htlc_is_known("some channel", 21));
         assert!(!invoice.htlc_is_known("not found", 42));
     }
+
+    #[test]
+    fn htlc_hold_duration_seconds() {
+        let accepted_at = Utc::now().naive_utc();
+        let htlc = Htlc {
+            id: 0,
+            invoice_id: 0,
+            state: InvoiceState::Paid.to_string(),
+            scid: "".to_string(),
+            channel_id: 0,
+            msat: 21_000,
+            created_at: Default::default(),
+            accepted_at: Some(accepted_at),
+            preimage: None,
+        };
+
+        assert_eq!(htlc.hold_duration_seconds(None), None);
+        assert_eq!(
+            htlc.hold_duration_seconds(Some(accepted_at + TimeDelta::seconds(42))),
+            Some(42)
+        );
+        assert_eq!(
+            htlc.hold_duration_seconds(Some(accepted_at - TimeDelta::seconds(1))),
+            None
+        );
+        assert_eq!(
+            Htlc {
+                accepted_at: None,
+                ..htlc
+            }
+            .hold_duration_seconds(Some(accepted_at)),
+            None
+        );
+    }
 }
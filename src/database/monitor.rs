@@ -0,0 +1,97 @@
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::database::Pool;
+use std::time::Duration;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+pub const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+const FAILURES_UNTIL_ERROR: u32 = 3;
+
+/// Pings the database periodically; failures are only logged, because HTLCs
+/// have to keep flowing even when the database is unreachable
+#[derive(Debug, Clone)]
+pub struct Monitor<T> {
+    invoice_helper: T,
+    pool: Pool,
+    interval: Duration,
+}
+
+impl<T> Monitor<T>
+where
+    T: InvoiceHelper + Sync + Send + Clone,
+{
+    pub fn new(invoice_helper: T, pool: Pool, interval: Duration) -> Self {
+        Monitor {
+            invoice_helper,
+            pool,
+            interval,
+        }
+    }
+
+    pub async fn start(&self, cancellation_token: CancellationToken) {
+        info!(
+            "Checking database connection every {} seconds",
+            self.interval.as_secs()
+        );
+        let mut interval = time::interval(self.interval);
+        let mut failures = 0;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = cancellation_token.cancelled() => {
+                    debug!("Stopping database monitor");
+                    break;
+                }
+            };
+
+            failures = self.check(failures);
+        }
+    }
+
+    /// Returns the number of consecutive failed pings
+    fn check(&self, failures: u32) -> u32 {
+        let err = match self.invoice_helper.ping() {
+            Ok(_) => {
+                if failures > 0 {
+                    info!(failures, "Database is reachable again");
+                }
+                return 0;
+            }
+            Err(err) => err,
+        };
+
+        let failures = failures + 1;
+        if failures >= FAILURES_UNTIL_ERROR {
+            let state = self.pool.state();
+            error!(
+                error = %err,
+                failures,
+                connections = state.connections,
+                idle_connections = state.idle_connections,
+                "Database is unreachable"
+            );
+        } else {
+            warn!(error = %err, failures, "Database ping failed");
+        }
+
+        failures
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::monitor::{Monitor, PING_INTERVAL};
+    use crate::database::test_utils::test_database;
+
+    #[test]
+    fn check_resets_failures() {
+        let database = test_database("monitor");
+        let monitor = Monitor::new(database.helper(), database.pool.clone(), PING_INTERVAL);
+
+        assert_eq!(monitor.check(0), 0);
+        assert_eq!(monitor.check(2), 0);
+    }
+}
@@ -7,6 +7,13 @@ diesel::table! {
         state -> Text,
         created_at -> Timestamp,
         settled_at -> Nullable<Timestamp>,
+        mpp_timeout -> Nullable<BigInt>,
+        settlement_deadline -> Nullable<BigInt>,
+        accepted_at -> Nullable<Timestamp>,
+        label -> Nullable<Text>,
+        cancel_reason -> Nullable<Text>,
+        expires_at -> Nullable<Timestamp>,
+        hash_algorithm -> Text,
     }
 }
 
@@ -19,9 +26,22 @@ diesel::table! {
         channel_id -> BigInt,
         msat -> BigInt,
         created_at -> Timestamp,
+        accepted_at -> Nullable<Timestamp>,
+        preimage -> Nullable<Binary>,
+    }
+}
+
+diesel::table! {
+    invoice_state_log (id) {
+        id -> BigInt,
+        invoice_id -> BigInt,
+        old_state -> Text,
+        new_state -> Text,
+        changed_at -> Timestamp,
     }
 }
 
 diesel::joinable!(htlcs -> invoices (invoice_id));
+diesel::joinable!(invoice_state_log -> invoices (invoice_id));
 
-diesel::allow_tables_to_appear_in_same_query!(invoices, htlcs,);
+diesel::allow_tables_to_appear_in_same_query!(invoices, htlcs, invoice_state_log,);
@@ -0,0 +1,166 @@
+use crate::database::helpers::invoice_helper::{InvoiceHelper, InvoiceHelperDatabase};
+use crate::database::model::{
+    HoldInvoice, Htlc, HtlcInsertable, Invoice, InvoiceInsertable, InvoiceState, InvoiceStateLog,
+    InvoiceStatistics, SortField,
+};
+use crate::database::Pool;
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveDateTime};
+use mockall::mock;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+mock! {
+    pub InvoiceHelper {}
+
+    impl Clone for InvoiceHelper {
+        fn clone(&self) -> Self;
+    }
+
+    impl InvoiceHelper for InvoiceHelper {
+        fn insert(&self, invoice: &InvoiceInsertable) -> Result<usize>;
+        fn insert_htlc(&self, htlc: &HtlcInsertable) -> Result<usize>;
+
+        fn set_invoice_state(
+            &self,
+            id: i64,
+            state: InvoiceState,
+            new_state: InvoiceState,
+        ) -> Result<usize>;
+        fn set_invoice_preimage(&self, payment_hash: &[u8], preimage: &[u8]) -> Result<()>;
+        fn set_invoice_cancel_reason(&self, payment_hash: &[u8], reason: &str) -> Result<()>;
+        fn set_htlc_state_by_id(
+            &self,
+            htlc_id: i64,
+            state: InvoiceState,
+            new_state: InvoiceState,
+        ) -> Result<usize>;
+        fn set_htlc_states_by_invoice(
+            &self,
+            invoice_id: i64,
+            state: InvoiceState,
+            new_state: InvoiceState,
+        ) -> Result<usize>;
+        fn set_htlc_preimage(&self, htlc_id: i64, preimage: &[u8]) -> Result<()>;
+        fn conditional_accept(
+            &self,
+            invoice_id: i64,
+            amount_threshold: u64,
+        ) -> Result<bool>;
+
+        fn clean(&self, state: Option<InvoiceState>, age: Option<u64>, dry_run: bool) -> Result<usize>;
+        fn clean_paid(&self, age: Option<u64>) -> Result<usize>;
+        fn delete_by_payment_hash(&self, payment_hash: &[u8]) -> Result<bool>;
+        fn settle_and_insert(
+            &self,
+            payment_hash: &[u8],
+            preimage: &[u8],
+            invoice: &InvoiceInsertable,
+        ) -> Result<Invoice>;
+
+        fn get_all(&self) -> Result<Vec<HoldInvoice>>;
+        fn get_all_sorted(&self, field: SortField, desc: bool) -> Result<Vec<HoldInvoice>>;
+        fn get_paginated(
+            &self,
+            after_id: i64,
+            limit: u64,
+            state: Option<InvoiceState>,
+        ) -> Result<Vec<HoldInvoice>>;
+        fn get_paginated_by_time(
+            &self,
+            after: Option<(NaiveDateTime, i64)>,
+            limit: u64,
+        ) -> Result<Vec<HoldInvoice>>;
+        fn get_by_payment_hash(&self, payment_hash: &[u8]) -> Result<Option<HoldInvoice>>;
+        fn get_by_preimage(&self, preimage: &[u8]) -> Result<Option<HoldInvoice>>;
+        fn get_by_bolt11(&self, bolt11: &str) -> Result<Option<HoldInvoice>>;
+        fn get_by_label(&self, label: &str) -> Result<Option<HoldInvoice>>;
+        fn batch_get_by_payment_hashes(&self, payment_hashes: &[Vec<u8>]) -> Result<Vec<HoldInvoice>>;
+        fn get_by_state(&self, state: InvoiceState) -> Result<Vec<HoldInvoice>>;
+        fn get_expiring_soon(&self, within_seconds: u64) -> Result<Vec<HoldInvoice>>;
+        fn get_oldest_unpaid(&self) -> Result<Option<HoldInvoice>>;
+        fn count_by_state(&self) -> Result<HashMap<InvoiceState, u64>>;
+        fn get_in_date_range(
+            &self,
+            from: Option<NaiveDateTime>,
+            to: Option<NaiveDateTime>,
+        ) -> Result<Vec<HoldInvoice>>;
+        fn get_created_in_range(
+            &self,
+            from: NaiveDateTime,
+            to: NaiveDateTime,
+        ) -> Result<Vec<HoldInvoice>>;
+        fn get_settled_in_range(
+            &self,
+            from: NaiveDateTime,
+            to: NaiveDateTime,
+        ) -> Result<Vec<HoldInvoice>>;
+        fn get_state_log(&self, invoice_ids: &[i64]) -> Result<Vec<InvoiceStateLog>>;
+        fn get_statistics(&self) -> Result<InvoiceStatistics>;
+        fn avg_hold_duration_seconds(&self) -> Result<Option<f64>>;
+        fn get_pending_htlc_count(&self) -> Result<u64>;
+        fn get_accepted_invoice_count(&self) -> Result<u64>;
+        fn count_htlcs(&self, invoice_id: i64) -> Result<HashMap<InvoiceState, u64>>;
+        fn sum_htlc_msat(&self, invoice_id: i64, state: InvoiceState) -> Result<u64>;
+        fn get_htlcs_by_scid(&self, scid: &str) -> Result<Vec<(Invoice, Htlc)>>;
+        fn total_volume_msat(
+            &self,
+            from: Option<NaiveDateTime>,
+            to: Option<NaiveDateTime>,
+        ) -> Result<u64>;
+        fn volume_by_day(&self, days: u32) -> Result<Vec<(NaiveDate, u64)>>;
+
+        fn ping(&self) -> Result<()>;
+    }
+}
+
+/// SQLite database in the temporary directory that is deleted when dropped
+pub struct TestDatabase {
+    path: PathBuf,
+    pub pool: Pool,
+}
+
+impl TestDatabase {
+    pub fn helper(&self) -> InvoiceHelperDatabase {
+        InvoiceHelperDatabase::new(self.pool.clone())
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Creates an empty database; `name` has to be unique across all tests
+pub fn test_database(name: &str) -> TestDatabase {
+    let path = std::env::temp_dir().join(format!("hold-test-{}.sqlite", name));
+    let _ = fs::remove_file(&path);
+
+    let pool = crate::database::connect(path.to_str().unwrap(), &Default::default()).unwrap();
+    TestDatabase { path, pool }
+}
+
+/// Unpaid invoice without label or custom timeouts
+pub fn invoice_insertable(payment_hash: &[u8]) -> InvoiceInsertable {
+    InvoiceInsertable {
+        label: None,
+        payment_hash: payment_hash.to_vec(),
+        hash_algorithm: "sha256".to_string(),
+        bolt11: "lnbcrt1".to_string(),
+        state: InvoiceState::Unpaid.into(),
+        mpp_timeout: None,
+        settlement_deadline: None,
+    }
+}
+
+/// Inserts an unpaid invoice and returns it as stored in the database
+pub fn insert_invoice<T: InvoiceHelper>(helper: &T, payment_hash: &[u8]) -> Invoice {
+    helper.insert(&invoice_insertable(payment_hash)).unwrap();
+    helper
+        .get_by_payment_hash(payment_hash)
+        .unwrap()
+        .unwrap()
+        .invoice
+}
@@ -1,19 +1,27 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bitcoin::hashes::{sha256, Hash};
-use cln_rpc::model::requests::SigninvoiceRequest;
+use chrono::{DateTime, Utc};
+use cln_rpc::model::requests::{GetinfoRequest, SigninvoiceRequest};
 use cln_rpc::ClnRpc;
-use lightning_invoice::{Currency, PaymentSecret, RouteHint};
+use lightning_invoice::{Bolt11Invoice, Currency, PaymentSecret, RouteHint};
 use secp256k1::rand::Rng;
 use secp256k1::{rand, Secp256k1, SecretKey};
+use serde::{Serialize, Serializer};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, MutexGuard};
 use tonic::async_trait;
+use tracing::warn;
 
 const DEFAULT_MIN_FINAL_CLTV_EXPIRY_DELTA: u64 = 80;
 
+/// Maximal length of a BOLT11 description in bytes
+pub const MAX_DESCRIPTION_LENGTH: usize = 639;
+
 #[derive(Debug)]
 enum NetworkError {
     InvalidNetwork,
@@ -89,6 +97,43 @@ impl InvoiceBuilder {
     }
 }
 
+/// What ended up in an encoded invoice, decoded from its bolt11 string
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InvoiceDetails {
+    #[serde(serialize_with = "serialize_hex")]
+    pub payment_hash: Vec<u8>,
+    pub amount_msat: Option<u64>,
+    pub expiry: u64,
+    pub min_final_cltv_expiry_delta: u64,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl InvoiceDetails {
+    pub fn from_bolt11(bolt11: &str) -> Result<Self> {
+        let invoice = Bolt11Invoice::from_str(bolt11)?;
+
+        let expires_at = invoice
+            .expires_at()
+            .and_then(|expires_at| DateTime::from_timestamp(expires_at.as_secs() as i64, 0))
+            .ok_or_else(|| anyhow!("invalid invoice expiry"))?;
+
+        Ok(InvoiceDetails {
+            payment_hash: invoice.payment_hash()[..].to_vec(),
+            amount_msat: invoice.amount_milli_satoshis(),
+            expiry: invoice.expiry_time().as_secs(),
+            min_final_cltv_expiry_delta: invoice.min_final_cltv_expiry_delta(),
+            expires_at,
+        })
+    }
+}
+
+fn serialize_hex<S>(value: &[u8], serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&hex::encode(value))
+}
+
 #[async_trait]
 pub trait InvoiceEncoder {
     async fn encode(&self, invoice_builder: InvoiceBuilder) -> Result<String>;
@@ -98,18 +143,56 @@ pub trait InvoiceEncoder {
 pub struct Encoder {
     network: Currency,
     secret_key: SecretKey,
-    rpc: Arc<Mutex<ClnRpc>>,
+    rpc_file: String,
+    rpcs: Arc<Vec<Mutex<ClnRpc>>>,
+    next_rpc: Arc<AtomicUsize>,
+    default_description: String,
 }
 
 impl Encoder {
-    pub async fn new(rpc_file: &str, network: &str) -> Result<Self> {
+    pub async fn new(
+        rpc_file: &str,
+        network: &str,
+        rpc_pool_size: usize,
+        default_description: String,
+    ) -> Result<Self> {
+        let network = Self::parse_network(network)?;
+
+        let mut rpcs = Vec::new();
+        for _ in 0..rpc_pool_size.max(1) {
+            rpcs.push(Mutex::new(ClnRpc::new(rpc_file).await?));
+        }
+
         Ok(Encoder {
-            network: Self::parse_network(network)?,
+            network,
             secret_key: SecretKey::new(&mut rand::thread_rng()),
-            rpc: Arc::new(Mutex::new(ClnRpc::new(rpc_file).await?)),
+            rpc_file: rpc_file.to_string(),
+            rpcs: Arc::new(rpcs),
+            next_rpc: Arc::new(AtomicUsize::new(0)),
+            default_description,
         })
     }
 
+    /// Picks the first idle connection, starting from the next one in round-robin order;
+    /// waits for that next one when all of them are busy
+    async fn rpc(&self) -> MutexGuard<'_, ClnRpc> {
+        let start = self.next_rpc.fetch_add(1, Ordering::Relaxed) % self.rpcs.len();
+
+        for index in 0..self.rpcs.len() {
+            if let Ok(rpc) = self.rpcs[(start + index) % self.rpcs.len()].try_lock() {
+                return rpc;
+            }
+        }
+
+        self.rpcs[start].lock().await
+    }
+
+    /// Compressed public key of the CLN node the plugin is running on
+    pub async fn node_id(&self) -> Result<Vec<u8>> {
+        let info = self.rpc().await.call_typed(&GetinfoRequest {}).await?;
+        Ok(info.id.serialize().to_vec())
+    }
+
     fn parse_network(network: &str) -> Result<Currency> {
         match network {
             "bitcoin" => Ok(Currency::Bitcoin),
@@ -154,7 +237,8 @@ impl InvoiceEncoder for Encoder {
                 },
             );
 
-        if let Some(amount) = invoice_builder.amount_msat {
+        // Zero is treated like no amount, which lets the payer choose it
+        if let Some(amount) = invoice_builder.amount_msat.filter(|amount| *amount > 0) {
             builder = builder.amount_milli_satoshis(amount);
         }
 
@@ -164,29 +248,254 @@ impl InvoiceEncoder for Encoder {
             }
         }
 
-        let builder = if let Some(desc) = invoice_builder.description {
-            match desc {
-                InvoiceDescription::Description(desc) => builder.description(desc),
-                InvoiceDescription::Hash(hash) => {
-                    builder.description_hash(Hash::from_slice(&hash)?)
-                }
-            }
-        } else {
-            builder.description("".into())
+        let builder = match invoice_builder
+            .description
+            .unwrap_or_else(|| InvoiceDescription::Description(self.default_description.clone()))
+        {
+            InvoiceDescription::Description(desc) => builder.description(desc),
+            InvoiceDescription::Hash(hash) => builder.description_hash(Hash::from_slice(&hash)?),
         };
 
         let invoice = builder
             .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &self.secret_key))?;
 
-        let signed = self
-            .rpc
-            .lock()
-            .await
+        let mut rpc = self.rpc().await;
+        let signed = match rpc
             .call_typed(&SigninvoiceRequest {
                 invstring: invoice.to_string(),
             })
-            .await?;
+            .await
+        {
+            Ok(signed) => signed,
+            Err(err) => {
+                // Errors without code were not returned by lightningd, but by the connection
+                if err.code.is_none() {
+                    warn!("Reconnecting to CLN RPC after error: {}", err);
+                    match ClnRpc::new(&self.rpc_file).await {
+                        Ok(reconnected) => *rpc = reconnected,
+                        Err(err) => warn!("Could not reconnect to CLN RPC: {}", err),
+                    }
+                }
+
+                return Err(err.into());
+            }
+        };
 
         Ok(signed.bolt11)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::encoder::{
+        Encoder, InvoiceBuilder, InvoiceDescription, InvoiceDetails, InvoiceEncoder,
+    };
+    use bitcoin::hashes::{sha256, Hash};
+    use chrono::DateTime;
+    use lightning_invoice::{Bolt11Invoice, Currency, PaymentSecret};
+    use secp256k1::{Secp256k1, SecretKey};
+    use serde_json::json;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::time::Duration;
+    use std::{fs, process};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    #[test]
+    fn invoice_details_from_bolt11() {
+        let payment_hash = sha256::Hash::hash(&[0]);
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let invoice = lightning_invoice::InvoiceBuilder::new(Currency::Regtest)
+            .description("".to_string())
+            .timestamp(std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .payment_hash(payment_hash)
+            .payment_secret(PaymentSecret([0; 32]))
+            .amount_milli_satoshis(21_000)
+            .expiry_time(Duration::from_secs(3_600))
+            .min_final_cltv_expiry_delta(144)
+            .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &secret_key))
+            .unwrap();
+
+        let details = InvoiceDetails::from_bolt11(&invoice.to_string()).unwrap();
+        assert_eq!(
+            details,
+            InvoiceDetails {
+                payment_hash: payment_hash[..].to_vec(),
+                amount_msat: Some(21_000),
+                expiry: 3_600,
+                min_final_cltv_expiry_delta: 144,
+                expires_at: DateTime::from_timestamp(1_700_003_600, 0).unwrap(),
+            }
+        );
+
+        let json = serde_json::to_value(&details).unwrap();
+        assert_eq!(json["payment_hash"], hex::encode(payment_hash));
+        assert_eq!(json["expires_at"], "2023-11-14T23:13:20Z");
+    }
+
+    #[test]
+    fn invoice_details_invalid() {
+        assert!(InvoiceDetails::from_bolt11("lnbcrt1").is_err());
+    }
+
+    #[tokio::test]
+    async fn encode_concurrently() {
+        let rpc_file = rpc_file_path("concurrent");
+        start_mock_rpc(&rpc_file, None);
+
+        let encoder = Encoder::new(rpc_file.to_str().unwrap(), "regtest", 4, "".to_string())
+            .await
+            .unwrap();
+
+        let tasks = (0..10u8)
+            .map(|i| {
+                let encoder = encoder.clone();
+                tokio::spawn(async move {
+                    let payment_hash = sha256::Hash::hash(&[i]);
+                    let bolt11 = encoder
+                        .encode(InvoiceBuilder::new(&payment_hash[..]).amount_msat(1_000))
+                        .await
+                        .unwrap();
+                    assert_eq!(
+                        Bolt11Invoice::from_str(&bolt11).unwrap().payment_hash(),
+                        &payment_hash
+                    );
+                })
+            })
+            .collect::<Vec<_>>();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            for task in tasks {
+                task.await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+
+        fs::remove_file(rpc_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn encode_reconnect() {
+        let rpc_file = rpc_file_path("reconnect");
+        // Every connection is closed after its first response
+        start_mock_rpc(&rpc_file, Some(1));
+
+        let encoder = Encoder::new(rpc_file.to_str().unwrap(), "regtest", 1, "".to_string())
+            .await
+            .unwrap();
+        let payment_hash = sha256::Hash::hash(&[0]);
+
+        assert!(encoder
+            .encode(InvoiceBuilder::new(&payment_hash[..]))
+            .await
+            .is_ok());
+        assert!(encoder
+            .encode(InvoiceBuilder::new(&payment_hash[..]))
+            .await
+            .is_err());
+        assert!(encoder
+            .encode(InvoiceBuilder::new(&payment_hash[..]))
+            .await
+            .is_ok());
+
+        fs::remove_file(rpc_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn encode_default_description() {
+        let rpc_file = rpc_file_path("default-description");
+        start_mock_rpc(&rpc_file, None);
+
+        let encoder = Encoder::new(
+            rpc_file.to_str().unwrap(),
+            "regtest",
+            1,
+            "Boltz".to_string(),
+        )
+        .await
+        .unwrap();
+        let payment_hash = sha256::Hash::hash(&[0]);
+
+        let description = |bolt11: String| {
+            Bolt11Invoice::from_str(&bolt11)
+                .unwrap()
+                .description()
+                .to_string()
+        };
+
+        assert_eq!(
+            description(
+                encoder
+                    .encode(InvoiceBuilder::new(&payment_hash[..]))
+                    .await
+                    .unwrap()
+            ),
+            "Boltz"
+        );
+        assert_eq!(
+            description(
+                encoder
+                    .encode(
+                        InvoiceBuilder::new(&payment_hash[..])
+                            .description(InvoiceDescription::Description("memo".to_string()))
+                    )
+                    .await
+                    .unwrap()
+            ),
+            "memo"
+        );
+
+        fs::remove_file(rpc_file).unwrap();
+    }
+
+    fn rpc_file_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hold-test-rpc-{}-{}", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Answers signinvoice calls by echoing the invoice after a short delay
+    fn start_mock_rpc(path: &Path, max_requests: Option<usize>) {
+        let listener = UnixListener::bind(path).unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    let mut handled = 0;
+
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if line.is_empty() {
+                            continue;
+                        }
+
+                        let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+
+                        let response = json!({
+                            "jsonrpc": "2.0",
+                            "id": request["id"],
+                            "result": {
+                                "bolt11": request["params"]["invstring"],
+                            },
+                        });
+                        write
+                            .write_all(format!("{}\n\n", response).as_bytes())
+                            .await
+                            .unwrap();
+
+                        handled += 1;
+                        if max_requests.is_some_and(|max| handled >= max) {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,141 @@
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::grpc::health::proto::health_check_response::ServingStatus;
+use crate::grpc::health::proto::health_server::Health;
+use crate::grpc::health::proto::{HealthCheckRequest, HealthCheckResponse};
+use log::{debug, warn};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
+use tonic::codegen::tokio_stream::Stream;
+use tonic::{async_trait, Code, Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+const HOLD_SERVICE: &str = "hold.Hold";
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct HealthService<T> {
+    invoice_helper: T,
+}
+
+impl<T> HealthService<T>
+where
+    T: InvoiceHelper + Send + Sync + Clone + 'static,
+{
+    pub fn new(invoice_helper: T) -> Self {
+        HealthService { invoice_helper }
+    }
+
+    fn is_known_service(service: &str) -> bool {
+        service.is_empty() || service == HOLD_SERVICE
+    }
+
+    async fn check_database(invoice_helper: T) -> ServingStatus {
+        let ping = tokio::task::spawn_blocking(move || invoice_helper.ping());
+
+        match tokio::time::timeout(CHECK_TIMEOUT, ping).await {
+            Ok(Ok(Ok(_))) => ServingStatus::Serving,
+            Ok(Ok(Err(err))) => {
+                warn!("Database health check failed: {}", err);
+                ServingStatus::NotServing
+            }
+            Ok(Err(err)) => {
+                warn!("Database health check panicked: {}", err);
+                ServingStatus::NotServing
+            }
+            Err(_) => {
+                warn!(
+                    "Database health check timed out after {}ms",
+                    CHECK_TIMEOUT.as_millis()
+                );
+                ServingStatus::NotServing
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T> Health for HealthService<T>
+where
+    T: InvoiceHelper + Send + Sync + Clone + 'static,
+{
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let params = request.into_inner();
+        if !Self::is_known_service(&params.service) {
+            return Err(Status::new(
+                Code::NotFound,
+                format!("unknown service: {}", params.service),
+            ));
+        }
+
+        Ok(Response::new(HealthCheckResponse {
+            status: Self::check_database(self.invoice_helper.clone()).await as i32,
+        }))
+    }
+
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<HealthCheckResponse, Status>> + Send>>;
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let params = request.into_inner();
+        let (tx, rx) = mpsc::channel(16);
+
+        if !Self::is_known_service(&params.service) {
+            if let Err(err) = tx
+                .send(Ok(HealthCheckResponse {
+                    status: ServingStatus::ServiceUnknown as i32,
+                }))
+                .await
+            {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("could not send health status: {}", err),
+                ));
+            }
+
+            return Ok(Response::new(Box::pin(ReceiverStream::new(rx))));
+        }
+
+        let invoice_helper = self.invoice_helper.clone();
+        tokio::spawn(async move {
+            let mut last_status = None;
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = tx.closed() => break,
+                };
+
+                let status = Self::check_database(invoice_helper.clone()).await;
+                if last_status == Some(status) {
+                    continue;
+                }
+
+                if let Err(err) = tx
+                    .send(Ok(HealthCheckResponse {
+                        status: status as i32,
+                    }))
+                    .await
+                {
+                    debug!("Could not send health status: {}", err);
+                    break;
+                }
+
+                last_status = Some(status);
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
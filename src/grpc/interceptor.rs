@@ -0,0 +1,101 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tonic::codegen::http::{HeaderValue, Request, Response};
+use tonic::codegen::Service;
+use tonic::Code;
+use tower_layer::Layer;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Tags every gRPC request with an ID, which is taken from the request metadata
+/// when the client sent one, and logs the method, status and duration of the call
+#[derive(Debug, Clone, Default)]
+pub struct TracingInterceptor {}
+
+impl TracingInterceptor {
+    pub fn new() -> Self {
+        TracingInterceptor {}
+    }
+}
+
+impl<S> Layer<S> for TracingInterceptor {
+    type Service = TracingService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TracingService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TracingService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for TracingService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let request_id = match req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+        {
+            Some(id) => id.to_string(),
+            None => {
+                let id = Uuid::new_v4().to_string();
+                if let Ok(value) = HeaderValue::from_str(&id) {
+                    req.headers_mut().insert(REQUEST_ID_HEADER, value);
+                }
+                id
+            }
+        };
+
+        let method = req.uri().path().to_string();
+        debug!(request_id = %request_id, method = %method, "Received gRPC request");
+
+        // The clone that was polled to be ready has to handle the call
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let start = Instant::now();
+            let mut res = inner.call(req).await?;
+
+            // Calls that succeed only send their status in the trailers
+            let status = res
+                .headers()
+                .get("grpc-status")
+                .map(|status| Code::from_bytes(status.as_bytes()))
+                .unwrap_or(Code::Ok);
+
+            info!(
+                request_id = %request_id,
+                method = %method,
+                status = ?status,
+                duration_ms = start.elapsed().as_millis() as u64,
+                "Handled gRPC request"
+            );
+
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+
+            Ok(res)
+        })
+    }
+}
@@ -1,5 +1,9 @@
 pub mod server;
+pub mod service;
+pub mod stream_limiter;
+pub mod transformers;
 
-mod service;
+mod health;
+mod interceptor;
+mod rate_limiter;
 mod tls;
-mod transformers;
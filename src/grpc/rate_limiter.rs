@@ -0,0 +1,80 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket that allows bursts of up to `capacity` requests and refills
+/// at `refill_rate` tokens per second
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u64, refill_rate: f64) -> Self {
+        RateLimiter {
+            capacity: capacity as f64,
+            refill_rate,
+            bucket: Mutex::new(Bucket {
+                tokens: capacity as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn per_minute(limit: u64) -> Self {
+        Self::new(limit, limit as f64 / 60.0)
+    }
+
+    /// Takes a token from the bucket; returns false when it is empty
+    pub fn try_acquire(&self) -> bool {
+        let mut bucket = match self.bucket.lock() {
+            Ok(bucket) => bucket,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_rate).min(self.capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::grpc::rate_limiter::RateLimiter;
+    use std::time::Duration;
+
+    #[test]
+    fn burst() {
+        let limiter = RateLimiter::per_minute(100);
+
+        let allowed = (0..200).filter(|_| limiter.try_acquire()).count();
+        assert_eq!(allowed, 100);
+    }
+
+    #[test]
+    fn refill() {
+        let limiter = RateLimiter::new(2, 20.0);
+
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(limiter.try_acquire());
+    }
+}
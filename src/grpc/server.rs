@@ -1,21 +1,53 @@
 use crate::database::helpers::invoice_helper::InvoiceHelper;
 use crate::encoder::InvoiceEncoder;
+use crate::grpc::health::proto::health_server::HealthServer;
+use crate::grpc::health::HealthService;
+use crate::grpc::interceptor::TracingInterceptor;
 use crate::grpc::service::hold::hold_server::HoldServer;
-use crate::grpc::service::HoldService;
-use crate::grpc::tls::load_certificates;
+use crate::grpc::service::{HoldService, NodeInfo};
+use crate::grpc::stream_limiter::StreamLimiter;
+use crate::grpc::tls::{
+    certificate_expiry, certificate_needs_renewal, load_certificates,
+    CERTIFICATE_EXPIRY_WARNING_DAYS, CERTIFICATE_NAMES,
+};
 use crate::settler::Settler;
 use anyhow::Result;
-use log::info;
-use std::net::{IpAddr, SocketAddr};
+use log::{debug, info, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 use tokio_util::sync::CancellationToken;
 use tonic::transport::ServerTlsConfig;
 
+const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("hold_descriptor");
+
+const CERTIFICATE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Keepalives of gRPC connections; `None` disables them
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Keepalive {
+    pub http2_interval: Option<Duration>,
+    pub http2_timeout: Option<Duration>,
+    pub tcp: Option<Duration>,
+}
+
 pub struct Server<T, E> {
     host: String,
     port: i64,
+    // 0 keeps the defaults of tonic
+    max_send_message_size: usize,
+    max_recv_message_size: usize,
+    // Invoices per minute; 0 disables the limit
+    rate_limit_invoices: u64,
+    keepalive: Keepalive,
+    // Concurrent requests per connection; 0 disables the limit
+    concurrent_streams: usize,
+    stream_limiter: StreamLimiter,
     is_regtest: bool,
+    // Listen on IPv4 and IPv6
+    bind_both: bool,
+    node_info: NodeInfo,
 
     directory: PathBuf,
     cancellation_token: CancellationToken,
@@ -34,7 +66,15 @@ where
     pub fn new(
         host: &str,
         port: i64,
+        max_send_message_size: usize,
+        max_recv_message_size: usize,
+        rate_limit_invoices: u64,
+        keepalive: Keepalive,
+        concurrent_streams: usize,
+        stream_limiter: StreamLimiter,
         is_regtest: bool,
+        bind_both: bool,
+        node_info: NodeInfo,
         cancellation_token: CancellationToken,
         directory: PathBuf,
         invoice_helper: T,
@@ -43,10 +83,18 @@ where
     ) -> Self {
         Self {
             port,
+            max_send_message_size,
+            max_recv_message_size,
+            rate_limit_invoices,
+            keepalive,
+            concurrent_streams,
+            stream_limiter,
             settler,
             encoder,
             directory,
             is_regtest,
+            bind_both,
+            node_info,
             invoice_helper,
             cancellation_token,
             host: host.to_string(),
@@ -65,95 +113,203 @@ where
         }
 
         // Always listen to all interfaces on regtest
-        let socket_addr = SocketAddr::new(
-            IpAddr::from_str(if !self.is_regtest {
-                self.host.as_str()
-            } else {
-                "0.0.0.0"
-            })?,
-            self.port as u16,
-        );
-        info!("Starting gRPC server on: {}", socket_addr);
+        let host = match (self.is_regtest, self.bind_both) {
+            (false, _) => self.host.as_str(),
+            (true, false) => "0.0.0.0",
+            (true, true) => "::",
+        };
+        let socket_addrs =
+            Self::socket_addrs(IpAddr::from_str(host)?, self.port as u16, self.bind_both);
+
+        loop {
+            for socket_addr in &socket_addrs {
+                info!("Starting gRPC server on: {}", socket_addr);
+            }
+
+            // Cancelled when the certificates have to be renewed
+            let restart_token = self.cancellation_token.child_token();
+            let certificate_watcher = tokio::spawn(Self::watch_certificates(
+                self.directory.clone(),
+                restart_token.clone(),
+            ));
+
+            let res = match socket_addrs.as_slice() {
+                [first, second] => tokio::try_join!(
+                    self.serve(*first, restart_token.clone()),
+                    self.serve(*second, restart_token.clone())
+                )
+                .map(|_| ()),
+                _ => self.serve(socket_addrs[0], restart_token).await,
+            };
+            certificate_watcher.abort();
+            res?;
+
+            if self.cancellation_token.is_cancelled() {
+                return Ok(());
+            }
 
+            info!("Restarting gRPC server to renew certificates");
+        }
+    }
+
+    /// Unspecified addresses are bound only once as IPv6, because that listener accepts
+    /// IPv4 connections too on dual-stack hosts and a second one would conflict with it
+    fn socket_addrs(ip: IpAddr, port: u16, bind_both: bool) -> Vec<SocketAddr> {
+        let ips = match ip {
+            ip if !bind_both => vec![ip],
+            ip if ip.is_unspecified() => vec![IpAddr::V6(Ipv6Addr::UNSPECIFIED)],
+            ip if ip.is_loopback() => vec![
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ],
+            ip => {
+                warn!(
+                    "gRPC host {} has no IPv6 equivalent; only listening on it",
+                    ip
+                );
+                vec![ip]
+            }
+        };
+
+        ips.into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect()
+    }
+
+    async fn serve(&self, socket_addr: SocketAddr, restart_token: CancellationToken) -> Result<()> {
         let (identity, ca) = load_certificates(self.directory.clone())?;
-        let mut server = tonic::transport::Server::builder().tls_config(
+        let mut server = tonic::transport::Server::builder()
+            .http2_keepalive_interval(self.keepalive.http2_interval)
+            .http2_keepalive_timeout(self.keepalive.http2_timeout)
+            .tcp_keepalive(self.keepalive.tcp);
+        if self.concurrent_streams > 0 {
+            server = server.concurrency_limit_per_connection(self.concurrent_streams);
+        }
+        let server = server.tls_config(
             ServerTlsConfig::new()
                 .identity(identity)
                 .client_ca_root(ca)
                 .client_auth_optional(false),
         )?;
 
+        let reflection = tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+            .build_v1()?;
+
+        let mut hold_server = HoldServer::new(HoldService::new(
+            self.invoice_helper.clone(),
+            self.encoder.clone(),
+            self.settler.clone(),
+            self.rate_limit_invoices,
+            self.stream_limiter.clone(),
+            self.node_info.clone(),
+        ));
+        if self.max_send_message_size > 0 {
+            hold_server = hold_server.max_encoding_message_size(self.max_send_message_size);
+        }
+        if self.max_recv_message_size > 0 {
+            hold_server = hold_server.max_decoding_message_size(self.max_recv_message_size);
+        }
+
         Ok(server
-            .add_service(HoldServer::new(HoldService::new(
+            .layer(TracingInterceptor::new())
+            .add_service(reflection)
+            .add_service(HealthServer::new(HealthService::new(
                 self.invoice_helper.clone(),
-                self.encoder.clone(),
-                self.settler.clone(),
             )))
+            .add_service(hold_server)
             .serve_with_shutdown(socket_addr, async move {
-                self.cancellation_token.cancelled().await;
+                restart_token.cancelled().await;
                 info!("Shutting down gRPC server");
             })
             .await?)
     }
+
+    async fn watch_certificates(directory: PathBuf, restart_token: CancellationToken) {
+        let mut interval = tokio::time::interval(CERTIFICATE_CHECK_INTERVAL);
+        // The certificates were just checked when they were loaded
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = restart_token.cancelled() => {
+                    return;
+                }
+            };
+
+            debug!("Checking expiry of gRPC certificates");
+            let mut needs_renewal = false;
+
+            for name in CERTIFICATE_NAMES {
+                let cert_path = directory.join(format!("{}.pem", name));
+
+                if let Ok(not_after) = certificate_expiry(&cert_path) {
+                    let remaining = not_after - time::OffsetDateTime::now_utc();
+                    if remaining < time::Duration::days(CERTIFICATE_EXPIRY_WARNING_DAYS) {
+                        warn!(
+                            "gRPC certificate {:?} expires in {} days",
+                            cert_path,
+                            remaining.whole_days()
+                        );
+                    }
+                }
+
+                needs_renewal |= certificate_needs_renewal(&cert_path);
+            }
+
+            if needs_renewal {
+                restart_token.cancel();
+                return;
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::database::helpers::invoice_helper::InvoiceHelper;
+    use crate::database::helpers::invoice_helper::InvoiceHelperError;
     use crate::database::model::*;
+    use crate::database::test_utils::MockInvoiceHelper;
     use crate::encoder::{InvoiceBuilder, InvoiceEncoder};
+    use crate::grpc::health::proto::health_check_response::ServingStatus;
+    use crate::grpc::health::proto::health_client::HealthClient;
+    use crate::grpc::health::proto::HealthCheckRequest;
+    use crate::grpc::interceptor::REQUEST_ID_HEADER;
     use crate::grpc::server::Server;
+    use crate::grpc::service::hold;
     use crate::grpc::service::hold::hold_client::HoldClient;
-    use crate::grpc::service::hold::GetInfoRequest;
-    use crate::settler::Settler;
+    use crate::grpc::service::hold::list_request::Constraint;
+    use crate::grpc::service::hold::settle_event::EventType;
+    use crate::grpc::service::hold::{
+        list_request, GetInfoRequest, InvoiceRequest, ListRequest, SettleAndCreateRequest,
+        SettleRequest, TrackAllRequest, TrackRequest,
+    };
+    use crate::grpc::service::NodeInfo;
+    use crate::grpc::stream_limiter::StreamLimiter;
+    use crate::hooks::HtlcCallbackResponse;
+    use crate::settler::{HtlcResolved, Settler};
     use anyhow::Result;
+    use bitcoin::hashes::{sha256, Hash};
+    use lightning_invoice::{Currency, PaymentSecret};
     use mockall::mock;
+    use secp256k1::{Secp256k1, SecretKey};
     use std::fs;
+    use std::net::IpAddr;
     use std::path::{Path, PathBuf};
-    use std::time::Duration;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
     use tokio::task::JoinHandle;
     use tokio_util::sync::CancellationToken;
     use tonic::async_trait;
+    use tonic::codegen::tokio_stream;
     use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
-
-    mock! {
-        InvoiceHelper {}
-
-        impl Clone for InvoiceHelper {
-            fn clone(&self) -> Self;
-        }
-
-        impl InvoiceHelper for InvoiceHelper {
-            fn insert(&self, invoice: &InvoiceInsertable) -> Result<usize>;
-            fn insert_htlc(&self, htlc: &HtlcInsertable) -> Result<usize>;
-
-            fn set_invoice_state(
-                &self,
-                id: i64,
-                state: InvoiceState,
-                new_state: InvoiceState,
-            ) -> Result<usize>;
-            fn set_invoice_preimage(&self, id: i64, preimage: &[u8]) -> Result<usize>;
-            fn set_htlc_state_by_id(
-                &self,
-                htlc_id: i64,
-                state: InvoiceState,
-                new_state: InvoiceState,
-            ) -> Result<usize>;
-            fn set_htlc_states_by_invoice(
-                &self,
-                invoice_id: i64,
-                state: InvoiceState,
-                new_state: InvoiceState,
-            ) -> Result<usize>;
-
-            fn clean_cancelled(&self, age: Option<u64>) -> Result<usize>;
-
-            fn get_all(&self) -> Result<Vec<HoldInvoice>>;
-            fn get_paginated(&self, index_start: i64, limit: u64) -> Result<Vec<HoldInvoice>>;
-            fn get_by_payment_hash(&self, payment_hash: &[u8]) -> Result<Option<HoldInvoice>>;
-        }
-    }
+    use tonic::{Code, Request};
+    use tonic_reflection::pb::v1::server_reflection_client::ServerReflectionClient;
+    use tonic_reflection::pb::v1::server_reflection_request::MessageRequest;
+    use tonic_reflection::pb::v1::server_reflection_response::MessageResponse;
+    use tonic_reflection::pb::v1::ServerReflectionRequest;
+    use uuid::Uuid;
 
     mock! {
         InvoiceEncoder {}
@@ -173,31 +329,556 @@ mod test {
         let port = 9124;
         let (certs_dir, token, server_thread) = start_server_tls(port).await;
 
-        let tls = ClientTlsConfig::new()
-            .domain_name("hold")
-            .ca_certificate(Certificate::from_pem(
-                fs::read_to_string(certs_dir.clone().join("ca.pem")).unwrap(),
-            ))
-            .identity(Identity::from_pem(
-                fs::read_to_string(certs_dir.clone().join("client.pem")).unwrap(),
-                fs::read_to_string(certs_dir.clone().join("client-key.pem")).unwrap(),
-            ));
+        let mut client = HoldClient::new(connect_tls(&certs_dir, port).await);
 
-        let channel = Channel::from_shared(format!("https://127.0.0.1:{}", port))
-            .unwrap()
-            .tls_config(tls)
+        let res = client
+            .get_info(GetInfoRequest {})
+            .await
             .unwrap()
-            .connect()
+            .into_inner();
+        assert_eq!(res.version, crate::utils::built_info::PKG_VERSION);
+        assert_eq!(res.pending_htlcs, 2);
+        assert_eq!(res.accepted_invoices, 1);
+        assert_eq!(res.node_id, vec![0; 33]);
+        assert_eq!(res.network, "regtest");
+        assert!(res.uptime_seconds < 60);
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn list_pagination_limit() {
+        let port = 9138;
+        let (certs_dir, token, server_thread) = start_server_tls(port).await;
+
+        let mut client = HoldClient::new(connect_tls(&certs_dir, port).await);
+
+        for constraint in [0, 1_001, u64::MAX].into_iter().flat_map(|limit| {
+            [
+                Constraint::Pagination(list_request::Pagination {
+                    index_start: 0,
+                    after_id: None,
+                    limit,
+                    state: None,
+                }),
+                Constraint::TimePagination(list_request::TimePagination {
+                    after_timestamp: None,
+                    after_id: None,
+                    limit,
+                }),
+            ]
+        }) {
+            let err = client
+                .list(ListRequest {
+                    constraint: Some(constraint),
+                    ..Default::default()
+                })
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.code(), Code::InvalidArgument);
+            assert_eq!(err.message(), "limit has to be between 1 and 1000");
+        }
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn list_pagination_index_start() {
+        let port = 9139;
+        let (certs_dir, token, server_thread) = start_server_tls(port).await;
+
+        let client = HoldClient::new(connect_tls(&certs_dir, port).await);
+        let list = |index_start: i64, after_id: Option<i64>| {
+            let mut client = client.clone();
+            async move {
+                client
+                    .list(ListRequest {
+                        constraint: Some(Constraint::Pagination(list_request::Pagination {
+                            index_start,
+                            after_id,
+                            limit: 10,
+                            state: None,
+                        })),
+                        ..Default::default()
+                    })
+                    .await
+            }
+        };
+
+        let first_id = |res: Result<tonic::Response<hold::ListResponse>, tonic::Status>| {
+            res.unwrap().into_inner().invoices[0].id
+        };
+
+        // Inclusive, unlike after_id
+        assert_eq!(first_id(list(5, None).await), 5);
+        assert_eq!(first_id(list(0, Some(5)).await), 6);
+        assert_eq!(first_id(list(0, None).await), 0);
+
+        let err = list(5, Some(5)).await.err().unwrap();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        assert_eq!(
+            err.message(),
+            "index_start cannot be combined with after_id"
+        );
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn list_history_invalid_state() {
+        let port = 9140;
+        let (certs_dir, token, server_thread) = start_server_tls(port).await;
+
+        let mut client = HoldClient::new(connect_tls(&certs_dir, port).await);
+        let err = client
+            .list(ListRequest {
+                constraint: Some(Constraint::Pagination(list_request::Pagination {
+                    index_start: 0,
+                    after_id: Some(12),
+                    limit: 10,
+                    state: None,
+                })),
+                history: true,
+                ..Default::default()
+            })
             .await
+            .err()
             .unwrap();
+        assert_eq!(err.code(), Code::Internal);
+        assert_eq!(
+            err.message(),
+            "invalid invoice state log: invalid invoice state invariant: invalid"
+        );
 
-        let mut client = HoldClient::new(channel);
+        token.cancel();
+        server_thread.await.unwrap();
 
-        let res = client.get_info(GetInfoRequest {}).await.unwrap();
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn max_message_size() {
+        let port = 9129;
+        let (certs_dir, token, server_thread) = start_server_tls_with_limits(port, 8, 0).await;
+
+        let mut client = HoldClient::new(connect_tls(&certs_dir, port).await);
+        let err = client.get_info(GetInfoRequest {}).await.err().unwrap();
+        assert_eq!(err.code(), Code::OutOfRange);
+
+        token.cancel();
+        server_thread.await.unwrap();
+        fs::remove_dir_all(certs_dir).unwrap();
+
+        let port = 9130;
+        let (certs_dir, token, server_thread) = start_server_tls(port).await;
+
+        // The limit of the client applies to the responses it receives
+        let mut client =
+            HoldClient::new(connect_tls(&certs_dir, port).await).max_decoding_message_size(8);
+        let err = client.get_info(GetInfoRequest {}).await.err().unwrap();
+        assert_eq!(err.code(), Code::OutOfRange);
+
+        let mut client = HoldClient::new(connect_tls(&certs_dir, port).await)
+            .max_decoding_message_size(1024 * 1024);
+        assert!(client.get_info(GetInfoRequest {}).await.is_ok());
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn track_resume() {
+        let port = 9131;
+        let (certs_dir, token, server_thread) = start_server_tls(port).await;
+
+        let mut client = HoldClient::new(connect_tls(&certs_dir, port).await);
+
+        // The invoice was paid while the client was disconnected
+        let mut stream = client
+            .track(TrackRequest {
+                payment_hash: vec![2],
+                resume_from_state: Some(hold::InvoiceState::Accepted as i32),
+            })
+            .await
+            .unwrap()
+            .into_inner();
         assert_eq!(
-            res.into_inner().version,
-            crate::utils::built_info::PKG_VERSION
+            stream.message().await.unwrap().unwrap().state,
+            hold::InvoiceState::Paid as i32
         );
+        assert!(stream.message().await.unwrap().is_none());
+
+        // Nothing changed while the client was disconnected
+        let mut stream = client
+            .track(TrackRequest {
+                payment_hash: vec![2],
+                resume_from_state: Some(hold::InvoiceState::Paid as i32),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(stream.message().await.unwrap().is_none());
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn track_resume_replay() {
+        let port = 9136;
+        let (certs_dir, token, server_thread) = start_server_tls(port).await;
+
+        let mut client = HoldClient::new(connect_tls(&certs_dir, port).await);
+
+        for (resume_from_state, expected) in [
+            (
+                hold::InvoiceState::Unpaid,
+                vec![hold::InvoiceState::Accepted, hold::InvoiceState::Paid],
+            ),
+            (hold::InvoiceState::Accepted, vec![hold::InvoiceState::Paid]),
+            (hold::InvoiceState::Paid, vec![]),
+            // Never seen by the client, so the whole history is replayed
+            (
+                hold::InvoiceState::Cancelled,
+                vec![
+                    hold::InvoiceState::Unpaid,
+                    hold::InvoiceState::Accepted,
+                    hold::InvoiceState::Paid,
+                ],
+            ),
+        ] {
+            let mut stream = client
+                .track(TrackRequest {
+                    payment_hash: vec![3],
+                    resume_from_state: Some(resume_from_state as i32),
+                })
+                .await
+                .unwrap()
+                .into_inner();
+
+            for state in expected {
+                assert_eq!(stream.message().await.unwrap().unwrap().state, state as i32);
+            }
+            assert!(stream.message().await.unwrap().is_none());
+        }
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn settle_stream() {
+        let port = 9132;
+        let mut settler = Settler::new(make_mock_invoice_helper(), 60, 15, 1_000);
+        let (certs_dir, token, server_thread) =
+            start_server_tls_with_settler(port, 0, 0, settler.clone()).await;
+
+        let preimage = vec![1; 32];
+        let payment_hash: sha256::Hash = Hash::hash(&preimage);
+        let payment_hash = payment_hash[..].to_vec();
+
+        let mut resolvers = Vec::new();
+        for channel_id in [0, 1] {
+            resolvers.push(
+                settler
+                    .add_htlc(&payment_hash, "scid".to_string(), channel_id, 1_000)
+                    .await,
+            );
+        }
+
+        let mut client = HoldClient::new(connect_tls(&certs_dir, port).await);
+        let mut stream = client
+            .settle_stream(SettleRequest {
+                payment_preimage: preimage,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+
+        // What the htlc_accepted hook does after handing the resolutions back to CLN
+        for (channel_id, resolver) in resolvers.iter_mut().enumerate() {
+            resolver.recv().await.unwrap();
+            for _ in 0..2 {
+                settler.htlc_resolved(HtlcResolved {
+                    payment_hash: payment_hash.clone(),
+                    scid: "scid".to_string(),
+                    channel_id: channel_id as u64,
+                    msat: 1_000,
+                });
+            }
+        }
+
+        for resolved_count in [1, 2] {
+            let event = stream.message().await.unwrap().unwrap();
+            assert_eq!(event.event_type, EventType::HtlcResolved as i32);
+            assert_eq!(event.resolved_count, resolved_count);
+            assert_eq!(event.total_count, 2);
+            assert_eq!(event.channel_id, Some(resolved_count - 1));
+        }
+
+        let event = stream.message().await.unwrap().unwrap();
+        assert_eq!(event.event_type, EventType::AllResolved as i32);
+        assert_eq!(event.resolved_count, 2);
+        assert!(stream.message().await.unwrap().is_none());
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn track_all_filter_states() {
+        let port = 9133;
+        let mut settler = Settler::new(make_mock_invoice_helper(), 60, 15, 1_000);
+        let (certs_dir, token, server_thread) =
+            start_server_tls_with_settler(port, 0, 0, settler.clone()).await;
+
+        let preimage = vec![1; 32];
+        let payment_hash: sha256::Hash = Hash::hash(&preimage);
+        let payment_hash = payment_hash[..].to_vec();
+
+        let mut client = HoldClient::new(connect_tls(&certs_dir, port).await);
+        let mut stream = client
+            .track_all(TrackAllRequest {
+                payment_hashes: vec![payment_hash.clone()],
+                filter_states: vec![hold::InvoiceState::Paid as i32],
+                include_initial_state: Some(false),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+
+        settler
+            .set_accepted(
+                &Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 1,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: payment_hash.clone(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Unpaid.to_string(),
+                },
+                1_000,
+            )
+            .await
+            .unwrap();
+        settler
+            .add_htlc(&payment_hash, "scid".to_string(), 0, 1_000)
+            .await;
+        settler.settle(&payment_hash, &preimage).await.unwrap();
+
+        let update = stream.message().await.unwrap().unwrap();
+        assert_eq!(update.state, hold::InvoiceState::Paid as i32);
+        assert_eq!(update.payment_hash, payment_hash);
+
+        drop(stream);
+        drop(client);
+
+        // The stream notices the closed connection with its next update
+        settler
+            .add_htlc(&payment_hash, "scid".to_string(), 1, 1_000)
+            .await;
+        settler.settle(&payment_hash, &preimage).await.unwrap();
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn settle_and_create() {
+        let port = 9135;
+
+        let preimage = vec![1; 32];
+        let payment_hash: sha256::Hash = Hash::hash(&preimage);
+        let payment_hash = payment_hash[..].to_vec();
+
+        for fail_insert in [true, false] {
+            let mut settler =
+                Settler::new(make_settle_and_create_helper(fail_insert), 60, 15, 1_000);
+            let (certs_dir, token, server_thread) = start_server_tls_with_stream_limiter(
+                port,
+                0,
+                0,
+                settler.clone(),
+                StreamLimiter::new(0),
+                make_settle_and_create_encoder(),
+                false,
+            )
+            .await;
+
+            let mut resolver = settler
+                .add_htlc(&payment_hash, "scid".to_string(), 0, 1_000)
+                .await;
+
+            let mut client = HoldClient::new(connect_tls(&certs_dir, port).await);
+            assert_eq!(
+                client
+                    .settle_and_create(SettleAndCreateRequest {
+                        payment_preimage: preimage.clone(),
+                        invoice: None,
+                    })
+                    .await
+                    .err()
+                    .unwrap()
+                    .code(),
+                Code::InvalidArgument
+            );
+
+            let res = client
+                .settle_and_create(SettleAndCreateRequest {
+                    payment_preimage: preimage.clone(),
+                    invoice: Some(InvoiceRequest {
+                        payment_hash: vec![2; 32],
+                        amount_msat: 1_000,
+                        ..Default::default()
+                    }),
+                })
+                .await;
+
+            if fail_insert {
+                assert_eq!(res.err().unwrap().code(), Code::AlreadyExists);
+                // The HTLCs of the invoice stay held
+                assert!(resolver.try_recv().is_err());
+                assert_eq!(settler.get_hold_summary().await.invoice_count, 1);
+            } else {
+                let invoice = res.unwrap().into_inner().invoice.unwrap();
+                assert_eq!(invoice.bolt11, settle_and_create_invoice());
+                assert_eq!(invoice.payment_hash, vec![2; 32]);
+                assert_eq!(invoice.amount_msat, Some(1_000));
+
+                assert_eq!(
+                    resolver.recv().await.unwrap(),
+                    HtlcCallbackResponse::Resolve {
+                        payment_key: hex::encode(&preimage),
+                    }
+                );
+                assert_eq!(settler.get_hold_summary().await.invoice_count, 0);
+            }
+
+            token.cancel();
+            server_thread.await.unwrap();
+
+            fs::remove_dir_all(certs_dir).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn request_id() {
+        let port = 9128;
+        let (certs_dir, token, server_thread) = start_server_tls(port).await;
+
+        let mut client = HoldClient::new(connect_tls(&certs_dir, port).await);
+
+        let res = client.get_info(GetInfoRequest {}).await.unwrap();
+        let generated = res
+            .metadata()
+            .get(REQUEST_ID_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert_eq!(Uuid::parse_str(generated).unwrap().get_version_num(), 4);
+
+        let mut req = Request::new(GetInfoRequest {});
+        req.metadata_mut()
+            .insert(REQUEST_ID_HEADER, "some-id".parse().unwrap());
+        let res = client.get_info(req).await.unwrap();
+        assert_eq!(res.metadata().get(REQUEST_ID_HEADER).unwrap(), "some-id");
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn health_check() {
+        let port = 9126;
+        let (certs_dir, token, server_thread) = start_server_tls(port).await;
+
+        let mut client = HealthClient::new(connect_tls(&certs_dir, port).await);
+
+        let res = client
+            .check(HealthCheckRequest {
+                service: "".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.into_inner().status, ServingStatus::Serving as i32);
+
+        let res = client
+            .check(HealthCheckRequest {
+                service: "hold.Hold".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.into_inner().status, ServingStatus::Serving as i32);
+
+        let res = client
+            .check(HealthCheckRequest {
+                service: "not.Found".to_string(),
+            })
+            .await;
+        assert_eq!(res.err().unwrap().code(), tonic::Code::NotFound);
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reflection() {
+        let port = 9127;
+        let (certs_dir, token, server_thread) = start_server_tls(port).await;
+
+        let mut client = ServerReflectionClient::new(connect_tls(&certs_dir, port).await);
+
+        let mut res = client
+            .server_reflection_info(tokio_stream::iter(vec![ServerReflectionRequest {
+                host: "".to_string(),
+                message_request: Some(MessageRequest::ListServices("".to_string())),
+            }]))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let services = match res.message().await.unwrap().unwrap().message_response {
+            Some(MessageResponse::ListServicesResponse(res)) => res
+                .service
+                .into_iter()
+                .map(|service| service.name)
+                .collect::<Vec<String>>(),
+            _ => panic!("unexpected reflection response"),
+        };
+        assert!(services.contains(&"hold.Hold".to_string()));
+        assert!(services.contains(&"grpc.health.v1.Health".to_string()));
 
         token.cancel();
         server_thread.await.unwrap();
@@ -235,7 +916,229 @@ mod test {
         fs::remove_dir_all(certs_dir).unwrap()
     }
 
+    async fn connect_tls(certs_dir: &Path, port: i64) -> Channel {
+        connect_tls_host(certs_dir, "127.0.0.1", port).await
+    }
+
+    async fn connect_tls_host(certs_dir: &Path, host: &str, port: i64) -> Channel {
+        let tls = ClientTlsConfig::new()
+            .domain_name("hold")
+            .ca_certificate(Certificate::from_pem(
+                fs::read_to_string(certs_dir.join("ca.pem")).unwrap(),
+            ))
+            .identity(Identity::from_pem(
+                fs::read_to_string(certs_dir.join("client.pem")).unwrap(),
+                fs::read_to_string(certs_dir.join("client-key.pem")).unwrap(),
+            ));
+
+        Channel::from_shared(format!("https://{}:{}", host, port))
+            .unwrap()
+            .tls_config(tls)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn socket_addrs() {
+        let addrs = |host: &str, bind_both: bool| {
+            Server::<MockInvoiceHelper, MockInvoiceEncoder>::socket_addrs(
+                IpAddr::from_str(host).unwrap(),
+                9292,
+                bind_both,
+            )
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(addrs("127.0.0.1", false), vec!["127.0.0.1:9292"]);
+        assert_eq!(addrs("0.0.0.0", false), vec!["0.0.0.0:9292"]);
+        assert_eq!(
+            addrs("127.0.0.1", true),
+            vec!["127.0.0.1:9292", "[::1]:9292"]
+        );
+        assert_eq!(addrs("::1", true), vec!["127.0.0.1:9292", "[::1]:9292"]);
+        assert_eq!(addrs("0.0.0.0", true), vec!["[::]:9292"]);
+        assert_eq!(addrs("::", true), vec!["[::]:9292"]);
+        assert_eq!(addrs("10.0.0.1", true), vec!["10.0.0.1:9292"]);
+    }
+
+    #[tokio::test]
+    async fn bind_both() {
+        let port = 9137;
+        let (certs_dir, token, server_thread) = start_server_tls_with_stream_limiter(
+            port,
+            0,
+            0,
+            Settler::new(make_mock_invoice_helper(), 60, 15, 1_000),
+            StreamLimiter::new(0),
+            make_mock_invoice_encoder(),
+            true,
+        )
+        .await;
+
+        for host in ["127.0.0.1", "[::1]"] {
+            let mut client = HoldClient::new(connect_tls_host(&certs_dir, host, port).await);
+            let res = client
+                .get_info(GetInfoRequest {})
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(res.version, crate::utils::built_info::PKG_VERSION);
+        }
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn global_max_streams() {
+        let port = 9134;
+        let mut settler = Settler::new(make_mock_invoice_helper(), 60, 15, 1_000);
+        let stream_limiter = StreamLimiter::new(2);
+        let (certs_dir, token, server_thread) = start_server_tls_with_stream_limiter(
+            port,
+            0,
+            0,
+            settler.clone(),
+            stream_limiter.clone(),
+            make_mock_invoice_encoder(),
+            false,
+        )
+        .await;
+
+        let preimage = vec![1; 32];
+        let payment_hash: sha256::Hash = Hash::hash(&preimage);
+        let payment_hash = payment_hash[..].to_vec();
+
+        let mut client = HoldClient::new(connect_tls(&certs_dir, port).await);
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            streams.push(
+                client
+                    .track(TrackRequest {
+                        payment_hash: payment_hash.clone(),
+                        resume_from_state: None,
+                    })
+                    .await
+                    .unwrap()
+                    .into_inner(),
+            );
+        }
+        assert_eq!(stream_limiter.active(), 2);
+
+        let err = client
+            .track(TrackRequest {
+                payment_hash: payment_hash.clone(),
+                resume_from_state: None,
+            })
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), Code::ResourceExhausted);
+        assert_eq!(err.message(), "maximal number of streams reached");
+
+        let err = client
+            .track_all(TrackAllRequest {
+                payment_hashes: vec![],
+                filter_states: vec![],
+                include_initial_state: None,
+            })
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), Code::ResourceExhausted);
+        assert_eq!(stream_limiter.active(), 2);
+
+        // Ends the streams, because the invoice is in a final state
+        settler
+            .add_htlc(&payment_hash, "scid".to_string(), 0, 1_000)
+            .await;
+        settler.settle(&payment_hash, &preimage).await.unwrap();
+
+        for stream in streams.iter_mut() {
+            while stream.message().await.unwrap().is_some() {}
+        }
+        drop(streams);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(stream_limiter.active(), 0);
+
+        // Streams of invoices without updates are released when the client disconnects
+        let stream = client
+            .track_all(TrackAllRequest {
+                payment_hashes: vec![],
+                filter_states: vec![],
+                include_initial_state: None,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(stream_limiter.active(), 1);
+
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while stream_limiter.active() != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        token.cancel();
+        server_thread.await.unwrap();
+
+        fs::remove_dir_all(certs_dir).unwrap()
+    }
+
     async fn start_server_tls(port: i64) -> (PathBuf, CancellationToken, JoinHandle<()>) {
+        start_server_tls_with_limits(port, 0, 0).await
+    }
+
+    async fn start_server_tls_with_limits(
+        port: i64,
+        max_send_message_size: usize,
+        max_recv_message_size: usize,
+    ) -> (PathBuf, CancellationToken, JoinHandle<()>) {
+        start_server_tls_with_settler(
+            port,
+            max_send_message_size,
+            max_recv_message_size,
+            Settler::new(make_mock_invoice_helper(), 60, 15, 1_000),
+        )
+        .await
+    }
+
+    async fn start_server_tls_with_settler(
+        port: i64,
+        max_send_message_size: usize,
+        max_recv_message_size: usize,
+        settler: Settler<MockInvoiceHelper>,
+    ) -> (PathBuf, CancellationToken, JoinHandle<()>) {
+        start_server_tls_with_stream_limiter(
+            port,
+            max_send_message_size,
+            max_recv_message_size,
+            settler,
+            StreamLimiter::new(0),
+            make_mock_invoice_encoder(),
+            false,
+        )
+        .await
+    }
+
+    async fn start_server_tls_with_stream_limiter(
+        port: i64,
+        max_send_message_size: usize,
+        max_recv_message_size: usize,
+        settler: Settler<MockInvoiceHelper>,
+        stream_limiter: StreamLimiter,
+        invoice_encoder: MockInvoiceEncoder,
+        bind_both: bool,
+    ) -> (PathBuf, CancellationToken, JoinHandle<()>) {
         let certs_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("test-certs-{}", port));
 
         let token = CancellationToken::new();
@@ -243,12 +1146,24 @@ mod test {
         let server = Server::new(
             "127.0.0.1",
             port,
+            max_send_message_size,
+            max_recv_message_size,
+            0,
+            Default::default(),
+            0,
+            stream_limiter,
             false,
+            bind_both,
+            NodeInfo {
+                node_id: vec![0; 33],
+                network: "regtest".to_string(),
+                started_at: Instant::now(),
+            },
             token.clone(),
             certs_dir.clone(),
             make_mock_invoice_helper(),
-            make_mock_invoice_encoder(),
-            Settler::new(make_mock_invoice_helper(), 60),
+            invoice_encoder,
+            settler,
         );
 
         let server_thread = tokio::spawn(async move {
@@ -265,10 +1180,188 @@ mod test {
         hook_helper
             .expect_clone()
             .returning(make_mock_invoice_helper);
+        hook_helper.expect_ping().returning(|| Ok(()));
+        hook_helper
+            .expect_set_invoice_preimage()
+            .returning(|_, _| Ok(()));
+        hook_helper
+            .expect_get_pending_htlc_count()
+            .returning(|| Ok(2));
+        hook_helper
+            .expect_get_accepted_invoice_count()
+            .returning(|| Ok(1));
+        hook_helper
+            .expect_conditional_accept()
+            .returning(|_, _| Ok(true));
+        hook_helper
+            .expect_set_invoice_state()
+            .returning(|_, _, _| Ok(1));
+        hook_helper
+            .expect_set_htlc_states_by_invoice()
+            .returning(|_, _, _| Ok(1));
+        // Only the invoice with ID 3 has a logged state history;
+        // the one of the invoice with ID 13 has an invalid state
+        hook_helper.expect_get_state_log().returning(|ids| {
+            Ok(ids
+                .iter()
+                .filter(|id| **id == 3 || **id == 13)
+                .flat_map(|id| {
+                    [
+                        (InvoiceState::Unpaid.to_string(), InvoiceState::Accepted),
+                        (
+                            if *id == 13 {
+                                "invalid".to_string()
+                            } else {
+                                InvoiceState::Accepted.to_string()
+                            },
+                            InvoiceState::Paid,
+                        ),
+                    ]
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, (old_state, new_state))| InvoiceStateLog {
+                        id: index as i64,
+                        invoice_id: *id,
+                        old_state,
+                        new_state: new_state.to_string(),
+                        changed_at: Default::default(),
+                    })
+                })
+                .collect())
+        });
+        // Returns a single invoice that directly follows the cursor
+        hook_helper
+            .expect_get_paginated()
+            .returning(|after_id, _, _| {
+                Ok(vec![HoldInvoice {
+                    invoice: Invoice {
+                        cancel_reason: None,
+                        expires_at: None,
+                        label: None,
+                        id: after_id + 1,
+                        preimage: None,
+                        settled_at: None,
+                        mpp_timeout: None,
+                        settlement_deadline: None,
+                        accepted_at: None,
+                        payment_hash: vec![1],
+                        hash_algorithm: "sha256".to_string(),
+                        bolt11: "".to_string(),
+                        created_at: Default::default(),
+                        state: InvoiceState::Unpaid.to_string(),
+                    },
+                    htlcs: vec![],
+                }])
+            });
+        hook_helper.expect_get_by_payment_hash().returning(|hash| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: hash[0] as i64,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Paid.to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
 
         hook_helper
     }
 
+    fn make_settle_and_create_helper(fail_insert: bool) -> MockInvoiceHelper {
+        let mut helper = MockInvoiceHelper::new();
+        helper
+            .expect_clone()
+            .returning(move || make_settle_and_create_helper(fail_insert));
+        helper.expect_get_by_payment_hash().returning(|hash| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 1,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Accepted.to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        helper
+            .expect_settle_and_insert()
+            .returning(move |payment_hash, _, invoice| {
+                if fail_insert {
+                    return Err(InvoiceHelperError::DuplicatePaymentHash(hex::encode(
+                        &invoice.payment_hash,
+                    ))
+                    .into());
+                }
+
+                Ok(Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 1,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: payment_hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Paid.to_string(),
+                })
+            });
+
+        helper
+    }
+
+    fn settle_and_create_invoice() -> String {
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+
+        lightning_invoice::InvoiceBuilder::new(Currency::Regtest)
+            .description("".to_string())
+            .duration_since_epoch(Duration::from_secs(1_700_000_000))
+            .payment_hash(sha256::Hash::from_slice(&[2; 32]).unwrap())
+            .payment_secret(PaymentSecret([3; 32]))
+            .amount_milli_satoshis(1_000)
+            .min_final_cltv_expiry_delta(80)
+            .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &secret_key))
+            .unwrap()
+            .to_string()
+    }
+
+    fn make_settle_and_create_encoder() -> MockInvoiceEncoder {
+        let mut invoice_encoder = MockInvoiceEncoder::new();
+        invoice_encoder
+            .expect_clone()
+            .returning(make_settle_and_create_encoder);
+        invoice_encoder
+            .expect_encode()
+            .returning(|_| Ok(settle_and_create_invoice()));
+
+        invoice_encoder
+    }
+
     fn make_mock_invoice_encoder() -> MockInvoiceEncoder {
         let mut invoice_encoder = MockInvoiceEncoder::new();
         invoice_encoder
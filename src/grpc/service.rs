@@ -1,32 +1,65 @@
-use crate::database::helpers::invoice_helper::InvoiceHelper;
-use crate::database::model::{InvoiceInsertable, InvoiceState};
-use crate::encoder::{InvoiceBuilder, InvoiceDescription, InvoiceEncoder};
+use crate::database::helpers::invoice_helper::{InvoiceHelper, InvoiceHelperError};
+use crate::database::model::{
+    HashAlgorithm, HoldInvoice, InvoiceInsertable, InvoiceState, InvoiceStateLog,
+    InvoiceStateParsingError, StateTransitionError,
+};
+use crate::encoder::{InvoiceBuilder, InvoiceDescription, InvoiceDetails, InvoiceEncoder};
+use crate::grpc::rate_limiter::RateLimiter;
+use crate::grpc::service::hold::batch_cancel_response::Failure;
 use crate::grpc::service::hold::hold_server::Hold;
 use crate::grpc::service::hold::invoice_request::Description;
 use crate::grpc::service::hold::list_request::Constraint;
+use crate::grpc::service::hold::settle_event::EventType;
 use crate::grpc::service::hold::{
-    CancelRequest, CancelResponse, CleanRequest, CleanResponse, GetInfoRequest, GetInfoResponse,
-    InvoiceRequest, InvoiceResponse, ListRequest, ListResponse, SettleRequest, SettleResponse,
-    TrackAllRequest, TrackAllResponse, TrackRequest, TrackResponse,
+    BatchCancelRequest, BatchCancelResponse, CancelRequest, CancelResponse, CleanRequest,
+    CleanResponse, DeleteRequest, DeleteResponse, GetInfoRequest, GetInfoResponse,
+    GetInvoiceRequest, GetInvoiceResponse, GetStatisticsRequest, GetStatisticsResponse,
+    InvoiceRequest, InvoiceResponse, ListRequest, ListResponse, PauseRequest, PauseResponse,
+    ResumeRequest, ResumeResponse, SettleAndCreateRequest, SettleAndCreateResponse, SettleEvent,
+    SettleRequest, SettleResponse, TrackAllRequest, TrackAllResponse, TrackRequest, TrackResponse,
+};
+use crate::grpc::stream_limiter::{GuardedStream, StreamLimiter};
+use crate::grpc::transformers::{
+    parse_clean_states, parse_failure_reason, parse_invoice_state, parse_sort_field,
+    parse_time_range, transform_invoice_state, transform_route_hints,
 };
-use crate::grpc::transformers::{transform_invoice_state, transform_route_hints};
-use crate::settler::Settler;
+use crate::hooks::FailureMessage;
+use crate::settler::{SettleError, Settler};
 use bitcoin::hashes::{sha256, Hash};
+use chrono::DateTime;
+use lightning_invoice::ParseOrSemanticError;
 use log::{debug, error, warn};
+use std::collections::{HashMap, HashSet};
 use std::pin::Pin;
+use std::time::Instant;
 use tokio::sync::mpsc;
 use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
 use tonic::codegen::tokio_stream::Stream;
 use tonic::{async_trait, Code, Request, Response, Status};
 
+const BATCH_LOOKUP_THRESHOLD: usize = 5;
+// Largest page that can be requested via pagination
+const MAX_PAGE_SIZE: u64 = 1_000;
+
 pub mod hold {
     tonic::include_proto!("hold");
 }
 
+/// Static information about the node and plugin that is returned by GetInfo
+#[derive(Debug, Clone)]
+pub struct NodeInfo {
+    pub node_id: Vec<u8>,
+    pub network: String,
+    pub started_at: Instant,
+}
+
 pub struct HoldService<T, E> {
+    node_info: NodeInfo,
     encoder: E,
     invoice_helper: T,
     settler: Settler<T>,
+    invoice_rate_limiter: Option<RateLimiter>,
+    stream_limiter: StreamLimiter,
 }
 
 impl<T, E> HoldService<T, E>
@@ -34,37 +67,40 @@ where
     T: InvoiceHelper + Send + Sync + Clone + 'static,
     E: InvoiceEncoder + Send + Sync + Clone + 'static,
 {
-    pub fn new(invoice_helper: T, encoder: E, settler: Settler<T>) -> Self {
+    pub fn new(
+        invoice_helper: T,
+        encoder: E,
+        settler: Settler<T>,
+        rate_limit_invoices: u64,
+        stream_limiter: StreamLimiter,
+        node_info: NodeInfo,
+    ) -> Self {
         HoldService {
+            node_info,
             encoder,
             settler,
             invoice_helper,
+            stream_limiter,
+            invoice_rate_limiter: match rate_limit_invoices {
+                0 => None,
+                limit => Some(RateLimiter::per_minute(limit)),
+            },
         }
     }
-}
 
-#[async_trait]
-impl<T, E> Hold for HoldService<T, E>
-where
-    T: InvoiceHelper + Send + Sync + Clone + 'static,
-    E: InvoiceEncoder + Send + Sync + Clone + 'static,
-{
-    async fn get_info(
-        &self,
-        _: Request<GetInfoRequest>,
-    ) -> Result<Response<GetInfoResponse>, Status> {
-        Ok(Response::new(GetInfoResponse {
-            version: crate::utils::built_info::PKG_VERSION.to_string(),
-        }))
-    }
-
-    async fn invoice(
-        &self,
-        request: Request<InvoiceRequest>,
-    ) -> Result<Response<InvoiceResponse>, Status> {
-        let params = request.into_inner();
+    /// Validates the request and encodes the invoice without saving it
+    #[allow(clippy::result_large_err)]
+    async fn encode_invoice(&self, params: &InvoiceRequest) -> Result<InvoiceInsertable, Status> {
+        if let Some(limiter) = &self.invoice_rate_limiter {
+            if !limiter.try_acquire() {
+                return Err(Status::new(
+                    Code::ResourceExhausted,
+                    "invoice rate limit exceeded",
+                ));
+            }
+        }
 
-        let route_hints = match transform_route_hints(params.routing_hints) {
+        let route_hints = match transform_route_hints(params.routing_hints.clone()) {
             Ok(hints) => hints,
             Err(err) => {
                 return Err(Status::new(
@@ -78,7 +114,7 @@ where
             .amount_msat(params.amount_msat)
             .route_hints(route_hints);
 
-        if let Some(description) = params.description {
+        if let Some(description) = params.description.clone() {
             builder = builder.description(match description {
                 Description::Memo(memo) => InvoiceDescription::Description(memo),
                 Description::Hash(hash) => InvoiceDescription::Hash(hash),
@@ -93,6 +129,26 @@ where
             builder = builder.min_final_cltv_expiry_delta(delta);
         }
 
+        let mpp_timeout = match params.mpp_timeout.map(i64::try_from).transpose() {
+            Ok(timeout) => timeout,
+            Err(err) => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!("invalid MPP timeout: {}", err),
+                ))
+            }
+        };
+
+        let settlement_deadline = match params.settlement_deadline.map(i64::try_from).transpose() {
+            Ok(deadline) => deadline,
+            Err(err) => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!("invalid settlement deadline: {}", err),
+                ))
+            }
+        };
+
         let invoice = match self.encoder.encode(builder).await {
             Ok(invoice) => invoice,
             Err(err) => {
@@ -103,25 +159,174 @@ where
             }
         };
 
-        if let Err(err) = self.invoice_helper.insert(&InvoiceInsertable {
-            bolt11: invoice.clone(),
+        Ok(InvoiceInsertable {
+            bolt11: invoice,
             payment_hash: params.payment_hash.clone(),
+            hash_algorithm: HashAlgorithm::default().into(),
             state: InvoiceState::Unpaid.into(),
-        }) {
-            return Err(Status::new(
-                Code::Internal,
-                format!("could not save invoice: {}", err),
-            ));
-        }
+            mpp_timeout,
+            settlement_deadline,
+            label: params.label.clone(),
+        })
+    }
+
+    /// Announces an invoice that was saved and decodes it for the response
+    #[allow(clippy::result_large_err)]
+    fn invoice_created(
+        &self,
+        invoice: InvoiceInsertable,
+        amount_msat: u64,
+    ) -> Result<InvoiceResponse, Status> {
+        self.settler.new_invoice(
+            invoice.bolt11.clone(),
+            invoice.payment_hash,
+            amount_msat,
+            invoice.label,
+        );
+
+        let details = match InvoiceDetails::from_bolt11(&invoice.bolt11) {
+            Ok(details) => details,
+            Err(err) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("could not decode invoice: {}", err),
+                ))
+            }
+        };
+
+        Ok(InvoiceResponse {
+            bolt11: invoice.bolt11,
+            payment_hash: details.payment_hash,
+            amount_msat: details.amount_msat,
+            expiry: details.expiry,
+            min_final_cltv_expiry_delta: details.min_final_cltv_expiry_delta,
+            expires_at: details.expires_at.timestamp(),
+        })
+    }
+}
+
+// A page without invoices cannot have a cursor for the next one
+fn page_limit_status(limit: u64) -> Option<Status> {
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Some(Status::new(
+            Code::InvalidArgument,
+            format!("limit has to be between 1 and {}", MAX_PAGE_SIZE),
+        ));
+    }
+
+    None
+}
+
+fn stream_limit_status() -> Status {
+    Status::new(Code::ResourceExhausted, "maximal number of streams reached")
+}
+
+#[async_trait]
+impl<T, E> Hold for HoldService<T, E>
+where
+    T: InvoiceHelper + Send + Sync + Clone + 'static,
+    E: InvoiceEncoder + Send + Sync + Clone + 'static,
+{
+    async fn get_info(
+        &self,
+        _: Request<GetInfoRequest>,
+    ) -> Result<Response<GetInfoResponse>, Status> {
+        let pending_htlcs = match self.invoice_helper.get_pending_htlc_count() {
+            Ok(count) => count,
+            Err(err) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("could not count pending HTLCs: {}", err),
+                ))
+            }
+        };
+        let accepted_invoices = match self.invoice_helper.get_accepted_invoice_count() {
+            Ok(count) => count,
+            Err(err) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("could not count accepted invoices: {}", err),
+                ))
+            }
+        };
 
-        self.settler
-            .new_invoice(invoice.clone(), params.payment_hash, params.amount_msat);
+        let summary = self.settler.get_hold_summary().await;
 
-        Ok(Response::new(InvoiceResponse { bolt11: invoice }))
+        Ok(Response::new(GetInfoResponse {
+            version: crate::utils::built_info::PKG_VERSION.to_string(),
+            pending_htlcs,
+            accepted_invoices,
+            held_invoices: summary.invoice_count as u64,
+            msat_locked: summary.total_msat_locked,
+            oldest_hold_seconds: summary.oldest_hold_seconds,
+            node_id: self.node_info.node_id.clone(),
+            network: self.node_info.network.clone(),
+            uptime_seconds: self.node_info.started_at.elapsed().as_secs(),
+        }))
+    }
+
+    async fn invoice(
+        &self,
+        request: Request<InvoiceRequest>,
+    ) -> Result<Response<InvoiceResponse>, Status> {
+        let params = request.into_inner();
+        let invoice = self.encode_invoice(&params).await?;
+
+        if let Err(err) = self.invoice_helper.insert(&invoice) {
+            return Err(insert_error_status(err));
+        }
+
+        Ok(Response::new(
+            self.invoice_created(invoice, params.amount_msat)?,
+        ))
     }
 
     async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
         let params = request.into_inner();
+        let invalid_state = |state: i32| {
+            Status::new(
+                Code::InvalidArgument,
+                format!("invalid invoice state: {}", state),
+            )
+        };
+
+        let invalid_timestamp = |timestamp: i64| {
+            Status::new(
+                Code::InvalidArgument,
+                format!("invalid timestamp: {}", timestamp),
+            )
+        };
+
+        let invalid_time_range = |after: i64, before: i64| {
+            Status::new(
+                Code::InvalidArgument,
+                format!("invalid time range: {} - {}", after, before),
+            )
+        };
+
+        let sort_by = match params.sort_by {
+            Some(sort_by) => match parse_sort_field(sort_by) {
+                Some(sort_by) => Some(sort_by),
+                None => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        format!("invalid sort field: {}", sort_by),
+                    ))
+                }
+            },
+            None => None,
+        };
+        if sort_by.is_some() && params.constraint.is_some() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "sort_by cannot be combined with a constraint",
+            ));
+        }
+
+        let mut has_more = false;
+        let mut next_cursor = None;
+        let mut next_cursor_timestamp = None;
+
         let invoices = match params.constraint {
             Some(constraint) => match constraint {
                 Constraint::PaymentHash(payment_hash) => {
@@ -133,20 +338,209 @@ where
                         Err(err) => Err(err),
                     }
                 }
-                Constraint::Pagination(pagination) => self
+                Constraint::Label(label) => self
+                    .invoice_helper
+                    .get_by_label(&label)
+                    .map(|invoice| invoice.into_iter().collect()),
+                Constraint::Scid(scid) => self
                     .invoice_helper
-                    .get_paginated(pagination.index_start, pagination.limit),
+                    .get_htlcs_by_scid(&scid)
+                    .map(HoldInvoice::from_htlc_pairs),
+                Constraint::Preimage(preimage) => self
+                    .invoice_helper
+                    .get_by_preimage(&preimage)
+                    .map(|invoice| invoice.into_iter().collect()),
+                Constraint::Pagination(pagination) => {
+                    if let Some(status) = page_limit_status(pagination.limit) {
+                        return Err(status);
+                    }
+
+                    // index_start is the deprecated and inclusive version of after_id
+                    let after_id = match (pagination.after_id, pagination.index_start) {
+                        (Some(after_id), 0) => after_id,
+                        (None, index_start) => index_start.saturating_sub(1),
+                        (Some(_), _) => {
+                            return Err(Status::new(
+                                Code::InvalidArgument,
+                                "index_start cannot be combined with after_id",
+                            ))
+                        }
+                    };
+
+                    let state = match pagination.state {
+                        Some(state) => match parse_invoice_state(state) {
+                            Some(state) => Some(state),
+                            None => return Err(invalid_state(state)),
+                        },
+                        None => None,
+                    };
+
+                    // One more than requested to know whether there is another page
+                    self.invoice_helper
+                        .get_paginated(after_id, pagination.limit.saturating_add(1), state)
+                        .map(|mut invoices| {
+                            if invoices.len() as u64 > pagination.limit {
+                                invoices.truncate(pagination.limit as usize);
+                                has_more = true;
+                                next_cursor = invoices.last().map(|invoice| invoice.invoice.id);
+                            }
+
+                            invoices
+                        })
+                }
+                Constraint::TimePagination(pagination) => {
+                    if let Some(status) = page_limit_status(pagination.limit) {
+                        return Err(status);
+                    }
+
+                    let after = match pagination.after_timestamp {
+                        Some(timestamp) => match DateTime::from_timestamp_micros(timestamp) {
+                            Some(created_at) => {
+                                Some((created_at.naive_utc(), pagination.after_id.unwrap_or(0)))
+                            }
+                            None => return Err(invalid_timestamp(timestamp)),
+                        },
+                        None => None,
+                    };
+
+                    // One more than requested to know whether there is another page
+                    self.invoice_helper
+                        .get_paginated_by_time(after, pagination.limit.saturating_add(1))
+                        .map(|mut invoices| {
+                            if invoices.len() as u64 > pagination.limit {
+                                invoices.truncate(pagination.limit as usize);
+                                has_more = true;
+                                if let Some(last) = invoices.last() {
+                                    next_cursor = Some(last.invoice.id);
+                                    next_cursor_timestamp =
+                                        Some(last.invoice.created_at.and_utc().timestamp_micros());
+                                }
+                            }
+
+                            invoices
+                        })
+                }
+                Constraint::State(state) => match parse_invoice_state(state) {
+                    Some(state) => self.invoice_helper.get_by_state(state),
+                    None => return Err(invalid_state(state)),
+                },
+                Constraint::CreatedRange(range) => {
+                    match parse_time_range(range.created_after, range.created_before) {
+                        Some((from, to)) => self.invoice_helper.get_created_in_range(from, to),
+                        None => {
+                            return Err(invalid_time_range(
+                                range.created_after,
+                                range.created_before,
+                            ))
+                        }
+                    }
+                }
+                Constraint::SettledRange(range) => {
+                    match parse_time_range(range.settled_after, range.settled_before) {
+                        Some((from, to)) => self.invoice_helper.get_settled_in_range(from, to),
+                        None => {
+                            return Err(invalid_time_range(
+                                range.settled_after,
+                                range.settled_before,
+                            ))
+                        }
+                    }
+                }
             },
-            None => self.invoice_helper.get_all(),
+            None => {
+                if let Some(sort_by) = sort_by {
+                    self.invoice_helper
+                        .get_all_sorted(sort_by, params.sort_desc)
+                } else {
+                    self.invoice_helper.get_all()
+                }
+            }
         };
 
-        match invoices {
-            Ok(invoices) => Ok(Response::new(ListResponse {
-                invoices: invoices.into_iter().map(|invoice| invoice.into()).collect(),
+        let invoices: Vec<HoldInvoice> = match invoices {
+            Ok(invoices) => invoices,
+            Err(err) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("could not fetch invoices: {}", err),
+                ))
+            }
+        };
+
+        let mut state_log: HashMap<i64, Vec<InvoiceStateLog>> = HashMap::new();
+        if params.history {
+            match self.invoice_helper.get_state_log(
+                &invoices
+                    .iter()
+                    .map(|invoice| invoice.invoice.id)
+                    .collect::<Vec<i64>>(),
+            ) {
+                Ok(entries) => {
+                    for entry in entries {
+                        state_log.entry(entry.invoice_id).or_default().push(entry);
+                    }
+                }
+                Err(err) => {
+                    return Err(Status::new(
+                        Code::Internal,
+                        format!("could not fetch invoice state log: {}", err),
+                    ))
+                }
+            }
+        }
+
+        let invoices = invoices
+            .into_iter()
+            .map(|invoice| {
+                let mut invoice: hold::Invoice = invoice.into();
+                invoice.state_log = state_log
+                    .remove(&invoice.id)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(hold::StateTransition::try_from)
+                    .collect::<Result<_, _>>()?;
+
+                Ok(invoice)
+            })
+            .collect::<Result<Vec<_>, InvoiceStateParsingError>>()
+            .map_err(|err| {
+                Status::new(
+                    Code::Internal,
+                    format!("invalid invoice state log: {}", err),
+                )
+            })?;
+
+        Ok(Response::new(ListResponse {
+            invoices,
+            has_more,
+            next_cursor,
+            next_cursor_timestamp,
+        }))
+    }
+
+    async fn get_invoice(
+        &self,
+        request: Request<GetInvoiceRequest>,
+    ) -> Result<Response<GetInvoiceResponse>, Status> {
+        let params = request.into_inner();
+
+        match self
+            .invoice_helper
+            .get_by_payment_hash(&params.payment_hash)
+        {
+            Ok(Some(invoice)) => Ok(Response::new(GetInvoiceResponse {
+                invoice: Some(invoice.into()),
             })),
+            Ok(None) => Err(Status::new(
+                Code::NotFound,
+                format!(
+                    "no invoice with payment hash {}",
+                    hex::encode(params.payment_hash)
+                ),
+            )),
             Err(err) => Err(Status::new(
                 Code::Internal,
-                format!("could not fetch invoices: {}", err),
+                format!("could not fetch invoice: {}", err),
             )),
         }
     }
@@ -164,27 +558,197 @@ where
             .settle(&payment_hash[..].to_vec(), preimage.as_ref())
             .await
         {
+            return Err(settle_error_status(err));
+        };
+
+        Ok(Response::new(SettleResponse {}))
+    }
+
+    async fn settle_and_create(
+        &self,
+        request: Request<SettleAndCreateRequest>,
+    ) -> Result<Response<SettleAndCreateResponse>, Status> {
+        let params = request.into_inner();
+        let invoice_params = match params.invoice {
+            Some(invoice) => invoice,
+            None => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    "invoice to create is missing",
+                ))
+            }
+        };
+
+        let payment_hash: sha256::Hash = Hash::hash(&params.payment_preimage);
+        if payment_hash[..] == invoice_params.payment_hash[..] {
             return Err(Status::new(
-                Code::Internal,
-                format!("could not settle invoice: {}", err),
+                Code::InvalidArgument,
+                "invoice to create has the payment hash of the settled one",
             ));
+        }
+
+        let invoice = self.encode_invoice(&invoice_params).await?;
+
+        if let Err(err) = self
+            .settler
+            .clone()
+            .settle_and_insert(
+                &payment_hash[..].to_vec(),
+                &params.payment_preimage,
+                &invoice,
+            )
+            .await
+        {
+            return Err(match err.downcast_ref::<InvoiceHelperError>() {
+                Some(InvoiceHelperError::InvalidSettleState(_)) => {
+                    Status::new(Code::FailedPrecondition, err.to_string())
+                }
+                Some(_) => insert_error_status(err),
+                None => settle_error_status(err),
+            });
+        }
+
+        Ok(Response::new(SettleAndCreateResponse {
+            invoice: Some(self.invoice_created(invoice, invoice_params.amount_msat)?),
+        }))
+    }
+
+    type SettleStreamStream = Pin<Box<dyn Stream<Item = Result<SettleEvent, Status>> + Send>>;
+
+    async fn settle_stream(
+        &self,
+        request: Request<SettleRequest>,
+    ) -> Result<Response<Self::SettleStreamStream>, Status> {
+        let preimage = request.into_inner().payment_preimage;
+        let payment_hash: sha256::Hash = Hash::hash(&preimage);
+        let payment_hash = payment_hash[..].to_vec();
+
+        // Subscribe before settling, so that no resolution is missed
+        let mut htlc_rx = self.settler.htlc_resolved_rx();
+
+        let total_count = match self
+            .settler
+            .clone()
+            .settle(&payment_hash, preimage.as_ref())
+            .await
+        {
+            Ok(count) => count as u64,
+            Err(err) => return Err(settle_error_status(err)),
         };
 
-        Ok(Response::new(SettleResponse {}))
+        let (tx, rx) = mpsc::channel(16);
+
+        tokio::spawn(async move {
+            // HTLCs that were delivered more than once are resolved more than once
+            let mut resolved = HashSet::new();
+
+            while (resolved.len() as u64) < total_count {
+                let htlc = match htlc_rx.recv().await {
+                    Ok(htlc) => htlc,
+                    Err(err) => {
+                        error!("Waiting for HTLC resolutions failed: {}", err);
+                        return;
+                    }
+                };
+
+                if htlc.payment_hash != payment_hash
+                    || !resolved.insert((htlc.scid.clone(), htlc.channel_id))
+                {
+                    continue;
+                }
+
+                if let Err(err) = tx
+                    .send(Ok(SettleEvent {
+                        event_type: EventType::HtlcResolved as i32,
+                        resolved_count: resolved.len() as u64,
+                        total_count,
+                        scid: Some(htlc.scid),
+                        channel_id: Some(htlc.channel_id),
+                        msat: Some(htlc.msat),
+                    }))
+                    .await
+                {
+                    debug!("Could not send HTLC resolution: {}", err);
+                    return;
+                }
+            }
+
+            if let Err(err) = tx
+                .send(Ok(SettleEvent {
+                    event_type: EventType::AllResolved as i32,
+                    resolved_count: resolved.len() as u64,
+                    total_count,
+                    scid: None,
+                    channel_id: None,
+                    msat: None,
+                }))
+                .await
+            {
+                debug!("Could not send HTLC resolution: {}", err);
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
     }
 
     async fn cancel(
         &self,
         request: Request<CancelRequest>,
     ) -> Result<Response<CancelResponse>, Status> {
+        let params = request.into_inner();
+
+        let payment_hash = match params.invoice {
+            Some(invoice) => {
+                if !params.payment_hash.is_empty() {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        "either payment hash or invoice has to be set",
+                    ));
+                }
+
+                match self.invoice_helper.get_by_bolt11(&invoice) {
+                    Ok(Some(invoice)) => invoice.invoice.payment_hash,
+                    Ok(None) => {
+                        return Err(Status::new(Code::NotFound, "invoice not found"));
+                    }
+                    Err(err) => {
+                        return Err(Status::new(
+                            match err.downcast_ref::<ParseOrSemanticError>() {
+                                Some(_) => Code::InvalidArgument,
+                                None => Code::Internal,
+                            },
+                            format!("could not find invoice: {}", err),
+                        ));
+                    }
+                }
+            }
+            None => params.payment_hash,
+        };
+
+        let failure = match params.failure_reason {
+            Some(reason) => match parse_failure_reason(reason) {
+                Some(failure) => failure,
+                None => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        format!("invalid failure reason: {}", reason),
+                    ))
+                }
+            },
+            None => FailureMessage::IncorrectPaymentDetails,
+        };
+
         if let Err(err) = self
             .settler
             .clone()
-            .cancel(&request.into_inner().payment_hash)
+            .cancel(&payment_hash, failure, params.reason.as_deref())
             .await
         {
             return Err(Status::new(
-                Code::Internal,
+                match err.downcast_ref::<InvoiceHelperError>() {
+                    Some(InvoiceHelperError::CancelReasonTooLong(_)) => Code::InvalidArgument,
+                    _ => Code::Internal,
+                },
                 format!("could not cancel invoice: {}", err),
             ));
         };
@@ -192,18 +756,127 @@ where
         Ok(Response::new(CancelResponse {}))
     }
 
+    async fn pause(
+        &self,
+        request: Request<PauseRequest>,
+    ) -> Result<Response<PauseResponse>, Status> {
+        match self.settler.pause(&request.into_inner().payment_hash) {
+            Ok(_) => Ok(Response::new(PauseResponse {})),
+            Err(err) => Err(pause_error_status("could not pause invoice", err)),
+        }
+    }
+
+    async fn resume(
+        &self,
+        request: Request<ResumeRequest>,
+    ) -> Result<Response<ResumeResponse>, Status> {
+        match self.settler.resume(&request.into_inner().payment_hash) {
+            Ok(_) => Ok(Response::new(ResumeResponse {})),
+            Err(err) => Err(pause_error_status("could not resume invoice", err)),
+        }
+    }
+
+    async fn batch_cancel(
+        &self,
+        request: Request<BatchCancelRequest>,
+    ) -> Result<Response<BatchCancelResponse>, Status> {
+        let mut response = BatchCancelResponse {
+            cancelled: Vec::new(),
+            failed: Vec::new(),
+        };
+
+        for (payment_hash, res) in self
+            .settler
+            .clone()
+            .cancel_batch(&request.into_inner().payment_hashes)
+            .await
+        {
+            match res {
+                Ok(_) => response.cancelled.push(payment_hash),
+                Err(err) => response.failed.push(Failure {
+                    payment_hash,
+                    error: err.to_string(),
+                }),
+            }
+        }
+
+        Ok(Response::new(response))
+    }
+
     async fn clean(
         &self,
         request: Request<CleanRequest>,
     ) -> Result<Response<CleanResponse>, Status> {
         let params = request.into_inner();
-        match self.invoice_helper.clean_cancelled(params.age) {
-            Ok(deleted) => Ok(Response::new(CleanResponse {
-                cleaned: deleted as u64,
+        let states = match params.state {
+            Some(state) => match parse_clean_states(state) {
+                Some(states) => states.into_iter().map(Some).collect(),
+                None => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        format!("invalid clean state: {}", state),
+                    ))
+                }
+            },
+            None => vec![None],
+        };
+
+        let mut cleaned = 0;
+        for state in states {
+            match self.invoice_helper.clean(state, params.age, params.dry_run) {
+                Ok(deleted) => cleaned += deleted as u64,
+                Err(err) => {
+                    return Err(Status::new(
+                        match err.downcast_ref::<InvoiceHelperError>() {
+                            Some(InvoiceHelperError::InvalidCleanState(_)) => Code::InvalidArgument,
+                            _ => Code::Internal,
+                        },
+                        format!("could not clean invoices: {}", err),
+                    ))
+                }
+            }
+        }
+
+        Ok(Response::new(CleanResponse { cleaned }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let params = request.into_inner();
+
+        match self
+            .invoice_helper
+            .delete_by_payment_hash(&params.payment_hash)
+        {
+            Ok(deleted) => Ok(Response::new(DeleteResponse { deleted })),
+            Err(err) => Err(Status::new(
+                match err.downcast_ref::<InvoiceHelperError>() {
+                    Some(InvoiceHelperError::InvalidDeleteState(_)) => Code::FailedPrecondition,
+                    _ => Code::Internal,
+                },
+                format!("could not delete invoice: {}", err),
+            )),
+        }
+    }
+
+    async fn get_statistics(
+        &self,
+        _: Request<GetStatisticsRequest>,
+    ) -> Result<Response<GetStatisticsResponse>, Status> {
+        match self.invoice_helper.get_statistics() {
+            Ok(statistics) => Ok(Response::new(GetStatisticsResponse {
+                count_by_state: statistics.count_by_state,
+                total_settled_msat: statistics.total_settled_msat,
+                avg_settlement_seconds: statistics.avg_settlement_seconds,
+                pending_htlc_count: statistics.pending_htlc_count,
+                avg_hold_duration_seconds: statistics.avg_hold_duration_seconds,
+                oldest_unpaid_age_seconds: statistics.oldest_unpaid_age_seconds,
             })),
             Err(err) => Err(Status::new(
                 Code::Internal,
-                format!("could not clean invoices: {}", err),
+                format!("could not get statistics: {}", err),
             )),
         }
     }
@@ -214,44 +887,99 @@ where
         &self,
         request: Request<TrackRequest>,
     ) -> Result<Response<Self::TrackStream>, Status> {
+        let guard = match self.stream_limiter.try_acquire() {
+            Some(guard) => guard,
+            None => return Err(stream_limit_status()),
+        };
         let params = request.into_inner();
         let (tx, rx) = mpsc::channel(16);
 
         let mut state_rx = self.settler.state_rx();
 
-        match self
-            .invoice_helper
-            .get_by_payment_hash(&params.payment_hash)
-        {
-            Ok(res) => {
-                if let Some(res) = res {
-                    if let Ok(state) = InvoiceState::try_from(res.invoice.state.as_str()) {
-                        if let Err(err) = tx
-                            .send(Ok(TrackResponse {
-                                state: transform_invoice_state(state),
-                            }))
-                            .await
-                        {
-                            error!("Could not send invoice state update: {}", err);
-                            return Err(Status::new(
-                                Code::Internal,
-                                format!("could not send initial invoice state: {}", err),
-                            ));
-                        }
+        let initial_states = match params.resume_from_state {
+            // Replays the transitions the client missed while it was disconnected
+            Some(resume_from_state) => match self.settler.replay_state_for(&params.payment_hash) {
+                Ok(replay) => {
+                    let states = replay
+                        .into_iter()
+                        .map(|update| update.state)
+                        .collect::<Vec<_>>();
+
+                    // Everything when the client has never seen the state it resumes from
+                    match states
+                        .iter()
+                        .rposition(|state| transform_invoice_state(*state) == resume_from_state)
+                    {
+                        Some(index) => states[index..].to_vec(),
+                        None => states,
                     }
                 }
+                Err(err) => {
+                    return Err(Status::new(
+                        Code::Internal,
+                        format!("could not replay invoice states: {}", err),
+                    ));
+                }
+            },
+            None => match self
+                .invoice_helper
+                .get_by_payment_hash(&params.payment_hash)
+            {
+                Ok(res) => res
+                    .and_then(|res| InvoiceState::try_from(res.invoice.state.as_str()).ok())
+                    .into_iter()
+                    .collect(),
+                Err(err) => {
+                    return Err(Status::new(
+                        Code::Internal,
+                        format!("could not fetch invoice state from database: {}", err),
+                    ));
+                }
+            },
+        };
+
+        for state in &initial_states {
+            // The state the client resumes from was seen already
+            if params.resume_from_state == Some(transform_invoice_state(*state)) {
+                continue;
             }
-            Err(err) => {
+
+            if let Err(err) = tx
+                .send(Ok(TrackResponse {
+                    state: transform_invoice_state(*state),
+                }))
+                .await
+            {
+                error!("Could not send invoice state update: {}", err);
                 return Err(Status::new(
                     Code::Internal,
-                    format!("could not fetch invoice state from database: {}", err),
+                    format!("could not send initial invoice state: {}", err),
                 ));
             }
-        };
+        }
+
+        // A resumed stream of an invoice in a final state gets no more updates
+        if params.resume_from_state.is_some()
+            && initial_states.last().is_some_and(|state| state.is_final())
+        {
+            return Ok(Response::new(Box::pin(GuardedStream::new(
+                ReceiverStream::new(rx),
+                guard,
+            ))));
+        }
 
         tokio::spawn(async move {
+            // Counts as open stream until the forwarding stops
+            let _guard = guard;
+
             loop {
-                match state_rx.recv().await {
+                let update = tokio::select! {
+                    update = state_rx.recv() => update,
+                    // Stops waiting for updates of an invoice once the client is gone
+                    _ = tx.closed() => break,
+                };
+
+                match update {
                     Ok(update) => {
                         if !update.payment_hash.eq(&params.payment_hash) {
                             continue;
@@ -288,6 +1016,10 @@ where
         &self,
         request: Request<TrackAllRequest>,
     ) -> Result<Response<Self::TrackAllStream>, Status> {
+        let guard = match self.stream_limiter.try_acquire() {
+            Some(guard) => guard,
+            None => return Err(stream_limit_status()),
+        };
         let params = request.into_inner();
 
         let (tx, rx) = mpsc::channel(128);
@@ -296,37 +1028,60 @@ where
         let mut state_rx = self.settler.state_rx();
 
         tokio::spawn(async move {
-            for hash in params.payment_hashes {
-                let invoice = match invoice_helper.get_by_payment_hash(&hash) {
-                    Ok(invoice) => match invoice {
-                        Some(invoice) => invoice,
-                        None => {
-                            warn!(
-                                "Could not find invoice with payment hash: {}",
-                                hex::encode(&hash)
-                            );
-                            continue;
-                        }
-                    },
-                    Err(err) => {
-                        let err = format!(
-                            "Could not get invoice with payment hash {}: {}",
-                            hex::encode(&hash),
-                            err
-                        );
-                        error!("{}", err);
-                        let _ = tx.send(Err(Status::new(Code::Internal, err))).await;
-                        return;
-                    }
-                };
+            // Counts as open stream until the forwarding stops
+            let _guard = guard;
+
+            let state_filtered = |state: i32| {
+                params.filter_states.is_empty() || params.filter_states.contains(&state)
+            };
+            let payment_hashes = if params.include_initial_state.unwrap_or(true) {
+                params.payment_hashes
+            } else {
+                Vec::new()
+            };
+
+            // Fetch larger batches in a single query instead of one per invoice
+            let invoices = if payment_hashes.len() > BATCH_LOOKUP_THRESHOLD {
+                invoice_helper.batch_get_by_payment_hashes(&payment_hashes)
+            } else {
+                payment_hashes
+                    .iter()
+                    .map(|hash| invoice_helper.get_by_payment_hash(hash))
+                    .collect::<anyhow::Result<Vec<Option<HoldInvoice>>>>()
+                    .map(|invoices| invoices.into_iter().flatten().collect())
+            };
 
+            let invoices = match invoices {
+                Ok(invoices) => invoices,
+                Err(err) => {
+                    let err = format!("Could not get invoices: {}", err);
+                    error!("{}", err);
+                    let _ = tx.send(Err(Status::new(Code::Internal, err))).await;
+                    return;
+                }
+            };
+
+            let found = invoices
+                .iter()
+                .map(|invoice| invoice.invoice.payment_hash.clone())
+                .collect::<HashSet<Vec<u8>>>();
+            for hash in payment_hashes.iter() {
+                if !found.contains(hash) {
+                    warn!(
+                        "Could not find invoice with payment hash: {}",
+                        hex::encode(hash)
+                    );
+                }
+            }
+
+            for invoice in invoices {
                 let state = transform_invoice_state(
                     match InvoiceState::try_from(invoice.invoice.state.as_str()) {
                         Ok(state) => state,
                         Err(err) => {
                             let err = format!(
                                 "Could not parse state of invoice {}: {}",
-                                hex::encode(&hash),
+                                hex::encode(&invoice.invoice.payment_hash),
                                 err
                             );
                             error!("{}", err);
@@ -335,12 +1090,16 @@ where
                         }
                     },
                 );
+                if !state_filtered(state) {
+                    continue;
+                }
 
                 if let Err(err) = tx
                     .send(Ok(TrackAllResponse {
                         state,
                         bolt11: invoice.invoice.bolt11,
                         payment_hash: invoice.invoice.payment_hash,
+                        label: invoice.invoice.label,
                     }))
                     .await
                 {
@@ -350,13 +1109,24 @@ where
             }
 
             loop {
-                match state_rx.recv().await {
+                let update = tokio::select! {
+                    update = state_rx.recv() => update,
+                    _ = tx.closed() => break,
+                };
+
+                match update {
                     Ok(update) => {
+                        let state = transform_invoice_state(update.state);
+                        if !state_filtered(state) {
+                            continue;
+                        }
+
                         if let Err(err) = tx
                             .send(Ok(TrackAllResponse {
                                 bolt11: update.bolt11,
                                 payment_hash: update.payment_hash,
-                                state: transform_invoice_state(update.state),
+                                label: update.label,
+                                state,
                             }))
                             .await
                         {
@@ -375,3 +1145,37 @@ where
         Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
     }
 }
+
+fn insert_error_status(err: anyhow::Error) -> Status {
+    match err.downcast_ref::<InvoiceHelperError>() {
+        Some(InvoiceHelperError::LabelTooLong(_)) => {
+            Status::new(Code::InvalidArgument, err.to_string())
+        }
+        Some(err) => Status::new(Code::AlreadyExists, err.to_string()),
+        None => Status::new(Code::Internal, format!("could not save invoice: {}", err)),
+    }
+}
+
+fn settle_error_status(err: anyhow::Error) -> Status {
+    Status::new(
+        match err.downcast_ref::<SettleError>() {
+            Some(SettleError::PreimageMismatch) => Code::InvalidArgument,
+            _ => Code::Internal,
+        },
+        format!("could not settle invoice: {}", err),
+    )
+}
+
+fn pause_error_status(message: &str, err: anyhow::Error) -> Status {
+    Status::new(
+        if err.downcast_ref::<StateTransitionError>().is_some() {
+            Code::FailedPrecondition
+        } else {
+            match err.downcast_ref::<SettleError>() {
+                Some(SettleError::InvoiceNotFound) => Code::NotFound,
+                _ => Code::Internal,
+            }
+        },
+        format!("{}: {}", message, err),
+    )
+}
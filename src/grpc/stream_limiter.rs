@@ -0,0 +1,112 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::codegen::tokio_stream::Stream;
+
+/// Limits the number of streams that are open at the same time across all connections
+#[derive(Debug, Clone)]
+pub struct StreamLimiter {
+    // 0 disables the limit
+    max: u64,
+    active: Arc<AtomicU64>,
+}
+
+/// Counts as active stream until it is dropped
+#[derive(Debug)]
+pub struct StreamGuard {
+    active: Arc<AtomicU64>,
+}
+
+impl StreamLimiter {
+    pub fn new(max: u64) -> Self {
+        StreamLimiter {
+            max,
+            active: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Returns `None` when the limit of open streams is reached
+    pub fn try_acquire(&self) -> Option<StreamGuard> {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                if self.max == 0 || active < self.max {
+                    Some(active + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| StreamGuard {
+                active: self.active.clone(),
+            })
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Keeps the stream counted as active until the client stops consuming it
+#[derive(Debug)]
+pub struct GuardedStream<S> {
+    inner: S,
+    _guard: StreamGuard,
+}
+
+impl<S> GuardedStream<S> {
+    pub fn new(inner: S, guard: StreamGuard) -> Self {
+        GuardedStream {
+            inner,
+            _guard: guard,
+        }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for GuardedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::grpc::stream_limiter::StreamLimiter;
+
+    #[test]
+    fn try_acquire() {
+        let limiter = StreamLimiter::new(2);
+
+        let first = limiter.try_acquire().unwrap();
+        let second = limiter.try_acquire().unwrap();
+        assert_eq!(limiter.active(), 2);
+        assert!(limiter.try_acquire().is_none());
+
+        drop(first);
+        assert_eq!(limiter.active(), 1);
+        assert!(limiter.try_acquire().is_some());
+
+        drop(second);
+        assert_eq!(limiter.active(), 0);
+    }
+
+    #[test]
+    fn try_acquire_no_limit() {
+        let limiter = StreamLimiter::new(0);
+        let guards = (0..1_000)
+            .map(|_| limiter.try_acquire().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(limiter.active(), 1_000);
+
+        drop(guards);
+        assert_eq!(limiter.active(), 0);
+    }
+}
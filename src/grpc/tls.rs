@@ -1,13 +1,20 @@
 use anyhow::Result;
-use log::{debug, trace};
+use log::{debug, info, trace, warn};
 use rcgen::{CertificateParams, KeyPair};
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::{ErrorKind, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
 use tonic::transport::{Certificate, Identity};
 
+const CERTIFICATE_VALIDITY_DAYS: i64 = 365;
+const CERTIFICATE_RENEWAL_DAYS: i64 = 30;
+pub const CERTIFICATE_EXPIRY_WARNING_DAYS: i64 = 7;
+
+pub const CERTIFICATE_NAMES: [&str; 3] = ["ca", "server", "client"];
+
 pub fn load_certificates(base_path: PathBuf) -> Result<(Identity, Certificate)> {
     debug!("Loading gRPC certificates from: {:?}", base_path);
     let base = Path::new(base_path.as_path());
@@ -16,6 +23,23 @@ pub fn load_certificates(base_path: PathBuf) -> Result<(Identity, Certificate)>
         fs::create_dir_all(base)?;
     }
 
+    // All certificates are recreated, because a new CA invalidates the ones it signed
+    if CERTIFICATE_NAMES.iter().any(|name| {
+        let cert_path = base.join(format!("{}.pem", name));
+        cert_path.exists() && certificate_needs_renewal(&cert_path)
+    }) {
+        info!("Renewing gRPC certificates");
+        for name in CERTIFICATE_NAMES {
+            for file in [format!("{}.pem", name), format!("{}-key.pem", name)] {
+                if let Err(err) = fs::remove_file(base.join(file)) {
+                    if err.kind() != ErrorKind::NotFound {
+                        return Err(err.into());
+                    }
+                }
+            }
+        }
+    }
+
     let (ca_key, ca_cert) = generate_or_load_certificate("Hold Root CA", base, "ca", None)?;
     let ca_keypair = KeyPair::from_pem(&String::from_utf8_lossy(&ca_key))?;
     let ca = (
@@ -35,6 +59,26 @@ pub fn load_certificates(base_path: PathBuf) -> Result<(Identity, Certificate)>
     ))
 }
 
+pub fn certificate_expiry(cert_path: &Path) -> Result<OffsetDateTime> {
+    let params = CertificateParams::from_ca_cert_pem(&fs::read_to_string(cert_path)?)?;
+    Ok(params.not_after)
+}
+
+pub fn certificate_needs_renewal(cert_path: &Path) -> bool {
+    match certificate_expiry(cert_path) {
+        Ok(not_after) => {
+            not_after - OffsetDateTime::now_utc() < time::Duration::days(CERTIFICATE_RENEWAL_DAYS)
+        }
+        Err(err) => {
+            warn!(
+                "Could not read expiry of certificate {:?}: {}",
+                cert_path, err
+            );
+            false
+        }
+    }
+}
+
 fn generate_or_load_certificate(
     name: &str,
     directory: &Path,
@@ -83,6 +127,10 @@ fn generate_certificate(
         .distinguished_name
         .push(rcgen::DnType::CommonName, name);
 
+    let now = OffsetDateTime::now_utc();
+    cert_params.not_before = now;
+    cert_params.not_after = now + time::Duration::days(CERTIFICATE_VALIDITY_DAYS);
+
     let cert = match parent {
         None => cert_params.self_signed(&key_pair),
         Some((ca_key, ca_cert)) => cert_params.signed_by(&key_pair, ca_cert, ca_key),
@@ -98,10 +146,14 @@ fn generate_certificate(
 
 #[cfg(test)]
 mod test {
-    use crate::grpc::tls::{generate_certificate, generate_or_load_certificate, load_certificates};
+    use crate::grpc::tls::{
+        certificate_expiry, certificate_needs_renewal, generate_certificate,
+        generate_or_load_certificate, load_certificates,
+    };
     use rcgen::{CertificateParams, KeyPair};
     use std::fs;
     use std::path::Path;
+    use time::OffsetDateTime;
 
     #[test]
     fn test_load_certificates() {
@@ -188,4 +240,72 @@ mod test {
 
         fs::remove_dir_all(certs_dir).unwrap();
     }
+
+    #[test]
+    fn test_certificate_needs_renewal() {
+        let certs_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test-certs-renewal");
+        fs::create_dir(certs_dir.clone()).unwrap();
+
+        let cert_path = certs_dir.clone().join("ca.pem");
+        generate_certificate(
+            "test",
+            certs_dir.clone().join("ca-key.pem"),
+            cert_path.clone(),
+            None,
+        )
+        .unwrap();
+
+        let expiry = certificate_expiry(&cert_path).unwrap() - OffsetDateTime::now_utc();
+        assert!(expiry > time::Duration::days(364));
+        assert!(expiry <= time::Duration::days(365));
+        assert!(!certificate_needs_renewal(&cert_path));
+
+        write_expiring_certificate(&cert_path, 29);
+        assert!(certificate_needs_renewal(&cert_path));
+
+        fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    #[test]
+    fn test_certificate_needs_renewal_invalid() {
+        let certs_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test-certs-invalid");
+        fs::create_dir(certs_dir.clone()).unwrap();
+
+        let cert_path = certs_dir.clone().join("ca.pem");
+        fs::write(cert_path.clone(), "not a certificate").unwrap();
+        assert!(!certificate_needs_renewal(&cert_path));
+
+        fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_certificates_renewal() {
+        let certs_dir = "test-certs-renew-all";
+        assert!(!Path::new(certs_dir).exists());
+
+        let (_, ca) = load_certificates(certs_dir.into()).unwrap();
+        let client_cert = fs::read(Path::new(certs_dir).join("client.pem")).unwrap();
+
+        write_expiring_certificate(&Path::new(certs_dir).join("server.pem"), 10);
+
+        let (_, ca_renewed) = load_certificates(certs_dir.into()).unwrap();
+        assert_ne!(ca.into_inner(), ca_renewed.into_inner());
+        assert_ne!(
+            client_cert,
+            fs::read(Path::new(certs_dir).join("client.pem")).unwrap()
+        );
+        assert!(!certificate_needs_renewal(
+            &Path::new(certs_dir).join("server.pem")
+        ));
+
+        fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    fn write_expiring_certificate(cert_path: &Path, days: i64) {
+        let key_pair = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let mut params = CertificateParams::new(vec!["hold".to_string()]).unwrap();
+        params.not_after = OffsetDateTime::now_utc() + time::Duration::days(days);
+
+        fs::write(cert_path, params.self_signed(&key_pair).unwrap().pem()).unwrap();
+    }
 }
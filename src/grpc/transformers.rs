@@ -1,5 +1,9 @@
-use crate::database::model::{HoldInvoice, Htlc, InvoiceState};
+use crate::database::model::{
+    HoldInvoice, Htlc, InvoiceState, InvoiceStateLog, InvoiceStateParsingError, SortField,
+};
 use crate::grpc::service::hold;
+use crate::hooks::FailureMessage;
+use chrono::{DateTime, NaiveDateTime};
 use lightning_invoice::{RouteHint, RouteHintHop, RoutingFees};
 use secp256k1::{Error, PublicKey};
 
@@ -12,10 +16,24 @@ impl From<Htlc> for hold::Htlc {
             channel_id: value.channel_id as u64,
             msat: value.msat as u64,
             created_at: value.created_at.and_utc().timestamp() as u64,
+            hold_duration_seconds: None,
+            preimage: value.preimage,
         }
     }
 }
 
+impl TryFrom<InvoiceStateLog> for hold::StateTransition {
+    type Error = InvoiceStateParsingError;
+
+    fn try_from(value: InvoiceStateLog) -> Result<Self, Self::Error> {
+        Ok(hold::StateTransition {
+            old_state: transform_invoice_state(InvoiceState::try_from(value.old_state.as_str())?),
+            new_state: transform_invoice_state(InvoiceState::try_from(value.new_state.as_str())?),
+            changed_at: value.changed_at.and_utc().timestamp() as u64,
+        })
+    }
+}
+
 impl From<HoldInvoice> for hold::Invoice {
     fn from(value: HoldInvoice) -> Self {
         hold::Invoice {
@@ -31,7 +49,32 @@ impl From<HoldInvoice> for hold::Invoice {
                 .invoice
                 .settled_at
                 .map(|t| t.and_utc().timestamp() as u64),
-            htlcs: value.htlcs.into_iter().map(|htlc| htlc.into()).collect(),
+            mpp_timeout: value.invoice.mpp_timeout.map(|t| t as u64),
+            settlement_deadline: value.invoice.settlement_deadline.map(|t| t as u64),
+            accepted_at: value
+                .invoice
+                .accepted_at
+                .map(|t| t.and_utc().timestamp() as u64),
+            label: value.invoice.label,
+            cancel_reason: value.invoice.cancel_reason,
+            expires_at: value
+                .invoice
+                .expires_at
+                .map(|t| t.and_utc().timestamp() as u64),
+            hash_algorithm: value.invoice.hash_algorithm,
+            htlcs: value
+                .htlcs
+                .into_iter()
+                .map(|htlc| {
+                    let hold_duration_seconds =
+                        htlc.hold_duration_seconds(value.invoice.settled_at);
+                    hold::Htlc {
+                        hold_duration_seconds,
+                        ..htlc.into()
+                    }
+                })
+                .collect(),
+            state_log: Vec::new(),
         }
     }
 }
@@ -42,10 +85,61 @@ pub fn transform_invoice_state(value: InvoiceState) -> i32 {
         InvoiceState::Unpaid => hold::InvoiceState::Unpaid,
         InvoiceState::Accepted => hold::InvoiceState::Accepted,
         InvoiceState::Cancelled => hold::InvoiceState::Cancelled,
+        InvoiceState::Paused => hold::InvoiceState::Paused,
     }
     .into()
 }
 
+pub fn parse_invoice_state(value: i32) -> Option<InvoiceState> {
+    match hold::InvoiceState::try_from(value).ok()? {
+        hold::InvoiceState::Paid => Some(InvoiceState::Paid),
+        hold::InvoiceState::Unpaid => Some(InvoiceState::Unpaid),
+        hold::InvoiceState::Accepted => Some(InvoiceState::Accepted),
+        hold::InvoiceState::Cancelled => Some(InvoiceState::Cancelled),
+        hold::InvoiceState::Paused => Some(InvoiceState::Paused),
+    }
+}
+
+pub fn parse_clean_states(value: i32) -> Option<Vec<InvoiceState>> {
+    match hold::CleanState::try_from(value).ok()? {
+        hold::CleanState::All => Some(vec![InvoiceState::Cancelled, InvoiceState::Paid]),
+        hold::CleanState::Paid => Some(vec![InvoiceState::Paid]),
+        hold::CleanState::Cancelled => Some(vec![InvoiceState::Cancelled]),
+    }
+}
+
+pub fn parse_sort_field(value: i32) -> Option<SortField> {
+    match hold::SortField::try_from(value).ok()? {
+        hold::SortField::Id => Some(SortField::Id),
+        hold::SortField::CreatedAt => Some(SortField::CreatedAt),
+        hold::SortField::SettledAt => Some(SortField::SettledAt),
+        hold::SortField::State => Some(SortField::State),
+    }
+}
+
+pub fn parse_failure_reason(value: i32) -> Option<FailureMessage> {
+    match hold::FailureReason::try_from(value).ok()? {
+        hold::FailureReason::IncorrectPaymentDetails => {
+            Some(FailureMessage::IncorrectPaymentDetails)
+        }
+        hold::FailureReason::MppTimeout => Some(FailureMessage::MppTimeout),
+        hold::FailureReason::TemporaryNodeFailure => Some(FailureMessage::TemporaryNodeFailure),
+    }
+}
+
+pub fn parse_timestamp(value: i64) -> Option<NaiveDateTime> {
+    DateTime::from_timestamp(value, 0).map(|res| res.naive_utc())
+}
+
+pub fn parse_time_range(after: i64, before: i64) -> Option<(NaiveDateTime, NaiveDateTime)> {
+    let (after, before) = (parse_timestamp(after)?, parse_timestamp(before)?);
+    if after > before {
+        return None;
+    }
+
+    Some((after, before))
+}
+
 pub fn transform_route_hints(hints: Vec<hold::RoutingHint>) -> Result<Vec<RouteHint>, Error> {
     let mut res = Vec::new();
 
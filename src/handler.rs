@@ -1,15 +1,13 @@
 use crate::database::helpers::invoice_helper::InvoiceHelper;
 use crate::database::model::{HoldInvoice, HtlcInsertable, InvoiceState};
 use crate::hooks::{FailureMessage, HtlcCallbackRequest, HtlcCallbackResponse};
+use crate::metrics::{Metrics, METRICS};
 use crate::settler::{Resolver, Settler};
 use anyhow::Result;
+use chrono::Utc;
 use lightning_invoice::Bolt11Invoice;
-use log::{debug, error, info, warn};
 use std::str::FromStr;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-
-const OVERPAYMENT_FACTOR: u64 = 2;
+use tracing::{debug, error, info, warn};
 
 #[derive(Debug)]
 pub enum Resolution {
@@ -20,50 +18,69 @@ pub enum Resolution {
 #[derive(Debug, Clone)]
 pub struct Handler<T> {
     invoice_helper: T,
-    lock: Arc<Mutex<()>>,
     settler: Settler<T>,
+    underpayment_tolerance: u64,
+    overpayment_factor: f64,
 }
 
 impl<T> Handler<T>
 where
     T: InvoiceHelper + Sync + Send + Clone,
 {
-    pub fn new(invoice_helper: T, settler: Settler<T>) -> Self {
+    pub fn new(
+        invoice_helper: T,
+        settler: Settler<T>,
+        underpayment_tolerance: u64,
+        overpayment_factor: f64,
+    ) -> Self {
         Handler {
             settler,
             invoice_helper,
-            lock: Arc::new(Mutex::new(())),
+            underpayment_tolerance,
+            overpayment_factor,
         }
     }
 
     pub async fn htlc_accepted(&mut self, args: HtlcCallbackRequest) -> Resolution {
         self.handle_htlc(args).await.unwrap_or_else(|err| {
-            error!("Could not handle HTLC: {}", err);
+            error!(error = %err, "Could not handle HTLC");
             // Continue to not crash CLN
             Resolution::Resolution(HtlcCallbackResponse::Continue)
         })
     }
 
     async fn handle_htlc(&mut self, args: HtlcCallbackRequest) -> Result<Resolution> {
-        let _lock = self.lock.lock().await;
+        let payment_hash = hex::decode(&args.htlc.payment_hash)?;
 
-        let invoice = match self
-            .invoice_helper
-            .get_by_payment_hash(&hex::decode(args.htlc.payment_hash.clone())?)?
-        {
+        let res = {
+            let lock = self.settler.get_invoice_lock(&payment_hash);
+            let _guard = lock.lock().await;
+            self.handle_htlc_locked(&payment_hash, args).await
+        };
+        self.settler.release_invoice_lock(&payment_hash);
+
+        res
+    }
+
+    async fn handle_htlc_locked(
+        &mut self,
+        payment_hash: &[u8],
+        args: HtlcCallbackRequest,
+    ) -> Result<Resolution> {
+        let invoice = match self.invoice_helper.get_by_payment_hash(payment_hash)? {
             Some(invoice) => invoice,
             None => {
-                debug!("No hold invoice for: {}", args.htlc.payment_hash);
+                debug!(payment_hash = %args.htlc.payment_hash, "No hold invoice for HTLC");
                 return Ok(Resolution::Resolution(HtlcCallbackResponse::Continue));
             }
         };
 
         if invoice.htlc_is_known(&args.htlc.short_channel_id, args.htlc.id) {
             info!(
-                "Found already accepted HTLC {}:{} for {}",
-                args.htlc.short_channel_id,
-                args.htlc.id,
-                hex::encode(invoice.invoice.payment_hash.clone())
+                payment_hash = %hex::encode(&invoice.invoice.payment_hash),
+                scid = %args.htlc.short_channel_id,
+                htlc_id = args.htlc.id,
+                "Found already accepted HTLC"
             );
             return Ok(Resolution::Resolver(
                 self.settler
@@ -71,11 +88,22 @@ where
                         &invoice.invoice.payment_hash,
                         args.htlc.short_channel_id.clone(),
                         args.htlc.id,
+                        args.htlc.amount_msat,
                     )
                     .await,
             ));
         }
 
+        // Senders may retry once the invoice is resumed
+        if invoice.invoice.state == InvoiceState::Paused.to_string() {
+            return self.reject_htlc(
+                &invoice,
+                &args,
+                FailureMessage::TemporaryNodeFailure,
+                "invoice is paused",
+            );
+        }
+
         if invoice.invoice.state != InvoiceState::Unpaid.to_string() {
             return self.reject_htlc(
                 &invoice,
@@ -99,6 +127,24 @@ where
             }
         }
 
+        // Senders include the total amount in the onion of single part payments too,
+        // so only a total above the amount of the HTLC means the payment is split
+        if args
+            .onion
+            .total_msat
+            .is_some_and(|total| total > args.htlc.amount_msat)
+            && !invoice_decoded
+                .features()
+                .is_some_and(|features| features.supports_basic_mpp())
+        {
+            return self.reject_htlc(
+                &invoice,
+                &args,
+                FailureMessage::IncorrectPaymentDetails,
+                "multi part payment for invoice without basic_mpp feature",
+            );
+        }
+
         if args.htlc.cltv_expiry_relative < invoice_decoded.min_final_cltv_expiry_delta() {
             return self.reject_htlc(
                 &invoice,
@@ -114,31 +160,116 @@ where
             );
         }
 
+        // CLN only compares the HTLC amount to the onion after the hook
+        if args.htlc.amount_msat < args.onion.forward_msat {
+            return self.reject_htlc(
+                &invoice,
+                &args,
+                FailureMessage::FinalIncorrectHtlcAmount,
+                format!(
+                    "HTLC amount less than onion amount ({} < {})",
+                    args.htlc.amount_msat, args.onion.forward_msat
+                )
+                .as_str(),
+            );
+        }
+
         let amount_paid = invoice.amount_paid_msat() + args.htlc.amount_msat;
+        let amount_invoice = invoice_decoded.amount_milli_satoshis();
 
-        {
-            let amount_max_accepted =
-                invoice_decoded.amount_milli_satoshis().unwrap_or(0) * OVERPAYMENT_FACTOR;
+        match amount_invoice {
+            Some(amount_invoice) => {
+                let amount_max_accepted = (amount_invoice as f64 * self.overpayment_factor) as u64;
 
-            if amount_max_accepted < amount_paid {
+                if amount_max_accepted < amount_paid {
+                    return self.reject_htlc(
+                        &invoice,
+                        &args,
+                        FailureMessage::IncorrectPaymentDetails,
+                        format!(
+                            "overpayment protection ({} < {})",
+                            amount_max_accepted, amount_paid
+                        )
+                        .as_str(),
+                    );
+                }
+            }
+            None => {
+                if args.htlc.amount_msat == 0 {
+                    return self.reject_htlc(
+                        &invoice,
+                        &args,
+                        FailureMessage::IncorrectPaymentDetails,
+                        "HTLC without amount for invoice without amount",
+                    );
+                }
+            }
+        };
+
+        let amount_min_accepted = match amount_invoice {
+            Some(amount_invoice) => amount_invoice.saturating_sub(self.underpayment_tolerance),
+            // The payer chooses the amount of invoices without one; all parts
+            // of a multi part payment are in once the total of the onion is reached
+            None => args.onion.total_msat.unwrap_or(amount_paid),
+        };
+
+        // Without a total amount in the onion, no further parts are coming
+        if args.onion.total_msat.is_none() && amount_paid < amount_min_accepted {
+            return self.reject_htlc(
+                &invoice,
+                &args,
+                FailureMessage::IncorrectPaymentDetails,
+                format!(
+                    "single part payment too little ({} < {})",
+                    amount_paid, amount_min_accepted
+                )
+                .as_str(),
+            );
+        }
+
+        // Decided on the HTLCs in the database like the acceptance below, rather than on the
+        // ones of the invoice fetched above, which could miss parts that were accepted already
+        let amount_accepted = self
+            .invoice_helper
+            .sum_htlc_msat(invoice.invoice.id, InvoiceState::Accepted)?
+            + args.htlc.amount_msat;
+        if amount_accepted >= amount_min_accepted {
+            if let Some(amount_invoice) = amount_invoice.filter(|amount| amount_accepted < *amount)
+            {
+                info!(
+                    payment_hash = %hex::encode(&invoice.invoice.payment_hash),
+                    amount_paid = amount_accepted,
+                    amount_invoice,
+                    "Applied underpayment tolerance"
+                );
+            }
+
+            let htlc_count = self
+                .invoice_helper
+                .count_htlcs(invoice.invoice.id)?
+                .get(&InvoiceState::Accepted)
+                .copied()
+                .unwrap_or(0)
+                + 1;
+            if !self
+                .settler
+                .run_pre_accept_hooks(&invoice.invoice, htlc_count as usize)
+            {
                 return self.reject_htlc(
                     &invoice,
                     &args,
                     FailureMessage::IncorrectPaymentDetails,
-                    format!(
-                        "overpayment protection ({} < {})",
-                        amount_max_accepted, amount_paid
-                    )
-                    .as_str(),
+                    "rejected by pre-accept hook",
                 );
             }
         }
 
         debug!(
-            "Accepted HTLC {}:{} for hold invoice {}",
-            args.htlc.short_channel_id,
-            args.htlc.id,
-            hex::encode(invoice.invoice.payment_hash.clone())
+            payment_hash = %hex::encode(&invoice.invoice.payment_hash),
+            scid = %args.htlc.short_channel_id,
+            htlc_id = args.htlc.id,
+            amount_msat = args.htlc.amount_msat,
+            "Accepted HTLC"
         );
         self.invoice_helper
             .insert_htlc(&Self::create_htlc_insertable(
@@ -146,11 +277,11 @@ where
                 &invoice,
                 &args,
             ))?;
+        Metrics::inc(&METRICS.htlcs_accepted);
 
-        if amount_paid >= invoice_decoded.amount_milli_satoshis().unwrap_or(0) {
-            self.settler
-                .set_accepted(&invoice.invoice, invoice.htlcs.len() + 1)?;
-        }
+        self.settler
+            .set_accepted(&invoice.invoice, amount_min_accepted)
+            .await?;
 
         Ok(Resolution::Resolver(
             self.settler
@@ -158,6 +289,7 @@ where
                     &invoice.invoice.payment_hash,
                     args.htlc.short_channel_id,
                     args.htlc.id,
+                    args.htlc.amount_msat,
                 )
                 .await,
         ))
@@ -171,11 +303,11 @@ where
         log_message: &str,
     ) -> Result<Resolution> {
         warn!(
-            "Rejected HTLC {}:{} for hold invoice {}: {}",
-            args.htlc.short_channel_id,
-            args.htlc.id,
-            hex::encode(invoice.invoice.payment_hash.clone()),
-            log_message
+            payment_hash = %hex::encode(&invoice.invoice.payment_hash),
+            scid = %args.htlc.short_channel_id,
+            htlc_id = args.htlc.id,
+            reason = log_message,
+            "Rejected HTLC"
         );
 
         self.invoice_helper
@@ -184,6 +316,7 @@ where
                 invoice,
                 args,
             ))?;
+        Metrics::inc(&METRICS.htlcs_cancelled);
 
         Ok(Resolution::Resolution(HtlcCallbackResponse::Fail {
             failure_message,
@@ -201,6 +334,11 @@ where
             scid: args.htlc.short_channel_id.clone(),
             channel_id: args.htlc.id as i64,
             msat: args.htlc.amount_msat as i64,
+            accepted_at: if state == InvoiceState::Accepted {
+                Some(Utc::now().naive_utc())
+            } else {
+                None
+            },
         }
     }
 }
@@ -208,64 +346,34 @@ where
 #[cfg(test)]
 mod test {
     use crate::database::helpers::invoice_helper::InvoiceHelper;
-    use crate::database::model::{
-        HoldInvoice, HtlcInsertable, Invoice, InvoiceInsertable, InvoiceState,
-    };
+    use crate::database::model::{HoldInvoice, Invoice, InvoiceInsertable, InvoiceState};
+    use crate::database::test_utils::{invoice_insertable, test_database, MockInvoiceHelper};
     use crate::handler::{Handler, Resolution};
     use crate::hooks::{FailureMessage, Htlc, HtlcCallbackRequest, HtlcCallbackResponse, Onion};
     use crate::settler::Settler;
-    use anyhow::Result;
-    use lightning_invoice::Bolt11Invoice;
-    use mockall::mock;
+    use bitcoin::hashes::{sha256, Hash};
+    use lightning_invoice::{Bolt11Invoice, Currency, PaymentSecret};
+    use secp256k1::{Secp256k1, SecretKey};
+    use std::collections::{HashMap, HashSet};
     use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::task::JoinSet;
 
     const INVOICE: &str = "lnbc10n1pnvfs4vsp57npt9tx2glnkx29ng98cmc0lt0as8se4x4776rtwqp3gr3hj807qpp5ysnte2hh3nv4z0jd4pfe5wla956zxxg3rmxs5ux4v0xfwplvlm8sdqdw3jhxar5v4ehgxqyjw5qcqpjrzjq2rnwvp7zt9cgeparuqcrqft2kd9dm6a0z6vg0gucrqurutaezrjyrze2uqq2wcqqyqqqqdyqqqqqpqqvs9qxpqysgqjkdwjjuzfy5ek4k9xgsv0ysrc3lg349caqqh3yearxmv4zgyqhqyuntk4gyjpvpezcc66v5lyzxm240wdfgp6cqkwt7fv2nngjjnlrspmaakpk";
 
-    mock! {
-        InvoiceHelper {}
-
-        impl Clone for InvoiceHelper {
-            fn clone(&self) -> Self;
-        }
-
-        impl InvoiceHelper for InvoiceHelper {
-            fn insert(&self, invoice: &InvoiceInsertable) -> Result<usize>;
-            fn insert_htlc(&self, htlc: &HtlcInsertable) -> Result<usize>;
-
-            fn set_invoice_state(
-                &self,
-                id: i64,
-                state: InvoiceState,
-                new_state: InvoiceState,
-            ) -> Result<usize>;
-            fn set_invoice_preimage(&self, id: i64, preimage: &[u8]) -> Result<usize>;
-            fn set_htlc_state_by_id(
-                &self,
-                htlc_id: i64,
-                state: InvoiceState,
-                new_state: InvoiceState,
-            ) -> Result<usize>;
-            fn set_htlc_states_by_invoice(
-                &self,
-                invoice_id: i64,
-                state: InvoiceState,
-                new_state: InvoiceState,
-            ) -> Result<usize>;
-
-            fn clean_cancelled(&self, age: Option<u64>) -> Result<usize>;
-
-            fn get_all(&self) -> Result<Vec<HoldInvoice>>;
-            fn get_paginated(&self, index_start: i64, limit: u64) -> Result<Vec<HoldInvoice>>;
-            fn get_by_payment_hash(&self, payment_hash: &[u8]) -> Result<Option<HoldInvoice>>;
-        }
-    }
-
     #[tokio::test]
     async fn no_invoice() {
         let mut helper = MockInvoiceHelper::new();
         helper.expect_get_by_payment_hash().returning(|_| Ok(None));
 
-        let mut handler = Handler::new(helper, Settler::new(MockInvoiceHelper::new(), 0));
+        let mut handler = Handler::new(
+            helper,
+            Settler::new(MockInvoiceHelper::new(), 0, 15, 1_000),
+            0,
+            2.0,
+        );
 
         let res = handler
             .htlc_accepted(HtlcCallbackRequest {
@@ -298,10 +406,17 @@ mod test {
         helper.expect_get_by_payment_hash().returning(|_| {
             Ok(Some(HoldInvoice {
                 invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
                     id: 0,
                     preimage: None,
                     settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
                     payment_hash: vec![],
+                    hash_algorithm: "sha256".to_string(),
                     bolt11: "".to_string(),
                     created_at: Default::default(),
                     state: InvoiceState::Paid.to_string(),
@@ -311,7 +426,12 @@ mod test {
         });
         helper.expect_insert_htlc().returning(|_| Ok(0));
 
-        let mut handler = Handler::new(helper, Settler::new(MockInvoiceHelper::new(), 0));
+        let mut handler = Handler::new(
+            helper,
+            Settler::new(MockInvoiceHelper::new(), 0, 15, 1_000),
+            0,
+            2.0,
+        );
 
         let res = handler
             .htlc_accepted(HtlcCallbackRequest {
@@ -343,16 +463,88 @@ mod test {
         };
     }
 
+    #[tokio::test]
+    async fn invoice_paused() {
+        let mut helper = MockInvoiceHelper::new();
+        helper.expect_get_by_payment_hash().returning(|_| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 0,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: vec![],
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Paused.to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        helper
+            .expect_insert_htlc()
+            .withf(|htlc| htlc.state == InvoiceState::Cancelled.to_string())
+            .times(1)
+            .returning(|_| Ok(0));
+
+        let mut handler = Handler::new(
+            helper,
+            Settler::new(MockInvoiceHelper::new(), 0, 15, 1_000),
+            0,
+            2.0,
+        );
+
+        let res = handler
+            .htlc_accepted(HtlcCallbackRequest {
+                onion: Onion::default(),
+                htlc: Htlc {
+                    short_channel_id: "".to_string(),
+                    id: 0,
+                    amount_msat: 0,
+                    cltv_expiry: 0,
+                    cltv_expiry_relative: 0,
+                    payment_hash: "00".to_string(),
+                },
+                forward_to: None,
+            })
+            .await;
+
+        match res {
+            Resolution::Resolution(res) => {
+                assert_eq!(
+                    res,
+                    HtlcCallbackResponse::Fail {
+                        failure_message: FailureMessage::TemporaryNodeFailure
+                    }
+                );
+            }
+            Resolution::Resolver(_) => unreachable!(),
+        };
+    }
+
     #[tokio::test]
     async fn invoice_incorrect_payment_secret() {
         let mut helper = MockInvoiceHelper::new();
         helper.expect_get_by_payment_hash().returning(|_| {
             Ok(Some(HoldInvoice {
                 invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
                     id: 0,
                     preimage: None,
                     settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
                     payment_hash: vec![],
+                    hash_algorithm: "sha256".to_string(),
                     bolt11: INVOICE.to_string(),
                     state: InvoiceState::Unpaid.to_string(),
                     created_at: Default::default(),
@@ -362,7 +554,12 @@ mod test {
         });
         helper.expect_insert_htlc().returning(|_| Ok(0));
 
-        let mut handler = Handler::new(helper, Settler::new(MockInvoiceHelper::new(), 0));
+        let mut handler = Handler::new(
+            helper,
+            Settler::new(MockInvoiceHelper::new(), 0, 15, 1_000),
+            0,
+            2.0,
+        );
 
         let res = handler
             .htlc_accepted(HtlcCallbackRequest {
@@ -409,10 +606,17 @@ mod test {
         helper.expect_get_by_payment_hash().returning(|_| {
             Ok(Some(HoldInvoice {
                 invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
                     id: 0,
                     preimage: None,
                     settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
                     payment_hash: vec![],
+                    hash_algorithm: "sha256".to_string(),
                     bolt11: INVOICE.to_string(),
                     state: InvoiceState::Unpaid.to_string(),
                     created_at: Default::default(),
@@ -422,7 +626,12 @@ mod test {
         });
         helper.expect_insert_htlc().returning(|_| Ok(0));
 
-        let mut handler = Handler::new(helper, Settler::new(MockInvoiceHelper::new(), 0));
+        let mut handler = Handler::new(
+            helper,
+            Settler::new(MockInvoiceHelper::new(), 0, 15, 1_000),
+            0,
+            2.0,
+        );
 
         let res = handler
             .htlc_accepted(HtlcCallbackRequest {
@@ -472,10 +681,17 @@ mod test {
         helper.expect_get_by_payment_hash().returning(|_| {
             Ok(Some(HoldInvoice {
                 invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
                     id: 0,
                     preimage: None,
                     settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
                     payment_hash: vec![],
+                    hash_algorithm: "sha256".to_string(),
                     bolt11: INVOICE.to_string(),
                     state: InvoiceState::Unpaid.to_string(),
                     created_at: Default::default(),
@@ -485,7 +701,12 @@ mod test {
         });
         helper.expect_insert_htlc().returning(|_| Ok(0));
 
-        let mut handler = Handler::new(helper, Settler::new(MockInvoiceHelper::new(), 0));
+        let mut handler = Handler::new(
+            helper,
+            Settler::new(MockInvoiceHelper::new(), 0, 15, 1_000),
+            0,
+            2.0,
+        );
 
         let res = handler
             .htlc_accepted(HtlcCallbackRequest {
@@ -539,20 +760,33 @@ mod test {
         helper.expect_get_by_payment_hash().returning(move |_| {
             Ok(Some(HoldInvoice {
                 invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
                     id: 0,
                     preimage: None,
                     settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
                     bolt11: INVOICE.to_string(),
                     created_at: Default::default(),
                     payment_hash: payment_hash_cp.clone(),
+                    hash_algorithm: "sha256".to_string(),
                     state: InvoiceState::Unpaid.to_string(),
                 },
                 htlcs: vec![],
             }))
         });
         helper.expect_insert_htlc().returning(|_| Ok(0));
+        helper.expect_sum_htlc_msat().returning(|_, _| Ok(0));
+        helper
+            .expect_count_htlcs()
+            .returning(|_| Ok(HashMap::new()));
 
-        let payment_hash_cp_settler = payment_hash.clone();
+        // The settler checks the preimage against the payment hash of the invoice
+        let preimage_hash: sha256::Hash = Hash::hash(&hex::decode("0011").unwrap());
+        let payment_hash_cp_settler = preimage_hash[..].to_vec();
 
         let mut helper_settler = MockInvoiceHelper::new();
         helper_settler
@@ -560,13 +794,20 @@ mod test {
             .returning(move |_| {
                 Ok(Some(HoldInvoice {
                     invoice: Invoice {
+                        cancel_reason: None,
+                        expires_at: None,
+                        label: None,
                         id: 0,
                         preimage: None,
                         settled_at: None,
+                        mpp_timeout: None,
+                        settlement_deadline: None,
+                        accepted_at: None,
                         bolt11: INVOICE.to_string(),
                         created_at: Default::default(),
                         state: InvoiceState::Unpaid.to_string(),
                         payment_hash: payment_hash_cp_settler.clone(),
+                        hash_algorithm: "sha256".to_string(),
                     },
                     htlcs: vec![],
                 }))
@@ -574,14 +815,17 @@ mod test {
         helper_settler
             .expect_set_htlc_states_by_invoice()
             .returning(|_, _, _| Ok(0));
+        helper_settler
+            .expect_conditional_accept()
+            .returning(|_, _| Ok(true));
         helper_settler
             .expect_set_invoice_state()
-            .returning(|_, _, _| Ok(0));
+            .returning(|_, _, _| Ok(1));
         helper_settler
             .expect_set_invoice_preimage()
-            .returning(|_, _| Ok(0));
+            .returning(|_, _| Ok(()));
 
-        let mut handler = Handler::new(helper, Settler::new(helper_settler, 0));
+        let mut handler = Handler::new(helper, Settler::new(helper_settler, 0, 15, 1_000), 0, 2.0);
 
         let res = handler
             .htlc_accepted(HtlcCallbackRequest {
@@ -614,7 +858,7 @@ mod test {
             Resolution::Resolution(_) => {
                 assert!(false);
             }
-            Resolution::Resolver(res) => {
+            Resolution::Resolver(mut res) => {
                 let preimage = &hex::decode("0011").unwrap();
                 handler
                     .settler
@@ -623,7 +867,7 @@ mod test {
                     .unwrap();
 
                 assert_eq!(
-                    res.await.unwrap(),
+                    res.recv().await.unwrap(),
                     HtlcCallbackResponse::Resolve {
                         payment_key: hex::encode(preimage)
                     }
@@ -631,4 +875,685 @@ mod test {
             }
         };
     }
+
+    #[tokio::test]
+    async fn pre_accept_hook_rejection() {
+        let mut helper = MockInvoiceHelper::new();
+        helper.expect_get_by_payment_hash().returning(|_| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 21,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: vec![],
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: INVOICE.to_string(),
+                    state: InvoiceState::Unpaid.to_string(),
+                    created_at: Default::default(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        helper
+            .expect_insert_htlc()
+            .withf(|htlc| htlc.state == InvoiceState::Cancelled.to_string())
+            .times(1)
+            .returning(|_| Ok(0));
+        helper.expect_sum_htlc_msat().returning(|_, _| Ok(0));
+        helper
+            .expect_count_htlcs()
+            .returning(|_| Ok(HashMap::new()));
+
+        // Accepting the invoice would need an expectation for set_invoice_state
+        let settler = Settler::new(MockInvoiceHelper::new(), 0, 15, 1_000);
+        settler.register_pre_accept_hook(Box::new(|invoice, _| invoice.id != 21));
+
+        let mut handler = Handler::new(helper, settler, 0, 2.0);
+
+        let res = handler
+            .htlc_accepted(HtlcCallbackRequest {
+                onion: Onion {
+                    payload: "".to_string(),
+                    type_field: "".to_string(),
+                    forward_msat: 0,
+                    outgoing_cltv_value: 0,
+                    total_msat: None,
+                    next_onion: "".to_string(),
+                    shared_secret: None,
+                    payment_secret: Some(
+                        "f4c2b2acca47e76328b3414f8de1ff5bfb03c335357ded0d6e006281c6f23bfc"
+                            .to_string(),
+                    ),
+                },
+                htlc: Htlc {
+                    short_channel_id: "".to_string(),
+                    id: 0,
+                    amount_msat: 1_000,
+                    cltv_expiry: 0,
+                    cltv_expiry_relative: 18,
+                    payment_hash: "00".to_string(),
+                },
+                forward_to: None,
+            })
+            .await;
+
+        match res {
+            Resolution::Resolution(res) => {
+                assert_eq!(
+                    res,
+                    HtlcCallbackResponse::Fail {
+                        failure_message: FailureMessage::IncorrectPaymentDetails
+                    }
+                );
+            }
+            Resolution::Resolver(_) => unreachable!(),
+        };
+    }
+
+    #[tokio::test]
+    async fn pre_accept_hook_parts_in_database() {
+        let mut helper = MockInvoiceHelper::new();
+        // The fetched invoice misses the part that is in the database already
+        helper.expect_get_by_payment_hash().returning(|_| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 21,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: vec![],
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: INVOICE.to_string(),
+                    state: InvoiceState::Unpaid.to_string(),
+                    created_at: Default::default(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        helper.expect_sum_htlc_msat().returning(|_, _| Ok(600));
+        helper
+            .expect_count_htlcs()
+            .returning(|_| Ok(HashMap::from([(InvoiceState::Accepted, 1)])));
+        helper
+            .expect_insert_htlc()
+            .withf(|htlc| htlc.state == InvoiceState::Cancelled.to_string())
+            .times(1)
+            .returning(|_| Ok(0));
+
+        let htlc_count = Arc::new(AtomicU64::new(0));
+        let htlc_count_cp = htlc_count.clone();
+        let settler = Settler::new(MockInvoiceHelper::new(), 0, 15, 1_000);
+        settler.register_pre_accept_hook(Box::new(move |_, num_htlcs| {
+            htlc_count_cp.store(num_htlcs as u64, Ordering::SeqCst);
+            false
+        }));
+
+        let mut handler = Handler::new(helper, settler, 0, 2.0);
+
+        let res = handler
+            .htlc_accepted(HtlcCallbackRequest {
+                onion: Onion {
+                    forward_msat: 400,
+                    total_msat: Some(1_000),
+                    payment_secret: Some(
+                        "f4c2b2acca47e76328b3414f8de1ff5bfb03c335357ded0d6e006281c6f23bfc"
+                            .to_string(),
+                    ),
+                    ..Default::default()
+                },
+                htlc: Htlc {
+                    short_channel_id: "".to_string(),
+                    id: 1,
+                    amount_msat: 400,
+                    cltv_expiry: 0,
+                    cltv_expiry_relative: 18,
+                    payment_hash: "00".to_string(),
+                },
+                forward_to: None,
+            })
+            .await;
+
+        assert!(matches!(
+            res,
+            Resolution::Resolution(HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::IncorrectPaymentDetails
+            })
+        ));
+        assert_eq!(htlc_count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn underpayment_tolerance_exact_amount() {
+        assert!(handle_htlc_with(1_000, Some(1_000), 10, 2.0).await.1);
+    }
+
+    #[tokio::test]
+    async fn underpayment_tolerance_within_tolerance() {
+        assert!(handle_htlc_with(990, Some(1_000), 10, 2.0).await.1);
+    }
+
+    #[tokio::test]
+    async fn underpayment_tolerance_exceeded() {
+        assert!(!handle_htlc_with(989, Some(1_000), 10, 2.0).await.1);
+    }
+
+    #[tokio::test]
+    async fn single_part_underpayment() {
+        let (res, accepted) = handle_htlc_with(999, None, 0, 2.0).await;
+        assert!(matches!(
+            res,
+            Resolution::Resolution(HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::IncorrectPaymentDetails
+            })
+        ));
+        assert!(!accepted);
+    }
+
+    #[tokio::test]
+    async fn single_part_underpayment_within_tolerance() {
+        let (res, accepted) = handle_htlc_with(990, None, 10, 2.0).await;
+        assert!(matches!(res, Resolution::Resolver(_)));
+        assert!(accepted);
+    }
+
+    #[tokio::test]
+    async fn htlc_amount_less_than_onion() {
+        for total_msat in [None, Some(1_000)] {
+            let (res, accepted) =
+                handle_htlc_with_onion_amount(999, 1_000, total_msat, 0, 2.0).await;
+            assert!(matches!(
+                res,
+                Resolution::Resolution(HtlcCallbackResponse::Fail {
+                    failure_message: FailureMessage::FinalIncorrectHtlcAmount
+                })
+            ));
+            assert!(!accepted);
+        }
+    }
+
+    #[tokio::test]
+    async fn htlc_amount_more_than_onion() {
+        let (res, accepted) = handle_htlc_with_onion_amount(1_000, 999, None, 0, 2.0).await;
+        assert!(matches!(res, Resolution::Resolver(_)));
+        assert!(accepted);
+    }
+
+    #[tokio::test]
+    async fn htlc_amountless_invoice_single_part() {
+        let (res, accepted) =
+            handle_htlc_for_invoice(amountless_invoice(true), 12_345, 12_345, None, 0, 1.0).await;
+        assert!(matches!(res, Resolution::Resolver(_)));
+        assert!(accepted);
+    }
+
+    #[tokio::test]
+    async fn htlc_amountless_invoice_multi_part() {
+        let (res, accepted) = handle_htlc_for_invoice(
+            amountless_invoice(true),
+            10_000,
+            10_000,
+            Some(20_000),
+            0,
+            1.0,
+        )
+        .await;
+        assert!(matches!(res, Resolution::Resolver(_)));
+        assert!(!accepted);
+
+        let (res, accepted) = handle_htlc_for_invoice(
+            amountless_invoice(true),
+            20_000,
+            20_000,
+            Some(20_000),
+            0,
+            1.0,
+        )
+        .await;
+        assert!(matches!(res, Resolution::Resolver(_)));
+        assert!(accepted);
+    }
+
+    #[tokio::test]
+    async fn htlc_multi_part_without_basic_mpp() {
+        let (res, accepted) = handle_htlc_for_invoice(
+            amountless_invoice(false),
+            10_000,
+            10_000,
+            Some(20_000),
+            0,
+            1.0,
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Resolution::Resolution(HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::IncorrectPaymentDetails
+            })
+        ));
+        assert!(!accepted);
+
+        let (res, accepted) = handle_htlc_for_invoice(
+            amountless_invoice(false),
+            20_000,
+            20_000,
+            Some(20_000),
+            0,
+            1.0,
+        )
+        .await;
+        assert!(matches!(res, Resolution::Resolver(_)));
+        assert!(accepted);
+    }
+
+    #[tokio::test]
+    async fn htlc_amountless_invoice_zero_amount() {
+        let (res, accepted) =
+            handle_htlc_for_invoice(amountless_invoice(true), 0, 0, None, 0, 1.0).await;
+        assert!(matches!(
+            res,
+            Resolution::Resolution(HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::IncorrectPaymentDetails
+            })
+        ));
+        assert!(!accepted);
+    }
+
+    #[tokio::test]
+    async fn multi_part_underpayment_held() {
+        let (res, accepted) = handle_htlc_with(999, Some(1_000), 0, 2.0).await;
+        assert!(matches!(res, Resolution::Resolver(_)));
+        assert!(!accepted);
+    }
+
+    #[tokio::test]
+    async fn overpayment_factor_exact_amount() {
+        let (res, accepted) = handle_htlc_with(1_000, Some(1_000), 0, 1.0).await;
+        assert!(matches!(res, Resolution::Resolver(_)));
+        assert!(accepted);
+
+        let (res, accepted) = handle_htlc_with(1_001, Some(1_000), 0, 1.0).await;
+        assert!(matches!(
+            res,
+            Resolution::Resolution(HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::IncorrectPaymentDetails
+            })
+        ));
+        assert!(!accepted);
+    }
+
+    #[tokio::test]
+    async fn overpayment_factor_fraction() {
+        let (res, accepted) = handle_htlc_with(1_500, Some(1_000), 0, 1.5).await;
+        assert!(matches!(res, Resolution::Resolver(_)));
+        assert!(accepted);
+
+        let (res, accepted) = handle_htlc_with(1_501, Some(1_000), 0, 1.5).await;
+        assert!(matches!(
+            res,
+            Resolution::Resolution(HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::IncorrectPaymentDetails
+            })
+        ));
+        assert!(!accepted);
+    }
+
+    #[tokio::test]
+    async fn concurrent_htlcs_different_invoices() {
+        fn paid_helper() -> MockInvoiceHelper {
+            let mut helper = MockInvoiceHelper::new();
+            helper
+                .expect_get_by_payment_hash()
+                .returning(|payment_hash| {
+                    Ok(Some(HoldInvoice {
+                        invoice: Invoice {
+                            cancel_reason: None,
+                            expires_at: None,
+                            label: None,
+                            id: 0,
+                            preimage: None,
+                            settled_at: None,
+                            mpp_timeout: None,
+                            settlement_deadline: None,
+                            accepted_at: None,
+                            payment_hash: payment_hash.to_vec(),
+                            hash_algorithm: "sha256".to_string(),
+                            bolt11: "".to_string(),
+                            created_at: Default::default(),
+                            state: InvoiceState::Paid.to_string(),
+                        },
+                        htlcs: vec![],
+                    }))
+                });
+            helper.expect_insert_htlc().returning(|_| Ok(0));
+            helper.expect_clone().returning(paid_helper);
+            helper
+        }
+
+        let mut settler_helper = MockInvoiceHelper::new();
+        settler_helper
+            .expect_clone()
+            .returning(MockInvoiceHelper::new);
+
+        let handler = Handler::new(
+            paid_helper(),
+            Settler::new(settler_helper, 0, 15, 1_000),
+            0,
+            2.0,
+        );
+
+        // HTLCs of other invoices must not wait for this one
+        let blocked = handler.settler.get_invoice_lock(&[0xaa]);
+        let _guard = blocked.lock().await;
+
+        let htlc = |payment_hash: &str| HtlcCallbackRequest {
+            onion: Onion::default(),
+            htlc: Htlc {
+                short_channel_id: "".to_string(),
+                id: 0,
+                amount_msat: 0,
+                cltv_expiry: 0,
+                cltv_expiry_relative: 0,
+                payment_hash: payment_hash.to_string(),
+            },
+            forward_to: None,
+        };
+
+        let mut first = handler.clone();
+        let mut second = handler.clone();
+        let (res_first, res_second) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(
+                first.htlc_accepted(htlc("bb")),
+                second.htlc_accepted(htlc("cc"))
+            )
+        })
+        .await
+        .unwrap();
+
+        for res in [res_first, res_second] {
+            assert!(matches!(
+                res,
+                Resolution::Resolution(HtlcCallbackResponse::Fail {
+                    failure_message: FailureMessage::IncorrectPaymentDetails
+                })
+            ));
+        }
+
+        assert_eq!(handler.settler.invoice_lock_count(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_invoices_and_htlcs() {
+        const INVOICE_COUNT: u8 = 100;
+        const AMOUNT_MSAT: u64 = 1_000;
+
+        let database = test_database("handler-concurrent");
+        let helper = database.helper();
+
+        let mut inserts = JoinSet::new();
+        for i in 0..INVOICE_COUNT {
+            let helper = helper.clone();
+            inserts.spawn_blocking(move || {
+                helper.insert(&InvoiceInsertable {
+                    bolt11: invoice_with_amount(&[i], AMOUNT_MSAT),
+                    ..invoice_insertable(&sha256::Hash::hash(&[i])[..])
+                })
+            });
+        }
+        while let Some(res) = inserts.join_next().await {
+            assert_eq!(res.unwrap().unwrap(), 1);
+        }
+
+        let handler = Handler::new(
+            helper.clone(),
+            Settler::new(helper.clone(), 0, 15, 1_000),
+            0,
+            2.0,
+        );
+
+        let mut htlcs = JoinSet::new();
+        for i in 0..INVOICE_COUNT {
+            let mut handler = handler.clone();
+            htlcs.spawn(async move {
+                handler
+                    .htlc_accepted(HtlcCallbackRequest {
+                        onion: Onion {
+                            forward_msat: AMOUNT_MSAT,
+                            payment_secret: Some(hex::encode([2; 32])),
+                            ..Default::default()
+                        },
+                        htlc: Htlc {
+                            short_channel_id: "1x1x1".to_string(),
+                            id: i as u64,
+                            amount_msat: AMOUNT_MSAT,
+                            cltv_expiry: 0,
+                            cltv_expiry_relative: 18,
+                            payment_hash: hex::encode(&sha256::Hash::hash(&[i])[..]),
+                        },
+                        forward_to: None,
+                    })
+                    .await
+            });
+        }
+        while let Some(res) = htlcs.join_next().await {
+            assert!(matches!(res.unwrap(), Resolution::Resolver(_)));
+        }
+
+        let invoices = helper.get_all().unwrap();
+        assert_eq!(invoices.len(), INVOICE_COUNT as usize);
+        let payment_hashes: HashSet<_> = invoices
+            .iter()
+            .map(|invoice| invoice.invoice.payment_hash.clone())
+            .collect();
+        assert_eq!(payment_hashes.len(), INVOICE_COUNT as usize);
+
+        for invoice in &invoices {
+            assert_eq!(invoice.invoice.state, InvoiceState::Accepted.to_string());
+            assert_eq!(invoice.htlcs.len(), 1);
+            assert_eq!(invoice.htlcs[0].msat, AMOUNT_MSAT as i64);
+            assert_eq!(invoice.htlcs[0].state, InvoiceState::Accepted.to_string());
+        }
+
+        let state_log = helper
+            .get_state_log(
+                &invoices
+                    .iter()
+                    .map(|invoice| invoice.invoice.id)
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+        assert_eq!(state_log.len(), INVOICE_COUNT as usize);
+        for entry in state_log {
+            assert_eq!(entry.old_state, InvoiceState::Unpaid.to_string());
+            assert_eq!(entry.new_state, InvoiceState::Accepted.to_string());
+        }
+    }
+
+    async fn handle_htlc_with(
+        amount_msat: u64,
+        total_msat: Option<u64>,
+        tolerance: u64,
+        overpayment_factor: f64,
+    ) -> (Resolution, bool) {
+        handle_htlc_with_onion_amount(
+            amount_msat,
+            amount_msat,
+            total_msat,
+            tolerance,
+            overpayment_factor,
+        )
+        .await
+    }
+
+    async fn handle_htlc_with_onion_amount(
+        amount_msat: u64,
+        forward_msat: u64,
+        total_msat: Option<u64>,
+        tolerance: u64,
+        overpayment_factor: f64,
+    ) -> (Resolution, bool) {
+        handle_htlc_for_invoice(
+            INVOICE.to_string(),
+            amount_msat,
+            forward_msat,
+            total_msat,
+            tolerance,
+            overpayment_factor,
+        )
+        .await
+    }
+
+    fn amountless_invoice(basic_mpp: bool) -> String {
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let mut payment_secret = [0; 32];
+        payment_secret.copy_from_slice(
+            &hex::decode("f4c2b2acca47e76328b3414f8de1ff5bfb03c335357ded0d6e006281c6f23bfc")
+                .unwrap(),
+        );
+
+        let builder = lightning_invoice::InvoiceBuilder::new(Currency::Regtest)
+            .description("".to_string())
+            .current_timestamp()
+            .payment_hash(sha256::Hash::hash(&[1]))
+            .payment_secret(PaymentSecret(payment_secret))
+            .min_final_cltv_expiry_delta(18);
+        let builder = if basic_mpp {
+            builder.basic_mpp()
+        } else {
+            builder
+        };
+
+        builder
+            .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &secret_key))
+            .unwrap()
+            .to_string()
+    }
+
+    fn invoice_with_amount(preimage: &[u8], amount_msat: u64) -> String {
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+
+        lightning_invoice::InvoiceBuilder::new(Currency::Regtest)
+            .description("".to_string())
+            .current_timestamp()
+            .amount_milli_satoshis(amount_msat)
+            .payment_hash(sha256::Hash::hash(preimage))
+            .payment_secret(PaymentSecret([2; 32]))
+            .min_final_cltv_expiry_delta(18)
+            .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &secret_key))
+            .unwrap()
+            .to_string()
+    }
+
+    async fn handle_htlc_for_invoice(
+        invoice: String,
+        amount_msat: u64,
+        forward_msat: u64,
+        total_msat: Option<u64>,
+        tolerance: u64,
+        overpayment_factor: f64,
+    ) -> (Resolution, bool) {
+        let invoice_decoded = Bolt11Invoice::from_str(&invoice).unwrap();
+        let payment_hash = invoice_decoded.payment_hash()[..].to_vec();
+        let payment_hash_cp = payment_hash.clone();
+
+        let mut helper = MockInvoiceHelper::new();
+        helper.expect_get_by_payment_hash().returning(move |_| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 0,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    bolt11: invoice.clone(),
+                    created_at: Default::default(),
+                    payment_hash: payment_hash_cp.clone(),
+                    hash_algorithm: "sha256".to_string(),
+                    state: InvoiceState::Unpaid.to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        // Sum of the accepted HTLCs as the database would have it
+        let amount_accepted = Arc::new(AtomicU64::new(0));
+        let amount_accepted_cp = amount_accepted.clone();
+        helper.expect_insert_htlc().returning(move |htlc| {
+            if htlc.state == InvoiceState::Accepted.to_string() {
+                amount_accepted_cp.fetch_add(htlc.msat as u64, Ordering::SeqCst);
+            }
+            Ok(0)
+        });
+        let amount_accepted_cp = amount_accepted.clone();
+        helper
+            .expect_sum_htlc_msat()
+            .returning(move |_, _| Ok(amount_accepted_cp.load(Ordering::SeqCst)));
+        helper
+            .expect_count_htlcs()
+            .returning(|_| Ok(HashMap::new()));
+
+        let accepted = Arc::new(AtomicBool::new(false));
+        let accepted_cp = accepted.clone();
+
+        let mut helper_settler = MockInvoiceHelper::new();
+        helper_settler
+            .expect_conditional_accept()
+            .returning(move |_, amount_threshold| {
+                let reached = amount_accepted.load(Ordering::SeqCst) >= amount_threshold;
+                if reached {
+                    accepted_cp.store(true, Ordering::SeqCst);
+                }
+                Ok(reached)
+            });
+
+        let mut handler = Handler::new(
+            helper,
+            Settler::new(helper_settler, 0, 15, 1_000),
+            tolerance,
+            overpayment_factor,
+        );
+
+        let res = handler
+            .htlc_accepted(HtlcCallbackRequest {
+                onion: Onion {
+                    payload: "".to_string(),
+                    type_field: "".to_string(),
+                    forward_msat,
+                    outgoing_cltv_value: 0,
+                    total_msat,
+                    next_onion: "".to_string(),
+                    shared_secret: None,
+                    payment_secret: Some(
+                        "f4c2b2acca47e76328b3414f8de1ff5bfb03c335357ded0d6e006281c6f23bfc"
+                            .to_string(),
+                    ),
+                },
+                htlc: Htlc {
+                    amount_msat,
+                    short_channel_id: "".to_string(),
+                    id: 0,
+                    cltv_expiry: 0,
+                    cltv_expiry_relative: 18,
+                    payment_hash: hex::encode(payment_hash),
+                },
+                forward_to: None,
+            })
+            .await;
+
+        let accepted = accepted.load(Ordering::SeqCst);
+        (res, accepted)
+    }
 }
@@ -1,6 +1,7 @@
 use crate::database::helpers::invoice_helper::InvoiceHelper;
 use crate::encoder::InvoiceEncoder;
 use crate::handler::Resolution;
+use crate::settler::HtlcResolved;
 use crate::State;
 use anyhow::Result;
 use cln_plugin::Plugin;
@@ -40,15 +41,19 @@ pub struct Htlc {
     pub payment_hash: String,
 }
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
 pub enum FailureMessage {
+    #[serde(rename = "0013")]
+    FinalIncorrectHtlcAmount,
     #[serde(rename = "0017")]
     MppTimeout,
+    #[serde(rename = "2002")]
+    TemporaryNodeFailure,
     #[serde(rename = "400F")]
     IncorrectPaymentDetails,
 }
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(tag = "result")]
 pub enum HtlcCallbackResponse {
     #[serde(rename = "continue")]
@@ -82,13 +87,24 @@ where
         return Ok(serde_json::to_value(HtlcCallbackResponse::Continue)?);
     }
 
+    let htlc = HtlcResolved {
+        payment_hash: hex::decode(&args.htlc.payment_hash).unwrap_or_default(),
+        scid: args.htlc.short_channel_id.clone(),
+        channel_id: args.htlc.id,
+        msat: args.htlc.amount_msat,
+    };
+
     let resolution = match plugin.state().handler.clone().htlc_accepted(args).await {
         Resolution::Resolution(res) => res,
-        Resolution::Resolver(solver) => solver.await.unwrap_or_else(|err| {
+        Resolution::Resolver(mut solver) => solver.recv().await.unwrap_or_else(|err| {
             error!("Could not wait for HTLC resolution: {}", err);
             HtlcCallbackResponse::Continue
         }),
     };
 
+    if matches!(resolution, HtlcCallbackResponse::Resolve { .. }) {
+        plugin.state().settler.htlc_resolved(htlc);
+    }
+
     Ok(serde_json::to_value(resolution)?)
 }
@@ -0,0 +1,372 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use cln_plugin::Plugin;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::atomic::{AtomicU8, Ordering};
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::{EnvFilter, Layer};
+
+const PLUGIN_TARGET: &str = env!("CARGO_CRATE_NAME");
+
+static LOG_FORMAT: AtomicU8 = AtomicU8::new(LogFormat::Text as u8);
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LogFormat {
+    Text = 0,
+    Json = 1,
+}
+
+impl TryFrom<&str> for LogFormat {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("invalid log format: {}", value)),
+        }
+    }
+}
+
+pub fn set_format(format: LogFormat) {
+    LOG_FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+fn get_format() -> LogFormat {
+    match LOG_FORMAT.load(Ordering::Relaxed) {
+        1 => LogFormat::Json,
+        _ => LogFormat::Text,
+    }
+}
+
+/// Most verbose level of the log messages of the plugin itself that are forwarded
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl TryFrom<&str> for LogLevel {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, <LogLevel as TryFrom<&str>>::Error> {
+        match value.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(anyhow!("invalid log level: {}", value)),
+        }
+    }
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                LogLevel::Error => "error",
+                LogLevel::Warn => "warn",
+                LogLevel::Info => "info",
+                LogLevel::Debug => "debug",
+                LogLevel::Trace => "trace",
+            }
+        )
+    }
+}
+
+impl From<Level> for LogLevel {
+    fn from(value: Level) -> Self {
+        match value {
+            Level::ERROR => LogLevel::Error,
+            Level::WARN => LogLevel::Warn,
+            Level::INFO => LogLevel::Info,
+            Level::DEBUG => LogLevel::Debug,
+            Level::TRACE => LogLevel::Trace,
+        }
+    }
+}
+
+/// Takes effect for all following log messages
+pub fn set_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn get_level() -> LogLevel {
+    match LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Error,
+        1 => LogLevel::Warn,
+        3 => LogLevel::Debug,
+        4 => LogLevel::Trace,
+        _ => LogLevel::Info,
+    }
+}
+
+// Messages of dependencies are filtered by CLN_PLUGIN_LOG only
+fn is_enabled(target: &str, level: Level) -> bool {
+    let is_plugin = target
+        .strip_prefix(PLUGIN_TARGET)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"));
+    !is_plugin || LogLevel::from(level) as u8 <= get_level() as u8
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct LogEntry {
+    level: &'static str,
+    message: String,
+}
+
+/// Installs the global tracing subscriber and bridges calls of the `log` crate to it.
+/// Log entries are buffered in the returned channel until they are forwarded to
+/// lightningd with [`forward`]
+pub fn init() -> Result<mpsc::UnboundedReceiver<LogEntry>> {
+    tracing_log::LogTracer::init()?;
+
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .with_env_var("CLN_PLUGIN_LOG")
+        .from_env_lossy();
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(LoggingLayer { sender }),
+    )?;
+
+    Ok(receiver)
+}
+
+pub fn forward<S>(plugin: Plugin<S>, mut receiver: mpsc::UnboundedReceiver<LogEntry>)
+where
+    S: Clone + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        while let Some(entry) = receiver.recv().await {
+            let entry = match serde_json::to_value(entry) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+
+            if plugin
+                .send_custom_notification("log".to_string(), entry)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+}
+
+struct LoggingLayer {
+    sender: mpsc::UnboundedSender<LogEntry>,
+}
+
+impl<S> Layer<S> for LoggingLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if !is_enabled(metadata.target(), *metadata.level()) {
+            return;
+        }
+
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+
+        let level = *metadata.level();
+        let _ = self.sender.send(LogEntry {
+            level: level_name(level),
+            message: format_entry(get_format(), level, visitor),
+        });
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::ERROR => "error",
+        Level::WARN => "warn",
+        Level::INFO => "info",
+        Level::DEBUG | Level::TRACE => "debug",
+    }
+}
+
+fn format_entry(format: LogFormat, level: Level, visitor: FieldVisitor) -> String {
+    match format {
+        LogFormat::Text => {
+            let mut res = visitor.message.unwrap_or_default();
+            for (key, value) in visitor.fields {
+                let value = match value {
+                    Value::String(value) => value,
+                    value => value.to_string(),
+                };
+                res.push_str(&format!(" {}={}", key, value));
+            }
+
+            res
+        }
+        LogFormat::Json => {
+            let mut res = Map::new();
+            res.insert(
+                "timestamp".to_string(),
+                Value::from(Utc::now().to_rfc3339()),
+            );
+            res.insert("level".to_string(), Value::from(level_name(level)));
+            res.insert(
+                "message".to_string(),
+                Value::from(visitor.message.unwrap_or_default()),
+            );
+            res.extend(visitor.fields);
+
+            Value::Object(res).to_string()
+        }
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: Option<String>,
+    fields: Map<String, Value>,
+}
+
+impl FieldVisitor {
+    fn record(&mut self, field: &Field, value: Value) {
+        match field.name() {
+            "message" => {
+                self.message = Some(match value {
+                    Value::String(value) => value,
+                    value => value.to_string(),
+                })
+            }
+            // Metadata added by the bridge of the log crate
+            name if name.starts_with("log.") => {}
+            name => {
+                self.fields.insert(name.to_string(), value);
+            }
+        }
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record(field, Value::from(format!("{:?}", value)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::logging::{
+        format_entry, get_level, set_level, FieldVisitor, LogFormat, LogLevel, LoggingLayer,
+    };
+    use serde_json::{Map, Value};
+    use tokio::sync::mpsc;
+    use tracing::Level;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn visitor() -> FieldVisitor {
+        let mut fields = Map::new();
+        fields.insert("payment_hash".to_string(), Value::from("0011"));
+        fields.insert("htlc_count".to_string(), Value::from(2));
+
+        FieldVisitor {
+            fields,
+            message: Some("Accepted hold invoice".to_string()),
+        }
+    }
+
+    #[test]
+    fn log_format_from_str() {
+        assert_eq!(LogFormat::try_from("text").unwrap(), LogFormat::Text);
+        assert_eq!(LogFormat::try_from("JSON").unwrap(), LogFormat::Json);
+        assert_eq!(
+            LogFormat::try_from("yaml").err().unwrap().to_string(),
+            "invalid log format: yaml"
+        );
+    }
+
+    #[test]
+    fn format_text() {
+        assert_eq!(
+            format_entry(LogFormat::Text, Level::INFO, visitor()),
+            "Accepted hold invoice payment_hash=0011 htlc_count=2"
+        );
+    }
+
+    #[test]
+    fn format_json() {
+        let entry: Value =
+            serde_json::from_str(&format_entry(LogFormat::Json, Level::WARN, visitor())).unwrap();
+
+        assert!(entry["timestamp"].is_string());
+        assert_eq!(entry["level"], "warn");
+        assert_eq!(entry["message"], "Accepted hold invoice");
+        assert_eq!(entry["payment_hash"], "0011");
+        assert_eq!(entry["htlc_count"], 2);
+    }
+
+    #[test]
+    fn log_level_from_str() {
+        assert_eq!(LogLevel::try_from("warn").unwrap(), LogLevel::Warn);
+        assert_eq!(LogLevel::try_from("TRACE").unwrap(), LogLevel::Trace);
+        assert_eq!(LogLevel::Debug.to_string(), "debug");
+        assert_eq!(
+            LogLevel::try_from("verbose").err().unwrap().to_string(),
+            "invalid log level: verbose"
+        );
+    }
+
+    #[test]
+    fn log_level_filter() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let subscriber = tracing_subscriber::registry().with(LoggingLayer { sender });
+
+        let previous = get_level();
+        set_level(LogLevel::Warn);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!("filtered");
+            tracing::warn!("forwarded");
+            // Only messages of the plugin are affected
+            tracing::debug!(target: "cln_plugin", "dependency");
+        });
+        set_level(previous);
+
+        let messages = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|entry| entry.message)
+            .collect::<Vec<_>>();
+        assert_eq!(messages, vec!["forwarded", "dependency"]);
+    }
+}
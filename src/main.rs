@@ -1,5 +1,18 @@
-use crate::config::{OPTION_DATABASE, OPTION_GRPC_HOST, OPTION_GRPC_PORT, OPTION_MPP_TIMEOUT};
-use crate::encoder::Encoder;
+use crate::cleaner::Cleaner;
+use crate::config::{
+    OPTION_AUTO_CLEAN_AGE, OPTION_AUTO_CLEAN_INTERVAL, OPTION_AUTO_CLEAN_PAID,
+    OPTION_AUTO_CLEAN_PAID_AGE, OPTION_CLN_RPC_POOL_SIZE, OPTION_DATABASE,
+    OPTION_DATABASE_BUSY_TIMEOUT, OPTION_DATABASE_CACHE_TTL, OPTION_DATABASE_CONNECT_TIMEOUT,
+    OPTION_DATABASE_MIN_IDLE, OPTION_DATABASE_POOL_SIZE, OPTION_GRPC_BIND_BOTH,
+    OPTION_GRPC_CONCURRENT_STREAMS, OPTION_GRPC_GLOBAL_MAX_STREAMS, OPTION_GRPC_HOST,
+    OPTION_GRPC_KEEPALIVE_TIME, OPTION_GRPC_KEEPALIVE_TIMEOUT, OPTION_GRPC_MAX_RECV_MESSAGE_SIZE,
+    OPTION_GRPC_MAX_SEND_MESSAGE_SIZE, OPTION_GRPC_PORT, OPTION_GRPC_RATE_LIMIT_INVOICES,
+    OPTION_GRPC_TCP_KEEPALIVE, OPTION_INVOICE_DESCRIPTION_DEFAULT, OPTION_LOG_FORMAT,
+    OPTION_LOG_LEVEL, OPTION_MAX_PENDING_HTLCS, OPTION_METRICS_PORT, OPTION_MPP_CHECK_INTERVAL,
+    OPTION_MPP_TIMEOUT, OPTION_OVERPAYMENT_FACTOR, OPTION_TIME_EXPIRY_CANCEL,
+    OPTION_UNDERPAYMENT_TOLERANCE, OPTION_WEBHOOK_SECRET, OPTION_WEBHOOK_URL,
+};
+use crate::encoder::{Encoder, MAX_DESCRIPTION_LENGTH};
 use crate::handler::Handler;
 use crate::settler::Settler;
 use anyhow::Result;
@@ -7,8 +20,10 @@ use cln_plugin::{Builder, RpcMethodBuilder};
 use log::{debug, error, info, warn};
 use std::fs;
 use std::path::Path;
+use std::time::{Duration, Instant};
 use tokio_util::sync::CancellationToken;
 
+mod cleaner;
 mod commands;
 mod config;
 mod database;
@@ -16,8 +31,11 @@ mod encoder;
 mod grpc;
 mod handler;
 mod hooks;
+mod logging;
+mod metrics;
 mod settler;
 mod utils;
+mod webhook;
 
 #[derive(Clone)]
 struct State<T, E> {
@@ -25,6 +43,7 @@ struct State<T, E> {
     settler: Settler<T>,
     encoder: E,
     invoice_helper: T,
+    stream_limiter: grpc::stream_limiter::StreamLimiter,
 }
 
 #[tokio::main]
@@ -33,6 +52,7 @@ async fn main() -> Result<()> {
         "CLN_PLUGIN_LOG",
         "cln_plugin=trace,hold=trace,debug,info,warn,error",
     );
+    let log_receiver = logging::init()?;
 
     info!(
         "Starting plugin {}-{}{}",
@@ -46,36 +66,125 @@ async fn main() -> Result<()> {
     );
 
     let plugin = match Builder::new(tokio::io::stdin(), tokio::io::stdout())
+        .with_logging(false)
         .dynamic()
         .option(OPTION_DATABASE)
+        .option(OPTION_DATABASE_BUSY_TIMEOUT)
+        .option(OPTION_DATABASE_POOL_SIZE)
+        .option(OPTION_DATABASE_MIN_IDLE)
+        .option(OPTION_DATABASE_CONNECT_TIMEOUT)
+        .option(OPTION_DATABASE_CACHE_TTL)
         .option(OPTION_MPP_TIMEOUT)
+        .option(OPTION_MPP_CHECK_INTERVAL)
+        .option(OPTION_UNDERPAYMENT_TOLERANCE)
+        .option(OPTION_OVERPAYMENT_FACTOR)
+        .option(OPTION_MAX_PENDING_HTLCS)
+        .option(OPTION_AUTO_CLEAN_INTERVAL)
+        .option(OPTION_AUTO_CLEAN_AGE)
+        .option(OPTION_AUTO_CLEAN_PAID)
+        .option(OPTION_AUTO_CLEAN_PAID_AGE)
+        .option(OPTION_CLN_RPC_POOL_SIZE)
+        .option(OPTION_INVOICE_DESCRIPTION_DEFAULT)
         .option(OPTION_GRPC_HOST)
+        .option(OPTION_GRPC_BIND_BOTH)
         .option(OPTION_GRPC_PORT)
+        .option(OPTION_GRPC_MAX_SEND_MESSAGE_SIZE)
+        .option(OPTION_GRPC_MAX_RECV_MESSAGE_SIZE)
+        .option(OPTION_GRPC_RATE_LIMIT_INVOICES)
+        .option(OPTION_GRPC_KEEPALIVE_TIME)
+        .option(OPTION_GRPC_KEEPALIVE_TIMEOUT)
+        .option(OPTION_GRPC_TCP_KEEPALIVE)
+        .option(OPTION_GRPC_CONCURRENT_STREAMS)
+        .option(OPTION_GRPC_GLOBAL_MAX_STREAMS)
+        .option(OPTION_METRICS_PORT)
+        .option(OPTION_LOG_FORMAT)
+        .option(OPTION_LOG_LEVEL)
+        .option(OPTION_WEBHOOK_URL)
+        .option(OPTION_WEBHOOK_SECRET)
+        .option(OPTION_TIME_EXPIRY_CANCEL)
         .hook("htlc_accepted", hooks::htlc_accepted)
         .rpcmethod_from_builder(
             RpcMethodBuilder::new("listholdinvoices", commands::list_invoices)
                 .description("Lists hold invoices")
-                .usage("[payment_hash] [bolt11]"),
+                .usage(
+                    "[payment_hash] [bolt11] [state] [from] [to] [label] [sort_by] [sort_desc] [scid] [preimage]",
+                ),
         )
         .rpcmethod_from_builder(
             RpcMethodBuilder::new("holdinvoice", commands::invoice)
                 .description("Creates a new hold invoice")
-                .usage("payment_hash amount"),
+                .usage(
+                    "payment_hash amount [description] [expiry] [min_cltv] [mpp_timeout] [label] [routing_hints]",
+                ),
         )
         .rpcmethod_from_builder(
             RpcMethodBuilder::new("settleholdinvoice", commands::settle)
                 .description("Settles a hold invoice")
                 .usage("preimage"),
         )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("batchsettleholdinvoices", commands::batch_settle)
+                .description("Settles multiple hold invoices")
+                .usage("preimages"),
+        )
         .rpcmethod_from_builder(
             RpcMethodBuilder::new("cancelholdinvoice", commands::cancel)
                 .description("Cancels a hold invoice")
+                .usage("[payment_hash] [invoice] [reason]"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("batchcancelholdinvoices", commands::batch_cancel)
+                .description("Cancels multiple hold invoices")
+                .usage("payment_hashes"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("pauseholdinvoice", commands::pause)
+                .description("Rejects new HTLCs of an unpaid hold invoice until it is resumed")
+                .usage("payment_hash"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("resumeholdinvoice", commands::resume)
+                .description("Accepts HTLCs of a paused hold invoice again")
                 .usage("payment_hash"),
         )
         .rpcmethod_from_builder(
             RpcMethodBuilder::new("cleanholdinvoices", commands::clean)
-                .description("Cleans canceled hold invoices")
-                .usage("[age]"),
+                .description("Cleans cancelled or paid hold invoices")
+                .usage("[age] [state] [dry_run]"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("deleteholdinvoice", commands::delete)
+                .description("Deletes an unpaid or cancelled hold invoice")
+                .usage("payment_hash"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("holdinvoicewait", commands::wait)
+                .description("Waits until a hold invoice is settled or cancelled")
+                .usage("payment_hash [timeout]"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("holdinvoicestats", commands::stats)
+                .description("Returns aggregate statistics of hold invoices")
+                .usage("[payment_hash]"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("holdaccounting", commands::accounting)
+                .description("Returns the volume of paid HTLCs in total and by day")
+                .usage("[from] [to]"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("setholdinvoiceoption", commands::set_option)
+                .description("Changes an option of hold at runtime")
+                .usage("option_name value"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("holdloglevel", commands::log_level)
+                .description("Changes the level of the log messages of hold")
+                .usage("level"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("holdstatus", commands::status)
+                .description("Returns a summary of the HTLCs that are held right now"),
         )
         .configure()
         .await?
@@ -84,6 +193,38 @@ async fn main() -> Result<()> {
         None => return Ok(()),
     };
 
+    match plugin.option(&OPTION_LOG_FORMAT) {
+        Ok(format) => match logging::LogFormat::try_from(format.as_str()) {
+            Ok(format) => logging::set_format(format),
+            Err(err) => {
+                plugin.disable(err.to_string().as_str()).await?;
+                return Ok(());
+            }
+        },
+        Err(err) => {
+            plugin
+                .disable(format!("invalid log format: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    match plugin.option(&OPTION_LOG_LEVEL) {
+        Ok(level) => match logging::LogLevel::try_from(level.as_str()) {
+            Ok(level) => logging::set_level(level),
+            Err(err) => {
+                plugin.disable(err.to_string().as_str()).await?;
+                return Ok(());
+            }
+        },
+        Err(err) => {
+            plugin
+                .disable(format!("invalid log level: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
     let db_url = match plugin.option(&OPTION_DATABASE) {
         Ok(host) => host,
         Err(err) => {
@@ -94,6 +235,108 @@ async fn main() -> Result<()> {
         }
     };
 
+    let db_busy_timeout = match plugin.option(&OPTION_DATABASE_BUSY_TIMEOUT) {
+        Ok(timeout) => {
+            if timeout < 0 {
+                plugin
+                    .disable("database busy timeout has to be positive")
+                    .await?;
+                return Ok(());
+            }
+
+            Duration::from_millis(timeout as u64)
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid database busy timeout: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let db_pool_size = match plugin.option(&OPTION_DATABASE_POOL_SIZE) {
+        Ok(size) => {
+            if size < 1 {
+                plugin
+                    .disable("database pool size has to be at least 1")
+                    .await?;
+                return Ok(());
+            }
+
+            size as u32
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid database pool size: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let db_min_idle = match plugin.option(&OPTION_DATABASE_MIN_IDLE) {
+        Ok(min_idle) => {
+            if min_idle < 0 {
+                plugin
+                    .disable("database min idle connections has to be positive")
+                    .await?;
+                return Ok(());
+            }
+
+            if min_idle > db_pool_size as i64 {
+                plugin
+                    .disable("database min idle connections cannot be more than the pool size")
+                    .await?;
+                return Ok(());
+            }
+
+            min_idle as u32
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid database min idle connections: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let db_connect_timeout = match plugin.option(&OPTION_DATABASE_CONNECT_TIMEOUT) {
+        Ok(timeout) => {
+            if timeout < 1 {
+                plugin
+                    .disable("database connect timeout has to be at least 1 second")
+                    .await?;
+                return Ok(());
+            }
+
+            Duration::from_secs(timeout as u64)
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid database connect timeout: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let db_cache_ttl = match plugin.option(&OPTION_DATABASE_CACHE_TTL) {
+        Ok(ttl) => {
+            if ttl < 0 {
+                plugin
+                    .disable("database cache TTL has to be positive")
+                    .await?;
+                return Ok(());
+            }
+
+            Duration::from_secs(ttl as u64)
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid database cache TTL: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
     let mut mpp_timeout = match plugin.option(&OPTION_MPP_TIMEOUT) {
         Ok(timeout) => {
             if timeout < 0 {
@@ -111,6 +354,155 @@ async fn main() -> Result<()> {
         }
     };
 
+    let mut mpp_check_interval = match plugin.option(&OPTION_MPP_CHECK_INTERVAL) {
+        Ok(interval) => {
+            if interval < 1 {
+                plugin
+                    .disable("MPP check interval has to be at least 1 second")
+                    .await?;
+                return Ok(());
+            }
+
+            interval as u64
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid MPP check interval: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let underpayment_tolerance = match plugin.option(&OPTION_UNDERPAYMENT_TOLERANCE) {
+        Ok(tolerance) => {
+            if tolerance < 0 {
+                plugin
+                    .disable("underpayment tolerance has to be positive")
+                    .await?;
+                return Ok(());
+            }
+
+            tolerance as u64
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid underpayment tolerance: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let overpayment_factor = match plugin.option(&OPTION_OVERPAYMENT_FACTOR) {
+        Ok(factor) => match factor.parse::<f64>() {
+            Ok(factor) => {
+                if factor.is_nan() || factor < 1.0 {
+                    plugin
+                        .disable("overpayment factor has to be at least 1.0")
+                        .await?;
+                    return Ok(());
+                }
+
+                factor
+            }
+            Err(err) => {
+                plugin
+                    .disable(format!("invalid overpayment factor: {}", err).as_str())
+                    .await?;
+                return Ok(());
+            }
+        },
+        Err(err) => {
+            plugin
+                .disable(format!("invalid overpayment factor: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let max_pending_htlcs = match plugin.option(&OPTION_MAX_PENDING_HTLCS) {
+        Ok(max) => {
+            if max < 1 {
+                plugin
+                    .disable("maximal number of pending HTLCs has to be at least 1")
+                    .await?;
+                return Ok(());
+            }
+
+            max as usize
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid maximal number of pending HTLCs: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let auto_clean_interval = match plugin.option(&OPTION_AUTO_CLEAN_INTERVAL) {
+        Ok(interval) => {
+            if interval < 0 {
+                plugin
+                    .disable("auto clean interval has to be positive")
+                    .await?;
+                return Ok(());
+            }
+
+            interval as u64
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid auto clean interval: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let auto_clean_age = match plugin.option(&OPTION_AUTO_CLEAN_AGE) {
+        Ok(age) => {
+            if age < 0 {
+                plugin.disable("auto clean age has to be positive").await?;
+                return Ok(());
+            }
+
+            age as u64
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid auto clean age: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let auto_clean_paid = match plugin.option(&OPTION_AUTO_CLEAN_PAID) {
+        Ok(enabled) => enabled,
+        Err(err) => {
+            plugin
+                .disable(format!("invalid auto clean paid: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let auto_clean_paid_age = match plugin.option(&OPTION_AUTO_CLEAN_PAID_AGE) {
+        Ok(age) => {
+            if age < 0 {
+                plugin
+                    .disable("auto clean paid age has to be positive")
+                    .await?;
+                return Ok(());
+            }
+
+            age as u64
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid auto clean paid age: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
     let grpc_host = match plugin.option(&OPTION_GRPC_HOST) {
         Ok(host) => host,
         Err(err) => {
@@ -121,6 +513,16 @@ async fn main() -> Result<()> {
         }
     };
 
+    let grpc_bind_both = match plugin.option(&OPTION_GRPC_BIND_BOTH) {
+        Ok(enabled) => enabled,
+        Err(err) => {
+            plugin
+                .disable(format!("invalid gRPC bind both: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
     let grpc_port = match plugin.option(&OPTION_GRPC_PORT) {
         Ok(port) => port,
         Err(err) => {
@@ -131,6 +533,190 @@ async fn main() -> Result<()> {
         }
     };
 
+    let grpc_max_send_message_size = match plugin.option(&OPTION_GRPC_MAX_SEND_MESSAGE_SIZE) {
+        Ok(size) => {
+            if size < 0 {
+                plugin
+                    .disable("gRPC max send message size has to be positive")
+                    .await?;
+                return Ok(());
+            }
+
+            size as usize
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid gRPC max send message size: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let grpc_max_recv_message_size = match plugin.option(&OPTION_GRPC_MAX_RECV_MESSAGE_SIZE) {
+        Ok(size) => {
+            if size < 0 {
+                plugin
+                    .disable("gRPC max receive message size has to be positive")
+                    .await?;
+                return Ok(());
+            }
+
+            size as usize
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid gRPC max receive message size: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let mut grpc_keepalive = grpc::server::Keepalive::default();
+    for (option, name, value) in [
+        (
+            &OPTION_GRPC_KEEPALIVE_TIME,
+            "gRPC keepalive time",
+            &mut grpc_keepalive.http2_interval,
+        ),
+        (
+            &OPTION_GRPC_KEEPALIVE_TIMEOUT,
+            "gRPC keepalive timeout",
+            &mut grpc_keepalive.http2_timeout,
+        ),
+        (
+            &OPTION_GRPC_TCP_KEEPALIVE,
+            "gRPC TCP keepalive",
+            &mut grpc_keepalive.tcp,
+        ),
+    ] {
+        match plugin.option(option) {
+            Ok(seconds) => {
+                if seconds < 0 {
+                    plugin
+                        .disable(format!("{} has to be positive", name).as_str())
+                        .await?;
+                    return Ok(());
+                }
+
+                *value = (seconds > 0).then(|| Duration::from_secs(seconds as u64));
+            }
+            Err(err) => {
+                plugin
+                    .disable(format!("invalid {}: {}", name, err).as_str())
+                    .await?;
+                return Ok(());
+            }
+        }
+    }
+
+    let grpc_rate_limit_invoices = match plugin.option(&OPTION_GRPC_RATE_LIMIT_INVOICES) {
+        Ok(limit) => {
+            if limit < 0 {
+                plugin
+                    .disable("gRPC invoice rate limit has to be positive")
+                    .await?;
+                return Ok(());
+            }
+
+            limit as u64
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid gRPC invoice rate limit: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let grpc_concurrent_streams = match plugin.option(&OPTION_GRPC_CONCURRENT_STREAMS) {
+        Ok(limit) => {
+            if limit < 0 {
+                plugin
+                    .disable("gRPC concurrent streams have to be positive")
+                    .await?;
+                return Ok(());
+            }
+
+            limit as usize
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid gRPC concurrent streams: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let grpc_global_max_streams = match plugin.option(&OPTION_GRPC_GLOBAL_MAX_STREAMS) {
+        Ok(limit) => {
+            if limit < 0 {
+                plugin
+                    .disable("gRPC global max streams have to be positive")
+                    .await?;
+                return Ok(());
+            }
+
+            limit as u64
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid gRPC global max streams: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let metrics_port = match plugin.option(&OPTION_METRICS_PORT) {
+        Ok(port) => port,
+        Err(err) => {
+            plugin
+                .disable(format!("invalid metrics port: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let time_expiry_cancel = match plugin.option(&OPTION_TIME_EXPIRY_CANCEL) {
+        Ok(enabled) => enabled,
+        Err(err) => {
+            plugin
+                .disable(format!("invalid time expiry cancel: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let webhook = match plugin.option(&OPTION_WEBHOOK_URL) {
+        Ok(Some(url)) => {
+            let secret = match plugin.option(&OPTION_WEBHOOK_SECRET) {
+                Ok(secret) => secret,
+                Err(err) => {
+                    plugin
+                        .disable(format!("invalid webhook secret: {}", err).as_str())
+                        .await?;
+                    return Ok(());
+                }
+            };
+
+            match webhook::Webhook::new(url, secret) {
+                Ok(webhook) => Some(webhook),
+                Err(err) => {
+                    plugin
+                        .disable(format!("could not create webhook client: {}", err).as_str())
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+        Ok(None) => None,
+        Err(err) => {
+            plugin
+                .disable(format!("invalid webhook URL: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
     let config = plugin.configuration();
 
     let plugin_dir = Path::new(config.lightning_dir.as_str()).join("hold");
@@ -138,7 +724,21 @@ async fn main() -> Result<()> {
         fs::create_dir(plugin_dir)?;
     }
 
-    let db = match database::connect(&db_url) {
+    info!(
+        "Database pool size {}, minimal idle connections {}, connect timeout {} seconds",
+        db_pool_size,
+        db_min_idle,
+        db_connect_timeout.as_secs()
+    );
+    let db = match database::connect(
+        &db_url,
+        &database::PoolOptions {
+            max_size: db_pool_size,
+            min_idle: db_min_idle,
+            connection_timeout: db_connect_timeout,
+            busy_timeout: db_busy_timeout,
+        },
+    ) {
         Ok(db) => db,
         Err(err) => {
             plugin
@@ -148,7 +748,58 @@ async fn main() -> Result<()> {
         }
     };
 
-    let encoder = match Encoder::new(&config.rpc_file, &config.network).await {
+    let cln_rpc_pool_size = match plugin.option(&OPTION_CLN_RPC_POOL_SIZE) {
+        Ok(size) => {
+            if size < 1 {
+                plugin
+                    .disable("CLN RPC pool size has to be at least 1")
+                    .await?;
+                return Ok(());
+            }
+
+            size as usize
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid CLN RPC pool size: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let default_description = match plugin.option(&OPTION_INVOICE_DESCRIPTION_DEFAULT) {
+        Ok(description) => {
+            if description.len() > MAX_DESCRIPTION_LENGTH {
+                plugin
+                    .disable(
+                        format!(
+                            "default invoice description has to be at most {} bytes long",
+                            MAX_DESCRIPTION_LENGTH
+                        )
+                        .as_str(),
+                    )
+                    .await?;
+                return Ok(());
+            }
+
+            description
+        }
+        Err(err) => {
+            plugin
+                .disable(format!("invalid default invoice description: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let encoder = match Encoder::new(
+        &config.rpc_file,
+        &config.network,
+        cln_rpc_pool_size,
+        default_description,
+    )
+    .await
+    {
         Ok(res) => res,
         Err(err) => {
             plugin
@@ -158,38 +809,141 @@ async fn main() -> Result<()> {
         }
     };
 
+    let node_info = match encoder.node_id().await {
+        Ok(node_id) => grpc::service::NodeInfo {
+            node_id,
+            network: config.network.clone(),
+            started_at: Instant::now(),
+        },
+        Err(err) => {
+            plugin
+                .disable(format!("could not get node id: {}", err).as_str())
+                .await?;
+            return Ok(());
+        }
+    };
+
     let is_regtest = config.network == "regtest";
 
     if is_regtest {
         mpp_timeout = 10;
         warn!("Using MPP timeout of {} seconds on regtest", mpp_timeout);
+
+        mpp_check_interval = 1;
+        warn!(
+            "Checking for MPP timeouts every {} second on regtest",
+            mpp_check_interval
+        );
     }
 
-    let invoice_helper = database::helpers::invoice_helper::InvoiceHelperDatabase::new(db);
-    let mut settler = Settler::new(invoice_helper.clone(), mpp_timeout);
+    let invoice_helper = database::helpers::invoice_helper::InvoiceHelperDatabase::new(db.clone())
+        .cache_ttl(db_cache_ttl);
+    let mut settler = Settler::new(
+        invoice_helper.clone(),
+        mpp_timeout,
+        mpp_check_interval,
+        max_pending_htlcs,
+    );
+
+    let stream_limiter = grpc::stream_limiter::StreamLimiter::new(grpc_global_max_streams);
 
     let plugin = plugin
         .start(State {
+            stream_limiter: stream_limiter.clone(),
             encoder: encoder.clone(),
             settler: settler.clone(),
             invoice_helper: invoice_helper.clone(),
-            handler: Handler::new(invoice_helper.clone(), settler.clone()),
+            handler: Handler::new(
+                invoice_helper.clone(),
+                settler.clone(),
+                underpayment_tolerance,
+                overpayment_factor,
+            ),
         })
         .await?;
+    logging::forward(plugin.clone(), log_receiver);
 
     let cancellation_token = CancellationToken::new();
 
     let grpc_server = grpc::server::Server::new(
         &grpc_host,
         grpc_port,
+        grpc_max_send_message_size,
+        grpc_max_recv_message_size,
+        grpc_rate_limit_invoices,
+        grpc_keepalive,
+        grpc_concurrent_streams,
+        stream_limiter,
         is_regtest,
+        grpc_bind_both,
+        node_info,
         cancellation_token.clone(),
         std::env::current_dir()?.join(utils::built_info::PKG_NAME),
-        invoice_helper,
+        invoice_helper.clone(),
         encoder,
         settler.clone(),
     );
 
+    let metrics_server = metrics::Server::new(
+        &grpc_host,
+        metrics_port,
+        cancellation_token.clone(),
+        invoice_helper.clone(),
+        settler.clone(),
+    );
+
+    let database_monitor = database::monitor::Monitor::new(
+        invoice_helper.clone(),
+        db,
+        database::monitor::PING_INTERVAL,
+    );
+    let monitor_token = cancellation_token.clone();
+    tokio::spawn(async move {
+        database_monitor.start(monitor_token).await;
+    });
+
+    if auto_clean_interval > 0 {
+        let cleaner = Cleaner::new(
+            invoice_helper,
+            Duration::from_secs(auto_clean_interval),
+            auto_clean_age,
+            auto_clean_paid.then_some(auto_clean_paid_age),
+        );
+        let cleaner_token = cancellation_token.clone();
+        tokio::spawn(async move {
+            cleaner.start(cleaner_token).await;
+        });
+    }
+
+    tokio::spawn(async move {
+        if let Err(err) = metrics_server.start().await {
+            error!("Could not start metrics server: {}", err);
+        }
+    });
+
+    if let Some(webhook) = webhook {
+        let state_rx = settler.state_rx();
+        tokio::spawn(async move {
+            webhook.listen(state_rx).await;
+        });
+    }
+
+    if let Err(err) = settler.recover_from_database() {
+        error!("Could not recover accepted invoices: {}", err);
+    }
+
+    let mut deadline_settler = settler.clone();
+    tokio::spawn(async move {
+        deadline_settler.settlement_deadline_loop().await;
+    });
+
+    if time_expiry_cancel {
+        let mut expiry_settler = settler.clone();
+        tokio::spawn(async move {
+            expiry_settler.time_expiry_loop().await;
+        });
+    }
+
     tokio::spawn(async move {
         settler.mpp_timeout_loop().await;
     });
@@ -0,0 +1,268 @@
+use crate::database::helpers::invoice_helper::InvoiceHelper;
+use crate::database::model::InvoiceState;
+use crate::settler::Settler;
+use anyhow::Result;
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub invoices_created: AtomicU64,
+    pub invoices_settled: AtomicU64,
+    pub invoices_cancelled: AtomicU64,
+    pub htlcs_accepted: AtomicU64,
+    pub htlcs_cancelled: AtomicU64,
+    pub mpp_timeouts: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            invoices_created: AtomicU64::new(0),
+            invoices_settled: AtomicU64::new(0),
+            invoices_cancelled: AtomicU64::new(0),
+            htlcs_accepted: AtomicU64::new(0),
+            htlcs_cancelled: AtomicU64::new(0),
+            mpp_timeouts: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        Self::add(counter, 1);
+    }
+
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn encode(
+        &self,
+        invoices_pending: u64,
+        invoices_by_state: Option<HashMap<InvoiceState, u64>>,
+    ) -> String {
+        let mut res = String::new();
+
+        for (name, help, value) in [
+            (
+                "hold_invoices_created_total",
+                "Number of created hold invoices",
+                &self.invoices_created,
+            ),
+            (
+                "hold_invoices_settled_total",
+                "Number of settled hold invoices",
+                &self.invoices_settled,
+            ),
+            (
+                "hold_invoices_cancelled_total",
+                "Number of cancelled hold invoices",
+                &self.invoices_cancelled,
+            ),
+            (
+                "hold_htlcs_accepted_total",
+                "Number of accepted HTLCs",
+                &self.htlcs_accepted,
+            ),
+            (
+                "hold_htlcs_cancelled_total",
+                "Number of cancelled HTLCs",
+                &self.htlcs_cancelled,
+            ),
+            (
+                "hold_mpp_timeouts_total",
+                "Number of HTLCs cancelled because of MPP timeouts",
+                &self.mpp_timeouts,
+            ),
+        ] {
+            Self::write_metric(
+                &mut res,
+                name,
+                help,
+                "counter",
+                value.load(Ordering::Relaxed),
+            );
+        }
+
+        Self::write_metric(
+            &mut res,
+            "hold_invoices_pending",
+            "Number of hold invoices with pending HTLCs",
+            "gauge",
+            invoices_pending,
+        );
+
+        if let Some(invoices_by_state) = invoices_by_state {
+            let _ = writeln!(res, "# HELP hold_invoices Number of hold invoices by state");
+            let _ = writeln!(res, "# TYPE hold_invoices gauge");
+
+            let mut invoices_by_state = invoices_by_state.into_iter().collect::<Vec<_>>();
+            invoices_by_state.sort_by_key(|(state, _)| state.to_string());
+            for (state, count) in invoices_by_state {
+                let _ = writeln!(res, "hold_invoices{{state=\"{}\"}} {}", state, count);
+            }
+        }
+
+        res
+    }
+
+    fn write_metric(res: &mut String, name: &str, help: &str, metric_type: &str, value: u64) {
+        let _ = writeln!(res, "# HELP {} {}", name, help);
+        let _ = writeln!(res, "# TYPE {} {}", name, metric_type);
+        let _ = writeln!(res, "{} {}", name, value);
+    }
+}
+
+pub struct Server<T> {
+    host: String,
+    port: i64,
+    cancellation_token: CancellationToken,
+    invoice_helper: T,
+    settler: Settler<T>,
+}
+
+impl<T> Server<T>
+where
+    T: InvoiceHelper + Sync + Send + Clone + 'static,
+{
+    pub fn new(
+        host: &str,
+        port: i64,
+        cancellation_token: CancellationToken,
+        invoice_helper: T,
+        settler: Settler<T>,
+    ) -> Self {
+        Self {
+            port,
+            settler,
+            invoice_helper,
+            cancellation_token,
+            host: host.to_string(),
+        }
+    }
+
+    pub async fn start(&self) -> Result<()> {
+        if self.port == -1 {
+            info!("Not starting metrics server");
+            return Ok(());
+        }
+
+        let socket_addr = SocketAddr::new(IpAddr::from_str(&self.host)?, self.port as u16);
+        let listener = TcpListener::bind(socket_addr).await?;
+        info!("Started metrics server on: {}", socket_addr);
+
+        loop {
+            let stream = tokio::select! {
+                res = listener.accept() => res?.0,
+                _ = self.cancellation_token.cancelled() => {
+                    info!("Shutting down metrics server");
+                    return Ok(());
+                }
+            };
+
+            let settler = self.settler.clone();
+            let invoice_helper = self.invoice_helper.clone();
+            tokio::spawn(async move {
+                if let Err(err) = http1::Builder::new()
+                    .serve_connection(
+                        TokioIo::new(stream),
+                        service_fn(move |req| {
+                            Self::handle(req, invoice_helper.clone(), settler.clone())
+                        }),
+                    )
+                    .await
+                {
+                    debug!("Could not serve metrics request: {}", err);
+                }
+            });
+        }
+    }
+
+    async fn handle(
+        req: Request<Incoming>,
+        invoice_helper: T,
+        settler: Settler<T>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        if req.uri().path() != "/metrics" {
+            let mut res = Response::new(Full::new(Bytes::from("not found")));
+            *res.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(res);
+        }
+
+        let invoices_by_state = match invoice_helper.count_by_state() {
+            Ok(res) => Some(res),
+            Err(err) => {
+                warn!("Could not count invoices by state: {}", err);
+                None
+            }
+        };
+
+        let mut res = Response::new(Full::new(Bytes::from(
+            METRICS.encode(settler.pending_count().await as u64, invoices_by_state),
+        )));
+        res.headers_mut()
+            .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::model::InvoiceState;
+    use crate::metrics::Metrics;
+    use std::collections::HashMap;
+
+    #[test]
+    fn encode() {
+        let metrics = Metrics::new();
+        Metrics::inc(&metrics.invoices_created);
+        Metrics::inc(&metrics.invoices_created);
+        Metrics::add(&metrics.htlcs_cancelled, 3);
+
+        let encoded = metrics.encode(5, None);
+        assert!(encoded.starts_with(
+            "# HELP hold_invoices_created_total Number of created hold invoices\n\
+            # TYPE hold_invoices_created_total counter\n\
+            hold_invoices_created_total 2\n"
+        ));
+        assert!(encoded.contains("\nhold_invoices_settled_total 0\n"));
+        assert!(encoded.contains("\nhold_htlcs_cancelled_total 3\n"));
+        assert!(encoded.ends_with(
+            "# TYPE hold_invoices_pending gauge\n\
+            hold_invoices_pending 5\n"
+        ));
+    }
+
+    #[test]
+    fn encode_invoices_by_state() {
+        let encoded = Metrics::new().encode(
+            0,
+            Some(HashMap::from([
+                (InvoiceState::Unpaid, 2),
+                (InvoiceState::Paid, 1),
+            ])),
+        );
+        assert!(encoded.ends_with(
+            "# HELP hold_invoices Number of hold invoices by state\n\
+            # TYPE hold_invoices gauge\n\
+            hold_invoices{state=\"paid\"} 1\n\
+            hold_invoices{state=\"unpaid\"} 2\n"
+        ));
+    }
+}
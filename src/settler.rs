@@ -1,26 +1,37 @@
-use crate::database::helpers::invoice_helper::InvoiceHelper;
-use crate::database::model::{HoldInvoice, Invoice, InvoiceState};
+use crate::database::helpers::invoice_helper::{InvoiceHelper, InvoiceHelperError};
+use crate::database::model::{
+    HashAlgorithm, HoldInvoice, Invoice, InvoiceInsertable, InvoiceState, MAX_CANCEL_REASON_LENGTH,
+};
 use crate::hooks::{FailureMessage, HtlcCallbackResponse};
-use anyhow::Result;
-use log::{info, trace, warn};
+use crate::metrics::{Metrics, METRICS};
+use anyhow::{anyhow, Result};
+use bitcoin::hashes::{sha256, Hash};
+use chrono::{TimeDelta, Utc};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::ops::Sub;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, SystemTime};
-use tokio::sync::{broadcast, oneshot, Mutex};
+use tokio::sync::{broadcast, Mutex};
 use tokio::time;
+use tracing::{debug, info, trace, warn};
 
-const MPP_INTERVAL_SECONDS: u64 = 15;
+const SETTLEMENT_DEADLINE_INTERVAL_SECONDS: u64 = 30;
+const TIME_EXPIRY_INTERVAL_SECONDS: u64 = 30;
 
-pub type Resolver = oneshot::Receiver<HtlcCallbackResponse>;
-type ResolverSender = oneshot::Sender<HtlcCallbackResponse>;
+pub type Resolver = broadcast::Receiver<HtlcCallbackResponse>;
+type ResolverSender = broadcast::Sender<HtlcCallbackResponse>;
+type InvoiceLocks = std::sync::Mutex<HashMap<Vec<u8>, Arc<Mutex<()>>>>;
 
 #[derive(Debug)]
 pub enum SettleError {
     NoHtlcsToSettle,
+    AlreadyCancelled,
     InvoiceNotFound,
+    PreimageMismatch,
     DatabaseFetchError(anyhow::Error),
     DatabaseUpdateError(anyhow::Error),
 }
@@ -29,7 +40,11 @@ impl Display for SettleError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             SettleError::NoHtlcsToSettle => write!(f, "no HTLCs to settle"),
+            SettleError::AlreadyCancelled => write!(f, "invoice was cancelled already"),
             SettleError::InvoiceNotFound => write!(f, "invoice not found"),
+            SettleError::PreimageMismatch => {
+                write!(f, "preimage does not match payment hash of invoice")
+            }
             SettleError::DatabaseFetchError(err) => {
                 write!(f, "could not fetch invoice from database: {}", err)
             }
@@ -46,36 +61,133 @@ impl Error for SettleError {}
 pub struct PendingHtlc {
     scid: String,
     channel_id: u64,
+    msat: u64,
     sender: ResolverSender,
     time: SystemTime,
 }
 
+/// Pending HTLCs of an invoice that are checked for MPP timeouts
+#[derive(Debug)]
+struct MppCheckItem {
+    payment_hash: Vec<u8>,
+    invoice: HoldInvoice,
+    mpp_timeout: Duration,
+    // Short channel ID and HTLC ID of the parts past their timeout
+    candidates: Vec<(String, u64)>,
+    // Removed from the pending HTLCs while holding the lock, but failed after releasing it
+    timed_out: Vec<PendingHtlc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HoldSummary {
+    pub invoice_count: usize,
+    pub total_msat_locked: u64,
+    pub oldest_hold_seconds: Option<u64>,
+}
+
 #[derive(Debug, Clone)]
 pub struct StateUpdate {
     pub payment_hash: Vec<u8>,
     pub bolt11: String,
+    pub label: Option<String>,
     pub state: InvoiceState,
 }
 
+/// Sent once the resolution of a settled HTLC was handed back to CLN
+#[derive(Debug, Clone)]
+pub struct HtlcResolved {
+    pub payment_hash: Vec<u8>,
+    pub scid: String,
+    pub channel_id: u64,
+    pub msat: u64,
+}
+
+type StateCallback = Box<dyn Fn(StateUpdate) + Send + Sync + 'static>;
+type PreAcceptHook = Box<dyn Fn(&Invoice, usize) -> bool + Send + Sync + 'static>;
+
+/// Identifies a callback registered with [`Settler::register_callback`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallbackHandle(u64);
+
+/// Identifies a hook registered with [`Settler::register_pre_accept_hook`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookHandle(u64);
+
+struct Registry<F> {
+    next_id: AtomicU64,
+    entries: RwLock<Vec<(u64, F)>>,
+}
+
+impl<F> Default for Registry<F> {
+    fn default() -> Self {
+        Registry {
+            next_id: AtomicU64::new(0),
+            entries: RwLock::new(Vec::new()),
+        }
+    }
+}
+
+impl<F> Registry<F> {
+    fn register(&self, entry: F) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.entries.write().unwrap().push((id, entry));
+        id
+    }
+
+    fn deregister(&self, id: u64) -> bool {
+        let mut entries = self.entries.write().unwrap();
+        let len_before = entries.len();
+        entries.retain(|(entry_id, _)| *entry_id != id);
+        entries.len() != len_before
+    }
+}
+
+impl<F> std::fmt::Debug for Registry<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Registry")
+            .field("count", &self.entries.read().unwrap().len())
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Settler<T> {
     invoice_helper: T,
-    mpp_timeout: Duration,
+    // Shared by all clones, because it can be changed at runtime
+    mpp_timeout: Arc<RwLock<Duration>>,
+    mpp_check_interval: Duration,
+    max_pending_htlcs: usize,
     state_tx: broadcast::Sender<StateUpdate>,
+    htlc_resolved_tx: broadcast::Sender<HtlcResolved>,
     pending_htlcs: Arc<Mutex<HashMap<Vec<u8>, Vec<PendingHtlc>>>>,
+    invoice_locks: Arc<InvoiceLocks>,
+    state_callbacks: Arc<Registry<StateCallback>>,
+    pre_accept_hooks: Arc<Registry<PreAcceptHook>>,
 }
 
 impl<T> Settler<T>
 where
     T: InvoiceHelper + Sync + Send + Clone,
 {
-    pub fn new(invoice_helper: T, mpp_timeout: u64) -> Self {
+    pub fn new(
+        invoice_helper: T,
+        mpp_timeout: u64,
+        mpp_check_interval: u64,
+        max_pending_htlcs: usize,
+    ) -> Self {
         let (state_tx, _) = broadcast::channel(128);
+        let (htlc_resolved_tx, _) = broadcast::channel(128);
         Settler {
             state_tx,
+            htlc_resolved_tx,
             invoice_helper,
-            mpp_timeout: Duration::from_secs(mpp_timeout),
+            max_pending_htlcs,
+            mpp_timeout: Arc::new(RwLock::new(Duration::from_secs(mpp_timeout))),
+            mpp_check_interval: Duration::from_secs(mpp_check_interval),
             pending_htlcs: Arc::new(Mutex::new(HashMap::new())),
+            invoice_locks: Arc::new(InvoiceLocks::new(HashMap::new())),
+            state_callbacks: Arc::new(Registry::default()),
+            pre_accept_hooks: Arc::new(Registry::default()),
         }
     }
 
@@ -83,38 +195,237 @@ where
         self.state_tx.subscribe()
     }
 
-    pub fn new_invoice(&self, invoice: String, payment_hash: Vec<u8>, amount_msat: u64) {
+    pub fn htlc_resolved_rx(&self) -> broadcast::Receiver<HtlcResolved> {
+        self.htlc_resolved_tx.subscribe()
+    }
+
+    pub fn mpp_timeout(&self) -> Duration {
+        *self.mpp_timeout.read().unwrap()
+    }
+
+    /// Applies to pending HTLCs with the next check
+    pub fn set_mpp_timeout(&self, secs: u64) {
+        *self.mpp_timeout.write().unwrap() = Duration::from_secs(secs);
+        info!("Set MPP timeout to {} seconds", secs);
+    }
+
+    /// Registers a callback that is invoked synchronously for every state update.
+    /// Callbacks must not block and must not (de)register callbacks themselves
+    #[allow(dead_code)]
+    pub fn register_callback(&self, callback: StateCallback) -> CallbackHandle {
+        CallbackHandle(self.state_callbacks.register(callback))
+    }
+
+    /// Returns whether a callback was registered for the handle
+    #[allow(dead_code)]
+    pub fn deregister_callback(&self, handle: CallbackHandle) -> bool {
+        self.state_callbacks.deregister(handle.0)
+    }
+
+    /// Registers a hook that is asked before an invoice is accepted with the invoice
+    /// and its number of HTLCs; the invoice is only accepted when all hooks return true
+    #[allow(dead_code)]
+    pub fn register_pre_accept_hook(&self, hook: PreAcceptHook) -> HookHandle {
+        HookHandle(self.pre_accept_hooks.register(hook))
+    }
+
+    /// Returns whether a hook was registered for the handle
+    #[allow(dead_code)]
+    pub fn deregister_hook(&self, handle: HookHandle) -> bool {
+        self.pre_accept_hooks.deregister(handle.0)
+    }
+
+    fn send_state_update(&self, update: StateUpdate) {
+        let _ = self.state_tx.send(update.clone());
+        for (_, callback) in self.state_callbacks.entries.read().unwrap().iter() {
+            callback(update.clone());
+        }
+    }
+
+    pub fn htlc_resolved(&self, htlc: HtlcResolved) {
+        let _ = self.htlc_resolved_tx.send(htlc);
+    }
+
+    /// Gets the lock that serializes the handling of HTLCs for a payment hash;
+    /// HTLCs of different invoices can be handled concurrently
+    pub fn get_invoice_lock(&self, payment_hash: &[u8]) -> Arc<Mutex<()>> {
+        self.invoice_locks
+            .lock()
+            .unwrap()
+            .entry(payment_hash.to_vec())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone()
+    }
+
+    /// Removes the lock of a payment hash when no other task holds a reference to it.
+    /// That does not depend on the state of the invoice; the next HTLC of the payment
+    /// hash creates a new lock. That keeps locks of idle invoices from piling up
+    pub fn release_invoice_lock(&self, payment_hash: &[u8]) {
+        let mut locks = self.invoice_locks.lock().unwrap();
+        if let Some(lock) = locks.get(payment_hash) {
+            if Arc::strong_count(lock) == 1 {
+                locks.remove(payment_hash);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn invoice_lock_count(&self) -> usize {
+        self.invoice_locks.lock().unwrap().len()
+    }
+
+    pub async fn pending_count(&self) -> usize {
+        self.pending_htlcs.lock().await.len()
+    }
+
+    /// Summarizes the HTLCs that are held right now, which are not necessarily
+    /// reflected in the database yet
+    pub async fn get_hold_summary(&self) -> HoldSummary {
+        let pending_htlcs = self.pending_htlcs.lock().await;
+        let now = SystemTime::now();
+
+        let htlcs = pending_htlcs.values().flatten();
+        HoldSummary {
+            invoice_count: pending_htlcs
+                .values()
+                .filter(|htlcs| !htlcs.is_empty())
+                .count(),
+            total_msat_locked: htlcs.clone().map(|htlc| htlc.msat).sum(),
+            oldest_hold_seconds: htlcs
+                .filter_map(|htlc| now.duration_since(htlc.time).ok())
+                .max()
+                .map(|held| held.as_secs()),
+        }
+    }
+
+    pub fn new_invoice(
+        &self,
+        invoice: String,
+        payment_hash: Vec<u8>,
+        amount_msat: u64,
+        label: Option<String>,
+    ) {
+        Metrics::inc(&METRICS.invoices_created);
         info!(
-            "Added hold invoice {} for {}msat",
-            hex::encode(payment_hash.clone()),
-            amount_msat
+            payment_hash = %hex::encode(&payment_hash),
+            amount_msat,
+            "Added hold invoice"
         );
 
-        let _ = self.state_tx.send(StateUpdate {
+        self.send_state_update(StateUpdate {
+            label,
             payment_hash,
             bolt11: invoice,
             state: InvoiceState::Unpaid,
         });
     }
 
-    pub fn set_accepted(&self, invoice: &Invoice, num_htlcs: usize) -> Result<()> {
+    /// Returns false when a pre-accept hook rejected accepting the invoice
+    /// with `num_htlcs` HTLCs
+    pub fn run_pre_accept_hooks(&self, invoice: &Invoice, num_htlcs: usize) -> bool {
+        if self
+            .pre_accept_hooks
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .all(|(_, hook)| hook(invoice, num_htlcs))
+        {
+            return true;
+        }
+
+        info!(
+            payment_hash = %hex::encode(&invoice.payment_hash),
+            htlc_count = num_htlcs,
+            "Pre-accept hook rejected hold invoice"
+        );
+        false
+    }
+
+    /// Accepts the invoice once its accepted HTLCs sum up to `amount_threshold`.
+    /// Returns whether this call accepted it; false when the threshold is not reached
+    /// yet or the invoice was accepted already.
+    /// Callers have to hold the invoice lock, so that the MPP timeout check cannot
+    /// fail parts of an invoice that is being accepted
+    pub async fn set_accepted(&self, invoice: &Invoice, amount_threshold: u64) -> Result<bool> {
+        let accepted = self
+            .invoice_helper
+            .conditional_accept(invoice.id, amount_threshold)?;
+        if !accepted {
+            trace!(
+                payment_hash = %hex::encode(&invoice.payment_hash),
+                amount_threshold,
+                "Hold invoice was not accepted"
+            );
+            return Ok(false);
+        }
+
         info!(
-            "Accepted hold invoice {} with {} HTLCs",
-            hex::encode(invoice.payment_hash.clone()),
-            num_htlcs
-        );
-        self.invoice_helper.set_invoice_state(
-            invoice.id,
-            InvoiceState::try_from(&invoice.state)?,
-            InvoiceState::Accepted,
-        )?;
-        let _ = self.state_tx.send(StateUpdate {
+            payment_hash = %hex::encode(&invoice.payment_hash),
+            "Accepted hold invoice"
+        );
+        self.send_state_update(StateUpdate {
             state: InvoiceState::Accepted,
             bolt11: invoice.bolt11.clone(),
+            label: invoice.label.clone(),
             payment_hash: invoice.payment_hash.clone(),
         });
 
-        Ok(())
+        Ok(true)
+    }
+
+    /// Returns every state the invoice has been in, oldest first, from its state log.
+    /// Invoices without logged transitions get their current state only
+    pub fn replay_state_for(&self, payment_hash: &[u8]) -> Result<Vec<StateUpdate>> {
+        let invoice = match self.invoice_helper.get_by_payment_hash(payment_hash)? {
+            Some(invoice) => invoice.invoice,
+            None => return Ok(Vec::new()),
+        };
+        let log = self.invoice_helper.get_state_log(&[invoice.id])?;
+
+        let mut states = Vec::with_capacity(log.len() + 1);
+        if let Some(first) = log.first() {
+            states.push(InvoiceState::try_from(&first.old_state)?);
+        }
+        for entry in &log {
+            states.push(InvoiceState::try_from(&entry.new_state)?);
+        }
+
+        let current = InvoiceState::try_from(&invoice.state)?;
+        if states.last() != Some(&current) {
+            states.push(current);
+        }
+
+        Ok(states
+            .into_iter()
+            .map(|state| StateUpdate {
+                state,
+                payment_hash: invoice.payment_hash.clone(),
+                bolt11: invoice.bolt11.clone(),
+                label: invoice.label.clone(),
+            })
+            .collect())
+    }
+
+    /// Broadcasts the state of invoices that were accepted before a restart again,
+    /// so that subscribers learn about them without waiting for CLN to replay their HTLCs
+    pub fn recover_from_database(&mut self) -> Result<u64> {
+        let invoices = self.invoice_helper.get_by_state(InvoiceState::Accepted)?;
+
+        for invoice in &invoices {
+            self.send_state_update(StateUpdate {
+                state: InvoiceState::Accepted,
+                bolt11: invoice.invoice.bolt11.clone(),
+                label: invoice.invoice.label.clone(),
+                payment_hash: invoice.invoice.payment_hash.clone(),
+            });
+        }
+
+        if !invoices.is_empty() {
+            info!(count = invoices.len(), "Recovered accepted hold invoices");
+        }
+
+        Ok(invoices.len() as u64)
     }
 
     pub async fn add_htlc(
@@ -122,13 +433,47 @@ where
         payment_hash: &Vec<u8>,
         scid: String,
         channel_id: u64,
+        msat: u64,
     ) -> Resolver {
-        let (tx, rx) = oneshot::channel::<HtlcCallbackResponse>();
         let mut htlcs = self.pending_htlcs.lock().await;
 
+        // The same HTLC can be delivered again, e.g. after a reconnect, while the first
+        // delivery is still held; both wait for the same resolution
+        if let Some(existing) = htlcs.get(payment_hash).and_then(|pending| {
+            pending
+                .iter()
+                .find(|htlc| htlc.scid == scid && htlc.channel_id == channel_id)
+        }) {
+            warn!(
+                payment_hash = %hex::encode(payment_hash),
+                scid = %scid,
+                htlc_id = channel_id,
+                "HTLC is pending already"
+            );
+            return existing.sender.subscribe();
+        }
+
+        let (tx, rx) = broadcast::channel::<HtlcCallbackResponse>(1);
+
+        let pending_count: usize = htlcs.values().map(Vec::len).sum();
+        if pending_count >= self.max_pending_htlcs {
+            warn!(
+                payment_hash = %hex::encode(payment_hash),
+                scid = %scid,
+                htlc_id = channel_id,
+                max_pending_htlcs = self.max_pending_htlcs,
+                "Rejected HTLC because the limit of pending HTLCs was reached"
+            );
+            let _ = tx.send(HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::TemporaryNodeFailure,
+            });
+            return rx;
+        }
+
         let pending = PendingHtlc {
             scid,
             channel_id,
+            msat,
             sender: tx,
             time: SystemTime::now(),
         };
@@ -142,19 +487,43 @@ where
         rx
     }
 
+    /// Returns the number of HTLCs that were resolved
+    ///
+    /// Settling an invoice that is paid already is idempotent and resolves
+    /// no HTLCs. [`SettleError::NoHtlcsToSettle`] is only returned when the
+    /// invoice is neither paid nor cancelled and has no pending HTLCs
     pub async fn settle(
         &mut self,
         payment_hash: &Vec<u8>,
         payment_preimage: &Vec<u8>,
-    ) -> Result<()> {
-        if self.get_invoice(payment_hash)?.invoice.state == InvoiceState::Paid.to_string() {
-            return Ok(());
+    ) -> Result<usize> {
+        let HoldInvoice {
+            invoice,
+            htlcs: db_htlcs,
+        } = self.get_invoice(payment_hash)?;
+
+        if HashAlgorithm::try_from(&invoice.hash_algorithm)?.hash(payment_preimage)
+            != invoice.payment_hash
+        {
+            return Err(SettleError::PreimageMismatch.into());
         }
 
         let htlcs = match self.pending_htlcs.lock().await.remove(payment_hash) {
             Some(res) => res,
             None => {
-                return Err(SettleError::NoHtlcsToSettle.into());
+                // The invoice could have been resolved since it was fetched
+                let state = self.get_invoice(payment_hash)?.invoice.state;
+                return match InvoiceState::try_from(&state) {
+                    Ok(InvoiceState::Paid) => {
+                        debug!(
+                            payment_hash = %hex::encode(payment_hash),
+                            "Hold invoice was settled already"
+                        );
+                        Ok(0)
+                    }
+                    Ok(InvoiceState::Cancelled) => Err(SettleError::AlreadyCancelled.into()),
+                    _ => Err(SettleError::NoHtlcsToSettle.into()),
+                };
             }
         };
         let htlc_count = htlcs.len();
@@ -166,24 +535,127 @@ where
             });
         }
 
-        let (invoice_id, bolt11) = self.update_database_states(payment_hash, InvoiceState::Paid)?;
+        let invoice = self.update_database_states(payment_hash, InvoiceState::Paid)?;
         self.invoice_helper
-            .set_invoice_preimage(invoice_id, payment_preimage)?;
-        let _ = self.state_tx.send(StateUpdate {
-            bolt11,
+            .set_invoice_preimage(payment_hash, payment_preimage)
+            .map_err(SettleError::DatabaseUpdateError)?;
+        for htlc in db_htlcs
+            .iter()
+            .filter(|htlc| htlc.state == InvoiceState::Accepted.to_string())
+        {
+            self.invoice_helper
+                .set_htlc_preimage(htlc.id, payment_preimage)
+                .map_err(SettleError::DatabaseUpdateError)?;
+        }
+        self.send_state_update(StateUpdate {
+            bolt11: invoice.bolt11,
+            label: invoice.label,
             state: InvoiceState::Paid,
             payment_hash: payment_hash.clone(),
         });
+        Metrics::inc(&METRICS.invoices_settled);
         info!(
-            "Resolved hold invoice {} with {} HTLCs",
-            hex::encode(payment_hash),
-            htlc_count
+            payment_hash = %hex::encode(payment_hash),
+            htlc_count,
+            "Resolved hold invoice"
         );
 
-        Ok(())
+        Ok(htlc_count)
+    }
+
+    /// Settles the invoice and saves a new one in the same database transaction.
+    /// The HTLCs are only resolved after that transaction was committed, so that they
+    /// stay held when the new invoice cannot be saved; once CLN resolved them,
+    /// there is nothing left to roll back
+    pub async fn settle_and_insert(
+        &mut self,
+        payment_hash: &Vec<u8>,
+        payment_preimage: &Vec<u8>,
+        new_invoice: &InvoiceInsertable,
+    ) -> Result<usize> {
+        let invoice = self.get_invoice(payment_hash)?.invoice;
+
+        if HashAlgorithm::try_from(&invoice.hash_algorithm)?.hash(payment_preimage)
+            != invoice.payment_hash
+        {
+            return Err(SettleError::PreimageMismatch.into());
+        }
+
+        // Held until the HTLCs are removed, so that none can be added in between
+        let mut pending_htlcs = self.pending_htlcs.lock().await;
+        if !pending_htlcs.contains_key(payment_hash) {
+            return match InvoiceState::try_from(&invoice.state) {
+                Ok(InvoiceState::Cancelled) => Err(SettleError::AlreadyCancelled.into()),
+                _ => Err(SettleError::NoHtlcsToSettle.into()),
+            };
+        }
+
+        let invoice =
+            self.invoice_helper
+                .settle_and_insert(payment_hash, payment_preimage, new_invoice)?;
+        let htlcs = pending_htlcs.remove(payment_hash).unwrap_or_default();
+        drop(pending_htlcs);
+
+        let htlc_count = htlcs.len();
+        let preimage_hex = hex::encode(payment_preimage);
+        for htlc in htlcs {
+            let _ = htlc.sender.send(HtlcCallbackResponse::Resolve {
+                payment_key: preimage_hex.clone(),
+            });
+        }
+
+        self.send_state_update(StateUpdate {
+            bolt11: invoice.bolt11,
+            label: invoice.label,
+            state: InvoiceState::Paid,
+            payment_hash: payment_hash.clone(),
+        });
+        Metrics::inc(&METRICS.invoices_settled);
+        info!(
+            payment_hash = %hex::encode(payment_hash),
+            htlc_count,
+            "Resolved hold invoice"
+        );
+
+        Ok(htlc_count)
+    }
+
+    /// Settles the invoices of the preimages one after another; a failure does not
+    /// stop the others from being settled. The results are in the order of `preimages`
+    pub async fn settle_batch(&mut self, preimages: &[Vec<u8>]) -> Vec<Result<(), SettleError>> {
+        let mut results = Vec::with_capacity(preimages.len());
+
+        for preimage in preimages {
+            let payment_hash: sha256::Hash = Hash::hash(preimage);
+            results.push(
+                self.settle(&payment_hash[..].to_vec(), preimage)
+                    .await
+                    .map(|_| ())
+                    .map_err(|err| match err.downcast::<SettleError>() {
+                        Ok(err) => err,
+                        // Invalid hash algorithm or state of the invoice in the database
+                        Err(err) => SettleError::DatabaseFetchError(err),
+                    }),
+            );
+        }
+
+        results
     }
 
-    pub async fn cancel(&mut self, payment_hash: &Vec<u8>) -> Result<()> {
+    /// The optional `reason` is stored with the invoice for auditing
+    pub async fn cancel(
+        &mut self,
+        payment_hash: &Vec<u8>,
+        failure: FailureMessage,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        // Validated before any HTLC is failed
+        if let Some(length) = reason.map(|reason| reason.chars().count()) {
+            if length > MAX_CANCEL_REASON_LENGTH {
+                return Err(InvoiceHelperError::CancelReasonTooLong(length).into());
+            }
+        }
+
         let htlcs = self
             .pending_htlcs
             .lock()
@@ -194,20 +666,89 @@ where
 
         for htlc in htlcs {
             let _ = htlc.sender.send(HtlcCallbackResponse::Fail {
-                failure_message: FailureMessage::IncorrectPaymentDetails,
+                failure_message: failure,
             });
         }
 
-        let (_, bolt11) = self.update_database_states(payment_hash, InvoiceState::Cancelled)?;
-        let _ = self.state_tx.send(StateUpdate {
-            bolt11,
+        let invoice = self.update_database_states(payment_hash, InvoiceState::Cancelled)?;
+        if let Some(reason) = reason {
+            self.invoice_helper
+                .set_invoice_cancel_reason(payment_hash, reason)?;
+        }
+        self.send_state_update(StateUpdate {
+            bolt11: invoice.bolt11,
+            label: invoice.label,
             state: InvoiceState::Cancelled,
             payment_hash: payment_hash.clone(),
         });
+        Metrics::inc(&METRICS.invoices_cancelled);
+        Metrics::add(&METRICS.htlcs_cancelled, htlc_count as u64);
+        info!(
+            payment_hash = %hex::encode(payment_hash),
+            htlc_count,
+            "Cancelled hold invoice"
+        );
+
+        Ok(())
+    }
+
+    pub async fn cancel_batch(&mut self, payment_hashes: &[Vec<u8>]) -> Vec<(Vec<u8>, Result<()>)> {
+        let mut results = Vec::with_capacity(payment_hashes.len());
+
+        for payment_hash in payment_hashes {
+            results.push((
+                payment_hash.clone(),
+                self.cancel(payment_hash, FailureMessage::IncorrectPaymentDetails, None)
+                    .await,
+            ));
+        }
+
+        results
+    }
+
+    /// Rejects new HTLCs of an unpaid invoice until it is resumed; HTLCs that are held
+    /// already stay held
+    pub fn pause(&self, payment_hash: &[u8]) -> Result<()> {
+        self.set_paused(payment_hash, InvoiceState::Paused)
+    }
+
+    pub fn resume(&self, payment_hash: &[u8]) -> Result<()> {
+        self.set_paused(payment_hash, InvoiceState::Unpaid)
+    }
+
+    fn set_paused(&self, payment_hash: &[u8], new_state: InvoiceState) -> Result<()> {
+        let invoice = self.get_invoice(payment_hash)?.invoice;
+        let state = InvoiceState::try_from(&invoice.state)?;
+        if state == new_state {
+            return Ok(());
+        }
+
+        state.validate_transition(new_state)?;
+
+        match self
+            .invoice_helper
+            .set_invoice_state(invoice.id, state, new_state)
+        {
+            Ok(0) => {
+                return Err(SettleError::DatabaseUpdateError(anyhow!(
+                    "invoice state changed concurrently"
+                ))
+                .into())
+            }
+            Ok(_) => {}
+            Err(err) => return Err(SettleError::DatabaseUpdateError(err).into()),
+        };
+
+        self.send_state_update(StateUpdate {
+            bolt11: invoice.bolt11,
+            label: invoice.label,
+            state: new_state,
+            payment_hash: payment_hash.to_vec(),
+        });
         info!(
-            "Cancelled hold invoice {} with {} pending HTLCs",
-            hex::encode(payment_hash),
-            htlc_count
+            payment_hash = %hex::encode(payment_hash),
+            state = %new_state,
+            "Changed state of hold invoice"
         );
 
         Ok(())
@@ -216,40 +757,81 @@ where
     pub async fn mpp_timeout_loop(&mut self) {
         info!(
             "Checking for MPP timeouts every {} seconds",
-            MPP_INTERVAL_SECONDS
+            self.mpp_check_interval.as_secs()
         );
-        let mut interval = time::interval(Duration::from_secs(MPP_INTERVAL_SECONDS));
+        let mut interval = time::interval(self.mpp_check_interval);
 
         loop {
             interval.tick().await;
-            trace!("Checking for MPP timeouts");
-
-            let now = SystemTime::now();
-
-            for (payment_hash, pending) in self.pending_htlcs.lock().await.iter_mut() {
-                let invoice = match self.invoice_helper.get_by_payment_hash(payment_hash) {
-                    Ok(invoice) => match invoice {
-                        Some(invoice) => invoice,
-                        None => {
-                            warn!(
-                                "Not database entry found for invoice: {}",
-                                hex::encode(payment_hash)
-                            );
-                            continue;
-                        }
-                    },
-                    Err(err) => {
-                        warn!("Could not fetch invoice: {}", err);
+            self.check_mpp_timeouts().await;
+        }
+    }
+
+    async fn check_mpp_timeouts(&self) {
+        trace!("Checking for MPP timeouts");
+
+        let now = SystemTime::now();
+        let default_mpp_timeout = self.mpp_timeout();
+
+        // The invoices are fetched without holding the lock, so that the database
+        // queries do not block HTLCs from being added or resolved in the meantime
+        let payment_hashes = self
+            .pending_htlcs
+            .lock()
+            .await
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut items = Vec::with_capacity(payment_hashes.len());
+        for payment_hash in payment_hashes {
+            let invoice = match self.invoice_helper.get_by_payment_hash(&payment_hash) {
+                Ok(invoice) => match invoice {
+                    Some(invoice) => invoice,
+                    None => {
+                        warn!(
+                            payment_hash = %hex::encode(&payment_hash),
+                            "No database entry found for invoice"
+                        );
                         continue;
                     }
-                };
-
-                if invoice.invoice.state == InvoiceState::Accepted.to_string() {
+                },
+                Err(err) => {
+                    warn!(
+                        payment_hash = %hex::encode(&payment_hash),
+                        error = %err,
+                        "Could not fetch invoice"
+                    );
                     continue;
                 }
+            };
+
+            if invoice.invoice.state == InvoiceState::Accepted.to_string() {
+                continue;
+            }
+
+            items.push(MppCheckItem {
+                mpp_timeout: match invoice.invoice.mpp_timeout {
+                    Some(timeout) => Duration::from_secs(timeout as u64),
+                    None => default_mpp_timeout,
+                },
+                payment_hash,
+                invoice,
+                candidates: Vec::new(),
+                timed_out: Vec::new(),
+            });
+        }
+
+        {
+            let pending_htlcs = self.pending_htlcs.lock().await;
+            for item in items.iter_mut() {
+                // Resolved while the invoice was being fetched
+                let pending = match pending_htlcs.get(&item.payment_hash) {
+                    Some(pending) => pending,
+                    None => continue,
+                };
 
-                for i in (0..pending.len()).rev() {
-                    let htlc = &pending[i];
+                for htlc in pending {
                     let since_accepted = match now.duration_since(htlc.time) {
                         Ok(since) => since,
                         Err(err) => {
@@ -258,91 +840,250 @@ where
                         }
                     };
 
-                    if since_accepted < self.mpp_timeout {
+                    if since_accepted < item.mpp_timeout {
                         trace!(
-                            "Cancelling payment part {}:{} of {} with MPP timeout in {:?}",
-                            htlc.scid,
-                            htlc.channel_id,
-                            hex::encode(payment_hash),
-                            self.mpp_timeout.sub(since_accepted)
+                            payment_hash = %hex::encode(&item.payment_hash),
+                            scid = %htlc.scid,
+                            htlc_id = htlc.channel_id,
+                            timeout_in = ?item.mpp_timeout.sub(since_accepted),
+                            "Payment part waiting for MPP timeout"
                         );
                         continue;
                     }
 
-                    let htlc = pending.remove(i);
-                    let _ = htlc.sender.send(HtlcCallbackResponse::Fail {
-                        failure_message: FailureMessage::MppTimeout,
-                    });
-                    let htlc_db = match invoice
-                        .htlcs
-                        .iter()
-                        .find(|h| h.scid == htlc.scid && h.channel_id as u64 == htlc.channel_id)
+                    item.candidates.push((htlc.scid.clone(), htlc.channel_id));
+                }
+            }
+        }
+
+        for item in items.iter_mut().filter(|item| !item.candidates.is_empty()) {
+            // Accepting happens with the invoice lock held, so the state read here
+            // holds until the parts are removed
+            let lock = self.get_invoice_lock(&item.payment_hash);
+            {
+                let _guard = lock.lock().await;
+                match self.invoice_helper.get_by_payment_hash(&item.payment_hash) {
+                    Ok(Some(invoice))
+                        if invoice.invoice.state != InvoiceState::Accepted.to_string() =>
                     {
-                        Some(htlc) => htlc,
-                        None => {
-                            warn!(
-                                "Could not find HTLC {}:{} of {} in database",
-                                htlc.scid,
-                                htlc.channel_id,
-                                hex::encode(payment_hash)
-                            );
-                            continue;
+                        let mut pending_htlcs = self.pending_htlcs.lock().await;
+                        if let Some(pending) = pending_htlcs.get_mut(&item.payment_hash) {
+                            // Only the parts that were not resolved in the meantime
+                            let (timed_out, still_pending) =
+                                pending.drain(..).partition(|htlc: &PendingHtlc| {
+                                    item.candidates.iter().any(|(scid, channel_id)| {
+                                        htlc.scid == *scid && htlc.channel_id == *channel_id
+                                    })
+                                });
+                            *pending = still_pending;
+                            item.timed_out = timed_out;
                         }
-                    };
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!(
+                            payment_hash = %hex::encode(&item.payment_hash),
+                            error = %err,
+                            "Could not fetch invoice"
+                        );
+                    }
+                }
+            }
+            drop(lock);
+            self.release_invoice_lock(&item.payment_hash);
+        }
 
-                    if let Err(err) = self.invoice_helper.set_htlc_state_by_id(
-                        htlc_db.id,
-                        match InvoiceState::try_from(&htlc_db.state) {
-                            Ok(state) => state,
-                            Err(err) => {
-                                warn!("Could not parse HTLC database state: {}", err);
-                                continue;
-                            }
-                        },
-                        InvoiceState::Cancelled,
-                    ) {
+        for item in items {
+            let payment_hash = &item.payment_hash;
+
+            for htlc in item.timed_out {
+                let _ = htlc.sender.send(HtlcCallbackResponse::Fail {
+                    failure_message: FailureMessage::MppTimeout,
+                });
+                Metrics::inc(&METRICS.mpp_timeouts);
+                Metrics::inc(&METRICS.htlcs_cancelled);
+                let htlc_db = match item
+                    .invoice
+                    .htlcs
+                    .iter()
+                    .find(|h| h.scid == htlc.scid && h.channel_id as u64 == htlc.channel_id)
+                {
+                    Some(htlc) => htlc,
+                    None => {
                         warn!(
-                            "Could not update database state of HTLC of {}: {}",
-                            hex::encode(payment_hash),
-                            err
+                            payment_hash = %hex::encode(payment_hash),
+                            scid = %htlc.scid,
+                            htlc_id = htlc.channel_id,
+                            "Could not find HTLC in database"
                         );
                         continue;
-                    };
+                    }
+                };
 
-                    info!(
-                        "Cancelled payment part {}:{} of {} with MPP timeout",
-                        htlc.scid,
-                        htlc.channel_id,
-                        hex::encode(payment_hash)
+                if let Err(err) = self.invoice_helper.set_htlc_state_by_id(
+                    htlc_db.id,
+                    match InvoiceState::try_from(&htlc_db.state) {
+                        Ok(state) => state,
+                        Err(err) => {
+                            warn!("Could not parse HTLC database state: {}", err);
+                            continue;
+                        }
+                    },
+                    InvoiceState::Cancelled,
+                ) {
+                    warn!(
+                        payment_hash = %hex::encode(payment_hash),
+                        error = %err,
+                        "Could not update database state of HTLC"
                     );
-                }
+                    continue;
+                };
+
+                info!(
+                    payment_hash = %hex::encode(payment_hash),
+                    scid = %htlc.scid,
+                    htlc_id = htlc.channel_id,
+                    "Cancelled payment part with MPP timeout"
+                );
             }
         }
     }
 
-    fn update_database_states(
-        &self,
-        payment_hash: &[u8],
-        state: InvoiceState,
-    ) -> Result<(i64, String)> {
-        let invoice = self.get_invoice(payment_hash)?;
-        let current_state = InvoiceState::try_from(&invoice.invoice.state)?;
+    pub async fn settlement_deadline_loop(&mut self) {
+        info!(
+            "Checking for settlement deadlines every {} seconds",
+            SETTLEMENT_DEADLINE_INTERVAL_SECONDS
+        );
+        let mut interval =
+            time::interval(Duration::from_secs(SETTLEMENT_DEADLINE_INTERVAL_SECONDS));
 
-        if let Err(err) =
-            self.invoice_helper
-                .set_invoice_state(invoice.invoice.id, current_state, state)
-        {
-            return Err(SettleError::DatabaseUpdateError(err).into());
-        }
+        loop {
+            interval.tick().await;
+            trace!("Checking for settlement deadlines");
 
-        if let Err(err) =
-            self.invoice_helper
-                .set_htlc_states_by_invoice(invoice.invoice.id, current_state, state)
-        {
-            return Err(SettleError::DatabaseUpdateError(err).into());
+            for payment_hash in self.get_expired_settlement_deadlines() {
+                warn!(
+                    payment_hash = %hex::encode(&payment_hash),
+                    "Cancelling hold invoice because its settlement deadline expired"
+                );
+
+                if let Err(err) = self
+                    .cancel(&payment_hash, FailureMessage::IncorrectPaymentDetails, None)
+                    .await
+                {
+                    warn!(
+                        payment_hash = %hex::encode(&payment_hash),
+                        error = %err,
+                        "Could not cancel hold invoice with expired settlement deadline"
+                    );
+                }
+            }
         }
+    }
+
+    pub async fn time_expiry_loop(&mut self) {
+        info!(
+            "Checking for expired unpaid invoices every {} seconds",
+            TIME_EXPIRY_INTERVAL_SECONDS
+        );
+        let mut interval = time::interval(Duration::from_secs(TIME_EXPIRY_INTERVAL_SECONDS));
 
-        Ok((invoice.invoice.id, invoice.invoice.bolt11))
+        loop {
+            interval.tick().await;
+            trace!("Checking for expired unpaid invoices");
+            self.cancel_expired_invoices().await;
+        }
+    }
+
+    /// Returns the number of expired unpaid invoices that were cancelled
+    async fn cancel_expired_invoices(&mut self) -> usize {
+        let invoices = match self.invoice_helper.get_expiring_soon(0) {
+            Ok(invoices) => invoices,
+            Err(err) => {
+                warn!(error = %err, "Could not fetch expired invoices");
+                return 0;
+            }
+        };
+
+        let mut cancelled = 0;
+
+        for invoice in invoices {
+            let payment_hash = invoice.invoice.payment_hash;
+            warn!(
+                payment_hash = %hex::encode(&payment_hash),
+                "Cancelling hold invoice because its time based expiry was reached"
+            );
+
+            match self
+                .cancel(&payment_hash, FailureMessage::IncorrectPaymentDetails, None)
+                .await
+            {
+                Ok(_) => cancelled += 1,
+                Err(err) => warn!(
+                    payment_hash = %hex::encode(&payment_hash),
+                    error = %err,
+                    "Could not cancel expired hold invoice"
+                ),
+            };
+        }
+
+        cancelled
+    }
+
+    fn get_expired_settlement_deadlines(&self) -> Vec<Vec<u8>> {
+        let invoices = match self.invoice_helper.get_by_state(InvoiceState::Accepted) {
+            Ok(invoices) => invoices,
+            Err(err) => {
+                warn!(error = %err, "Could not fetch accepted invoices");
+                return Vec::new();
+            }
+        };
+
+        let now = Utc::now().naive_utc();
+
+        invoices
+            .into_iter()
+            .filter(|invoice| {
+                match (
+                    invoice.invoice.accepted_at,
+                    invoice.invoice.settlement_deadline,
+                ) {
+                    (Some(accepted_at), Some(deadline)) => {
+                        accepted_at + TimeDelta::seconds(deadline) < now
+                    }
+                    _ => false,
+                }
+            })
+            .map(|invoice| invoice.invoice.payment_hash)
+            .collect()
+    }
+
+    fn update_database_states(&self, payment_hash: &[u8], state: InvoiceState) -> Result<Invoice> {
+        let invoice = self.get_invoice(payment_hash)?;
+        let current_state = InvoiceState::try_from(&invoice.invoice.state)?;
+
+        match self
+            .invoice_helper
+            .set_invoice_state(invoice.invoice.id, current_state, state)
+        {
+            Ok(0) => {
+                return Err(SettleError::DatabaseUpdateError(anyhow!(
+                    "invoice state changed concurrently"
+                ))
+                .into())
+            }
+            Ok(_) => {}
+            Err(err) => return Err(SettleError::DatabaseUpdateError(err).into()),
+        };
+
+        if let Err(err) =
+            self.invoice_helper
+                .set_htlc_states_by_invoice(invoice.invoice.id, current_state, state)
+        {
+            return Err(SettleError::DatabaseUpdateError(err).into());
+        }
+
+        Ok(invoice.invoice)
     }
 
     fn get_invoice(&self, payment_hash: &[u8]) -> Result<HoldInvoice> {
@@ -355,3 +1096,1396 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::database::helpers::invoice_helper::{InvoiceHelper, InvoiceHelperError};
+    use crate::database::model::{
+        HoldInvoice, Htlc, HtlcInsertable, Invoice, InvoiceInsertable, InvoiceState,
+        StateTransitionError,
+    };
+    use crate::database::test_utils::{
+        insert_invoice, invoice_insertable, test_database, MockInvoiceHelper,
+    };
+    use crate::hooks::{FailureMessage, HtlcCallbackResponse};
+    use crate::settler::{HoldSummary, SettleError, Settler, StateUpdate};
+    use bitcoin::hashes::{sha256, Hash};
+    use chrono::{TimeDelta, Utc};
+    use std::sync::{Arc, Barrier};
+    use std::time::{Duration, SystemTime};
+
+    fn hash(preimage: &[u8]) -> Vec<u8> {
+        let hash: sha256::Hash = Hash::hash(preimage);
+        hash[..].to_vec()
+    }
+
+    #[tokio::test]
+    async fn settle_batch() {
+        let preimage_pending = vec![1; 32];
+        let preimage_no_htlcs = vec![2; 32];
+
+        let mut helper = MockInvoiceHelper::new();
+        helper.expect_get_by_payment_hash().returning(|hash| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 0,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Accepted.to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        helper.expect_set_invoice_state().returning(|_, _, _| Ok(1));
+        helper
+            .expect_set_htlc_states_by_invoice()
+            .returning(|_, _, _| Ok(1));
+        helper
+            .expect_set_invoice_preimage()
+            .returning(|_, _| Ok(()));
+
+        let mut settler = Settler::new(helper, 60, 15, 1_000);
+        let mut resolver = settler
+            .add_htlc(&hash(&preimage_pending), "scid".to_string(), 1, 1_000)
+            .await;
+
+        let results = settler
+            .settle_batch(&[preimage_no_htlcs.clone(), preimage_pending.clone()])
+            .await;
+        assert_eq!(results.len(), 2);
+
+        assert!(matches!(results[0], Err(SettleError::NoHtlcsToSettle)));
+        assert!(results[1].is_ok());
+
+        assert_eq!(
+            resolver.recv().await.unwrap(),
+            HtlcCallbackResponse::Resolve {
+                payment_key: hex::encode(preimage_pending),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn settle_no_htlcs() {
+        let preimage = vec![1; 32];
+        let payment_hash = hash(&preimage);
+
+        for (state, expected) in [
+            (InvoiceState::Unpaid, SettleError::NoHtlcsToSettle),
+            (InvoiceState::Cancelled, SettleError::AlreadyCancelled),
+        ] {
+            let mut helper = MockInvoiceHelper::new();
+            helper.expect_get_by_payment_hash().returning(move |hash| {
+                Ok(Some(HoldInvoice {
+                    invoice: Invoice {
+                        cancel_reason: None,
+                        expires_at: None,
+                        label: None,
+                        id: 0,
+                        preimage: None,
+                        settled_at: None,
+                        mpp_timeout: None,
+                        settlement_deadline: None,
+                        accepted_at: None,
+                        payment_hash: hash.to_vec(),
+                        hash_algorithm: "sha256".to_string(),
+                        bolt11: "".to_string(),
+                        created_at: Default::default(),
+                        state: state.to_string(),
+                    },
+                    htlcs: vec![],
+                }))
+            });
+
+            let mut settler = Settler::new(helper, 60, 15, 1_000);
+            let err = settler
+                .settle(&payment_hash, &preimage)
+                .await
+                .err()
+                .unwrap();
+
+            assert_eq!(err.to_string(), expected.to_string());
+            assert_eq!(
+                std::mem::discriminant(err.downcast_ref::<SettleError>().unwrap()),
+                std::mem::discriminant(&expected)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn settle_twice() {
+        let preimage = vec![1; 32];
+        let payment_hash = hash(&preimage);
+
+        let state = Arc::new(std::sync::Mutex::new(InvoiceState::Accepted));
+
+        let mut helper = MockInvoiceHelper::new();
+        let state_cloned = state.clone();
+        helper.expect_get_by_payment_hash().returning(move |hash| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 0,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: state_cloned.lock().unwrap().to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        let state_cloned = state.clone();
+        helper
+            .expect_set_invoice_state()
+            .returning(move |_, _, new_state| {
+                *state_cloned.lock().unwrap() = new_state;
+                Ok(1)
+            });
+        helper
+            .expect_set_htlc_states_by_invoice()
+            .returning(|_, _, _| Ok(1));
+        helper
+            .expect_set_invoice_preimage()
+            .returning(|_, _| Ok(()));
+
+        let mut settler = Settler::new(helper, 60, 15, 1_000);
+        let mut resolver = settler
+            .add_htlc(&payment_hash, "scid".to_string(), 1, 1_000)
+            .await;
+
+        assert_eq!(settler.settle(&payment_hash, &preimage).await.unwrap(), 1);
+        assert_eq!(
+            resolver.recv().await.unwrap(),
+            HtlcCallbackResponse::Resolve {
+                payment_key: hex::encode(&preimage),
+            }
+        );
+        assert_eq!(*state.lock().unwrap(), InvoiceState::Paid);
+
+        assert_eq!(settler.settle(&payment_hash, &preimage).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn state_callbacks() {
+        let preimage = vec![1; 32];
+        let payment_hash = hash(&preimage);
+
+        let mut helper = MockInvoiceHelper::new();
+        helper.expect_get_by_payment_hash().returning(|hash| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: Some("label".to_string()),
+                    id: 0,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "bolt11".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Accepted.to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        helper.expect_set_invoice_state().returning(|_, _, _| Ok(1));
+        helper
+            .expect_set_htlc_states_by_invoice()
+            .returning(|_, _, _| Ok(1));
+        helper
+            .expect_set_invoice_preimage()
+            .returning(|_, _| Ok(()));
+
+        let mut settler = Settler::new(helper, 60, 15, 1_000);
+
+        let updates = Arc::new(std::sync::Mutex::new(Vec::<StateUpdate>::new()));
+        let updates_cloned = updates.clone();
+        let handle = settler.register_callback(Box::new(move |update| {
+            updates_cloned.lock().unwrap().push(update);
+        }));
+
+        let _ = settler
+            .add_htlc(&payment_hash, "scid".to_string(), 1, 1_000)
+            .await;
+        settler.settle(&payment_hash, &preimage).await.unwrap();
+
+        {
+            let updates = updates.lock().unwrap();
+            assert_eq!(updates.len(), 1);
+            assert_eq!(updates[0].state, InvoiceState::Paid);
+            assert_eq!(updates[0].payment_hash, payment_hash);
+            assert_eq!(updates[0].bolt11, "bolt11");
+            assert_eq!(updates[0].label, Some("label".to_string()));
+        }
+
+        assert!(settler.deregister_callback(handle));
+        assert!(!settler.deregister_callback(handle));
+
+        settler.new_invoice("invoice".to_string(), payment_hash.clone(), 1, None);
+        assert_eq!(updates.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn settle_preimage_mismatch() {
+        let preimage = vec![1; 32];
+        let preimage_wrong = vec![2; 32];
+
+        let mut helper = MockInvoiceHelper::new();
+        helper.expect_get_by_payment_hash().returning(|hash| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 0,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Accepted.to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        helper.expect_set_invoice_state().returning(|_, _, _| Ok(1));
+        helper
+            .expect_set_htlc_states_by_invoice()
+            .returning(|_, _, _| Ok(1));
+        helper
+            .expect_set_invoice_preimage()
+            .returning(|_, _| Ok(()));
+
+        let payment_hash = hash(&preimage);
+        let mut settler = Settler::new(helper, 60, 15, 1_000);
+        let mut resolver = settler
+            .add_htlc(&payment_hash, "scid".to_string(), 1, 1_000)
+            .await;
+
+        match settler
+            .settle(&payment_hash, &preimage_wrong)
+            .await
+            .err()
+            .unwrap()
+            .downcast_ref::<SettleError>()
+        {
+            Some(SettleError::PreimageMismatch) => {}
+            _ => unreachable!(),
+        };
+        assert_eq!(settler.pending_count().await, 1);
+
+        settler.settle(&payment_hash, &preimage).await.unwrap();
+        assert_eq!(
+            resolver.recv().await.unwrap(),
+            HtlcCallbackResponse::Resolve {
+                payment_key: hex::encode(preimage),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn settle_htlc_preimages() {
+        let preimage = vec![1; 32];
+        let payment_hash = hash(&preimage);
+
+        let mut helper = MockInvoiceHelper::new();
+        helper.expect_get_by_payment_hash().returning(|hash| {
+            let htlc = |id: i64, state: InvoiceState| Htlc {
+                accepted_at: None,
+                preimage: None,
+                id,
+                invoice_id: 0,
+                state: state.to_string(),
+                scid: "scid".to_string(),
+                channel_id: 1,
+                msat: 1_000,
+                created_at: Default::default(),
+            };
+
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 0,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Accepted.to_string(),
+                },
+                htlcs: vec![
+                    htlc(1, InvoiceState::Accepted),
+                    htlc(2, InvoiceState::Cancelled),
+                    htlc(3, InvoiceState::Accepted),
+                ],
+            }))
+        });
+        helper.expect_set_invoice_state().returning(|_, _, _| Ok(1));
+        helper
+            .expect_set_htlc_states_by_invoice()
+            .returning(|_, _, _| Ok(2));
+        helper
+            .expect_set_invoice_preimage()
+            .returning(|_, _| Ok(()));
+
+        let stored = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let stored_cloned = stored.clone();
+        helper
+            .expect_set_htlc_preimage()
+            .times(2)
+            .returning(move |id, preimage| {
+                stored_cloned.lock().unwrap().push((id, preimage.to_vec()));
+                Ok(())
+            });
+
+        let mut settler = Settler::new(helper, 60, 15, 1_000);
+        settler
+            .add_htlc(&payment_hash, "scid".to_string(), 1, 1_000)
+            .await;
+
+        settler.settle(&payment_hash, &preimage).await.unwrap();
+        assert_eq!(
+            *stored.lock().unwrap(),
+            vec![(1, preimage.clone()), (3, preimage)]
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel_batch() {
+        let payment_hash_found = vec![1; 32];
+        let payment_hash_not_found = vec![2; 32];
+
+        let found = payment_hash_found.clone();
+        let mut helper = MockInvoiceHelper::new();
+        helper.expect_get_by_payment_hash().returning(move |hash| {
+            if hash != found.as_slice() {
+                return Ok(None);
+            }
+
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 0,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Accepted.to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        helper.expect_set_invoice_state().returning(|_, _, _| Ok(1));
+        helper
+            .expect_set_htlc_states_by_invoice()
+            .returning(|_, _, _| Ok(1));
+
+        let mut settler = Settler::new(helper, 60, 15, 1_000);
+        let mut resolver = settler
+            .add_htlc(&payment_hash_found, "scid".to_string(), 1, 1_000)
+            .await;
+
+        let results = settler
+            .cancel_batch(&[payment_hash_not_found.clone(), payment_hash_found.clone()])
+            .await;
+        assert_eq!(results.len(), 2);
+
+        assert_eq!(results[0].0, payment_hash_not_found);
+        match results[0]
+            .1
+            .as_ref()
+            .err()
+            .unwrap()
+            .downcast_ref::<SettleError>()
+        {
+            Some(SettleError::InvoiceNotFound) => {}
+            _ => unreachable!(),
+        };
+
+        assert_eq!(results[1].0, payment_hash_found);
+        assert!(results[1].1.is_ok());
+
+        assert_eq!(
+            resolver.recv().await.unwrap(),
+            HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::IncorrectPaymentDetails,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel_failure_message() {
+        let payment_hash = vec![1; 32];
+
+        let mut helper = MockInvoiceHelper::new();
+        helper.expect_get_by_payment_hash().returning(|hash| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 0,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Accepted.to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        helper.expect_set_invoice_state().returning(|_, _, _| Ok(1));
+        helper
+            .expect_set_htlc_states_by_invoice()
+            .returning(|_, _, _| Ok(1));
+
+        let mut settler = Settler::new(helper, 60, 15, 1_000);
+        let mut resolver = settler
+            .add_htlc(&payment_hash, "scid".to_string(), 1, 1_000)
+            .await;
+
+        settler
+            .cancel(&payment_hash, FailureMessage::TemporaryNodeFailure, None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            resolver.recv().await.unwrap(),
+            HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::TemporaryNodeFailure,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel_state_changed_concurrently() {
+        let payment_hash = vec![1; 32];
+
+        let mut helper = MockInvoiceHelper::new();
+        helper.expect_get_by_payment_hash().returning(|hash| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 0,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Accepted.to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        helper.expect_set_invoice_state().returning(|_, _, _| Ok(0));
+        helper.expect_set_htlc_states_by_invoice().never();
+
+        let mut settler = Settler::new(helper, 60, 15, 1_000);
+        let mut state_rx = settler.state_rx();
+
+        let err = settler
+            .cancel(&payment_hash, FailureMessage::TemporaryNodeFailure, None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "could not update invoice in database: invoice state changed concurrently"
+        );
+        assert!(state_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn get_expired_settlement_deadlines() {
+        let now = Utc::now().naive_utc();
+        let invoice = |payment_hash: u8, deadline: Option<i64>, accepted_ago: i64| HoldInvoice {
+            invoice: Invoice {
+                cancel_reason: None,
+                expires_at: None,
+                label: None,
+                id: payment_hash as i64,
+                preimage: None,
+                settled_at: None,
+                mpp_timeout: None,
+                settlement_deadline: deadline,
+                accepted_at: Some(now - TimeDelta::seconds(accepted_ago)),
+                payment_hash: vec![payment_hash],
+                hash_algorithm: "sha256".to_string(),
+                bolt11: "".to_string(),
+                created_at: Default::default(),
+                state: InvoiceState::Accepted.to_string(),
+            },
+            htlcs: vec![],
+        };
+
+        let invoices = vec![
+            invoice(1, Some(60), 61),
+            invoice(2, Some(60), 30),
+            invoice(3, None, 3_600),
+        ];
+
+        let mut helper = MockInvoiceHelper::new();
+        helper
+            .expect_get_by_state()
+            .withf(|state| *state == InvoiceState::Accepted)
+            .returning(move |_| Ok(invoices.clone()));
+
+        let settler = Settler::new(helper, 60, 15, 1_000);
+        assert_eq!(settler.get_expired_settlement_deadlines(), vec![vec![1]]);
+    }
+
+    #[tokio::test]
+    async fn add_htlc_max_pending() {
+        let mut settler = Settler::new(MockInvoiceHelper::new(), 60, 15, 2);
+
+        let mut first = settler
+            .add_htlc(&vec![1], "scid".to_string(), 0, 1_000)
+            .await;
+        let mut second = settler
+            .add_htlc(&vec![2], "scid".to_string(), 0, 1_000)
+            .await;
+        assert_eq!(settler.pending_count().await, 2);
+
+        let mut rejected = settler
+            .add_htlc(&vec![3], "scid".to_string(), 0, 1_000)
+            .await;
+        assert_eq!(
+            rejected.try_recv().unwrap(),
+            HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::TemporaryNodeFailure
+            }
+        );
+        assert_eq!(settler.pending_count().await, 2);
+
+        assert!(first.try_recv().is_err());
+        assert!(second.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn add_htlc_duplicate() {
+        let mut helper = MockInvoiceHelper::new();
+        helper.expect_get_by_payment_hash().returning(|hash| {
+            Ok(Some(HoldInvoice {
+                invoice: Invoice {
+                    cancel_reason: None,
+                    expires_at: None,
+                    label: None,
+                    id: 0,
+                    preimage: None,
+                    settled_at: None,
+                    mpp_timeout: None,
+                    settlement_deadline: None,
+                    accepted_at: None,
+                    payment_hash: hash.to_vec(),
+                    hash_algorithm: "sha256".to_string(),
+                    bolt11: "".to_string(),
+                    created_at: Default::default(),
+                    state: InvoiceState::Accepted.to_string(),
+                },
+                htlcs: vec![],
+            }))
+        });
+        helper.expect_set_invoice_state().returning(|_, _, _| Ok(1));
+        helper
+            .expect_set_htlc_states_by_invoice()
+            .returning(|_, _, _| Ok(1));
+
+        let mut settler = Settler::new(helper, 60, 15, 2);
+
+        let mut first = settler
+            .add_htlc(&vec![1], "scid".to_string(), 0, 1_000)
+            .await;
+        let mut duplicate = settler
+            .add_htlc(&vec![1], "scid".to_string(), 0, 1_000)
+            .await;
+
+        // The duplicate does not count towards the limit of pending HTLCs
+        let mut other = settler
+            .add_htlc(&vec![1], "scid".to_string(), 1, 1_000)
+            .await;
+        assert!(other.try_recv().is_err());
+        assert_eq!(settler.get_hold_summary().await.total_msat_locked, 2_000);
+
+        settler
+            .cancel(&vec![1], FailureMessage::IncorrectPaymentDetails, None)
+            .await
+            .unwrap();
+
+        for resolver in [&mut first, &mut duplicate, &mut other] {
+            assert_eq!(
+                resolver.recv().await.unwrap(),
+                HtlcCallbackResponse::Fail {
+                    failure_message: FailureMessage::IncorrectPaymentDetails,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn recover_from_database() {
+        let database = test_database("settler-recover");
+        let helper = database.helper();
+
+        for payment_hash in [vec![1], vec![2]] {
+            insert_invoice(&helper, &payment_hash);
+        }
+
+        let accepted = helper.get_by_payment_hash(&[1]).unwrap().unwrap();
+        helper
+            .set_invoice_state(
+                accepted.invoice.id,
+                InvoiceState::Unpaid,
+                InvoiceState::Accepted,
+            )
+            .unwrap();
+
+        let mut settler = Settler::new(helper, 60, 15, 1_000);
+        let mut state_rx = settler.state_rx();
+
+        assert_eq!(settler.recover_from_database().unwrap(), 1);
+
+        let update = state_rx.try_recv().unwrap();
+        assert_eq!(update.payment_hash, vec![1]);
+        assert_eq!(update.state, InvoiceState::Accepted);
+        assert!(state_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn cancel_reason() {
+        let database = test_database("settler-cancel-reason");
+        let helper = database.helper();
+
+        for payment_hash in [vec![1], vec![2]] {
+            insert_invoice(&helper, &payment_hash);
+        }
+
+        let mut settler = Settler::new(helper.clone(), 60, 15, 1_000);
+
+        let err = settler
+            .cancel(
+                &vec![1],
+                FailureMessage::IncorrectPaymentDetails,
+                Some(&"a".repeat(256)),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<InvoiceHelperError>().unwrap(),
+            &InvoiceHelperError::CancelReasonTooLong(256)
+        );
+
+        settler
+            .cancel(
+                &vec![1],
+                FailureMessage::IncorrectPaymentDetails,
+                Some("swap expired"),
+            )
+            .await
+            .unwrap();
+        settler
+            .cancel(&vec![2], FailureMessage::IncorrectPaymentDetails, None)
+            .await
+            .unwrap();
+
+        let invoices = helper.get_all().unwrap();
+        assert_eq!(invoices.len(), 2);
+        assert_eq!(
+            invoices[0].invoice.state,
+            InvoiceState::Cancelled.to_string()
+        );
+        assert_eq!(
+            invoices[0].invoice.cancel_reason,
+            Some("swap expired".to_string())
+        );
+        assert_eq!(invoices[1].invoice.cancel_reason, None);
+    }
+
+    #[tokio::test]
+    async fn cancel_expired_invoices() {
+        let database = test_database("settler-time-expiry");
+        let helper = database.helper();
+
+        // Expired in 2024
+        let expired = "lnbc10n1pnvfs4vsp57npt9tx2glnkx29ng98cmc0lt0as8se4x4776rtwqp3gr3hj807qpp5ysnte2hh3nv4z0jd4pfe5wla956zxxg3rmxs5ux4v0xfwplvlm8sdqdw3jhxar5v4ehgxqyjw5qcqpjrzjq2rnwvp7zt9cgeparuqcrqft2kd9dm6a0z6vg0gucrqurutaezrjyrze2uqq2wcqqyqqqqdyqqqqqpqqvs9qxpqysgqjkdwjjuzfy5ek4k9xgsv0ysrc3lg349caqqh3yearxmv4zgyqhqyuntk4gyjpvpezcc66v5lyzxm240wdfgp6cqkwt7fv2nngjjnlrspmaakpk";
+
+        for (payment_hash, bolt11) in [(vec![1], expired), (vec![2], "lnbcrt1")] {
+            helper
+                .insert(&InvoiceInsertable {
+                    bolt11: bolt11.to_string(),
+                    ..invoice_insertable(&payment_hash)
+                })
+                .unwrap();
+        }
+
+        let expiring = helper.get_expiring_soon(0).unwrap();
+        assert_eq!(expiring.len(), 1);
+        assert_eq!(expiring[0].invoice.payment_hash, vec![1]);
+
+        let mut settler = Settler::new(helper.clone(), 60, 15, 1_000);
+        assert_eq!(settler.cancel_expired_invoices().await, 1);
+
+        let invoices = helper.get_all().unwrap();
+        assert_eq!(
+            invoices[0].invoice.state,
+            InvoiceState::Cancelled.to_string()
+        );
+        assert_eq!(invoices[1].invoice.state, InvoiceState::Unpaid.to_string());
+
+        assert!(helper.get_expiring_soon(0).unwrap().is_empty());
+        assert_eq!(settler.cancel_expired_invoices().await, 0);
+    }
+
+    #[tokio::test]
+    async fn set_accepted_concurrently() {
+        let database = test_database("settler-set-accepted");
+        let helper = database.helper();
+        // Both parts saw the invoice before either of them was inserted
+        let invoice = insert_invoice(&helper, &[1]);
+
+        let settler = Settler::new(helper.clone(), 60, 15, 1_000);
+        let mut state_rx = settler.state_rx();
+
+        let insert_htlc = |msat: i64| {
+            helper
+                .insert_htlc(&HtlcInsertable {
+                    accepted_at: None,
+                    invoice_id: invoice.id,
+                    msat,
+                    state: InvoiceState::Accepted.into(),
+                    scid: "".to_string(),
+                    channel_id: 0,
+                })
+                .unwrap();
+        };
+
+        insert_htlc(400);
+        assert!(!settler.set_accepted(&invoice, 1_000).await.unwrap());
+        assert!(state_rx.try_recv().is_err());
+
+        // Each part reaching the threshold tries to accept, but only one of them does
+        insert_htlc(600);
+        let (first, second) = tokio::join!(
+            settler.set_accepted(&invoice, 1_000),
+            settler.set_accepted(&invoice, 1_000)
+        );
+        assert!(first.unwrap() ^ second.unwrap());
+        assert!(!settler.set_accepted(&invoice, 1_000).await.unwrap());
+
+        let invoice = helper.get_by_payment_hash(&[1]).unwrap().unwrap().invoice;
+        assert_eq!(invoice.state, InvoiceState::Accepted.to_string());
+        assert_eq!(helper.get_state_log(&[invoice.id]).unwrap().len(), 1);
+
+        assert_eq!(state_rx.try_recv().unwrap().state, InvoiceState::Accepted);
+        assert!(state_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn pause_resume() {
+        let database = test_database("settler-pause-resume");
+        let helper = database.helper();
+        for payment_hash in [vec![1], vec![2]] {
+            insert_invoice(&helper, &payment_hash);
+        }
+        helper
+            .set_invoice_state(2, InvoiceState::Unpaid, InvoiceState::Accepted)
+            .unwrap();
+
+        let settler = Settler::new(helper.clone(), 60, 15, 1_000);
+        let mut state_rx = settler.state_rx();
+
+        settler.pause(&[1]).unwrap();
+        // Pausing twice is a no-op
+        settler.pause(&[1]).unwrap();
+        let state = |payment_hash: &[u8]| {
+            helper
+                .get_by_payment_hash(payment_hash)
+                .unwrap()
+                .unwrap()
+                .invoice
+                .state
+        };
+        assert_eq!(state(&[1]), InvoiceState::Paused.to_string());
+        assert_eq!(state_rx.try_recv().unwrap().state, InvoiceState::Paused);
+        assert!(state_rx.try_recv().is_err());
+
+        settler.resume(&[1]).unwrap();
+        assert_eq!(state(&[1]), InvoiceState::Unpaid.to_string());
+        assert_eq!(state_rx.try_recv().unwrap().state, InvoiceState::Unpaid);
+        assert_eq!(helper.get_state_log(&[1]).unwrap().len(), 2);
+
+        assert_eq!(
+            settler
+                .pause(&[2])
+                .err()
+                .unwrap()
+                .downcast_ref::<StateTransitionError>()
+                .unwrap(),
+            &StateTransitionError::InvalidTransition(InvoiceState::Accepted, InvoiceState::Paused)
+        );
+        assert_eq!(
+            settler
+                .resume(&[2])
+                .err()
+                .unwrap()
+                .downcast_ref::<StateTransitionError>()
+                .unwrap(),
+            &StateTransitionError::InvalidTransition(InvoiceState::Accepted, InvoiceState::Unpaid)
+        );
+        assert!(matches!(
+            settler
+                .pause(&[3])
+                .err()
+                .unwrap()
+                .downcast_ref::<SettleError>(),
+            Some(SettleError::InvoiceNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn settle_and_insert() {
+        let database = test_database("settler-settle-and-insert");
+        let helper = database.helper();
+
+        let preimage = vec![1; 32];
+        let payment_hash: sha256::Hash = Hash::hash(&preimage);
+        let payment_hash = payment_hash[..].to_vec();
+
+        let new_invoice = |payment_hash: Vec<u8>| invoice_insertable(&payment_hash);
+        helper.insert(&new_invoice(payment_hash.clone())).unwrap();
+
+        let mut settler = Settler::new(helper.clone(), 60, 15, 1_000);
+        let mut state_rx = settler.state_rx();
+
+        let invoice = helper.get_by_payment_hash(&payment_hash).unwrap().unwrap();
+        helper
+            .insert_htlc(&HtlcInsertable {
+                accepted_at: None,
+                invoice_id: invoice.invoice.id,
+                msat: 1_000,
+                state: InvoiceState::Accepted.into(),
+                scid: "".to_string(),
+                channel_id: 0,
+            })
+            .unwrap();
+        assert!(settler.set_accepted(&invoice.invoice, 1_000).await.unwrap());
+        assert_eq!(state_rx.try_recv().unwrap().state, InvoiceState::Accepted);
+
+        assert_eq!(
+            settler
+                .settle_and_insert(&payment_hash, &vec![2; 32], &new_invoice(vec![2]))
+                .await
+                .err()
+                .unwrap()
+                .to_string(),
+            SettleError::PreimageMismatch.to_string()
+        );
+        assert_eq!(
+            settler
+                .settle_and_insert(&payment_hash, &preimage, &new_invoice(vec![2]))
+                .await
+                .err()
+                .unwrap()
+                .to_string(),
+            SettleError::NoHtlcsToSettle.to_string()
+        );
+
+        let mut resolver = settler
+            .add_htlc(&payment_hash, "scid".to_string(), 0, 1_000)
+            .await;
+        assert_eq!(
+            settler
+                .settle_and_insert(&payment_hash, &preimage, &new_invoice(vec![2]))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            resolver.recv().await.unwrap(),
+            HtlcCallbackResponse::Resolve {
+                payment_key: hex::encode(&preimage),
+            }
+        );
+        assert_eq!(state_rx.try_recv().unwrap().state, InvoiceState::Paid);
+
+        assert_eq!(
+            helper
+                .get_by_payment_hash(&payment_hash)
+                .unwrap()
+                .unwrap()
+                .invoice
+                .state,
+            InvoiceState::Paid.to_string()
+        );
+        assert!(helper.get_by_payment_hash(&[2]).unwrap().is_some());
+    }
+
+    #[test]
+    fn replay_state_for() {
+        let database = test_database("settler-replay-state");
+        let helper = database.helper();
+        helper
+            .insert(&InvoiceInsertable {
+                label: Some("receipt".to_string()),
+                ..invoice_insertable(&[1])
+            })
+            .unwrap();
+
+        let settler = Settler::new(helper.clone(), 60, 15, 1_000);
+        let replayed_states = |payment_hash: &[u8]| {
+            settler
+                .replay_state_for(payment_hash)
+                .unwrap()
+                .into_iter()
+                .map(|update| update.state)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(replayed_states(&[2]).is_empty());
+        assert_eq!(replayed_states(&[1]), vec![InvoiceState::Unpaid]);
+
+        helper
+            .set_invoice_state(1, InvoiceState::Unpaid, InvoiceState::Accepted)
+            .unwrap();
+        helper
+            .set_invoice_state(1, InvoiceState::Accepted, InvoiceState::Paid)
+            .unwrap();
+
+        let replay = settler.replay_state_for(&[1]).unwrap();
+        assert_eq!(
+            replay.iter().map(|update| update.state).collect::<Vec<_>>(),
+            vec![
+                InvoiceState::Unpaid,
+                InvoiceState::Accepted,
+                InvoiceState::Paid
+            ]
+        );
+        assert!(replay
+            .iter()
+            .all(|update| update.payment_hash == vec![1]
+                && update.label == Some("receipt".to_string())));
+    }
+
+    #[test]
+    fn pre_accept_hooks() {
+        let settler = Settler::new(MockInvoiceHelper::new(), 60, 15, 1_000);
+        let invoice = |id: i64| Invoice {
+            cancel_reason: None,
+            expires_at: None,
+            label: None,
+            id,
+            preimage: None,
+            settled_at: None,
+            mpp_timeout: None,
+            settlement_deadline: None,
+            accepted_at: None,
+            payment_hash: vec![id as u8],
+            hash_algorithm: "sha256".to_string(),
+            bolt11: "".to_string(),
+            created_at: Default::default(),
+            state: InvoiceState::Unpaid.to_string(),
+        };
+        assert!(settler.run_pre_accept_hooks(&invoice(1), 1));
+
+        let handle = settler.register_pre_accept_hook(Box::new(|invoice, num_htlcs| {
+            invoice.id != 1 && num_htlcs < 3
+        }));
+        assert!(!settler.run_pre_accept_hooks(&invoice(1), 1));
+        assert!(settler.run_pre_accept_hooks(&invoice(2), 2));
+        assert!(!settler.run_pre_accept_hooks(&invoice(2), 3));
+
+        assert!(settler.deregister_hook(handle));
+        assert!(!settler.deregister_hook(handle));
+        assert!(settler.run_pre_accept_hooks(&invoice(1), 1));
+    }
+
+    #[tokio::test]
+    async fn check_mpp_timeouts() {
+        let mut helper = MockInvoiceHelper::new();
+        helper
+            .expect_get_by_payment_hash()
+            .returning(|payment_hash| {
+                Ok(Some(HoldInvoice {
+                    invoice: Invoice {
+                        cancel_reason: None,
+                        expires_at: None,
+                        label: None,
+                        id: 1,
+                        preimage: None,
+                        settled_at: None,
+                        mpp_timeout: None,
+                        settlement_deadline: None,
+                        accepted_at: None,
+                        payment_hash: payment_hash.to_vec(),
+                        hash_algorithm: "sha256".to_string(),
+                        bolt11: "".to_string(),
+                        created_at: Default::default(),
+                        state: InvoiceState::Unpaid.to_string(),
+                    },
+                    htlcs: vec![Htlc {
+                        accepted_at: None,
+                        preimage: None,
+                        id: 21,
+                        invoice_id: 1,
+                        state: InvoiceState::Accepted.to_string(),
+                        scid: "scid".to_string(),
+                        channel_id: 2,
+                        msat: 1_000,
+                        created_at: Default::default(),
+                    }],
+                }))
+            });
+        helper
+            .expect_set_htlc_state_by_id()
+            .withf(|id, state, new_state| {
+                *id == 21
+                    && *state == InvoiceState::Accepted
+                    && *new_state == InvoiceState::Cancelled
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(1));
+
+        let mut settler = Settler::new(helper, 0, 0, 1_000);
+        let mut resolver = settler
+            .add_htlc(&vec![1], "scid".to_string(), 2, 1_000)
+            .await;
+
+        settler.check_mpp_timeouts().await;
+        assert_eq!(
+            resolver.try_recv().unwrap(),
+            HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::MppTimeout
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn check_mpp_timeouts_custom_timeout() {
+        async fn accepted_ago(settler: &Settler<MockInvoiceHelper>, secs: u64) {
+            for htlc in settler
+                .pending_htlcs
+                .lock()
+                .await
+                .get_mut(&vec![1])
+                .unwrap()
+            {
+                htlc.time = SystemTime::now() - Duration::from_secs(secs);
+            }
+        }
+
+        let mut helper = MockInvoiceHelper::new();
+        helper
+            .expect_get_by_payment_hash()
+            .returning(|payment_hash| {
+                Ok(Some(HoldInvoice {
+                    invoice: Invoice {
+                        cancel_reason: None,
+                        expires_at: None,
+                        label: None,
+                        id: 1,
+                        preimage: None,
+                        settled_at: None,
+                        mpp_timeout: Some(120),
+                        settlement_deadline: None,
+                        accepted_at: None,
+                        payment_hash: payment_hash.to_vec(),
+                        hash_algorithm: "sha256".to_string(),
+                        bolt11: "".to_string(),
+                        created_at: Default::default(),
+                        state: InvoiceState::Unpaid.to_string(),
+                    },
+                    htlcs: vec![Htlc {
+                        accepted_at: None,
+                        preimage: None,
+                        id: 21,
+                        invoice_id: 1,
+                        state: InvoiceState::Accepted.to_string(),
+                        scid: "scid".to_string(),
+                        channel_id: 2,
+                        msat: 1_000,
+                        created_at: Default::default(),
+                    }],
+                }))
+            });
+        helper
+            .expect_set_htlc_state_by_id()
+            .times(1)
+            .returning(|_, _, _| Ok(1));
+
+        let mut settler = Settler::new(helper, 60, 15, 1_000);
+        let mut resolver = settler
+            .add_htlc(&vec![1], "scid".to_string(), 2, 1_000)
+            .await;
+
+        // Past the default timeout, but within the one of the invoice
+        accepted_ago(&settler, 90).await;
+        settler.check_mpp_timeouts().await;
+        assert!(resolver.try_recv().is_err());
+
+        accepted_ago(&settler, 121).await;
+        settler.check_mpp_timeouts().await;
+        assert_eq!(
+            resolver.try_recv().unwrap(),
+            HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::MppTimeout
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn check_mpp_timeouts_accepted_during_fetch() {
+        let mut helper = MockInvoiceHelper::new();
+        let fetches = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        helper
+            .expect_get_by_payment_hash()
+            .times(2)
+            .returning(move |payment_hash| {
+                // Accepted between the fetch without the lock and the one with it
+                let state = if fetches.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                    InvoiceState::Unpaid
+                } else {
+                    InvoiceState::Accepted
+                };
+                Ok(Some(HoldInvoice {
+                    invoice: Invoice {
+                        cancel_reason: None,
+                        expires_at: None,
+                        label: None,
+                        id: 1,
+                        preimage: None,
+                        settled_at: None,
+                        mpp_timeout: None,
+                        settlement_deadline: None,
+                        accepted_at: None,
+                        payment_hash: payment_hash.to_vec(),
+                        hash_algorithm: "sha256".to_string(),
+                        bolt11: "".to_string(),
+                        created_at: Default::default(),
+                        state: state.to_string(),
+                    },
+                    htlcs: vec![],
+                }))
+            });
+        helper.expect_set_htlc_state_by_id().never();
+
+        let mut settler = Settler::new(helper, 0, 0, 1_000);
+        let mut resolver = settler
+            .add_htlc(&vec![1], "scid".to_string(), 2, 1_000)
+            .await;
+
+        settler.check_mpp_timeouts().await;
+        assert!(resolver.try_recv().is_err());
+        assert_eq!(settler.pending_count().await, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn check_mpp_timeouts_not_locked_during_fetch() {
+        fn blocking_helper(fetching: Arc<Barrier>, release: Arc<Barrier>) -> MockInvoiceHelper {
+            let mut helper = MockInvoiceHelper::new();
+            {
+                let (fetching, release) = (fetching.clone(), release.clone());
+                helper
+                    .expect_get_by_payment_hash()
+                    .returning(move |payment_hash| {
+                        // Blocks the fetch until the test added another HTLC
+                        fetching.wait();
+                        release.wait();
+                        Ok(Some(HoldInvoice {
+                            invoice: Invoice {
+                                cancel_reason: None,
+                                expires_at: None,
+                                label: None,
+                                id: 1,
+                                preimage: None,
+                                settled_at: None,
+                                mpp_timeout: None,
+                                settlement_deadline: None,
+                                accepted_at: None,
+                                payment_hash: payment_hash.to_vec(),
+                                hash_algorithm: "sha256".to_string(),
+                                bolt11: "".to_string(),
+                                created_at: Default::default(),
+                                state: InvoiceState::Unpaid.to_string(),
+                            },
+                            htlcs: vec![],
+                        }))
+                    });
+            }
+            helper
+                .expect_clone()
+                .returning(move || blocking_helper(fetching.clone(), release.clone()));
+            helper
+        }
+
+        let fetching = Arc::new(Barrier::new(2));
+        let release = Arc::new(Barrier::new(2));
+
+        let mut settler = Settler::new(
+            blocking_helper(fetching.clone(), release.clone()),
+            0,
+            15,
+            1_000,
+        );
+        let mut resolver = settler
+            .add_htlc(&vec![1], "scid".to_string(), 0, 1_000)
+            .await;
+
+        let checker = settler.clone();
+        let check = tokio::spawn(async move { checker.check_mpp_timeouts().await });
+
+        // Neither the first fetch nor the one before removing timed out parts holds the lock
+        for channel_id in [1, 2] {
+            let fetching = fetching.clone();
+            tokio::task::spawn_blocking(move || fetching.wait())
+                .await
+                .unwrap();
+
+            // Would never complete if the lock was held while fetching
+            tokio::time::timeout(
+                Duration::from_secs(10),
+                settler.add_htlc(&vec![2], "scid".to_string(), channel_id, 1_000),
+            )
+            .await
+            .unwrap();
+
+            let release = release.clone();
+            tokio::task::spawn_blocking(move || release.wait())
+                .await
+                .unwrap();
+        }
+
+        check.await.unwrap();
+        assert_eq!(
+            resolver.try_recv().unwrap(),
+            HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::MppTimeout
+            }
+        );
+        assert_eq!(settler.pending_htlcs.lock().await[&vec![2]].len(), 2);
+    }
+
+    #[tokio::test]
+    async fn set_mpp_timeout() {
+        let mut helper = MockInvoiceHelper::new();
+        helper
+            .expect_get_by_payment_hash()
+            .returning(|payment_hash| {
+                Ok(Some(HoldInvoice {
+                    invoice: Invoice {
+                        cancel_reason: None,
+                        expires_at: None,
+                        label: None,
+                        id: 1,
+                        preimage: None,
+                        settled_at: None,
+                        mpp_timeout: None,
+                        settlement_deadline: None,
+                        accepted_at: None,
+                        payment_hash: payment_hash.to_vec(),
+                        hash_algorithm: "sha256".to_string(),
+                        bolt11: "".to_string(),
+                        created_at: Default::default(),
+                        state: InvoiceState::Unpaid.to_string(),
+                    },
+                    htlcs: vec![],
+                }))
+            });
+        helper.expect_clone().returning(MockInvoiceHelper::new);
+
+        let mut settler = Settler::new(helper, 3_600, 0, 1_000);
+        let mut resolver = settler
+            .add_htlc(&vec![1], "scid".to_string(), 2, 1_000)
+            .await;
+
+        settler.check_mpp_timeouts().await;
+        assert!(resolver.try_recv().is_err());
+
+        // Clones share the timeout
+        settler.clone().set_mpp_timeout(0);
+        assert_eq!(settler.mpp_timeout(), Duration::ZERO);
+
+        settler.check_mpp_timeouts().await;
+        assert_eq!(
+            resolver.try_recv().unwrap(),
+            HtlcCallbackResponse::Fail {
+                failure_message: FailureMessage::MppTimeout
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn get_hold_summary() {
+        let mut settler = Settler::new(MockInvoiceHelper::new(), 60, 15, 1_000);
+        assert_eq!(
+            settler.get_hold_summary().await,
+            HoldSummary {
+                invoice_count: 0,
+                total_msat_locked: 0,
+                oldest_hold_seconds: None,
+            }
+        );
+
+        let _first = settler
+            .add_htlc(&vec![1], "scid".to_string(), 0, 1_000)
+            .await;
+        let _second = settler
+            .add_htlc(&vec![1], "scid".to_string(), 1, 2_500)
+            .await;
+        let _third = settler.add_htlc(&vec![2], "scid".to_string(), 2, 21).await;
+
+        let summary = settler.get_hold_summary().await;
+        assert_eq!(summary.invoice_count, 2);
+        assert_eq!(summary.total_msat_locked, 3_521);
+        assert!(summary.oldest_hold_seconds.unwrap() < 5);
+    }
+}
@@ -0,0 +1,258 @@
+use crate::settler::StateUpdate;
+use anyhow::{anyhow, Result};
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use chrono::Utc;
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+const SIGNATURE_HEADER: &str = "X-Hold-Signature";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RETRIES: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const QUEUE_SIZE: usize = 1_024;
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Payload {
+    payment_hash: String,
+    state: String,
+    invoice: String,
+    timestamp: i64,
+}
+
+impl From<StateUpdate> for Payload {
+    fn from(value: StateUpdate) -> Self {
+        Payload {
+            payment_hash: hex::encode(value.payment_hash),
+            state: value.state.to_string(),
+            invoice: value.bolt11,
+            timestamp: Utc::now().timestamp(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+    secret: Option<String>,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(url: String, secret: Option<String>) -> Result<Self> {
+        Ok(Webhook {
+            url,
+            secret,
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+        })
+    }
+
+    pub async fn listen(&self, mut state_rx: broadcast::Receiver<StateUpdate>) {
+        info!("Sending invoice state updates to webhook: {}", self.url);
+
+        // Deliveries are retried by a single worker in the background, so that updates
+        // arrive in the order they happened without blocking invoice updates
+        let (queue_tx, queue_rx) = mpsc::channel(QUEUE_SIZE);
+        let worker = self.clone();
+        tokio::spawn(async move {
+            worker.deliver(queue_rx).await;
+        });
+
+        loop {
+            match state_rx.recv().await {
+                Ok(update) => {
+                    if queue_tx.send(Payload::from(update)).await.is_err() {
+                        debug!("Webhook delivery worker stopped");
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Webhook skipped {} invoice state updates", skipped);
+                }
+                Err(RecvError::Closed) => {
+                    debug!("Invoice state update channel closed");
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn deliver(&self, mut queue_rx: mpsc::Receiver<Payload>) {
+        while let Some(payload) = queue_rx.recv().await {
+            let payment_hash = payload.payment_hash.clone();
+            if let Err(err) = self.send(payload).await {
+                error!(
+                    payment_hash = %payment_hash,
+                    error = %err,
+                    "Could not deliver webhook"
+                );
+            }
+        }
+    }
+
+    async fn send(&self, payload: Payload) -> Result<()> {
+        let body = serde_json::to_string(&payload)?;
+
+        let mut attempt = 0;
+        loop {
+            let err = match self.post(&body).await {
+                Ok(_) => return Ok(()),
+                Err(err) => err,
+            };
+
+            if attempt == MAX_RETRIES {
+                return Err(err);
+            }
+
+            let backoff = RETRY_BACKOFF * 2u32.pow(attempt);
+            attempt += 1;
+            warn!(
+                payment_hash = %payload.payment_hash,
+                error = %err,
+                attempt,
+                "Webhook delivery failed; retrying in {:?}",
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    async fn post(&self, body: &str) -> Result<()> {
+        let mut req = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+
+        if let Some(secret) = &self.secret {
+            req = req.header(SIGNATURE_HEADER, sign(secret, body));
+        }
+
+        let res = req.send().await?;
+        if !res.status().is_success() {
+            return Err(anyhow!("unexpected status code: {}", res.status()));
+        }
+
+        Ok(())
+    }
+}
+
+fn sign(secret: &str, body: &str) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body.as_bytes());
+    Hmac::<sha256::Hash>::from_engine(engine).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::model::InvoiceState;
+    use crate::settler::StateUpdate;
+    use crate::webhook::{sign, Payload, Webhook};
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::{Bytes, Incoming};
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::sync::{broadcast, mpsc};
+
+    #[test]
+    fn payload_from_state_update() {
+        let payload: Payload = StateUpdate {
+            payment_hash: vec![0, 1, 2],
+            bolt11: "lnbcrt1".to_string(),
+            label: None,
+            state: InvoiceState::Accepted,
+        }
+        .into();
+
+        assert_eq!(payload.payment_hash, "000102");
+        assert_eq!(payload.state, "accepted");
+        assert_eq!(payload.invoice, "lnbcrt1");
+        assert!(payload.timestamp > 0);
+    }
+
+    #[test]
+    fn sign_payload() {
+        // Test case 2 of RFC 4231
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn listen_delivers_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let (received_tx, mut received_rx) = mpsc::unbounded_channel::<String>();
+        let requests = Arc::new(AtomicU64::new(0));
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (received_tx, requests) = (received_tx.clone(), requests.clone());
+                tokio::spawn(async move {
+                    let _ = http1::Builder::new()
+                        .serve_connection(
+                            TokioIo::new(stream),
+                            service_fn(move |req: Request<Incoming>| {
+                                let (received_tx, requests) =
+                                    (received_tx.clone(), requests.clone());
+                                async move {
+                                    let body = req.into_body().collect().await.unwrap();
+                                    let payload: serde_json::Value =
+                                        serde_json::from_slice(&body.to_bytes()).unwrap();
+
+                                    // The first delivery fails and has to be retried
+                                    let mut res = Response::new(Full::new(Bytes::new()));
+                                    if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                                        *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                    } else {
+                                        received_tx
+                                            .send(payload["state"].as_str().unwrap().to_string())
+                                            .unwrap();
+                                    }
+
+                                    Ok::<_, Infallible>(res)
+                                }
+                            }),
+                        )
+                        .await;
+                });
+            }
+        });
+
+        let (state_tx, state_rx) = broadcast::channel(16);
+        let webhook = Webhook::new(url, None).unwrap();
+        let listen = tokio::spawn(async move { webhook.listen(state_rx).await });
+
+        for state in [InvoiceState::Accepted, InvoiceState::Paid] {
+            state_tx
+                .send(StateUpdate {
+                    payment_hash: vec![1],
+                    bolt11: "lnbcrt1".to_string(),
+                    label: None,
+                    state,
+                })
+                .unwrap();
+        }
+        drop(state_tx);
+        listen.await.unwrap();
+
+        assert_eq!(received_rx.recv().await.unwrap(), "accepted");
+        assert_eq!(received_rx.recv().await.unwrap(), "paid");
+    }
+}
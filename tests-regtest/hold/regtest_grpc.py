@@ -3,13 +3,18 @@ from __future__ import annotations
 import concurrent.futures
 import time
 
+import grpc
 import pytest
 
 from hold.protos.hold_pb2 import (
+    BatchCancelRequest,
+    BatchCancelResponse,
     CancelRequest,
     CleanRequest,
     GetInfoRequest,
     GetInfoResponse,
+    GetInvoiceRequest,
+    GetInvoiceResponse,
     Hop,
     Invoice,
     InvoiceRequest,
@@ -194,12 +199,59 @@ class TestGrpc:
                 assert decoded_hop["fee_proportional_millionths"] == hint.ppm_fee
                 assert decoded_hop["cltv_expiry_delta"] == hint.cltv_expiry_delta
 
+    def test_invoice_mpp_timeout(self, cl: HoldStub) -> None:
+        (_, payment_hash) = new_preimage_bytes()
+        cl.Invoice(
+            InvoiceRequest(payment_hash=payment_hash, amount_msat=1, mpp_timeout=30)
+        )
+
+        invoice: Invoice = cl.List(ListRequest(payment_hash=payment_hash)).invoices[0]
+        assert invoice.mpp_timeout == 30
+
+    def test_invoice_settlement_deadline(self, cl: HoldStub) -> None:
+        (_, payment_hash) = new_preimage_bytes()
+        cl.Invoice(
+            InvoiceRequest(
+                payment_hash=payment_hash, amount_msat=1, settlement_deadline=600
+            )
+        )
+
+        invoice: Invoice = cl.List(ListRequest(payment_hash=payment_hash)).invoices[0]
+        assert invoice.settlement_deadline == 600
+        assert not invoice.HasField("accepted_at")
+
     def test_list_all(self, cl: HoldStub) -> None:
         cl.Invoice(InvoiceRequest(payment_hash=new_preimage_bytes()[1], amount_msat=1))
 
         hold_list: ListResponse = cl.List(ListRequest())
         assert len(hold_list.invoices) > 0
 
+    def test_list_date_range(self, cl: HoldStub) -> None:
+        (_, payment_hash) = new_preimage_bytes()
+        invoice: InvoiceResponse = cl.Invoice(
+            InvoiceRequest(payment_hash=payment_hash, amount_msat=1)
+        )
+
+        now = int(time_now().timestamp())
+
+        hold_list: ListResponse = cl.List(
+            ListRequest(
+                created_range=ListRequest.CreatedRange(
+                    created_after=now - 60, created_before=now + 60
+                )
+            )
+        )
+        assert any(i.bolt11 == invoice.bolt11 for i in hold_list.invoices)
+
+        hold_list = cl.List(
+            ListRequest(
+                created_range=ListRequest.CreatedRange(
+                    created_after=now + 60, created_before=now + 120
+                )
+            )
+        )
+        assert len(hold_list.invoices) == 0
+
     def test_list_payment_hash(self, cl: HoldStub) -> None:
         (_, payment_hash) = new_preimage_bytes()
         invoice: InvoiceResponse = cl.Invoice(
@@ -218,29 +270,146 @@ class TestGrpc:
         hold_list: ListResponse = cl.List(ListRequest(payment_hash=payment_hash))
         assert len(hold_list.invoices) == 0
 
+    def test_get_invoice(self, cl: HoldStub) -> None:
+        (_, payment_hash) = new_preimage_bytes()
+        cl.Invoice(InvoiceRequest(payment_hash=payment_hash, amount_msat=21_000))
+
+        hold_list: ListResponse = cl.List(ListRequest(payment_hash=payment_hash))
+        res: GetInvoiceResponse = cl.GetInvoice(
+            GetInvoiceRequest(payment_hash=payment_hash)
+        )
+
+        assert res.HasField("invoice")
+        assert res.invoice == hold_list.invoices[0]
+
+    def test_get_invoice_not_found(self, cl: HoldStub) -> None:
+        (_, payment_hash) = new_preimage_bytes()
+
+        with pytest.raises(grpc.RpcError) as e:
+            cl.GetInvoice(GetInvoiceRequest(payment_hash=payment_hash))
+
+        assert e.value.code() == grpc.StatusCode.NOT_FOUND
+        assert e.value.details() == f"no invoice with payment hash {payment_hash.hex()}"
+
     def test_list_pagination(self, cl: HoldStub) -> None:
         for _ in range(10):
             (_, payment_hash) = new_preimage_bytes()
             cl.Invoice(InvoiceRequest(payment_hash=payment_hash, amount_msat=1))
 
         page: ListResponse = cl.List(
-            ListRequest(pagination=ListRequest.Pagination(index_start=0, limit=2))
+            ListRequest(pagination=ListRequest.Pagination(limit=2))
         )
         assert len(page.invoices) == 2
         assert page.invoices[0].id == 1
         assert page.invoices[1].id == 2
+        assert page.has_more
+        assert page.next_cursor == 2
 
         page: ListResponse = cl.List(
-            ListRequest(pagination=ListRequest.Pagination(index_start=2, limit=1))
+            ListRequest(
+                pagination=ListRequest.Pagination(after_id=page.next_cursor, limit=1)
+            )
         )
         assert len(page.invoices) == 1
-        assert page.invoices[0].id == 2
+        assert page.invoices[0].id == 3
+        assert page.has_more
+        assert page.next_cursor == 3
 
         page: ListResponse = cl.List(
-            ListRequest(pagination=ListRequest.Pagination(index_start=3, limit=5))
+            ListRequest(pagination=ListRequest.Pagination(after_id=3, limit=5))
         )
         assert len(page.invoices) == 5
-        assert page.invoices[0].id == 3
+        assert page.invoices[0].id == 4
+
+    def test_list_pagination_last_page(self, cl: HoldStub) -> None:
+        invoice_count = len(cl.List(ListRequest()).invoices)
+
+        ids = []
+        after_id = 0
+        while True:
+            page: ListResponse = cl.List(
+                ListRequest(
+                    pagination=ListRequest.Pagination(after_id=after_id, limit=3)
+                )
+            )
+            ids.extend(invoice.id for invoice in page.invoices)
+
+            if not page.has_more:
+                assert not page.HasField("next_cursor")
+                break
+
+            after_id = page.next_cursor
+
+        assert len(ids) == invoice_count
+        assert ids == sorted(ids)
+
+    def test_list_state(self, cl: HoldStub) -> None:
+        (_, payment_hash) = new_preimage_bytes()
+        cl.Invoice(InvoiceRequest(payment_hash=payment_hash, amount_msat=1))
+        cl.Cancel(CancelRequest(payment_hash=payment_hash))
+
+        res: ListResponse = cl.List(ListRequest(state=InvoiceState.CANCELLED))
+        assert len(res.invoices) > 0
+        assert all(i.state == InvoiceState.CANCELLED for i in res.invoices)
+        assert any(i.payment_hash == payment_hash for i in res.invoices)
+
+    def test_list_history(self, cl: HoldStub) -> None:
+        (_, payment_hash) = new_preimage_bytes()
+        cl.Invoice(InvoiceRequest(payment_hash=payment_hash, amount_msat=1))
+        cl.Cancel(CancelRequest(payment_hash=payment_hash))
+
+        invoice: Invoice = cl.List(ListRequest(payment_hash=payment_hash)).invoices[0]
+        assert len(invoice.state_log) == 0
+
+        invoice = cl.List(
+            ListRequest(payment_hash=payment_hash, history=True)
+        ).invoices[0]
+        assert len(invoice.state_log) == 1
+        assert invoice.state_log[0].old_state == InvoiceState.UNPAID
+        assert invoice.state_log[0].new_state == InvoiceState.CANCELLED
+        assert invoice.state_log[0].changed_at - int(time_now().timestamp()) < 2
+
+    def test_list_pagination_state(self, cl: HoldStub) -> None:
+        for _ in range(3):
+            (_, payment_hash) = new_preimage_bytes()
+            cl.Invoice(InvoiceRequest(payment_hash=payment_hash, amount_msat=1))
+
+        page: ListResponse = cl.List(
+            ListRequest(
+                pagination=ListRequest.Pagination(limit=2, state=InvoiceState.UNPAID)
+            )
+        )
+        assert len(page.invoices) == 2
+        assert all(i.state == InvoiceState.UNPAID for i in page.invoices)
+        assert page.invoices[0].id < page.invoices[1].id
+
+    def test_invoice_label(self, cl: HoldStub) -> None:
+        (_, payment_hash) = new_preimage_bytes()
+        label = f"swap-{payment_hash.hex()}"
+        cl.Invoice(InvoiceRequest(payment_hash=payment_hash, amount_msat=1, label=label))
+
+        res: ListResponse = cl.List(ListRequest(label=label))
+        assert len(res.invoices) == 1
+        assert res.invoices[0].payment_hash == payment_hash
+        assert res.invoices[0].label == label
+
+        (_, payment_hash) = new_preimage_bytes()
+        with pytest.raises(Exception) as e:
+            cl.Invoice(
+                InvoiceRequest(payment_hash=payment_hash, amount_msat=1, label=label)
+            )
+
+        assert e.value.code() == grpc.StatusCode.ALREADY_EXISTS
+
+    def test_cancel_invoice(self, cl: HoldStub) -> None:
+        (_, payment_hash) = new_preimage_bytes()
+        invoice: InvoiceResponse = cl.Invoice(
+            InvoiceRequest(payment_hash=payment_hash, amount_msat=1)
+        )
+        cl.Cancel(CancelRequest(invoice=invoice.bolt11))
+
+        res: ListResponse = cl.List(ListRequest(payment_hash=payment_hash))
+        assert res.invoices[0].state == InvoiceState.CANCELLED
 
     def test_clean_cancelled(self, cl: HoldStub) -> None:
         # One that we are not going to cancel which should not be cleaned
@@ -268,6 +437,23 @@ class TestGrpc:
         res = cl.List(ListRequest())
         assert len(res.invoices) > 0
 
+    def test_batch_cancel(self, cl: HoldStub) -> None:
+        (_, payment_hash) = new_preimage_bytes()
+        cl.Invoice(InvoiceRequest(payment_hash=payment_hash, amount_msat=1))
+
+        (_, payment_hash_unknown) = new_preimage_bytes()
+
+        res: BatchCancelResponse = cl.BatchCancel(
+            BatchCancelRequest(payment_hashes=[payment_hash, payment_hash_unknown])
+        )
+        assert list(res.cancelled) == [payment_hash]
+        assert len(res.failed) == 1
+        assert res.failed[0].payment_hash == payment_hash_unknown
+        assert res.failed[0].error == "invoice not found"
+
+        invoice: Invoice = cl.List(ListRequest(payment_hash=payment_hash)).invoices[0]
+        assert invoice.state == InvoiceState.CANCELLED
+
     def test_track_settle(self, cl: HoldStub) -> None:
         (preimage, payment_hash) = new_preimage_bytes()
         invoice: InvoiceResponse = cl.Invoice(
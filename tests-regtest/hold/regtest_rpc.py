@@ -1,6 +1,7 @@
 from __future__ import annotations
 
 import time
+from datetime import timedelta
 from typing import Any
 
 from hold.utils import LndPay, lightning, new_preimage, time_now
@@ -59,6 +60,40 @@ class TestRpc:
         assert len(list_entries) == 1
         check_unpaid_invoice(list_entries[0], payment_hash, invoice)
 
+    def test_list_state(self) -> None:
+        (_, payment_hash) = new_preimage()
+        invoice = lightning("holdinvoice", payment_hash, "1")["bolt11"]
+
+        list_unpaid = lightning("listholdinvoices", '""', '""', "unpaid")[
+            "holdinvoices"
+        ]
+        assert len(list_unpaid) > 0
+        assert all(e["state"] == "unpaid" for e in list_unpaid)
+        assert any(e["bolt11"] == invoice for e in list_unpaid)
+
+        list_paid = lightning("listholdinvoices", payment_hash, "null", "paid")[
+            "holdinvoices"
+        ]
+        assert len(list_paid) == 0
+
+    def test_list_date_range(self) -> None:
+        (_, payment_hash) = new_preimage()
+        invoice = lightning("holdinvoice", payment_hash, "1")["bolt11"]
+
+        now = time_now()
+        from_time = (now - timedelta(minutes=1)).isoformat()
+        to_time = (now + timedelta(minutes=1)).isoformat()
+
+        list_range = lightning(
+            "listholdinvoices", '""', '""', '""', from_time, to_time
+        )["holdinvoices"]
+        assert any(e["bolt11"] == invoice for e in list_range)
+
+        list_future = lightning("listholdinvoices", '""', '""', '""', to_time)[
+            "holdinvoices"
+        ]
+        assert len(list_future) == 0
+
     def test_settle(self) -> None:
         amount = 1_000
         (preimage, payment_hash) = new_preimage()
@@ -93,6 +128,32 @@ class TestRpc:
         # Settling again should not error
         assert lightning("settleholdinvoice", preimage) == {}
 
+    def test_batch_settle(self) -> None:
+        (preimage, payment_hash) = new_preimage()
+        invoice = lightning("holdinvoice", payment_hash, "1000")["bolt11"]
+
+        payer = LndPay(1, invoice)
+        payer.start()
+        time.sleep(1)
+
+        (preimage_unpaid, payment_hash_unpaid) = new_preimage()
+        lightning("holdinvoice", payment_hash_unpaid, "1000")
+
+        res = lightning(
+            "batchsettleholdinvoices", f'\'["{preimage}", "{preimage_unpaid}"]\''
+        )
+
+        payer.join()
+        assert payer.res["status"] == "SUCCEEDED"
+
+        assert res["settled"] == [payment_hash]
+        assert len(res["failed"]) == 1
+        assert res["failed"][0]["payment_hash"] == payment_hash_unpaid
+        assert res["failed"][0]["error"] == "no HTLCs to settle"
+
+        data = lightning("listholdinvoices", payment_hash)["holdinvoices"][0]
+        assert data["state"] == "paid"
+
     def test_cancel(self) -> None:
         (_, payment_hash) = new_preimage()
         invoice = lightning("holdinvoice", payment_hash, "1000")["bolt11"]
@@ -123,6 +184,101 @@ class TestRpc:
         # Cancelling again should not error
         assert lightning("cancelholdinvoice", payment_hash) == {}
 
+    def test_cancel_invoice(self) -> None:
+        (_, payment_hash) = new_preimage()
+        invoice = lightning("holdinvoice", payment_hash, "1000")["bolt11"]
+
+        assert lightning("cancelholdinvoice", invoice) == {}
+
+        data = lightning("listholdinvoices", payment_hash)["holdinvoices"][0]
+        assert data["state"] == "cancelled"
+
+    def test_cancel_invalid_params(self) -> None:
+        (_, payment_hash) = new_preimage()
+        invoice = lightning("holdinvoice", payment_hash, "1000")["bolt11"]
+
+        res = lightning("cancelholdinvoice", payment_hash, invoice)
+        assert (
+            res["message"]
+            == "invalid parameter: only one of payment_hash and invoice can be set"
+        )
+
+        res = lightning("cancelholdinvoice", payment_hash[:62])
+        assert (
+            res["message"]
+            == "invalid parameter: payment_hash has to be 64 hex characters"
+        )
+
+    def test_batch_cancel(self) -> None:
+        (_, payment_hash) = new_preimage()
+        invoice = lightning("holdinvoice", payment_hash, "1000")["bolt11"]
+
+        payer = LndPay(1, invoice)
+        payer.start()
+        time.sleep(1)
+
+        (_, payment_hash_unknown) = new_preimage()
+
+        res = lightning(
+            "batchcancelholdinvoices",
+            f'\'["{payment_hash}", "{payment_hash_unknown}"]\'',
+        )
+
+        payer.join()
+        assert payer.res["status"] == "FAILED"
+
+        assert res["cancelled"] == [payment_hash]
+        assert len(res["failed"]) == 1
+        assert res["failed"][0]["payment_hash"] == payment_hash_unknown
+        assert res["failed"][0]["error"] == "invoice not found"
+
+        data = lightning("listholdinvoices", payment_hash)["holdinvoices"][0]
+        assert data["state"] == "cancelled"
+
+    def test_wait_settled(self) -> None:
+        (preimage, payment_hash) = new_preimage()
+        invoice = lightning("holdinvoice", payment_hash, "1000")["bolt11"]
+
+        payer = LndPay(1, invoice)
+        payer.start()
+        time.sleep(1)
+
+        lightning("settleholdinvoice", preimage)
+        payer.join()
+
+        assert lightning("holdinvoicewait", payment_hash) == {
+            "state": "paid",
+            "preimage": preimage,
+        }
+
+    def test_wait_cancelled(self) -> None:
+        (_, payment_hash) = new_preimage()
+        lightning("holdinvoice", payment_hash, "1000")
+        lightning("cancelholdinvoice", payment_hash)
+
+        assert lightning("holdinvoicewait", payment_hash) == {"state": "cancelled"}
+
+    def test_wait_timeout(self) -> None:
+        (_, payment_hash) = new_preimage()
+        lightning("holdinvoice", payment_hash, "1000")
+
+        assert lightning("holdinvoicewait", payment_hash, "1") == {"error": "timeout"}
+
+    def test_stats(self) -> None:
+        lightning("holdinvoice", new_preimage()[1], "1")
+
+        stats = lightning("holdinvoicestats")
+        assert set(stats["count_by_state"].keys()) == {
+            "unpaid",
+            "accepted",
+            "paid",
+            "cancelled",
+        }
+        assert stats["count_by_state"]["unpaid"] > 0
+        assert stats["total_settled_msat"] >= 0
+        assert stats["pending_htlc_count"] >= 0
+        assert stats["oldest_unpaid_age_seconds"] >= 0
+
     def test_clean(self) -> None:
         # One that we are not going to cancel which should not be cleaned
         (_, payment_hash) = new_preimage()
@@ -145,3 +301,14 @@ class TestRpc:
 
         res = lightning("listholdinvoices")["holdinvoices"]
         assert len(res) > 0
+
+    def test_clean_dry_run(self) -> None:
+        (_, payment_hash) = new_preimage()
+        lightning("holdinvoice", payment_hash, "1000")
+        lightning("cancelholdinvoice", payment_hash)
+
+        res = lightning("cleanholdinvoices", "null", "cancelled", "true")
+        assert res["would_clean"] > 0
+
+        res = lightning("listholdinvoices", payment_hash)["holdinvoices"]
+        assert len(res) == 1
@@ -22,6 +22,19 @@ class TestState:
 
         channel.close()
 
+    def test_invoice_duplicate(self, cl: HoldStub) -> None:
+        (_, payment_hash) = new_preimage_bytes()
+        cl.Invoice(InvoiceRequest(payment_hash=payment_hash, amount_msat=1_000))
+
+        with pytest.raises(Exception) as e:
+            cl.Invoice(InvoiceRequest(payment_hash=payment_hash, amount_msat=1_000))
+
+        assert e.value.code() == grpc.StatusCode.ALREADY_EXISTS
+        assert (
+            e.value.details()
+            == f"invoice with payment hash {payment_hash.hex()} exists already"
+        )
+
     def test_invoice_settle_unpaid(self, cl: HoldStub) -> None:
         (preimage, payment_hash) = new_preimage_bytes()
         cl.Invoice(InvoiceRequest(payment_hash=payment_hash, amount_msat=1_000))
@@ -32,6 +45,33 @@ class TestState:
         assert e.value.code() == grpc.StatusCode.INTERNAL
         assert e.value.details() == "could not settle invoice: no HTLCs to settle"
 
+    def test_invoice_settle_cancelled(self, cl: HoldStub) -> None:
+        (preimage, payment_hash) = new_preimage_bytes()
+        cl.Invoice(InvoiceRequest(payment_hash=payment_hash, amount_msat=1_000))
+        cl.Cancel(CancelRequest(payment_hash=payment_hash))
+
+        with pytest.raises(Exception) as e:
+            cl.Settle(SettleRequest(payment_preimage=preimage))
+
+        assert e.value.code() == grpc.StatusCode.INTERNAL
+        assert e.value.details() == "could not settle invoice: invoice was cancelled already"
+
+    def test_invoice_settle_paid(self, cl: HoldStub) -> None:
+        (preimage, payment_hash) = new_preimage_bytes()
+        invoice: InvoiceResponse = cl.Invoice(
+            InvoiceRequest(payment_hash=payment_hash, amount_msat=1_000)
+        )
+
+        pay = LndPay(1, invoice.bolt11)
+        pay.start()
+        time.sleep(1)
+
+        cl.Settle(SettleRequest(payment_preimage=preimage))
+        pay.join()
+
+        # Settling again is idempotent
+        cl.Settle(SettleRequest(payment_preimage=preimage))
+
     def test_invoice_cancel_paid(self, cl: HoldStub) -> None:
         (preimage, payment_hash) = new_preimage_bytes()
         invoice: InvoiceResponse = cl.Invoice(